@@ -0,0 +1,2 @@
+-- Settings other than the plex url and tokens, stored as a JSON config layer
+ALTER TABLE config ADD COLUMN settings TEXT;
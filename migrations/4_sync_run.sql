@@ -0,0 +1,11 @@
+CREATE TABLE sync_run (
+  id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+  status TEXT NOT NULL,
+  dry_run BOOLEAN NOT NULL,
+  started_at INTEGER NOT NULL,
+  finished_at INTEGER,
+  updated INT NOT NULL DEFAULT 0,
+  failed INT NOT NULL DEFAULT 0,
+  unchanged INT NOT NULL DEFAULT 0,
+  error TEXT
+);
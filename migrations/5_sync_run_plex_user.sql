@@ -0,0 +1 @@
+ALTER TABLE sync_run ADD COLUMN plex_user TEXT;
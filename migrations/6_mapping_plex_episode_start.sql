@@ -0,0 +1,5 @@
+-- Mappings used to count Plex episodes from 0. Entries chained after the first one in a season
+-- were stored as the previous start plus its length, so every mapping of a season whose first
+-- mapping starts at 0 is shifted, not only that first one.
+UPDATE mapping SET plex_episode_start = plex_episode_start + 1
+WHERE plex_id IN (SELECT plex_id FROM mapping WHERE plex_episode_start = 0);
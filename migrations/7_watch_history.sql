@@ -0,0 +1,7 @@
+CREATE TABLE watch_history (
+  id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+  account_id INTEGER NOT NULL,
+  rating_key TEXT NOT NULL,
+  viewed_at INTEGER NOT NULL,
+  UNIQUE (account_id, rating_key, viewed_at)
+);
@@ -0,0 +1,4 @@
+CREATE TABLE client (
+  id INTEGER NOT NULL PRIMARY KEY,
+  identifier TEXT NOT NULL
+);
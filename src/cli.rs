@@ -0,0 +1,543 @@
+use std::{process::ExitCode, time::Duration};
+
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use log::{error, info};
+
+use crate::{
+    services::{
+        anime_list_service::{
+            anilist_service::AnilistService, anime_list_service::AnimeListService,
+        },
+        api::api::{self, ApiState},
+        config::config::{build_config, load_config, merge_layers, Config},
+        dbstore::{
+            dbstore::DbStore,
+            sqlite::{Mapping, Sqlite},
+        },
+        mapping_handler::id_mappings,
+        media_server::media_server::MediaServer,
+        metrics::metrics,
+        plex::{
+            library_selector::{select_libraries, LibrarySelector},
+            plex_api::PlexInterface,
+            plex_tv::{load_client_identifier, PlexResource, PlexTv},
+        },
+        scheduler::scheduler::Scheduler,
+        sync_service::{sync_report::SyncReport, sync_runner::run_sync},
+    },
+    utils::get_db_file_location,
+};
+
+#[derive(Parser)]
+#[command(version, about = "Sync Anilist with a Plex library")]
+pub struct Cli {
+    /// Path to a TOML config file
+    #[arg(long, global = true, env = "CONFIG_FILE")]
+    pub config: Option<String>,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Sync Plex watch state to Anilist
+    Sync(SyncArgs),
+    /// Run the scheduler and sync on the configured schedule. This is the default command
+    Daemon,
+    /// Inspect and edit Plex to Anilist mappings
+    #[command(subcommand)]
+    Mappings(MappingsCommand),
+    /// Manage cached Anilist responses
+    #[command(subcommand)]
+    Cache(CacheCommand),
+    /// Inspect Plex libraries
+    #[command(subcommand)]
+    Libraries(LibrariesCommand),
+    /// Manage the dataset linking tvdb, tmdb and imdb ids to Anilist
+    #[command(subcommand)]
+    IdMappings(IdMappingsCommand),
+    /// Sign in to Plex and pick the server to sync
+    #[command(subcommand)]
+    Plex(PlexCommand),
+}
+
+#[derive(Args)]
+pub struct SyncArgs {
+    /// Run a single sync and exit. The exit code is non-zero if any update failed
+    #[arg(long)]
+    pub once: bool,
+    /// Show what would change on Anilist without changing anything or saving new mappings.
+    /// Implies --once
+    #[arg(long)]
+    pub dry_run: bool,
+    /// How to print the changes. They're printed by default for dry runs
+    #[arg(long, value_enum)]
+    pub output: Option<OutputFormat>,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+pub enum OutputFormat {
+    Table,
+    Json,
+}
+
+#[derive(Subcommand)]
+pub enum MappingsCommand {
+    /// List all mappings
+    List,
+    /// Show a single mapping
+    Show { id: u32 },
+    /// Ignore a mapping so it is skipped during sync
+    Ignore { id: u32 },
+    /// Enable a mapping and stop ignoring it
+    Enable { id: u32 },
+    /// Delete a mapping so it is recreated on the next sync
+    Delete { id: u32 },
+}
+
+#[derive(Subcommand)]
+pub enum CacheCommand {
+    /// Clear the cached Anilist search results
+    Clear,
+}
+
+#[derive(Subcommand)]
+pub enum LibrariesCommand {
+    /// List the libraries on the Plex server
+    List,
+    /// Pick the libraries to sync by key. Without keys the libraries that look like anime
+    /// libraries are picked
+    Select { keys: Vec<String> },
+}
+
+#[derive(Subcommand)]
+pub enum IdMappingsCommand {
+    /// Download the latest dataset to `id_mapping_file`
+    Update,
+}
+
+#[derive(Subcommand)]
+pub enum PlexCommand {
+    /// Sign in to plex.tv with a PIN and save the token
+    Login,
+    /// List the Plex servers the account can use and their connections
+    Servers,
+    /// Sync the server with this name or client identifier, through its best connection
+    UseServer { name: String },
+}
+
+/// How often plex.tv is asked whether the user signed in, and for how long
+const PIN_POLL_INTERVAL: Duration = Duration::from_secs(2);
+const PIN_TIMEOUT: Duration = Duration::from_secs(600);
+
+pub async fn run(cli: Cli) -> ExitCode {
+    info!("Performing database migrations");
+    let mut db_store = Sqlite::new(&get_db_file_location()).await;
+    db_store.migrate().await;
+    load_client_identifier(&db_store).await;
+
+    let config_file = cli.config.as_deref();
+    let result = match cli.command.unwrap_or(Command::Daemon) {
+        Command::Sync(args) if args.once || args.dry_run => {
+            sync_once(&db_store, config_file, args).await
+        }
+        Command::Sync(_) | Command::Daemon => daemon(db_store, config_file).await,
+        Command::Mappings(command) => mappings(&db_store, config_file, command).await,
+        Command::Cache(CacheCommand::Clear) => {
+            db_store.clear_anime_search_cache().await;
+            Ok(ExitCode::SUCCESS)
+        }
+        Command::Libraries(LibrariesCommand::List) => list_libraries(&db_store, config_file).await,
+        Command::Libraries(LibrariesCommand::Select { keys }) => {
+            choose_libraries(&db_store, config_file, keys).await
+        }
+        Command::IdMappings(IdMappingsCommand::Update) => {
+            update_id_mappings(&db_store, config_file).await
+        }
+        Command::Plex(PlexCommand::Login) => plex_login(&db_store, config_file).await,
+        Command::Plex(PlexCommand::Servers) => list_servers(&db_store, config_file).await,
+        Command::Plex(PlexCommand::UseServer { name }) => {
+            use_server(&db_store, config_file, &name).await
+        }
+    };
+
+    match result {
+        Ok(x) => x,
+        Err(e) => {
+            error!("{:#}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+async fn get_config(db_store: &Sqlite, config_file: Option<&str>) -> Result<Config, anyhow::Error> {
+    info!("Loading config");
+    load_config(db_store, config_file)
+        .await
+        .map_err(|e| anyhow::anyhow!("Invalid configuration. {}", e))
+}
+
+async fn sync_once(
+    db_store: &Sqlite,
+    config_file: Option<&str>,
+    args: SyncArgs,
+) -> Result<ExitCode, anyhow::Error> {
+    let mut config = get_config(db_store, config_file).await?;
+    config.dry_run |= args.dry_run;
+
+    // Each Plex user gets their own report
+    let mut success = true;
+    for result in run_sync(db_store, &config).await {
+        let report = match result {
+            Ok(x) => x,
+            Err(e) => {
+                error!("{:#}", e);
+                success = false;
+                continue;
+            }
+        };
+
+        if config.dry_run || args.output.is_some() {
+            print_report(&report, args.output.unwrap_or(OutputFormat::Table))?;
+        }
+        success &= report.is_success();
+    }
+
+    match success {
+        true => Ok(ExitCode::SUCCESS),
+        false => Ok(ExitCode::FAILURE),
+    }
+}
+
+fn print_report(report: &SyncReport, format: OutputFormat) -> Result<(), anyhow::Error> {
+    match format {
+        OutputFormat::Table => println!("{}", report.to_table()),
+        OutputFormat::Json => println!("{}", serde_json::to_string_pretty(report)?),
+    }
+
+    Ok(())
+}
+
+async fn daemon(db_store: Sqlite, config_file: Option<&str>) -> Result<ExitCode, anyhow::Error> {
+    let config = get_config(&db_store, config_file).await?;
+
+    let scheduler = Scheduler::new(db_store.clone(), config_file.map(String::from));
+
+    if config.api_enabled {
+        metrics::init();
+        let state = ApiState {
+            db_store,
+            config_file: config_file.map(String::from),
+            scheduler: scheduler.handle(),
+            anilist_url: None,
+        };
+        let address = config.api_address;
+        tokio::spawn(async move {
+            if let Err(e) = api::serve(address, state).await {
+                error!("API server stopped. {:#}", e);
+            }
+        });
+    }
+
+    scheduler.run(config).await;
+
+    Ok(ExitCode::SUCCESS)
+}
+
+async fn mappings(
+    db_store: &Sqlite,
+    config_file: Option<&str>,
+    command: MappingsCommand,
+) -> Result<ExitCode, anyhow::Error> {
+    match command {
+        MappingsCommand::List => {
+            let mappings = db_store.get_mappings().await?;
+            println!(
+                "{:>6}  {:>10}  {:>10}  {:>10}  {:>8}  {:>7}  {:>7}",
+                "id", "series", "season", "anilist", "episodes", "enabled", "ignored"
+            );
+            for mapping in mappings.iter() {
+                println!(
+                    "{:>6}  {:>10}  {:>10}  {:>10}  {:>8}  {:>7}  {:>7}",
+                    mapping.id,
+                    mapping.plex_series_id,
+                    mapping.plex_id,
+                    mapping.anime_list_id,
+                    get_episode_range(mapping),
+                    mapping.enabled,
+                    mapping.ignored
+                );
+            }
+        }
+        MappingsCommand::Show { id } => {
+            let mapping = get_mapping(db_store, id).await?;
+            let config = get_config(db_store, config_file).await?;
+            let anilist_service =
+                AnilistService::new(config.get_anilist_token()?, db_store.clone(), None);
+            let title = match anilist_service.get_anime(mapping.anime_list_id).await {
+                Ok(Some(x)) => x.get_title().to_string(),
+                _ => "Unknown".to_string(),
+            };
+
+            println!("Id:             {}", mapping.id);
+            println!("Plex series:    {}", mapping.plex_series_id);
+            println!("Plex season:    {}", mapping.plex_id);
+            println!("Plex episodes:  {}", get_episode_range(&mapping));
+            println!("Anilist id:     {}", mapping.anime_list_id);
+            println!("Anilist title:  {}", title);
+            println!("Episode start:  {}", mapping.episode_start);
+            println!("Enabled:        {}", mapping.enabled);
+            println!("Ignored:        {}", mapping.ignored);
+        }
+        MappingsCommand::Ignore { id } => {
+            let mut mapping = get_mapping(db_store, id).await?;
+            mapping.ignored = true;
+            db_store.update_mapping(&mapping).await?;
+            println!("Ignored mapping {}", id);
+        }
+        MappingsCommand::Enable { id } => {
+            let mut mapping = get_mapping(db_store, id).await?;
+            mapping.enabled = true;
+            mapping.ignored = false;
+            db_store.update_mapping(&mapping).await?;
+            println!("Enabled mapping {}", id);
+        }
+        MappingsCommand::Delete { id } => {
+            if !db_store.delete_mapping(id).await? {
+                anyhow::bail!("No mapping found with id {}", id);
+            }
+            println!("Deleted mapping {}", id);
+        }
+    }
+
+    Ok(ExitCode::SUCCESS)
+}
+
+async fn get_mapping(db_store: &Sqlite, id: u32) -> Result<Mapping, anyhow::Error> {
+    match db_store.get_mapping(id).await? {
+        Some(x) => Ok(x),
+        None => anyhow::bail!("No mapping found with id {}", id),
+    }
+}
+
+fn get_episode_range(mapping: &Mapping) -> String {
+    format!(
+        "{}-{}",
+        mapping.plex_episode_start,
+        mapping.plex_episode_start + mapping.season_length.saturating_sub(1)
+    )
+}
+
+async fn list_libraries(
+    db_store: &Sqlite,
+    config_file: Option<&str>,
+) -> Result<ExitCode, anyhow::Error> {
+    let config = get_config(db_store, config_file).await?;
+    let plex_service = MediaServer::connect(&config).await?;
+
+    let libraries = plex_service.get_libraries().await?;
+    let selected = select_libraries(libraries.clone(), &config.libraries);
+
+    println!(
+        "{:>4}  {:<6}  {:<8}  {:<5}  {:<32}  {:<24}  {:<8}  title",
+        "key", "type", "selected", "anime", "agent", "scanner", "language"
+    );
+    for library in libraries.iter() {
+        let is_selected = selected.iter().any(|x| x.key == library.key);
+        println!(
+            "{:>4}  {:<6}  {:<8}  {:<5}  {:<32}  {:<24}  {:<8}  {}",
+            library.key,
+            library.library_type,
+            is_selected,
+            library.is_likely_anime(),
+            library.agent,
+            library.scanner,
+            library.language,
+            library.title
+        );
+        for location in library.locations.iter() {
+            println!("{:>4}  {}", "", location.path);
+        }
+    }
+
+    Ok(ExitCode::SUCCESS)
+}
+
+async fn choose_libraries(
+    db_store: &Sqlite,
+    config_file: Option<&str>,
+    keys: Vec<String>,
+) -> Result<ExitCode, anyhow::Error> {
+    let config = get_config(db_store, config_file).await?;
+    let plex_service = MediaServer::connect(&config).await?;
+    let libraries = plex_service.get_libraries().await?;
+
+    let chosen: Vec<_> = match keys.is_empty() {
+        true => libraries.iter().filter(|x| x.is_likely_anime()).collect(),
+        false => libraries.iter().filter(|x| keys.contains(&x.key)).collect(),
+    };
+    if chosen.is_empty() || (!keys.is_empty() && chosen.len() != keys.len()) {
+        anyhow::bail!(
+            "Couldn't find the libraries to sync, run 'libraries list' to see the library keys"
+        );
+    }
+
+    let selectors: Vec<LibrarySelector> = chosen
+        .iter()
+        .map(|x| LibrarySelector::Key(x.key.clone()))
+        .collect();
+    let mut db_layer = db_store.get_config().await;
+    db_layer.libraries = Some(selectors.iter().map(|x| x.to_string()).collect());
+    let config = build_config(db_layer.clone(), config_file)?;
+    db_store.save_config(&db_layer).await?;
+
+    for library in chosen {
+        println!("Syncing '{}' ({})", library.title, library.key);
+    }
+    if config.libraries != selectors {
+        println!("PLEX_LIBRARIES or the config file sets other libraries, which are used instead");
+    }
+
+    Ok(ExitCode::SUCCESS)
+}
+
+async fn update_id_mappings(
+    db_store: &Sqlite,
+    config_file: Option<&str>,
+) -> Result<ExitCode, anyhow::Error> {
+    // Only the file path is needed, so this works before Plex and Anilist are set up
+    let layer = merge_layers(db_store.get_config().await, config_file)?;
+    let path = layer
+        .id_mapping_file
+        .unwrap_or(id_mappings::DEFAULT_ID_MAPPING_FILE.to_string());
+
+    let count = id_mappings::download(&path).await?;
+    println!("Saved {} id mappings to '{}'", count, path);
+
+    Ok(ExitCode::SUCCESS)
+}
+
+async fn plex_login(
+    db_store: &Sqlite,
+    config_file: Option<&str>,
+) -> Result<ExitCode, anyhow::Error> {
+    let plex_tv = PlexTv::without_token(None);
+    let pin = plex_tv.create_pin().await?;
+    println!("Sign in to Plex at");
+    println!("{}", PlexTv::get_auth_url(&pin));
+
+    let started = tokio::time::Instant::now();
+    let token = loop {
+        tokio::time::sleep(PIN_POLL_INTERVAL).await;
+        if let Some(x) = plex_tv.check_pin(&pin).await?.auth_token {
+            break x;
+        }
+        if started.elapsed() > PIN_TIMEOUT {
+            anyhow::bail!("Nobody signed in to Plex in time, run 'plex login' again");
+        }
+    };
+
+    let mut db_layer = db_store.get_config().await;
+    db_layer.plex_token = Some(token.clone());
+    db_store.save_config(&db_layer).await?;
+    println!("Signed in to Plex");
+    if merge_layers(db_layer, config_file)?.plex_token.as_ref() != Some(&token) {
+        println!("PLEX_TOKEN or the config file sets another token, which is used instead");
+    }
+
+    print_servers(&PlexTv::new(token, None), None).await?;
+    println!("Run 'plex use-server <name>' to pick the server to sync");
+
+    Ok(ExitCode::SUCCESS)
+}
+
+async fn list_servers(
+    db_store: &Sqlite,
+    config_file: Option<&str>,
+) -> Result<ExitCode, anyhow::Error> {
+    let layer = merge_layers(db_store.get_config().await, config_file)?;
+    let token = match layer.plex_token {
+        Some(x) => x,
+        None => anyhow::bail!("Sign in with 'plex login' or set PLEX_TOKEN first"),
+    };
+
+    print_servers(&PlexTv::new(token, None), layer.plex_url.as_deref()).await?;
+    Ok(ExitCode::SUCCESS)
+}
+
+/// Prints the servers with their connections, `plex_url` is marked when it's one of them.
+async fn print_servers(plex_tv: &PlexTv, plex_url: Option<&str>) -> Result<(), anyhow::Error> {
+    let servers = plex_tv.get_servers().await?;
+    if servers.is_empty() {
+        println!("The account has no Plex servers");
+    }
+
+    for server in servers.iter() {
+        let owner = match server.owned {
+            true => "owned",
+            false => "shared",
+        };
+        println!("{} ({}, {})", server.name, owner, server.client_identifier);
+        for connection in server.connections.iter() {
+            let kind = match (connection.relay, connection.local) {
+                (true, _) => "relay",
+                (false, true) => "local",
+                (false, false) => "remote",
+            };
+            let selected = match plex_url.is_some_and(|x| x.trim_end_matches('/') == connection.uri)
+            {
+                true => "*",
+                false => " ",
+            };
+            println!("  {} {:<6}  {}", selected, kind, connection.uri);
+        }
+    }
+
+    Ok(())
+}
+
+async fn use_server(
+    db_store: &Sqlite,
+    config_file: Option<&str>,
+    name: &str,
+) -> Result<ExitCode, anyhow::Error> {
+    let mut db_layer = db_store.get_config().await;
+    let token = match merge_layers(db_layer.clone(), config_file)?.plex_token {
+        Some(x) => x,
+        None => anyhow::bail!("Sign in with 'plex login' or set PLEX_TOKEN first"),
+    };
+
+    let servers = PlexTv::new(token, None).get_servers().await?;
+    let server: &PlexResource = match servers
+        .iter()
+        .find(|x| x.name.eq_ignore_ascii_case(name) || x.client_identifier == name)
+    {
+        Some(x) => x,
+        None => anyhow::bail!(
+            "There is no Plex server called '{}', run 'plex servers' to see them",
+            name
+        ),
+    };
+    let connection = match server.get_best_connection() {
+        Some(x) => x,
+        None => anyhow::bail!("Plex doesn't know how to connect to '{}'", server.name),
+    };
+
+    db_layer.plex_url = Some(connection.uri.clone());
+    // Servers shared with the account have their own token
+    if !server.owned {
+        if let Some(x) = &server.access_token {
+            db_layer.plex_token = Some(x.clone());
+        }
+    }
+    let config = build_config(db_layer.clone(), config_file)?;
+    db_store.save_config(&db_layer).await?;
+
+    println!("Syncing '{}' through {}", server.name, connection.uri);
+    if config.plex_url != connection.uri {
+        println!("PLEX_URL or the config file sets another server, which is used instead");
+    }
+
+    Ok(ExitCode::SUCCESS)
+}
@@ -1,20 +1,46 @@
-use std::{thread, time::Duration};
+use std::{fmt, thread, time::Duration};
 
 use async_trait::async_trait;
 use log::{error, info};
-use reqwest::header::{self, HeaderMap, HeaderValue, ACCEPT, AUTHORIZATION, CONTENT_TYPE};
+use reqwest::{
+    header::{self, HeaderMap, HeaderValue, ACCEPT, AUTHORIZATION, CONTENT_TYPE},
+    StatusCode,
+};
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use serde_json::json;
 
-use crate::services::dbstore::dbstore::DbStore;
+use crate::services::{dbstore::dbstore::DbStore, metrics::metrics};
 
 use super::anime_list_service::{
-    AnilistWatchStatus, AnimeListEntry, AnimeListService, AnimeResult, RelationType,
+    AnilistWatchStatus, AnimeListEntry, AnimeListService, AnimeResult, MediaFormat, RelationType,
 };
 
-// We need to use to visit this page then we'll redirect them back to the main page to get the auth
-// code token thing https://anilist.gitbook.io/anilist-apiv2-docs/overview/oauth/implicit-grant
-// https://anilist.co/api/v2/oauth/authorize?client_id=4688&response_type=token
+pub const ANILIST_AUTHORIZE_URL: &str = "https://anilist.co/api/v2/oauth/authorize";
+
+/// Returned when Anilist rejects the token because it has expired or been revoked.
+#[derive(Debug)]
+pub struct InvalidTokenError;
+
+impl fmt::Display for InvalidTokenError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "The Anilist token is invalid or has expired, log in to Anilist again"
+        )
+    }
+}
+
+impl std::error::Error for InvalidTokenError {}
+
+/// The url users are sent to when logging in, Anilist redirects back with the token in the url
+/// fragment. https://anilist.gitbook.io/anilist-apiv2-docs/overview/oauth/implicit-grant
+pub fn get_authorize_url(client_id: &str) -> String {
+    format!(
+        "{}?client_id={}&response_type=token",
+        ANILIST_AUTHORIZE_URL, client_id
+    )
+}
+
 pub struct AnilistService<K: DbStore> {
     anilist_token: String,
     dbstore: K,
@@ -54,10 +80,14 @@ where
         headers
     }
 
+    /// `query` names the request in the metrics.
     async fn make_request<R: DeserializeOwned, D: Serialize>(
         &self,
+        query: &'static str,
         data: D,
     ) -> Result<AnilistResponse<R>, anyhow::Error> {
+        metrics::record_anilist_request(query);
+
         // Sleep to avoid rate limit
         let ten_millis = Duration::from_millis(1000);
         thread::sleep(ten_millis);
@@ -69,6 +99,7 @@ where
             .headers(self.get_headers())
             .send()
             .await?;
+        let status = response.status();
 
         let response_body = match response.text().await {
             Ok(x) => x,
@@ -78,6 +109,10 @@ where
             }
         };
 
+        if is_invalid_token_response(status, &response_body) {
+            return Err(InvalidTokenError.into());
+        }
+
         let response: AnilistResponse<R> = match serde_json::from_str(&response_body) {
             Ok(x) => x,
             Err(e) => {
@@ -92,80 +127,36 @@ where
         Ok(response)
     }
 
-    pub async fn get_user(&self) -> Result<AnilistUser, anyhow::Error> {
-        let query = r#"query {
-                        Viewer {
-                            id
-                            name
-                        }
-                    }"#;
-
-        let data = GraphQlBody {
-            query: String::from(query),
-            variables: json!({}),
+    /// Searches Anilist, or the cache, for anime matching `search_term`. Searches limited to a
+    /// format are cached separately.
+    async fn search(
+        &self,
+        search_term: &str,
+        format: Option<MediaFormat>,
+    ) -> Result<Vec<AnimeResult>, anyhow::Error> {
+        let cache_key = match &format {
+            Some(x) => format!("{:?}:{}", x, search_term),
+            None => search_term.to_string(),
         };
-
-        let result: AnilistResponse<AnilistUserResponse> = self.make_request(data).await?;
-
-        return Ok(result.data.viewer);
-    }
-}
-
-#[derive(Deserialize)]
-struct AnilistUserResponse {
-    #[serde(rename = "Viewer")]
-    viewer: AnilistUser,
-}
-
-#[derive(Deserialize)]
-pub struct AnilistUser {
-    pub id: u32,
-    pub name: String,
-}
-
-#[derive(Serialize)]
-struct MediaListCollectionVars {
-    user_id: u32,
-}
-
-#[derive(Serialize)]
-struct SearchAnimeVars {
-    anime_name: String,
-}
-
-#[derive(Serialize)]
-struct GetAnimeVars {
-    anime_id: u32,
-}
-
-#[derive(Serialize)]
-struct UpdateAnimeListEntryVars {
-    media_id: u32,
-    status: AnilistWatchStatus,
-    progress: u16,
-}
-
-#[async_trait]
-impl<J: DbStore> AnimeListService for AnilistService<J> {
-    async fn search_anime(&self, search_term: &str) -> Result<Vec<AnimeResult>, anyhow::Error> {
         let result = self
             .dbstore
-            .get_cached_anime_search_result(search_term)
+            .get_cached_anime_search_result(&cache_key)
             .await;
+        metrics::record_cache_lookup("anime_search", result.is_some());
 
         if let Some(result) = result {
             info!(
                 "Found cached anilist search response for search term: {}",
-                search_term
+                cache_key
             );
             return Ok(result);
         }
 
-        info!("Quering anilist API for search term: {}", search_term);
+        info!("Quering anilist API for search term: {}", cache_key);
 
-        let query = r#"query ($anime_name: String) {
+        let query = r#"query ($anime_name: String, $format: MediaFormat) {
                 Page(perPage: 10) {
-                    media(search: $anime_name, type: ANIME, sort: SEARCH_MATCH) {
+                    media(search: $anime_name, type: ANIME, format: $format, sort: SEARCH_MATCH) {
                         id
                         format
                         episodes
@@ -211,23 +202,123 @@ impl<J: DbStore> AnimeListService for AnilistService<J> {
 
         let vars = SearchAnimeVars {
             anime_name: search_term.to_string(),
+            format,
         };
         let data = GraphQlBody {
             query: String::from(query),
             variables: json!(vars),
         };
 
-        let result: AnilistResponse<AnimeSearchRequestResult> = self.make_request(data).await?;
+        let result: AnilistResponse<AnimeSearchRequestResult> =
+            self.make_request("search_anime", data).await?;
 
         self.dbstore
-            .save_cached_anime_search_result(search_term, result.data.page.media.clone())
+            .save_cached_anime_search_result(&cache_key, result.data.page.media.clone())
             .await;
 
         return Ok(result.data.page.media);
     }
 
+    pub async fn get_user(&self) -> Result<AnilistUser, anyhow::Error> {
+        let query = r#"query {
+                        Viewer {
+                            id
+                            name
+                        }
+                    }"#;
+
+        let data = GraphQlBody {
+            query: String::from(query),
+            variables: json!({}),
+        };
+
+        let result: AnilistResponse<AnilistUserResponse> =
+            self.make_request("get_user", data).await?;
+
+        return Ok(result.data.viewer);
+    }
+}
+
+/// Anilist answers requests made with an expired token with a 401, and requests with a malformed
+/// token with a 400 and an "Invalid token" error. Other 400s are mistakes in the query.
+fn is_invalid_token_response(status: StatusCode, response_body: &str) -> bool {
+    match status {
+        StatusCode::UNAUTHORIZED => true,
+        StatusCode::BAD_REQUEST => serde_json::from_str::<AnilistErrorResponse>(response_body)
+            .map(|response| {
+                response
+                    .errors
+                    .iter()
+                    .any(|x| x.message.eq_ignore_ascii_case("invalid token"))
+            })
+            .unwrap_or(false),
+        _ => false,
+    }
+}
+
+#[derive(Deserialize)]
+struct AnilistErrorResponse {
+    errors: Vec<AnilistError>,
+}
+
+#[derive(Deserialize)]
+struct AnilistError {
+    message: String,
+}
+
+#[derive(Deserialize)]
+struct AnilistUserResponse {
+    #[serde(rename = "Viewer")]
+    viewer: AnilistUser,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AnilistUser {
+    pub id: u32,
+    pub name: String,
+}
+
+#[derive(Serialize)]
+struct MediaListCollectionVars {
+    user_id: u32,
+}
+
+#[derive(Serialize)]
+struct SearchAnimeVars {
+    anime_name: String,
+    /// Only searches entries of this format when set
+    #[serde(skip_serializing_if = "Option::is_none")]
+    format: Option<MediaFormat>,
+}
+
+#[derive(Serialize)]
+struct GetAnimeVars {
+    anime_id: u32,
+}
+
+#[derive(Serialize)]
+struct UpdateAnimeListEntryVars {
+    media_id: u32,
+    status: AnilistWatchStatus,
+    progress: u16,
+    /// Left out to keep the current setting
+    #[serde(skip_serializing_if = "Option::is_none")]
+    private: Option<bool>,
+}
+
+#[async_trait]
+impl<J: DbStore> AnimeListService for AnilistService<J> {
+    async fn search_anime(&self, search_term: &str) -> Result<Vec<AnimeResult>, anyhow::Error> {
+        self.search(search_term, None).await
+    }
+
+    async fn search_movie(&self, title: &str) -> Result<Vec<AnimeResult>, anyhow::Error> {
+        self.search(title, Some(MediaFormat::Movie)).await
+    }
+
     async fn get_anime(&self, anime_id: u32) -> Result<Option<AnimeResult>, anyhow::Error> {
         let result = self.dbstore.get_cached_anime_result(anime_id).await;
+        metrics::record_cache_lookup("anime", result.is_some());
 
         if result.is_some() {
             info!(
@@ -288,7 +379,8 @@ impl<J: DbStore> AnimeListService for AnilistService<J> {
             variables: json!(vars),
         };
 
-        let result: AnilistResponse<GetAnimeRequestResult> = self.make_request(data).await?;
+        let result: AnilistResponse<GetAnimeRequestResult> =
+            self.make_request("get_anime", data).await?;
 
         self.dbstore
             .save_cached_anime_result(anime_id, result.data.media.clone())
@@ -322,6 +414,8 @@ impl<J: DbStore> AnimeListService for AnilistService<J> {
             entries {
                 mediaId
                 progress
+                updatedAt
+                private
             }
         }
     }
@@ -334,7 +428,7 @@ impl<J: DbStore> AnimeListService for AnilistService<J> {
         };
 
         let result: AnilistResponse<AnilistListsMediaListCollectionResponse> =
-            self.make_request(data).await?;
+            self.make_request("get_list", data).await?;
         let mut anime_list: Vec<AnimeListEntry> = vec![];
 
         for list in result.data.media_list_collection.lists {
@@ -348,6 +442,8 @@ impl<J: DbStore> AnimeListService for AnilistService<J> {
                     status: status.clone(),
                     progress: entry.progress,
                     media_id: entry.media_id,
+                    updated_at: entry.updated_at,
+                    private: entry.private,
                 });
             }
         }
@@ -360,9 +456,10 @@ impl<J: DbStore> AnimeListService for AnilistService<J> {
         media_id: u32,
         status: AnilistWatchStatus,
         progress: u16,
+        private: bool,
     ) -> Result<SaveMediaListEntry, anyhow::Error> {
-        let query = r#"mutation ($media_id: Int, $status: MediaListStatus, $progress: Int) {
-                SaveMediaListEntry (mediaId: $media_id, status: $status, progress: $progress) {
+        let query = r#"mutation ($media_id: Int, $status: MediaListStatus, $progress: Int, $private: Boolean) {
+                SaveMediaListEntry (mediaId: $media_id, status: $status, progress: $progress, private: $private) {
                     id
                     status,
                     progress
@@ -373,6 +470,7 @@ impl<J: DbStore> AnimeListService for AnilistService<J> {
             progress,
             status,
             media_id,
+            private: private.then_some(true),
         };
 
         let data = GraphQlBody {
@@ -380,7 +478,8 @@ impl<J: DbStore> AnimeListService for AnilistService<J> {
             variables: json!(vars),
         };
 
-        let result: AnilistResponse<SaveMediaListEntryResponse> = self.make_request(data).await?;
+        let result: AnilistResponse<SaveMediaListEntryResponse> =
+            self.make_request("update_list_entry", data).await?;
 
         Ok(result.data.save_media_list_entry)
     }
@@ -425,6 +524,10 @@ pub struct AnilistListEntryResponse {
     #[serde(rename = "mediaId")]
     pub media_id: u32,
     pub progress: u16,
+    #[serde(rename = "updatedAt", default)]
+    pub updated_at: Option<i64>,
+    #[serde(default)]
+    pub private: bool,
 }
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
@@ -508,25 +611,23 @@ mod tests {
         let mut db_store = Sqlite::new("sqlite::memory:").await;
         db_store.migrate().await;
 
-        let mut config = db_store.get_config().await;
-        config.anilist_token = "testToken123".to_string();
+        let anilist_token = "testToken123".to_string();
 
         let mock_server = MockServer::start().await;
         Mock::given(method("POST"))
             .and(path("/"))
             .and(headers(CONTENT_TYPE, vec!["application/json"]))
             .and(headers(ACCEPT, vec!["application/json"]))
-            .and(bearer_token(&config.anilist_token))
+            .and(bearer_token(&anilist_token))
             .respond_with(ResponseTemplate::new(200).set_body_string(response))
             .expect(1)
             .mount(&mock_server)
             .await;
 
-        let list_service =
-            AnilistService::new(config.anilist_token, db_store, Some(mock_server.uri()));
+        let list_service = AnilistService::new(anilist_token, db_store, Some(mock_server.uri()));
 
         let response = list_service
-            .update_list_entry(12345, AnilistWatchStatus::Planning, 5)
+            .update_list_entry(12345, AnilistWatchStatus::Planning, 5, false)
             .await
             .expect("Failed to update anilist entry");
 
@@ -541,22 +642,20 @@ mod tests {
         let mut db_store = Sqlite::new("sqlite::memory:").await;
         db_store.migrate().await;
 
-        let mut config = db_store.get_config().await;
-        config.anilist_token = "testToken123".to_string();
+        let anilist_token = "testToken123".to_string();
 
         let mock_server = MockServer::start().await;
         Mock::given(method("POST"))
             .and(path("/"))
             .and(headers(CONTENT_TYPE, vec!["application/json"]))
             .and(headers(ACCEPT, vec!["application/json"]))
-            .and(bearer_token(&config.anilist_token))
+            .and(bearer_token(&anilist_token))
             .respond_with(ResponseTemplate::new(200).set_body_string(response))
             .expect(1)
             .mount(&mock_server)
             .await;
 
-        let list_service =
-            AnilistService::new(config.anilist_token, db_store, Some(mock_server.uri()));
+        let list_service = AnilistService::new(anilist_token, db_store, Some(mock_server.uri()));
 
         let response = list_service
             .get_list(12345)
@@ -577,22 +676,20 @@ mod tests {
         let mut db_store = Sqlite::new("sqlite::memory:").await;
         db_store.migrate().await;
 
-        let mut config = db_store.get_config().await;
-        config.anilist_token = "testToken123".to_string();
+        let anilist_token = "testToken123".to_string();
 
         let mock_server = MockServer::start().await;
         Mock::given(method("POST"))
             .and(path("/"))
             .and(headers(CONTENT_TYPE, vec!["application/json"]))
             .and(headers(ACCEPT, vec!["application/json"]))
-            .and(bearer_token(&config.anilist_token))
+            .and(bearer_token(&anilist_token))
             .respond_with(ResponseTemplate::new(200).set_body_string(response))
             .expect(1)
             .mount(&mock_server)
             .await;
 
-        let list_service =
-            AnilistService::new(config.anilist_token, db_store, Some(mock_server.uri()));
+        let list_service = AnilistService::new(anilist_token, db_store, Some(mock_server.uri()));
 
         let response = list_service
             .get_user()
@@ -603,6 +700,79 @@ mod tests {
         assert_eq!(12345, response.id);
     }
 
+    #[tokio::test]
+    async fn test_get_user_with_expired_token() {
+        init_logger();
+
+        let mut db_store = Sqlite::new("sqlite::memory:").await;
+        db_store.migrate().await;
+
+        let mock_server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/"))
+            .respond_with(ResponseTemplate::new(401).set_body_string(
+                r#"{"errors":[{"message":"Unauthorized.","status":401}],"data":null}"#,
+            ))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        let list_service =
+            AnilistService::new("expired123".to_string(), db_store, Some(mock_server.uri()));
+
+        let error = list_service
+            .get_user()
+            .await
+            .expect_err("Expired token was accepted");
+
+        assert!(error.is::<InvalidTokenError>());
+    }
+
+    #[tokio::test]
+    async fn test_get_user_with_invalid_token() {
+        init_logger();
+
+        let mut db_store = Sqlite::new("sqlite::memory:").await;
+        db_store.migrate().await;
+
+        let mock_server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/"))
+            .and(bearer_token("invalid123"))
+            .respond_with(ResponseTemplate::new(400).set_body_string(
+                r#"{"errors":[{"message":"Invalid token","status":400}],"data":null}"#,
+            ))
+            .mount(&mock_server)
+            .await;
+        Mock::given(method("POST"))
+            .and(path("/"))
+            .and(bearer_token("valid123"))
+            .respond_with(ResponseTemplate::new(400).set_body_string(
+                r#"{"errors":[{"message":"Unauthorized field: moderatorRoles","status":400}],"data":null}"#,
+            ))
+            .mount(&mock_server)
+            .await;
+
+        let list_service = AnilistService::new(
+            "invalid123".to_string(),
+            db_store.clone(),
+            Some(mock_server.uri()),
+        );
+        let error = list_service
+            .get_user()
+            .await
+            .expect_err("Invalid token was accepted");
+        assert!(error.is::<InvalidTokenError>());
+
+        let list_service =
+            AnilistService::new("valid123".to_string(), db_store, Some(mock_server.uri()));
+        let error = list_service
+            .get_user()
+            .await
+            .expect_err("Bad query succeeded");
+        assert!(!error.is::<InvalidTokenError>());
+    }
+
     #[tokio::test]
     async fn test_anilist_search() {
         init_logger();
@@ -611,8 +781,7 @@ mod tests {
         let mut db_store = Sqlite::new("sqlite::memory:").await;
         db_store.migrate().await;
 
-        let mut config = db_store.get_config().await;
-        config.anilist_token = "testToken123".to_string();
+        let anilist_token = "testToken123".to_string();
 
         let search_term = "Sword Art Online";
         let expected_body = json!({
@@ -626,14 +795,13 @@ mod tests {
             .and(body_partial_json(expected_body))
             .and(headers(CONTENT_TYPE, vec!["application/json"]))
             .and(headers(ACCEPT, vec!["application/json"]))
-            .and(bearer_token(&config.anilist_token))
+            .and(bearer_token(&anilist_token))
             .respond_with(ResponseTemplate::new(200).set_body_string(response))
             .expect(1)
             .mount(&mock_server)
             .await;
 
-        let list_service =
-            AnilistService::new(config.anilist_token, db_store, Some(mock_server.uri()));
+        let list_service = AnilistService::new(anilist_token, db_store, Some(mock_server.uri()));
 
         let response = list_service
             .search_anime(search_term)
@@ -651,8 +819,7 @@ mod tests {
         let mut db_store = Sqlite::new("sqlite::memory:").await;
         db_store.migrate().await;
 
-        let mut config = db_store.get_config().await;
-        config.anilist_token = "testToken123".to_string();
+        let anilist_token = "testToken123".to_string();
 
         let anime_id = 11757;
         let expected_body = json!({
@@ -667,13 +834,12 @@ mod tests {
             .and(body_partial_json(expected_body))
             .and(headers(CONTENT_TYPE, vec!["application/json"]))
             .and(headers(ACCEPT, vec!["application/json"]))
-            .and(bearer_token(&config.anilist_token))
+            .and(bearer_token(&anilist_token))
             .respond_with(ResponseTemplate::new(200).set_body_string(response))
             .mount(&mock_server)
             .await;
 
-        let list_service =
-            AnilistService::new(config.anilist_token, db_store, Some(mock_server.uri()));
+        let list_service = AnilistService::new(anilist_token, db_store, Some(mock_server.uri()));
 
         let response = list_service
             .get_anime(anime_id)
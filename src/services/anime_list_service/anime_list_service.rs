@@ -6,17 +6,21 @@ use super::anilist_service::SaveMediaListEntry;
 #[async_trait]
 pub trait AnimeListService: Sync + Send {
     async fn search_anime(&self, search_term: &str) -> Result<Vec<AnimeResult>, anyhow::Error>;
+    /// Searches anime films only.
+    async fn search_movie(&self, title: &str) -> Result<Vec<AnimeResult>, anyhow::Error>;
     async fn get_anime(&self, anime_id: u32) -> Result<Option<AnimeResult>, anyhow::Error>;
     async fn find_sequel(
         &self,
         anime_result: AnimeResult,
     ) -> Result<Option<AnimeResult>, anyhow::Error>;
     async fn get_list(&self, user_id: u32) -> Result<Vec<AnimeListEntry>, anyhow::Error>;
+    /// Entries are made private when `private` is set, otherwise their setting is kept.
     async fn update_list_entry(
         &self,
         media_id: u32,
         status: AnilistWatchStatus,
         progress: u16,
+        private: bool,
     ) -> Result<SaveMediaListEntry, anyhow::Error>;
 }
 
@@ -25,6 +29,10 @@ pub struct AnimeListEntry {
     pub media_id: u32,
     pub status: AnilistWatchStatus,
     pub progress: u16,
+    /// When the entry was last changed on Anilist, as a Unix timestamp in seconds
+    pub updated_at: Option<i64>,
+    /// Hidden from other Anilist users
+    pub private: bool,
 }
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
@@ -35,6 +43,8 @@ pub enum AnilistWatchStatus {
     Paused,
     Dropped,
     Completed,
+    /// Watching again after completing it
+    Repeating,
 }
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
@@ -97,7 +107,7 @@ pub struct AnimeResult {
 impl AnimeResult {
     pub fn get_title(&self) -> &str {
         match &self.title.english {
-            Some(x) => &x,
+            Some(x) => x,
             None => &self.title.romaji,
         }
     }
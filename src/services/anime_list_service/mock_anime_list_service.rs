@@ -47,6 +47,11 @@ impl AnimeListService for MockAnimeListService {
         return Ok(result.data.page.media);
     }
 
+    /// The test data has no movie search, the anime search stands in for it.
+    async fn search_movie(&self, search_term: &str) -> Result<Vec<AnimeResult>, anyhow::Error> {
+        self.search_anime(search_term).await
+    }
+
     async fn get_anime(&self, _: u32) -> Result<Option<AnimeResult>, anyhow::Error> {
         todo!()
     }
@@ -64,6 +69,7 @@ impl AnimeListService for MockAnimeListService {
         _: u32,
         _: AnilistWatchStatus,
         _: u16,
+        _: bool,
     ) -> Result<SaveMediaListEntry, anyhow::Error> {
         todo!()
     }
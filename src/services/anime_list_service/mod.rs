@@ -1,3 +1,4 @@
 pub mod anilist_service;
 pub mod anime_list_service;
+#[cfg(test)]
 pub mod mock_anime_list_service;
@@ -0,0 +1,750 @@
+use std::net::SocketAddr;
+
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    routing::{get, post},
+    Json, Router,
+};
+use log::info;
+use serde::{Deserialize, Serialize};
+
+use crate::services::{
+    config::config::{build_config, load_config, Config, ConfigLayer, REDACTED},
+    dbstore::{
+        dbstore::DbStore,
+        sqlite::{Mapping, SyncRun},
+    },
+    metrics::metrics,
+    scheduler::scheduler::SchedulerHandle,
+};
+
+use super::{api_error::ApiError, auth, libraries, review, webhook};
+
+#[derive(Clone)]
+pub struct ApiState<D>
+where
+    D: DbStore + Clone,
+{
+    pub db_store: D,
+    pub config_file: Option<String>,
+    pub scheduler: SchedulerHandle,
+    /// Overrides the Anilist API url
+    pub anilist_url: Option<String>,
+}
+
+/// The fields a client can set on a mapping. Only the Plex and Anilist ids and the season length
+/// are required.
+#[derive(Debug, Deserialize)]
+pub struct MappingBody {
+    pub plex_id: String,
+    pub plex_series_id: String,
+    #[serde(default = "default_episode_start")]
+    pub plex_episode_start: u32,
+    pub season_length: u32,
+    pub anime_list_id: u32,
+    #[serde(default = "default_episode_start")]
+    pub episode_start: u32,
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+    #[serde(default)]
+    pub ignored: bool,
+    pub episodes: Option<u16>,
+}
+
+fn default_episode_start() -> u32 {
+    1
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+impl MappingBody {
+    fn into_mapping(self, id: u32) -> Result<Mapping, ApiError> {
+        if self.plex_episode_start == 0 {
+            return Err(ApiError::BadRequest(
+                "plex_episode_start is a Plex episode number, which starts at 1".to_string(),
+            ));
+        }
+        if self.episode_start == 0 {
+            return Err(ApiError::BadRequest(
+                "episode_start starts counting at 1".to_string(),
+            ));
+        }
+        if self.season_length == 0 {
+            return Err(ApiError::BadRequest(
+                "season_length must be greater than zero".to_string(),
+            ));
+        }
+
+        Ok(Mapping {
+            id,
+            list_provider_id: 1,
+            plex_id: self.plex_id,
+            plex_series_id: self.plex_series_id,
+            plex_episode_start: self.plex_episode_start,
+            season_length: self.season_length,
+            anime_list_id: self.anime_list_id,
+            episode_start: self.episode_start,
+            enabled: self.enabled,
+            ignored: self.ignored,
+            episodes: self.episodes,
+        })
+    }
+}
+
+/// The merged config and the values stored in the database, with tokens redacted. `error` is set
+/// instead of `config` when the merged config isn't valid.
+#[derive(Debug, Serialize)]
+pub struct ConfigResponse {
+    pub config: Option<Config>,
+    pub error: Option<String>,
+    pub database: ConfigLayer,
+}
+
+pub fn router<D>(state: ApiState<D>) -> Router
+where
+    D: DbStore + Clone + 'static,
+{
+    Router::new()
+        .route("/", get(review::index))
+        .route("/api/series", get(review::list_series::<D>))
+        .route(
+            "/api/libraries",
+            get(libraries::list_libraries::<D>).put(libraries::select_libraries::<D>),
+        )
+        .route("/api/anilist/search", get(review::search_anime::<D>))
+        .route(auth::LOGIN_PATH, get(auth::login::<D>))
+        .route("/auth/anilist/callback", get(auth::callback))
+        .route(
+            "/api/auth/anilist",
+            get(auth::get_status::<D>).post(auth::save_token::<D>),
+        )
+        .route(
+            "/api/mappings",
+            get(list_mappings::<D>).post(create_mapping::<D>),
+        )
+        .route(
+            "/api/mappings/:id",
+            get(get_mapping::<D>)
+                .put(update_mapping::<D>)
+                .delete(delete_mapping::<D>),
+        )
+        .route("/api/mappings/:id/enable", post(enable_mapping::<D>))
+        .route("/api/mappings/:id/ignore", post(ignore_mapping::<D>))
+        .route("/api/config", get(get_config::<D>).put(update_config::<D>))
+        .route("/api/sync", post(start_sync::<D>))
+        .route("/api/runs/last", get(get_last_run::<D>))
+        .route("/api/webhooks/plex", post(webhook::plex_webhook::<D>))
+        .route("/metrics", get(get_metrics))
+        .with_state(state)
+}
+
+pub async fn serve<D>(address: SocketAddr, state: ApiState<D>) -> Result<(), anyhow::Error>
+where
+    D: DbStore + Clone + 'static,
+{
+    let server = axum::Server::try_bind(&address)?;
+    info!("API listening on http://{}", address);
+    server.serve(router(state).into_make_service()).await?;
+
+    Ok(())
+}
+
+async fn list_mappings<D>(State(state): State<ApiState<D>>) -> Result<Json<Vec<Mapping>>, ApiError>
+where
+    D: DbStore + Clone,
+{
+    Ok(Json(state.db_store.get_mappings().await?))
+}
+
+async fn create_mapping<D>(
+    State(state): State<ApiState<D>>,
+    Json(body): Json<MappingBody>,
+) -> Result<(StatusCode, Json<Mapping>), ApiError>
+where
+    D: DbStore + Clone,
+{
+    let mapping = body.into_mapping(0)?;
+    let id = state.db_store.save_mapping(&mapping).await?;
+    let mapping = find_mapping(&state.db_store, id).await?;
+
+    Ok((StatusCode::CREATED, Json(mapping)))
+}
+
+async fn get_mapping<D>(
+    State(state): State<ApiState<D>>,
+    Path(id): Path<u32>,
+) -> Result<Json<Mapping>, ApiError>
+where
+    D: DbStore + Clone,
+{
+    Ok(Json(find_mapping(&state.db_store, id).await?))
+}
+
+async fn update_mapping<D>(
+    State(state): State<ApiState<D>>,
+    Path(id): Path<u32>,
+    Json(body): Json<MappingBody>,
+) -> Result<Json<Mapping>, ApiError>
+where
+    D: DbStore + Clone,
+{
+    let mapping = body.into_mapping(id)?;
+    if !state.db_store.update_mapping(&mapping).await? {
+        return Err(mapping_not_found(id));
+    }
+
+    Ok(Json(find_mapping(&state.db_store, id).await?))
+}
+
+async fn enable_mapping<D>(
+    State(state): State<ApiState<D>>,
+    Path(id): Path<u32>,
+) -> Result<Json<Mapping>, ApiError>
+where
+    D: DbStore + Clone,
+{
+    let mut mapping = find_mapping(&state.db_store, id).await?;
+    mapping.enabled = true;
+    mapping.ignored = false;
+    state.db_store.update_mapping(&mapping).await?;
+
+    Ok(Json(mapping))
+}
+
+async fn ignore_mapping<D>(
+    State(state): State<ApiState<D>>,
+    Path(id): Path<u32>,
+) -> Result<Json<Mapping>, ApiError>
+where
+    D: DbStore + Clone,
+{
+    let mut mapping = find_mapping(&state.db_store, id).await?;
+    mapping.ignored = true;
+    state.db_store.update_mapping(&mapping).await?;
+
+    Ok(Json(mapping))
+}
+
+async fn delete_mapping<D>(
+    State(state): State<ApiState<D>>,
+    Path(id): Path<u32>,
+) -> Result<StatusCode, ApiError>
+where
+    D: DbStore + Clone,
+{
+    match state.db_store.delete_mapping(id).await? {
+        true => Ok(StatusCode::NO_CONTENT),
+        false => Err(mapping_not_found(id)),
+    }
+}
+
+async fn get_config<D>(State(state): State<ApiState<D>>) -> Json<ConfigResponse>
+where
+    D: DbStore + Clone,
+{
+    let database = state.db_store.get_config().await;
+    Json(get_config_response(&state, database))
+}
+
+/// Replaces the config stored in the database. Tokens sent back as the redacted placeholder keep
+/// their current value.
+async fn update_config<D>(
+    State(state): State<ApiState<D>>,
+    Json(mut body): Json<ConfigLayer>,
+) -> Result<Json<ConfigResponse>, ApiError>
+where
+    D: DbStore + Clone,
+{
+    let current = state.db_store.get_config().await;
+    if body.plex_token.as_deref() == Some(REDACTED) {
+        body.plex_token = current.plex_token;
+    }
+    if body.anilist_token.as_deref() == Some(REDACTED) {
+        body.anilist_token = current.anilist_token;
+    }
+    if body.media_server_api_key.as_deref() == Some(REDACTED) {
+        body.media_server_api_key = current.media_server_api_key;
+    }
+    for user in body.users.iter_mut().flatten() {
+        let existing = current
+            .users
+            .iter()
+            .flatten()
+            .find(|x| x.plex_user == user.plex_user);
+        if user.anilist_token.as_deref() == Some(REDACTED) {
+            user.anilist_token = existing.and_then(|x| x.anilist_token.clone());
+        }
+        if user.pin.as_deref() == Some(REDACTED) {
+            user.pin = existing.and_then(|x| x.pin.clone());
+        }
+    }
+
+    build_config(body.clone(), state.config_file.as_deref())
+        .map_err(|e| ApiError::BadRequest(e.to_string()))?;
+
+    state.db_store.save_config(&body).await?;
+    info!("Config updated through the API");
+    state.scheduler.reload();
+
+    Ok(Json(get_config_response(&state, body)))
+}
+
+async fn start_sync<D>(State(state): State<ApiState<D>>) -> StatusCode
+where
+    D: DbStore + Clone,
+{
+    info!("Sync requested through the API");
+    state.scheduler.sync_now();
+    StatusCode::ACCEPTED
+}
+
+async fn get_metrics() -> Result<String, ApiError> {
+    metrics::render().ok_or(ApiError::NotFound(
+        "Metrics aren't being collected".to_string(),
+    ))
+}
+
+async fn get_last_run<D>(State(state): State<ApiState<D>>) -> Result<Json<SyncRun>, ApiError>
+where
+    D: DbStore + Clone,
+{
+    match state.db_store.get_last_sync_run().await? {
+        Some(x) => Ok(Json(x)),
+        None => Err(ApiError::NotFound("No sync has run yet".to_string())),
+    }
+}
+
+fn get_config_response<D>(state: &ApiState<D>, database: ConfigLayer) -> ConfigResponse
+where
+    D: DbStore + Clone,
+{
+    let (config, error) = match build_config(database.clone(), state.config_file.as_deref()) {
+        Ok(x) => (Some(x.redacted()), None),
+        Err(e) => (None, Some(e.to_string())),
+    };
+
+    ConfigResponse {
+        config,
+        error,
+        database: database.redacted(),
+    }
+}
+
+/// Loads the current config, answering with a bad request when it isn't valid.
+pub(super) async fn load_state_config<D>(state: &ApiState<D>) -> Result<Config, ApiError>
+where
+    D: DbStore + Clone,
+{
+    load_config(&state.db_store, state.config_file.as_deref())
+        .await
+        .map_err(|e| ApiError::BadRequest(format!("Invalid configuration. {}", e)))
+}
+
+async fn find_mapping(db_store: &impl DbStore, id: u32) -> Result<Mapping, ApiError> {
+    match db_store.get_mapping(id).await? {
+        Some(x) => Ok(x),
+        None => Err(mapping_not_found(id)),
+    }
+}
+
+fn mapping_not_found(id: u32) -> ApiError {
+    ApiError::NotFound(format!("No mapping found with id {}", id))
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{
+        body::Body,
+        http::{header::CONTENT_TYPE, Method, Request},
+        response::Response,
+    };
+    use serde_json::{json, Value};
+    use tower::ServiceExt;
+    use wiremock::{
+        matchers::{bearer_token, method},
+        Mock, MockServer, ResponseTemplate,
+    };
+
+    use crate::services::dbstore::sqlite::{Sqlite, SyncRunStatus};
+
+    use super::*;
+
+    async fn init() -> (Router, Sqlite) {
+        init_with_anilist(None).await
+    }
+
+    async fn init_with_anilist(anilist_url: Option<String>) -> (Router, Sqlite) {
+        let mut db_store = Sqlite::new("sqlite::memory:").await;
+        db_store.migrate().await;
+
+        let state = ApiState {
+            db_store: db_store.clone(),
+            config_file: None,
+            scheduler: SchedulerHandle::default(),
+            anilist_url,
+        };
+
+        (router(state), db_store)
+    }
+
+    async fn send(router: &Router, method: Method, uri: &str, body: Option<Value>) -> Response {
+        let request = Request::builder()
+            .method(method)
+            .uri(uri)
+            .header(CONTENT_TYPE, "application/json");
+        let request = match body {
+            Some(x) => request.body(Body::from(x.to_string())),
+            None => request.body(Body::empty()),
+        }
+        .expect("Failed to build request");
+
+        router
+            .clone()
+            .oneshot(request)
+            .await
+            .expect("Failed to send request")
+    }
+
+    async fn read_json(response: Response) -> Value {
+        let body = hyper::body::to_bytes(response.into_body())
+            .await
+            .expect("Failed to read body");
+        serde_json::from_slice(&body).expect("Failed to parse body")
+    }
+
+    #[tokio::test]
+    async fn test_create_and_edit_mapping() {
+        let (router, _) = init().await;
+        let body = json!({
+            "plex_id": "17457",
+            "plex_series_id": "17456",
+            "season_length": 25,
+            "anime_list_id": 16498,
+        });
+
+        let response = send(&router, Method::POST, "/api/mappings", Some(body)).await;
+        assert_eq!(StatusCode::CREATED, response.status());
+        let created = read_json(response).await;
+        assert_eq!(1, created["episode_start"]);
+        assert_eq!(true, created["enabled"]);
+
+        let uri = format!("/api/mappings/{}/ignore", created["id"]);
+        let response = send(&router, Method::POST, &uri, None).await;
+        assert_eq!(StatusCode::OK, response.status());
+
+        let response = send(&router, Method::GET, "/api/mappings", None).await;
+        let mappings = read_json(response).await;
+        assert_eq!(1, mappings.as_array().unwrap().len());
+        assert_eq!(true, mappings[0]["ignored"]);
+    }
+
+    #[tokio::test]
+    async fn test_create_mapping_with_invalid_episode_start() {
+        let (router, _) = init().await;
+        let body = json!({
+            "plex_id": "17457",
+            "plex_series_id": "17456",
+            "season_length": 25,
+            "anime_list_id": 16498,
+            "episode_start": 0,
+        });
+
+        let response = send(&router, Method::POST, "/api/mappings", Some(body)).await;
+
+        assert_eq!(StatusCode::BAD_REQUEST, response.status());
+    }
+
+    #[tokio::test]
+    async fn test_create_mapping_with_invalid_plex_episode_start() {
+        let (router, db_store) = init().await;
+        let body = json!({
+            "plex_id": "17457",
+            "plex_series_id": "17456",
+            "plex_episode_start": 0,
+            "season_length": 25,
+            "anime_list_id": 16498,
+        });
+
+        let response = send(&router, Method::POST, "/api/mappings", Some(body)).await;
+
+        assert_eq!(StatusCode::BAD_REQUEST, response.status());
+        assert!(db_store
+            .get_mappings()
+            .await
+            .expect("Failed to get mappings")
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_index_serves_web_ui() {
+        let (router, _) = init().await;
+
+        let response = send(&router, Method::GET, "/", None).await;
+
+        assert_eq!(StatusCode::OK, response.status());
+        assert!(response.headers()[CONTENT_TYPE]
+            .to_str()
+            .unwrap()
+            .starts_with("text/html"));
+    }
+
+    #[tokio::test]
+    async fn test_delete_missing_mapping() {
+        let (router, _) = init().await;
+
+        let response = send(&router, Method::DELETE, "/api/mappings/42", None).await;
+
+        assert_eq!(StatusCode::NOT_FOUND, response.status());
+    }
+
+    #[tokio::test]
+    async fn test_update_config_redacts_and_keeps_tokens() {
+        let (router, db_store) = init().await;
+        let body = json!({
+            "plex_token": "plex123",
+            "anilist_token": "anilist123",
+            "media_server_api_key": "jellyfin123",
+            "dropped_after_days": 0,
+            "users": [{ "plex_user": "Alice", "anilist_token": "alice123" }],
+        });
+
+        let response = send(&router, Method::PUT, "/api/config", Some(body)).await;
+        assert_eq!(StatusCode::OK, response.status());
+        let config = read_json(response).await;
+        assert_eq!(REDACTED, config["database"]["plex_token"]);
+        assert_eq!(REDACTED, config["database"]["media_server_api_key"]);
+        assert_eq!(REDACTED, config["database"]["users"][0]["anilist_token"]);
+
+        let body = json!({
+            "plex_token": REDACTED,
+            "anilist_token": REDACTED,
+            "media_server_api_key": REDACTED,
+            "paused_after_days": 7,
+            "users": [{ "plex_user": "Alice", "anilist_token": REDACTED }],
+        });
+        let response = send(&router, Method::PUT, "/api/config", Some(body)).await;
+        assert_eq!(StatusCode::OK, response.status());
+
+        let saved = db_store.get_config().await;
+        assert_eq!(Some("plex123".to_string()), saved.plex_token);
+        assert_eq!(Some("jellyfin123".to_string()), saved.media_server_api_key);
+        assert_eq!(Some(7), saved.paused_after_days);
+        assert_eq!(None, saved.dropped_after_days);
+        assert_eq!(
+            Some("alice123".to_string()),
+            saved.users.expect("Users are missing")[0].anilist_token
+        );
+    }
+
+    #[tokio::test]
+    async fn test_update_config_with_invalid_value() {
+        let (router, db_store) = init().await;
+        let body = json!({
+            "plex_token": "plex123",
+            "anilist_token": "anilist123",
+            "schedule": "every day",
+        });
+
+        let response = send(&router, Method::PUT, "/api/config", Some(body)).await;
+
+        assert_eq!(StatusCode::BAD_REQUEST, response.status());
+        assert_eq!(None, db_store.get_config().await.schedule);
+    }
+
+    #[tokio::test]
+    async fn test_get_last_run() {
+        let (router, db_store) = init().await;
+
+        let response = send(&router, Method::GET, "/api/runs/last", None).await;
+        assert_eq!(StatusCode::NOT_FOUND, response.status());
+
+        let id = db_store
+            .start_sync_run(true, None)
+            .await
+            .expect("Failed to start sync run");
+        let response = send(&router, Method::GET, "/api/runs/last", None).await;
+        let run = read_json(response).await;
+        assert_eq!(id, run["id"]);
+        assert_eq!("running", run["status"]);
+
+        db_store
+            .finish_sync_run(&SyncRun {
+                id,
+                status: SyncRunStatus::Succeeded,
+                dry_run: true,
+                started_at: 0,
+                finished_at: None,
+                updated: 0,
+                failed: 0,
+                unchanged: 3,
+                error: None,
+                plex_user: None,
+            })
+            .await
+            .expect("Failed to finish sync run");
+        let response = send(&router, Method::GET, "/api/runs/last", None).await;
+        let run = read_json(response).await;
+        assert_eq!("succeeded", run["status"]);
+        assert_eq!(3, run["unchanged"]);
+        assert!(run["finished_at"].is_i64());
+    }
+
+    async fn mock_anilist(token: &str, response: ResponseTemplate) -> MockServer {
+        let mock_server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(bearer_token(token))
+            .respond_with(response)
+            .mount(&mock_server)
+            .await;
+        mock_server
+    }
+
+    #[tokio::test]
+    async fn test_anilist_status_without_token() {
+        let (router, _) = init().await;
+
+        let response = send(&router, Method::GET, "/api/auth/anilist", None).await;
+        assert_eq!(StatusCode::OK, response.status());
+        let status = read_json(response).await;
+
+        assert_eq!(false, status["logged_in"]);
+        assert_eq!("/auth/anilist", status["login_url"]);
+    }
+
+    #[tokio::test]
+    async fn test_anilist_login_redirects_to_anilist() {
+        let (router, db_store) = init().await;
+
+        let response = send(&router, Method::GET, "/auth/anilist", None).await;
+        assert_eq!(StatusCode::BAD_REQUEST, response.status());
+
+        let config = ConfigLayer {
+            anilist_client_id: Some("4688".to_string()),
+            ..Default::default()
+        };
+        db_store
+            .save_config(&config)
+            .await
+            .expect("Failed to save config");
+        let response = send(&router, Method::GET, "/auth/anilist", None).await;
+
+        assert_eq!(StatusCode::TEMPORARY_REDIRECT, response.status());
+        assert_eq!(
+            "https://anilist.co/api/v2/oauth/authorize?client_id=4688&response_type=token",
+            response.headers()["location"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_save_anilist_token() {
+        let user = json!({ "data": { "Viewer": { "id": 12345, "name": "UserName" } } });
+        let mock_server =
+            mock_anilist("anilist123", ResponseTemplate::new(200).set_body_json(user)).await;
+        let (router, db_store) = init_with_anilist(Some(mock_server.uri())).await;
+        let body = json!({ "access_token": "anilist123" });
+
+        let response = send(&router, Method::POST, "/api/auth/anilist", Some(body)).await;
+        assert_eq!(StatusCode::OK, response.status());
+        let status = read_json(response).await;
+
+        assert_eq!(true, status["logged_in"]);
+        assert_eq!("UserName", status["user"]["name"]);
+        assert_eq!(
+            Some("anilist123".to_string()),
+            db_store.get_config().await.anilist_token
+        );
+    }
+
+    #[tokio::test]
+    async fn test_save_rejected_anilist_token() {
+        let error =
+            json!({ "errors": [{ "message": "Invalid token", "status": 400 }], "data": null });
+        let mock_server = mock_anilist(
+            "expired123",
+            ResponseTemplate::new(400).set_body_json(error),
+        )
+        .await;
+        let (router, db_store) = init_with_anilist(Some(mock_server.uri())).await;
+        let body = json!({ "access_token": "expired123" });
+
+        let response = send(&router, Method::POST, "/api/auth/anilist", Some(body)).await;
+
+        assert_eq!(StatusCode::BAD_REQUEST, response.status());
+        assert_eq!(None, db_store.get_config().await.anilist_token);
+    }
+
+    #[tokio::test]
+    async fn test_plex_webhook() {
+        let (router, _) = init().await;
+        let payload = json!({ "event": "media.play", "Metadata": { "type": "episode" } });
+        let body = format!(
+            "--boundary\r\nContent-Disposition: form-data; name=\"payload\"\r\n\r\n{}\r\n--boundary--\r\n",
+            payload
+        );
+        let request = Request::builder()
+            .method(Method::POST)
+            .uri("/api/webhooks/plex")
+            .header(CONTENT_TYPE, "multipart/form-data; boundary=boundary")
+            .body(Body::from(body))
+            .expect("Failed to build request");
+
+        let response = router
+            .oneshot(request)
+            .await
+            .expect("Failed to send request");
+
+        assert_eq!(StatusCode::OK, response.status());
+    }
+
+    #[tokio::test]
+    async fn test_plex_webhook_without_payload() {
+        let (router, _) = init().await;
+
+        let response = send(&router, Method::POST, "/api/webhooks/plex", None).await;
+
+        assert!(response.status().is_client_error());
+    }
+
+    #[tokio::test]
+    async fn test_select_libraries() {
+        let mock_server = MockServer::start().await;
+        let libraries = json!({ "MediaContainer": { "Directory": [
+            { "key": "1", "type": "movie", "title": "Movies", "agent": "tv.plex.agents.movie" },
+            { "key": "2", "type": "show", "title": "Anime", "agent": "com.plexapp.agents.hama" },
+        ]}});
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(libraries))
+            .mount(&mock_server)
+            .await;
+        let (router, db_store) = init().await;
+        let config = ConfigLayer {
+            plex_url: Some(mock_server.uri()),
+            plex_token: Some("plex123".to_string()),
+            ..Default::default()
+        };
+        db_store
+            .save_config(&config)
+            .await
+            .expect("Failed to save config");
+
+        let body = json!({ "keys": [] });
+        let response = send(&router, Method::PUT, "/api/libraries", Some(body)).await;
+        assert_eq!(StatusCode::BAD_REQUEST, response.status());
+
+        let body = json!({ "keys": ["2"] });
+        let response = send(&router, Method::PUT, "/api/libraries", Some(body)).await;
+        assert_eq!(StatusCode::OK, response.status());
+        let libraries = read_json(response).await;
+
+        assert_eq!(false, libraries[0]["selected"]);
+        assert_eq!(true, libraries[1]["selected"]);
+        assert_eq!(true, libraries[1]["likely_anime"]);
+        assert_eq!(
+            Some(vec!["key:2".to_string()]),
+            db_store.get_config().await.libraries
+        );
+    }
+}
@@ -0,0 +1,161 @@
+use axum::{
+    extract::State,
+    response::{Html, Redirect},
+    Json,
+};
+use log::info;
+use serde::{Deserialize, Serialize};
+
+use crate::services::{
+    anime_list_service::anilist_service::{
+        get_authorize_url, AnilistService, AnilistUser, InvalidTokenError,
+    },
+    config::config::{
+        merge_layers, validate_token, ConfigError, ConfigLayer, ANILIST_CLIENT_ID_ENV,
+        ANILIST_TOKEN_ENV,
+    },
+    dbstore::dbstore::DbStore,
+};
+
+use super::{api::ApiState, api_error::ApiError};
+
+pub const LOGIN_PATH: &str = "/auth/anilist";
+
+/// Whether the current Anilist token works. `error` says why the user needs to log in when
+/// `logged_in` is false.
+#[derive(Debug, Serialize)]
+pub struct AnilistLoginStatus {
+    pub logged_in: bool,
+    pub user: Option<AnilistUser>,
+    pub error: Option<String>,
+    pub login_url: &'static str,
+    /// Set when the token was saved but another config source overrides it
+    pub warning: Option<String>,
+}
+
+impl AnilistLoginStatus {
+    fn logged_in(user: AnilistUser) -> Self {
+        Self {
+            logged_in: true,
+            user: Some(user),
+            error: None,
+            login_url: LOGIN_PATH,
+            warning: None,
+        }
+    }
+
+    fn needs_login(error: String) -> Self {
+        Self {
+            logged_in: false,
+            user: None,
+            error: Some(error),
+            login_url: LOGIN_PATH,
+            warning: None,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TokenBody {
+    pub access_token: String,
+}
+
+/// Sends the user to Anilist to authorize the app. Anilist redirects back to the callback page.
+pub async fn login<D>(State(state): State<ApiState<D>>) -> Result<Redirect, ApiError>
+where
+    D: DbStore + Clone,
+{
+    match get_layer(&state).await?.anilist_client_id {
+        Some(client_id) => Ok(Redirect::temporary(&get_authorize_url(&client_id))),
+        None => Err(ApiError::BadRequest(format!(
+            "Logging in needs an Anilist API client. Set {} to the id of the client or set {} instead",
+            ANILIST_CLIENT_ID_ENV, ANILIST_TOKEN_ENV
+        ))),
+    }
+}
+
+/// Anilist puts the token in the url fragment, which only the browser can see, so this page reads
+/// it and posts it back to `save_token`.
+pub async fn callback() -> Html<&'static str> {
+    Html(include_str!("anilist_callback.html"))
+}
+
+pub async fn get_status<D>(
+    State(state): State<ApiState<D>>,
+) -> Result<Json<AnilistLoginStatus>, ApiError>
+where
+    D: DbStore + Clone,
+{
+    let anilist_token = match get_layer(&state).await?.anilist_token {
+        Some(x) => x,
+        None => {
+            return Ok(Json(AnilistLoginStatus::needs_login(
+                ConfigError::NotLoggedIn.to_string(),
+            )))
+        }
+    };
+
+    let anilist_service = AnilistService::new(
+        anilist_token,
+        state.db_store.clone(),
+        state.anilist_url.clone(),
+    );
+    match anilist_service.get_user().await {
+        Ok(user) => Ok(Json(AnilistLoginStatus::logged_in(user))),
+        Err(e) if e.is::<InvalidTokenError>() => {
+            Ok(Json(AnilistLoginStatus::needs_login(e.to_string())))
+        }
+        Err(e) => Err(ApiError::Internal(e.context("Failed to get anilist user"))),
+    }
+}
+
+/// Checks the token with Anilist and saves it to the config in the database.
+pub async fn save_token<D>(
+    State(state): State<ApiState<D>>,
+    Json(body): Json<TokenBody>,
+) -> Result<Json<AnilistLoginStatus>, ApiError>
+where
+    D: DbStore + Clone,
+{
+    let anilist_token = body.access_token.trim().to_string();
+    validate_token("access_token", ANILIST_TOKEN_ENV, &anilist_token)
+        .map_err(|e| ApiError::BadRequest(e.to_string()))?;
+
+    let anilist_service = AnilistService::new(
+        anilist_token.clone(),
+        state.db_store.clone(),
+        state.anilist_url.clone(),
+    );
+    let user = match anilist_service.get_user().await {
+        Ok(x) => x,
+        Err(e) if e.is::<InvalidTokenError>() => return Err(ApiError::BadRequest(e.to_string())),
+        Err(e) => return Err(ApiError::Internal(e.context("Failed to get anilist user"))),
+    };
+
+    let mut db_layer = state.db_store.get_config().await;
+    db_layer.anilist_token = Some(anilist_token.clone());
+    state.db_store.save_config(&db_layer).await?;
+    info!("Logged in to Anilist as '{}'", user.name);
+    state.scheduler.reload();
+
+    let mut status = AnilistLoginStatus::logged_in(user);
+    if get_layer(&state).await?.anilist_token != Some(anilist_token) {
+        status.warning = Some(format!(
+            "The token was saved but {} or the config file sets a different token, which is used instead",
+            ANILIST_TOKEN_ENV
+        ));
+    }
+
+    Ok(Json(status))
+}
+
+async fn get_layer<D>(state: &ApiState<D>) -> Result<ConfigLayer, ApiError>
+where
+    D: DbStore + Clone,
+{
+    merge_layers(
+        state.db_store.get_config().await,
+        state.config_file.as_deref(),
+    )
+    .map_err(|e| ApiError::BadRequest(format!("Invalid configuration. {}", e)))
+}
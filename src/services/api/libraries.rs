@@ -0,0 +1,104 @@
+use axum::{extract::State, Json};
+use log::info;
+use serde::{Deserialize, Serialize};
+
+use crate::services::{
+    config::config::{build_config, Config},
+    dbstore::dbstore::DbStore,
+    media_server::media_server::MediaServer,
+    plex::{
+        library_selector::LibrarySelector,
+        plex_api::{PlexInterface, PlexLibraryLocation},
+    },
+};
+
+use super::{
+    api::{load_state_config, ApiState},
+    api_error::ApiError,
+};
+
+#[derive(Debug, Serialize)]
+pub struct LibraryResponse {
+    pub key: String,
+    pub title: String,
+    #[serde(rename = "type")]
+    pub library_type: String,
+    pub agent: String,
+    pub scanner: String,
+    pub language: String,
+    pub locations: Vec<PlexLibraryLocation>,
+    pub likely_anime: bool,
+    pub selected: bool,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SelectLibrariesBody {
+    pub keys: Vec<String>,
+}
+
+/// Lists the libraries on the Plex server and which of them are synced.
+pub async fn list_libraries<D>(
+    State(state): State<ApiState<D>>,
+) -> Result<Json<Vec<LibraryResponse>>, ApiError>
+where
+    D: DbStore + Clone,
+{
+    let config = load_state_config(&state).await?;
+    Ok(Json(get_libraries(&config).await?))
+}
+
+/// Replaces the library selection in the database config with the libraries picked by key.
+pub async fn select_libraries<D>(
+    State(state): State<ApiState<D>>,
+    Json(body): Json<SelectLibrariesBody>,
+) -> Result<Json<Vec<LibraryResponse>>, ApiError>
+where
+    D: DbStore + Clone,
+{
+    if body.keys.is_empty() {
+        return Err(ApiError::BadRequest(
+            "Pick at least one library".to_string(),
+        ));
+    }
+
+    let mut db_layer = state.db_store.get_config().await;
+    db_layer.libraries = Some(
+        body.keys
+            .iter()
+            .map(|x| LibrarySelector::Key(x.clone()).to_string())
+            .collect(),
+    );
+    let config = build_config(db_layer.clone(), state.config_file.as_deref())
+        .map_err(|e| ApiError::BadRequest(e.to_string()))?;
+
+    state.db_store.save_config(&db_layer).await?;
+    info!("Library selection updated through the API");
+    state.scheduler.reload();
+
+    Ok(Json(get_libraries(&config).await?))
+}
+
+async fn get_libraries(config: &Config) -> Result<Vec<LibraryResponse>, ApiError> {
+    let plex_service = MediaServer::connect(config)
+        .await
+        .map_err(ApiError::Internal)?;
+    let libraries = plex_service
+        .get_libraries()
+        .await
+        .map_err(|e| ApiError::Internal(e.into()))?;
+
+    Ok(libraries
+        .into_iter()
+        .map(|x| LibraryResponse {
+            likely_anime: x.is_likely_anime(),
+            selected: config.libraries.iter().any(|s| s.matches(&x)),
+            key: x.key,
+            title: x.title,
+            library_type: x.library_type,
+            agent: x.agent,
+            scanner: x.scanner,
+            language: x.language,
+            locations: x.locations,
+        })
+        .collect())
+}
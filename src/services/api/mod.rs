@@ -0,0 +1,6 @@
+pub mod api;
+pub mod api_error;
+pub mod auth;
+pub mod libraries;
+pub mod review;
+pub mod webhook;
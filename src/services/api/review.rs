@@ -0,0 +1,246 @@
+use axum::{
+    extract::{Query, State},
+    response::Html,
+    Json,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::services::{
+    anime_list_service::{
+        anilist_service::{AnilistService, InvalidTokenError},
+        anime_list_service::{AnimeListService, AnimeResult},
+    },
+    dbstore::{dbstore::DbStore, sqlite::Mapping},
+    mapping_handler::mapping_utils::get_mapped_episode_count,
+    media_server::media_server::MediaServer,
+    plex::plex_api::PlexSeries,
+    sync_service::sync_runner::get_library_series,
+};
+
+use super::{
+    api::{load_state_config, ApiState},
+    api_error::ApiError,
+};
+
+#[derive(Debug, Serialize, PartialEq)]
+pub struct SeriesCoverage {
+    pub rating_key: String,
+    pub title: String,
+    pub library: String,
+    pub seasons: Vec<SeasonCoverage>,
+    /// Why the seasons of the series couldn't be loaded from Plex
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Serialize, PartialEq)]
+pub struct SeasonCoverage {
+    pub rating_key: String,
+    pub index: u8,
+    pub episodes: u32,
+    pub mapped_episodes: u32,
+    pub status: CoverageStatus,
+    /// Ids of the mappings for this season
+    pub mappings: Vec<u32>,
+}
+
+#[derive(Debug, Serialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum CoverageStatus {
+    Mapped,
+    Partial,
+    Unmapped,
+    /// Specials aren't mapped automatically
+    Skipped,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SearchQuery {
+    pub q: String,
+}
+
+pub fn get_series_coverage(
+    library: &str,
+    series: &[PlexSeries],
+    mappings: &[Mapping],
+) -> Vec<SeriesCoverage> {
+    series
+        .iter()
+        .map(|series| SeriesCoverage {
+            rating_key: series.rating_key.clone(),
+            title: series.title.clone(),
+            library: library.to_string(),
+            seasons: series
+                .seasons
+                .iter()
+                .map(|season| {
+                    let episodes = season.get_episode_count();
+                    let mapped_episodes = get_mapped_episode_count(mappings, &season.rating_key);
+                    let status = match (season.index, mapped_episodes) {
+                        (0, _) => CoverageStatus::Skipped,
+                        (_, 0) => CoverageStatus::Unmapped,
+                        (_, x) if x < episodes => CoverageStatus::Partial,
+                        _ => CoverageStatus::Mapped,
+                    };
+
+                    SeasonCoverage {
+                        rating_key: season.rating_key.clone(),
+                        index: season.index,
+                        episodes,
+                        mapped_episodes,
+                        status,
+                        mappings: mappings
+                            .iter()
+                            .filter(|x| x.plex_id == season.rating_key)
+                            .map(|x| x.id)
+                            .collect(),
+                    }
+                })
+                .collect(),
+            error: None,
+        })
+        .collect()
+}
+
+pub async fn index() -> Html<&'static str> {
+    Html(include_str!("index.html"))
+}
+
+/// Lists every series in the selected libraries with how much of each season is mapped.
+pub async fn list_series<D>(
+    State(state): State<ApiState<D>>,
+) -> Result<Json<Vec<SeriesCoverage>>, ApiError>
+where
+    D: DbStore + Clone,
+{
+    let config = load_state_config(&state).await?;
+    let plex_service = MediaServer::connect(&config)
+        .await
+        .map_err(ApiError::Internal)?;
+    let mappings = state.db_store.get_mappings().await?;
+
+    let mut result = vec![];
+    for library in get_library_series(&plex_service, &config)
+        .await
+        .map_err(ApiError::Internal)?
+    {
+        let title = &library.library.title;
+        result.append(&mut get_series_coverage(title, &library.series, &mappings));
+        result.extend(library.fetch_errors.into_iter().map(|e| SeriesCoverage {
+            rating_key: e.rating_key,
+            title: e.title,
+            library: title.clone(),
+            seasons: vec![],
+            error: Some(e.error),
+        }));
+    }
+
+    Ok(Json(result))
+}
+
+/// Searches Anilist for mapping candidates, the same search used when matching automatically.
+pub async fn search_anime<D>(
+    State(state): State<ApiState<D>>,
+    Query(query): Query<SearchQuery>,
+) -> Result<Json<Vec<AnimeResult>>, ApiError>
+where
+    D: DbStore + Clone,
+{
+    let config = load_state_config(&state).await?;
+    let anilist_token = config
+        .get_anilist_token()
+        .map_err(|e| ApiError::BadRequest(e.to_string()))?;
+    let anilist_service = AnilistService::new(
+        anilist_token,
+        state.db_store.clone(),
+        state.anilist_url.clone(),
+    );
+
+    let results = anilist_service
+        .search_anime(query.q.trim())
+        .await
+        .map_err(|e| match e.is::<InvalidTokenError>() {
+            true => ApiError::BadRequest(e.to_string()),
+            false => ApiError::Internal(e),
+        })?;
+
+    Ok(Json(results))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::services::plex::plex_api::{PlexEpisode, PlexMediaKind, PlexSeason};
+
+    use super::*;
+
+    fn season(rating_key: &str, index: u8, episodes: usize) -> PlexSeason {
+        PlexSeason {
+            guids: vec![],
+            labels: vec![],
+            rating_key: rating_key.to_string(),
+            index,
+            parent_title: "Attack on Titan".to_string(),
+            episodes: vec![
+                PlexEpisode {
+                    rating_key: "1".to_string(),
+                    last_viewed_at: None,
+                    first_viewed_at: None,
+                    view_count: 0,
+                    index: None,
+                    parent_index: None,
+                    originally_available_at: None,
+                    view_offset: None,
+                    duration: None,
+                };
+                episodes
+            ],
+        }
+    }
+
+    fn mapping(id: u32, plex_id: &str, season_length: u32) -> Mapping {
+        Mapping {
+            id,
+            list_provider_id: 1,
+            plex_id: plex_id.to_string(),
+            plex_series_id: "17456".to_string(),
+            plex_episode_start: 1,
+            season_length,
+            anime_list_id: 16498,
+            episode_start: 1,
+            enabled: true,
+            ignored: false,
+            episodes: Some(season_length as u16),
+        }
+    }
+
+    #[test]
+    fn test_get_series_coverage() {
+        let series = vec![PlexSeries {
+            guids: vec![],
+            labels: vec![],
+            kind: PlexMediaKind::Show,
+            rating_key: "17456".to_string(),
+            title: "Attack on Titan".to_string(),
+            seasons: vec![
+                season("30036", 0, 3),
+                season("30037", 1, 25),
+                season("30038", 2, 22),
+                season("30039", 3, 12),
+            ],
+        }];
+        let mappings = vec![mapping(1, "30037", 25), mapping(2, "30038", 12)];
+
+        let result = get_series_coverage("Anime", &series, &mappings);
+        let seasons = &result[0].seasons;
+
+        assert_eq!("Anime", result[0].library);
+        assert_eq!(CoverageStatus::Skipped, seasons[0].status);
+        assert_eq!(CoverageStatus::Mapped, seasons[1].status);
+        assert_eq!(vec![1], seasons[1].mappings);
+        assert_eq!(CoverageStatus::Partial, seasons[2].status);
+        assert_eq!(12, seasons[2].mapped_episodes);
+        assert_eq!(22, seasons[2].episodes);
+        assert_eq!(CoverageStatus::Unmapped, seasons[3].status);
+        assert!(seasons[3].mappings.is_empty());
+    }
+}
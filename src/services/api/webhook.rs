@@ -0,0 +1,272 @@
+use axum::{
+    extract::{Multipart, State},
+    http::StatusCode,
+};
+use log::{debug, info};
+use serde::Deserialize;
+
+use crate::services::{dbstore::dbstore::DbStore, sync_service::sync_runner::SyncTarget};
+
+use super::{api::ApiState, api_error::ApiError};
+
+/// The Plex events that can change watch progress
+const SYNC_EVENTS: [&str; 2] = ["media.scrobble", "media.rate"];
+
+/// The parts of a Plex webhook payload we use.
+/// https://support.plex.tv/articles/115002267687-webhooks/
+#[derive(Debug, Deserialize)]
+pub struct PlexWebhook {
+    pub event: String,
+    /// The Plex account that played the media
+    #[serde(rename = "Account")]
+    pub account: Option<PlexWebhookAccount>,
+    #[serde(rename = "Metadata")]
+    pub metadata: Option<PlexWebhookMetadata>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PlexWebhookAccount {
+    pub title: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PlexWebhookMetadata {
+    #[serde(rename = "type")]
+    pub media_type: String,
+    /// The episode number
+    pub index: Option<u32>,
+    pub parent_rating_key: Option<String>,
+    pub grandparent_rating_key: Option<String>,
+    pub grandparent_title: Option<String>,
+    /// Plex sends this as a number
+    #[serde(rename = "librarySectionID")]
+    pub library_section_id: Option<serde_json::Value>,
+}
+
+/// Receives Plex webhooks. Plex posts a multipart form with the event as JSON in the `payload`
+/// field. Watched episodes queue a sync of the Anilist entries they're mapped to.
+pub async fn plex_webhook<D>(
+    State(state): State<ApiState<D>>,
+    mut multipart: Multipart,
+) -> Result<StatusCode, ApiError>
+where
+    D: DbStore + Clone,
+{
+    let mut payload = None;
+    while let Some(field) = multipart
+        .next_field()
+        .await
+        .map_err(|e| ApiError::BadRequest(e.to_string()))?
+    {
+        if field.name() == Some("payload") {
+            payload = Some(
+                field
+                    .text()
+                    .await
+                    .map_err(|e| ApiError::BadRequest(e.to_string()))?,
+            );
+        }
+    }
+
+    let payload = payload.ok_or(ApiError::BadRequest(
+        "The webhook has no payload field".to_string(),
+    ))?;
+    let webhook: PlexWebhook = serde_json::from_str(&payload)
+        .map_err(|e| ApiError::BadRequest(format!("Invalid webhook payload. {}", e)))?;
+
+    match get_sync_target(&state.db_store, &webhook).await? {
+        Some(target) => {
+            info!(
+                "Plex reported '{}' for '{}', queueing a sync of {} Anilist entries",
+                webhook.event,
+                target.series_title,
+                target.anime_ids.len()
+            );
+            state.scheduler.sync_target(target);
+            Ok(StatusCode::ACCEPTED)
+        }
+        None => Ok(StatusCode::OK),
+    }
+}
+
+/// Finds the Anilist entries mapped to the episode in the webhook. Returns `None` for events that
+/// don't need a sync.
+pub async fn get_sync_target(
+    db_store: &impl DbStore,
+    webhook: &PlexWebhook,
+) -> Result<Option<SyncTarget>, sqlx::Error> {
+    if !SYNC_EVENTS.contains(&webhook.event.as_str()) {
+        debug!("Ignoring Plex event '{}'", webhook.event);
+        return Ok(None);
+    }
+
+    let metadata = match &webhook.metadata {
+        Some(x) if x.media_type == "episode" => x,
+        _ => {
+            debug!(
+                "Ignoring Plex event '{}' for something other than an episode",
+                webhook.event
+            );
+            return Ok(None);
+        }
+    };
+    let (season_key, series_key, library_key) = match (
+        &metadata.parent_rating_key,
+        &metadata.grandparent_rating_key,
+        metadata
+            .library_section_id
+            .as_ref()
+            .and_then(get_library_key),
+    ) {
+        (Some(season), Some(series), Some(library)) => (season, series, library),
+        _ => return Ok(None),
+    };
+    let series_title = metadata
+        .grandparent_title
+        .clone()
+        .unwrap_or_else(|| series_key.clone());
+
+    let mut anime_ids = vec![];
+    for mapping in db_store.get_mappings().await? {
+        let covers_episode = match metadata.index {
+            Some(x) => {
+                x >= mapping.plex_episode_start
+                    && x < mapping.plex_episode_start + mapping.season_length
+            }
+            None => true,
+        };
+        if &mapping.plex_id == season_key
+            && &mapping.plex_series_id == series_key
+            && mapping.enabled
+            && !mapping.ignored
+            && covers_episode
+            && !anime_ids.contains(&mapping.anime_list_id)
+        {
+            anime_ids.push(mapping.anime_list_id);
+        }
+    }
+
+    if anime_ids.is_empty() {
+        info!(
+            "No mapping for the episode of '{}' Plex reported, it's left for the next full sync",
+            series_title
+        );
+        return Ok(None);
+    }
+
+    Ok(Some(SyncTarget {
+        library_key,
+        series_rating_key: series_key.clone(),
+        series_title,
+        anime_ids,
+        plex_user: webhook.account.as_ref().map(|x| x.title.clone()),
+    }))
+}
+
+fn get_library_key(value: &serde_json::Value) -> Option<String> {
+    match value {
+        serde_json::Value::Number(x) => Some(x.to_string()),
+        serde_json::Value::String(x) => Some(x.clone()),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use crate::services::dbstore::sqlite::{Mapping, Sqlite};
+
+    use super::*;
+
+    fn mapping(plex_id: &str, plex_episode_start: u32, anime_list_id: u32) -> Mapping {
+        Mapping {
+            id: 0,
+            list_provider_id: 1,
+            plex_id: plex_id.to_string(),
+            plex_series_id: "17456".to_string(),
+            plex_episode_start,
+            season_length: 12,
+            anime_list_id,
+            episode_start: 1,
+            enabled: true,
+            ignored: false,
+            episodes: Some(12),
+        }
+    }
+
+    fn webhook(event: &str, index: u32) -> PlexWebhook {
+        serde_json::from_value(json!({
+            "event": event,
+            "Account": { "id": 2, "title": "Alice" },
+            "Metadata": {
+                "type": "episode",
+                "index": index,
+                "parentRatingKey": "30038",
+                "grandparentRatingKey": "17456",
+                "grandparentTitle": "Attack on Titan",
+                "librarySectionID": 2,
+            },
+        }))
+        .expect("Failed to parse webhook")
+    }
+
+    async fn init() -> Sqlite {
+        let mut db_store = Sqlite::new("sqlite::memory:").await;
+        db_store.migrate().await;
+        for x in [
+            mapping("30037", 1, 16498),
+            mapping("30038", 1, 20958),
+            mapping("30038", 13, 99147),
+        ] {
+            db_store
+                .save_mapping(&x)
+                .await
+                .expect("Failed to save mapping");
+        }
+        db_store
+    }
+
+    #[tokio::test]
+    async fn test_get_sync_target_for_scrobbled_episode() {
+        let db_store = init().await;
+
+        let result = get_sync_target(&db_store, &webhook("media.scrobble", 14))
+            .await
+            .expect("Failed to get sync target");
+
+        assert_eq!(
+            Some(SyncTarget {
+                library_key: "2".to_string(),
+                series_rating_key: "17456".to_string(),
+                series_title: "Attack on Titan".to_string(),
+                anime_ids: vec![99147],
+                plex_user: Some("Alice".to_string()),
+            }),
+            result
+        );
+    }
+
+    #[tokio::test]
+    async fn test_get_sync_target_ignores_other_events() {
+        let db_store = init().await;
+
+        let result = get_sync_target(&db_store, &webhook("media.play", 1))
+            .await
+            .expect("Failed to get sync target");
+
+        assert_eq!(None, result);
+    }
+
+    #[tokio::test]
+    async fn test_get_sync_target_without_mapping() {
+        let db_store = init().await;
+
+        let result = get_sync_target(&db_store, &webhook("media.rate", 30))
+            .await
+            .expect("Failed to get sync target");
+
+        assert_eq!(None, result);
+    }
+}
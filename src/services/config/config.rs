@@ -0,0 +1,1175 @@
+use std::{env, fmt, fs, io, net::SocketAddr, path::Path, str::FromStr};
+
+use chrono_tz::Tz;
+use log::info;
+use serde::{Deserialize, Serialize};
+use url::Url;
+
+use crate::services::{
+    dbstore::dbstore::DbStore,
+    mapping_handler::id_mappings::DEFAULT_ID_MAPPING_FILE,
+    media_server::media_server::MediaServerKind,
+    plex::{
+        library_selector::LibrarySelector,
+        plex_api::ResponsePlexLibrary,
+        plex_api_service::{DEFAULT_CONCURRENCY, DEFAULT_PAGE_SIZE},
+    },
+    scheduler::schedule::Schedule,
+    sync_service::sync_handler::{ConflictPolicy, StatusRules, DEFAULT_WATCHED_PERCENT},
+};
+
+pub const DEFAULT_MEDIA_SERVER: &str = "plex";
+pub const DEFAULT_PLEX_URL: &str = "http://localhost:32400";
+pub const DEFAULT_CONFIG_FILE: &str = "./data/config.toml";
+pub const DEFAULT_SCHEDULE: &str = "0 23 * * *";
+pub const DEFAULT_TIMEZONE: &str = "UTC";
+pub const DEFAULT_LIBRARY: &str = "key:1";
+pub const DEFAULT_API_ADDRESS: &str = "127.0.0.1:8080";
+pub const DEFAULT_CONFLICT_POLICY: &str = "newest";
+pub const DEFAULT_EXCLUDE_LABEL: &str = "no-anisync";
+pub const DEFAULT_PRIVATE_LABEL: &str = "anisync-private";
+/// Shown instead of tokens when the config is returned from the API
+pub const REDACTED: &str = "********";
+
+pub const MEDIA_SERVER_ENV: &str = "MEDIA_SERVER";
+pub const MEDIA_SERVER_URL_ENV: &str = "MEDIA_SERVER_URL";
+pub const MEDIA_SERVER_API_KEY_ENV: &str = "MEDIA_SERVER_API_KEY";
+pub const MEDIA_SERVER_USER_ENV: &str = "MEDIA_SERVER_USER";
+pub const PLEX_URL_ENV: &str = "PLEX_URL";
+pub const PLEX_TOKEN_ENV: &str = "PLEX_TOKEN";
+pub const LIBRARIES_ENV: &str = "PLEX_LIBRARIES";
+pub const ANILIST_TOKEN_ENV: &str = "ANILIST_TOKEN";
+pub const ANILIST_CLIENT_ID_ENV: &str = "ANILIST_CLIENT_ID";
+pub const SCHEDULE_ENV: &str = "SCHEDULE";
+pub const TIMEZONE_ENV: &str = "SYNC_TIMEZONE";
+/// Read when `SYNC_TIMEZONE` isn't set. It also sets the timezone of the whole process, so it
+/// isn't the first choice.
+pub const TIMEZONE_FALLBACK_ENV: &str = "TZ";
+pub const RUN_ON_STARTUP_ENV: &str = "RUN_ON_STARTUP";
+pub const JITTER_SECONDS_ENV: &str = "SCHEDULE_JITTER_SECONDS";
+pub const DRY_RUN_ENV: &str = "DRY_RUN";
+pub const DROPPED_AFTER_DAYS_ENV: &str = "DROPPED_AFTER_DAYS";
+pub const PAUSED_AFTER_DAYS_ENV: &str = "PAUSED_AFTER_DAYS";
+pub const UPDATE_PLANNING_ENV: &str = "UPDATE_PLANNING";
+pub const API_ENABLED_ENV: &str = "API_ENABLED";
+pub const API_ADDRESS_ENV: &str = "API_ADDRESS";
+pub const ID_MAPPING_FILE_ENV: &str = "ID_MAPPING_FILE";
+pub const PLEX_PAGE_SIZE_ENV: &str = "PLEX_PAGE_SIZE";
+pub const PLEX_CONCURRENCY_ENV: &str = "PLEX_CONCURRENCY";
+pub const TWO_WAY_SYNC_ENV: &str = "TWO_WAY_SYNC";
+pub const CONFLICT_POLICY_ENV: &str = "CONFLICT_POLICY";
+pub const WATCHED_PERCENT_ENV: &str = "WATCHED_PERCENT";
+pub const EXCLUDE_LABEL_ENV: &str = "EXCLUDE_LABEL";
+pub const PRIVATE_LABEL_ENV: &str = "PRIVATE_LABEL";
+
+/// One source of configuration values. Every field is optional so layers can be stacked, with
+/// later layers overriding earlier ones.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ConfigLayer {
+    /// `plex`, `jellyfin` or `emby`
+    pub media_server: Option<String>,
+    /// The url of the Jellyfin or Emby server
+    pub media_server_url: Option<String>,
+    /// An API key made in the Jellyfin or Emby dashboard
+    pub media_server_api_key: Option<String>,
+    /// The Jellyfin or Emby user whose watch state is synced to `anilist_token`
+    pub media_server_user: Option<String>,
+    pub plex_url: Option<String>,
+    pub plex_token: Option<String>,
+    /// Library selectors such as `title:Anime`, `key:3`, `type:show` or `agent:...`
+    pub libraries: Option<Vec<String>>,
+    pub anilist_token: Option<String>,
+    /// The id of the Anilist API client used to log in from the web UI
+    pub anilist_client_id: Option<String>,
+    /// A cron expression such as `0 23 * * *` or an interval such as `6h`
+    pub schedule: Option<String>,
+    pub timezone: Option<String>,
+    pub run_on_startup: Option<bool>,
+    /// Delays each scheduled run by a random amount up to this many seconds
+    pub jitter_seconds: Option<u64>,
+    /// Runs syncs without changing anything on Anilist or saving new mappings
+    pub dry_run: Option<bool>,
+    /// Days since an entry was last watched before it's marked Dropped, 0 turns this off
+    pub dropped_after_days: Option<u32>,
+    /// Days since an entry was last watched before it's marked Paused, 0 turns this off
+    pub paused_after_days: Option<u32>,
+    pub update_planning: Option<bool>,
+    /// How much of an episode has to be played before it counts as watched, from 1 to 100
+    pub watched_percent: Option<u8>,
+    /// Status settings for specific libraries, the first entry matching a library is used
+    pub library_rules: Option<Vec<LibraryRulesLayer>>,
+    pub api_enabled: Option<bool>,
+    /// The address the HTTP API listens on, such as `0.0.0.0:8080`
+    pub api_address: Option<String>,
+    /// Plex Home users synced to their own Anilist accounts
+    pub users: Option<Vec<PlexUserLayer>>,
+    /// A JSON dataset linking tvdb, tmdb and imdb ids to Anilist ids
+    pub id_mapping_file: Option<String>,
+    /// The number of series loaded from Plex at a time
+    pub plex_page_size: Option<u32>,
+    /// The number of requests sent to Plex at the same time
+    pub plex_concurrency: Option<usize>,
+    /// Also mark episodes as watched in Plex when Anilist has more progress
+    pub two_way_sync: Option<bool>,
+    /// `anilist`, `plex` or `newest`, decides which side wins when Anilist has more progress
+    pub conflict_policy: Option<String>,
+    /// Plex series and seasons with this label or in a collection with this name aren't mapped
+    /// or synced
+    pub exclude_label: Option<String>,
+    /// The Anilist entries of Plex series and seasons with this label or in a collection with
+    /// this name are made private
+    pub private_label: Option<String>,
+}
+
+/// Overrides the status settings for the libraries matching `library`. Unset values fall back to
+/// the global settings.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LibraryRulesLayer {
+    pub library: String,
+    pub dropped_after_days: Option<u32>,
+    pub paused_after_days: Option<u32>,
+    pub update_planning: Option<bool>,
+}
+
+/// Pairs a Plex Home or managed user with an Anilist account.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PlexUserLayer {
+    /// The title or username of the user in the Plex Home
+    pub plex_user: String,
+    pub anilist_token: Option<String>,
+    /// Needed for users protected with a PIN
+    pub pin: Option<String>,
+}
+
+impl PlexUserLayer {
+    fn redacted(self) -> Self {
+        Self {
+            anilist_token: self.anilist_token.map(|_| REDACTED.to_string()),
+            pin: self.pin.map(|_| REDACTED.to_string()),
+            ..self
+        }
+    }
+}
+
+impl ConfigLayer {
+    pub fn defaults() -> Self {
+        Self {
+            media_server: Some(DEFAULT_MEDIA_SERVER.to_string()),
+            plex_url: Some(DEFAULT_PLEX_URL.to_string()),
+            libraries: Some(vec![DEFAULT_LIBRARY.to_string()]),
+            schedule: Some(DEFAULT_SCHEDULE.to_string()),
+            timezone: Some(DEFAULT_TIMEZONE.to_string()),
+            run_on_startup: Some(false),
+            jitter_seconds: Some(0),
+            dry_run: Some(false),
+            dropped_after_days: Some(30),
+            paused_after_days: Some(14),
+            update_planning: Some(false),
+            watched_percent: Some(DEFAULT_WATCHED_PERCENT),
+            api_enabled: Some(true),
+            api_address: Some(DEFAULT_API_ADDRESS.to_string()),
+            id_mapping_file: Some(DEFAULT_ID_MAPPING_FILE.to_string()),
+            plex_page_size: Some(DEFAULT_PAGE_SIZE),
+            plex_concurrency: Some(DEFAULT_CONCURRENCY),
+            two_way_sync: Some(false),
+            conflict_policy: Some(DEFAULT_CONFLICT_POLICY.to_string()),
+            exclude_label: Some(DEFAULT_EXCLUDE_LABEL.to_string()),
+            private_label: Some(DEFAULT_PRIVATE_LABEL.to_string()),
+            ..Default::default()
+        }
+    }
+
+    /// Reads a TOML config file. Returns `None` when the file doesn't exist.
+    pub fn from_file(path: &Path) -> Result<Option<Self>, ConfigError> {
+        let contents = match fs::read_to_string(path) {
+            Ok(x) => x,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => {
+                return Err(ConfigError::File {
+                    path: path.display().to_string(),
+                    reason: e.to_string(),
+                })
+            }
+        };
+
+        toml::from_str(&contents)
+            .map(Some)
+            .map_err(|e| ConfigError::File {
+                path: path.display().to_string(),
+                reason: e.to_string(),
+            })
+    }
+
+    pub fn from_env() -> Result<Self, ConfigError> {
+        Self::from_vars(|key| env::var(key).ok())
+    }
+
+    fn from_vars(lookup: impl Fn(&str) -> Option<String>) -> Result<Self, ConfigError> {
+        // Treat empty variables as unset so `PLEX_TOKEN=` doesn't wipe out a lower layer
+        let get = |key: &str| lookup(key).filter(|x| !x.trim().is_empty());
+
+        Ok(Self {
+            media_server: get(MEDIA_SERVER_ENV),
+            media_server_url: get(MEDIA_SERVER_URL_ENV),
+            media_server_api_key: get(MEDIA_SERVER_API_KEY_ENV),
+            media_server_user: get(MEDIA_SERVER_USER_ENV),
+            plex_url: get(PLEX_URL_ENV),
+            plex_token: get(PLEX_TOKEN_ENV),
+            libraries: get(LIBRARIES_ENV).map(|x| split_list(&x)),
+            anilist_token: get(ANILIST_TOKEN_ENV),
+            anilist_client_id: get(ANILIST_CLIENT_ID_ENV),
+            schedule: get(SCHEDULE_ENV),
+            timezone: get(TIMEZONE_ENV).or_else(|| get(TIMEZONE_FALLBACK_ENV)),
+            run_on_startup: parse_var(RUN_ON_STARTUP_ENV, get(RUN_ON_STARTUP_ENV))?,
+            jitter_seconds: parse_var(JITTER_SECONDS_ENV, get(JITTER_SECONDS_ENV))?,
+            dry_run: parse_var(DRY_RUN_ENV, get(DRY_RUN_ENV))?,
+            dropped_after_days: parse_var(DROPPED_AFTER_DAYS_ENV, get(DROPPED_AFTER_DAYS_ENV))?,
+            paused_after_days: parse_var(PAUSED_AFTER_DAYS_ENV, get(PAUSED_AFTER_DAYS_ENV))?,
+            update_planning: parse_var(UPDATE_PLANNING_ENV, get(UPDATE_PLANNING_ENV))?,
+            watched_percent: parse_var(WATCHED_PERCENT_ENV, get(WATCHED_PERCENT_ENV))?,
+            library_rules: None,
+            api_enabled: parse_var(API_ENABLED_ENV, get(API_ENABLED_ENV))?,
+            api_address: get(API_ADDRESS_ENV),
+            users: None,
+            id_mapping_file: get(ID_MAPPING_FILE_ENV),
+            plex_page_size: parse_var(PLEX_PAGE_SIZE_ENV, get(PLEX_PAGE_SIZE_ENV))?,
+            plex_concurrency: parse_var(PLEX_CONCURRENCY_ENV, get(PLEX_CONCURRENCY_ENV))?,
+            two_way_sync: parse_var(TWO_WAY_SYNC_ENV, get(TWO_WAY_SYNC_ENV))?,
+            conflict_policy: get(CONFLICT_POLICY_ENV),
+            exclude_label: get(EXCLUDE_LABEL_ENV),
+            private_label: get(PRIVATE_LABEL_ENV),
+        })
+    }
+
+    /// Returns a new layer with any values set in `overrides` taking precedence.
+    pub fn merge(self, overrides: ConfigLayer) -> ConfigLayer {
+        ConfigLayer {
+            media_server: overrides.media_server.or(self.media_server),
+            media_server_url: overrides.media_server_url.or(self.media_server_url),
+            media_server_api_key: overrides.media_server_api_key.or(self.media_server_api_key),
+            media_server_user: overrides.media_server_user.or(self.media_server_user),
+            plex_url: overrides.plex_url.or(self.plex_url),
+            plex_token: overrides.plex_token.or(self.plex_token),
+            libraries: overrides.libraries.or(self.libraries),
+            anilist_token: overrides.anilist_token.or(self.anilist_token),
+            anilist_client_id: overrides.anilist_client_id.or(self.anilist_client_id),
+            schedule: overrides.schedule.or(self.schedule),
+            timezone: overrides.timezone.or(self.timezone),
+            run_on_startup: overrides.run_on_startup.or(self.run_on_startup),
+            jitter_seconds: overrides.jitter_seconds.or(self.jitter_seconds),
+            dry_run: overrides.dry_run.or(self.dry_run),
+            dropped_after_days: overrides.dropped_after_days.or(self.dropped_after_days),
+            paused_after_days: overrides.paused_after_days.or(self.paused_after_days),
+            update_planning: overrides.update_planning.or(self.update_planning),
+            watched_percent: overrides.watched_percent.or(self.watched_percent),
+            library_rules: overrides.library_rules.or(self.library_rules),
+            api_enabled: overrides.api_enabled.or(self.api_enabled),
+            api_address: overrides.api_address.or(self.api_address),
+            users: overrides.users.or(self.users),
+            id_mapping_file: overrides.id_mapping_file.or(self.id_mapping_file),
+            plex_page_size: overrides.plex_page_size.or(self.plex_page_size),
+            plex_concurrency: overrides.plex_concurrency.or(self.plex_concurrency),
+            two_way_sync: overrides.two_way_sync.or(self.two_way_sync),
+            conflict_policy: overrides.conflict_policy.or(self.conflict_policy),
+            exclude_label: overrides.exclude_label.or(self.exclude_label),
+            private_label: overrides.private_label.or(self.private_label),
+        }
+    }
+
+    /// Replaces any tokens so the layer can be shown to users.
+    pub fn redacted(self) -> Self {
+        Self {
+            media_server_api_key: self.media_server_api_key.map(|_| REDACTED.to_string()),
+            plex_token: self.plex_token.map(|_| REDACTED.to_string()),
+            anilist_token: self.anilist_token.map(|_| REDACTED.to_string()),
+            users: self
+                .users
+                .map(|x| x.into_iter().map(PlexUserLayer::redacted).collect()),
+            ..self
+        }
+    }
+}
+
+/// The fully merged and validated configuration.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Config {
+    pub media_server: MediaServerKind,
+    /// Empty when syncing from Plex
+    pub media_server_url: String,
+    pub media_server_api_key: String,
+    pub media_server_user: String,
+    pub plex_url: String,
+    /// Empty when syncing from Jellyfin or Emby
+    pub plex_token: String,
+    pub libraries: Vec<LibrarySelector>,
+    /// Unset until the user logs in to Anilist
+    pub anilist_token: Option<String>,
+    pub anilist_client_id: Option<String>,
+    pub schedule: Schedule,
+    pub timezone: Tz,
+    pub run_on_startup: bool,
+    pub jitter_seconds: u64,
+    pub dry_run: bool,
+    pub status_rules: StatusRules,
+    pub library_rules: Vec<LibraryRules>,
+    pub api_enabled: bool,
+    pub api_address: SocketAddr,
+    pub users: Vec<PlexUser>,
+    pub id_mapping_file: String,
+    pub plex_page_size: u32,
+    pub plex_concurrency: usize,
+    pub two_way_sync: bool,
+    pub conflict_policy: ConflictPolicy,
+    pub exclude_label: String,
+    pub private_label: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct PlexUser {
+    pub plex_user: String,
+    pub anilist_token: String,
+    pub pin: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct LibraryRules {
+    pub library: LibrarySelector,
+    pub rules: StatusRules,
+}
+
+impl Config {
+    pub fn from_layer(layer: ConfigLayer) -> Result<Self, ConfigError> {
+        let plex_url = require(layer.plex_url, "plex_url", PLEX_URL_ENV)?;
+        validate_url("plex_url", &plex_url)?;
+
+        let media_server = require(layer.media_server, "media_server", MEDIA_SERVER_ENV)?;
+        let media_server = parse_value("media_server", &media_server)?;
+
+        // Plex is reached with the plex token, Jellyfin and Emby with an API key and a user
+        let (plex_token, media_server_url, media_server_api_key, media_server_user) =
+            match media_server {
+                MediaServerKind::Plex => {
+                    let plex_token = require(layer.plex_token, "plex_token", PLEX_TOKEN_ENV)?;
+                    validate_token("plex_token", PLEX_TOKEN_ENV, &plex_token)?;
+                    (plex_token, String::new(), String::new(), String::new())
+                }
+                _ => {
+                    let url = require(
+                        layer.media_server_url,
+                        "media_server_url",
+                        MEDIA_SERVER_URL_ENV,
+                    )?;
+                    validate_url("media_server_url", &url)?;
+                    let api_key = require(
+                        layer.media_server_api_key,
+                        "media_server_api_key",
+                        MEDIA_SERVER_API_KEY_ENV,
+                    )?;
+                    validate_token("media_server_api_key", MEDIA_SERVER_API_KEY_ENV, &api_key)?;
+                    let user = require(
+                        layer.media_server_user,
+                        "media_server_user",
+                        MEDIA_SERVER_USER_ENV,
+                    )?;
+                    (String::new(), url, api_key, user)
+                }
+            };
+
+        let libraries = layer.libraries.unwrap_or_default();
+        if libraries.is_empty() {
+            return Err(ConfigError::Missing {
+                key: "libraries",
+                env_var: LIBRARIES_ENV,
+            });
+        }
+        let libraries = libraries
+            .iter()
+            .map(|x| parse_value("libraries", x))
+            .collect::<Result<_, _>>()?;
+
+        if let Some(anilist_token) = &layer.anilist_token {
+            validate_token("anilist_token", ANILIST_TOKEN_ENV, anilist_token)?;
+        }
+
+        let watched_percent = layer.watched_percent.unwrap_or(DEFAULT_WATCHED_PERCENT);
+        if !(1..=100).contains(&watched_percent) {
+            return Err(ConfigError::InvalidValue {
+                key: "watched_percent",
+                value: watched_percent.to_string(),
+                reason: "has to be between 1 and 100".to_string(),
+            });
+        }
+        let status_rules = StatusRules {
+            dropped_after_days: layer.dropped_after_days.filter(|x| *x > 0),
+            paused_after_days: layer.paused_after_days.filter(|x| *x > 0),
+            update_planning: layer.update_planning.unwrap_or(false),
+            watched_percent,
+        };
+        let library_rules = layer
+            .library_rules
+            .unwrap_or_default()
+            .into_iter()
+            .map(|x| {
+                Ok(LibraryRules {
+                    library: parse_value("library_rules.library", &x.library)?,
+                    rules: StatusRules {
+                        dropped_after_days: x
+                            .dropped_after_days
+                            .or(status_rules.dropped_after_days)
+                            .filter(|x| *x > 0),
+                        paused_after_days: x
+                            .paused_after_days
+                            .or(status_rules.paused_after_days)
+                            .filter(|x| *x > 0),
+                        update_planning: x.update_planning.unwrap_or(status_rules.update_planning),
+                        watched_percent,
+                    },
+                })
+            })
+            .collect::<Result<_, ConfigError>>()?;
+
+        let mut users: Vec<PlexUser> = vec![];
+        for user in layer.users.unwrap_or_default() {
+            if user.plex_user.trim().is_empty() {
+                return Err(ConfigError::InvalidValue {
+                    key: "users.plex_user",
+                    value: user.plex_user,
+                    reason: "the Plex user can't be empty".to_string(),
+                });
+            }
+            if users
+                .iter()
+                .any(|x| x.plex_user.eq_ignore_ascii_case(&user.plex_user))
+            {
+                return Err(ConfigError::InvalidValue {
+                    key: "users.plex_user",
+                    value: user.plex_user,
+                    reason: "the Plex user is listed more than once".to_string(),
+                });
+            }
+            let anilist_token = match user.anilist_token {
+                Some(x) => x,
+                None => {
+                    return Err(ConfigError::InvalidValue {
+                        key: "users.anilist_token",
+                        value: user.plex_user,
+                        reason: "every Plex user needs an Anilist token".to_string(),
+                    })
+                }
+            };
+            validate_token("users.anilist_token", ANILIST_TOKEN_ENV, &anilist_token)?;
+            users.push(PlexUser {
+                plex_user: user.plex_user,
+                anilist_token,
+                pin: user.pin,
+            });
+        }
+
+        let id_mapping_file = require(
+            layer.id_mapping_file,
+            "id_mapping_file",
+            ID_MAPPING_FILE_ENV,
+        )?;
+
+        let plex_page_size = layer.plex_page_size.unwrap_or(DEFAULT_PAGE_SIZE);
+        if plex_page_size == 0 {
+            return Err(ConfigError::InvalidValue {
+                key: "plex_page_size",
+                value: plex_page_size.to_string(),
+                reason: "at least one series has to be loaded at a time".to_string(),
+            });
+        }
+        let plex_concurrency = layer.plex_concurrency.unwrap_or(DEFAULT_CONCURRENCY);
+        if plex_concurrency == 0 {
+            return Err(ConfigError::InvalidValue {
+                key: "plex_concurrency",
+                value: plex_concurrency.to_string(),
+                reason: "at least one request has to be allowed at a time".to_string(),
+            });
+        }
+
+        let conflict_policy = require(
+            layer.conflict_policy,
+            "conflict_policy",
+            CONFLICT_POLICY_ENV,
+        )?;
+        let conflict_policy = parse_value("conflict_policy", &conflict_policy)?;
+
+        let exclude_label = require(layer.exclude_label, "exclude_label", EXCLUDE_LABEL_ENV)?;
+        let private_label = require(layer.private_label, "private_label", PRIVATE_LABEL_ENV)?;
+
+        let api_address = require(layer.api_address, "api_address", API_ADDRESS_ENV)?;
+        let api_address = parse_value("api_address", &api_address)?;
+
+        let schedule = require(layer.schedule, "schedule", SCHEDULE_ENV)?;
+        let schedule = parse_value("schedule", &schedule)?;
+
+        let timezone = require(layer.timezone, "timezone", TIMEZONE_ENV)?;
+        let timezone = parse_value("timezone", &timezone)?;
+
+        Ok(Self {
+            media_server,
+            media_server_url,
+            media_server_api_key,
+            media_server_user,
+            plex_url,
+            plex_token,
+            libraries,
+            anilist_token: layer.anilist_token,
+            anilist_client_id: layer.anilist_client_id,
+            schedule,
+            timezone,
+            run_on_startup: layer.run_on_startup.unwrap_or(false),
+            jitter_seconds: layer.jitter_seconds.unwrap_or(0),
+            dry_run: layer.dry_run.unwrap_or(false),
+            status_rules,
+            library_rules,
+            api_enabled: layer.api_enabled.unwrap_or(true),
+            api_address,
+            users,
+            id_mapping_file,
+            plex_page_size,
+            plex_concurrency,
+            two_way_sync: layer.two_way_sync.unwrap_or(false),
+            conflict_policy,
+            exclude_label,
+            private_label,
+        })
+    }
+
+    /// Replaces the tokens so the config can be shown to users.
+    pub fn redacted(self) -> Self {
+        Self {
+            media_server_api_key: REDACTED.to_string(),
+            plex_token: REDACTED.to_string(),
+            anilist_token: self.anilist_token.map(|_| REDACTED.to_string()),
+            users: self
+                .users
+                .into_iter()
+                .map(|x| PlexUser {
+                    anilist_token: REDACTED.to_string(),
+                    pin: x.pin.map(|_| REDACTED.to_string()),
+                    ..x
+                })
+                .collect(),
+            ..self
+        }
+    }
+
+    /// The Anilist token, or an error asking the user to log in when there isn't one.
+    pub fn get_anilist_token(&self) -> Result<String, ConfigError> {
+        self.anilist_token.clone().ok_or(ConfigError::NotLoggedIn)
+    }
+
+    /// The status settings for a library, either from the first matching library rule or the
+    /// global settings.
+    pub fn get_status_rules(&self, library: &ResponsePlexLibrary) -> &StatusRules {
+        self.library_rules
+            .iter()
+            .find(|x| x.library.matches(library))
+            .map(|x| &x.rules)
+            .unwrap_or(&self.status_rules)
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ConfigError {
+    Missing {
+        key: &'static str,
+        env_var: &'static str,
+    },
+    InvalidUrl {
+        key: &'static str,
+        value: String,
+        reason: String,
+    },
+    InvalidToken {
+        key: &'static str,
+        reason: String,
+    },
+    InvalidValue {
+        key: &'static str,
+        value: String,
+        reason: String,
+    },
+    File {
+        path: String,
+        reason: String,
+    },
+    /// There is no Anilist token, the user has to log in first
+    NotLoggedIn,
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Missing { key, env_var } => write!(
+                f,
+                "'{}' is not set. Set the {} environment variable or add it to the config file",
+                key, env_var
+            ),
+            ConfigError::NotLoggedIn => write!(
+                f,
+                "Not logged in to Anilist. Log in from the web UI or set the {} environment variable",
+                ANILIST_TOKEN_ENV
+            ),
+            ConfigError::InvalidUrl { key, value, reason } => {
+                write!(f, "'{}' is not a valid url ('{}'): {}", key, value, reason)
+            }
+            ConfigError::InvalidToken { key, reason } => {
+                write!(f, "'{}' is not a valid token: {}", key, reason)
+            }
+            ConfigError::InvalidValue { key, value, reason } => {
+                write!(
+                    f,
+                    "'{}' has an invalid value ('{}'): {}",
+                    key, value, reason
+                )
+            }
+            ConfigError::File { path, reason } => {
+                write!(f, "Failed to read config file '{}': {}", path, reason)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+fn require(
+    value: Option<String>,
+    key: &'static str,
+    env_var: &'static str,
+) -> Result<String, ConfigError> {
+    match value {
+        Some(x) if !x.trim().is_empty() => Ok(x.trim().to_string()),
+        _ => Err(ConfigError::Missing { key, env_var }),
+    }
+}
+
+fn parse_value<T>(key: &'static str, value: &str) -> Result<T, ConfigError>
+where
+    T: FromStr,
+    T::Err: fmt::Display,
+{
+    value
+        .parse()
+        .map_err(|e: T::Err| ConfigError::InvalidValue {
+            key,
+            value: value.to_string(),
+            reason: e.to_string(),
+        })
+}
+
+fn parse_var<T>(key: &'static str, value: Option<String>) -> Result<Option<T>, ConfigError>
+where
+    T: FromStr,
+    T::Err: fmt::Display,
+{
+    value.map(|x| parse_value(key, x.trim())).transpose()
+}
+
+/// Splits a comma separated environment variable, dropping empty entries.
+fn split_list(value: &str) -> Vec<String> {
+    value
+        .split(',')
+        .map(|x| x.trim().to_string())
+        .filter(|x| !x.is_empty())
+        .collect()
+}
+
+fn validate_url(key: &'static str, value: &str) -> Result<(), ConfigError> {
+    let invalid = |reason: String| ConfigError::InvalidUrl {
+        key,
+        value: value.to_string(),
+        reason,
+    };
+
+    let url = Url::parse(value).map_err(|e| invalid(e.to_string()))?;
+    if url.scheme() != "http" && url.scheme() != "https" {
+        return Err(invalid(format!(
+            "expected an http or https url but got scheme '{}'",
+            url.scheme()
+        )));
+    }
+    if url.host_str().is_none() {
+        return Err(invalid("url has no host".to_string()));
+    }
+
+    Ok(())
+}
+
+pub fn validate_token(key: &'static str, env_var: &str, value: &str) -> Result<(), ConfigError> {
+    // The docker-compose example ships with the variable names as placeholder values
+    if value == env_var {
+        return Err(ConfigError::InvalidToken {
+            key,
+            reason: "the example placeholder value hasn't been replaced".to_string(),
+        });
+    }
+
+    if value.chars().any(|x| x.is_whitespace() || x.is_control()) {
+        return Err(ConfigError::InvalidToken {
+            key,
+            reason: "tokens can't contain whitespace or control characters".to_string(),
+        });
+    }
+
+    Ok(())
+}
+
+/// Builds the config from, in order of precedence: environment variables, the config file, the
+/// config table in the database and finally the built-in defaults.
+///
+/// A config file passed in explicitly must exist, the default one is optional.
+pub async fn load_config(
+    db_store: &impl DbStore,
+    config_file: Option<&str>,
+) -> Result<Config, ConfigError> {
+    build_config(db_store.get_config().await, config_file)
+}
+
+/// Same as `load_config` but with the database layer passed in, so a new database layer can be
+/// validated before it's saved.
+pub fn build_config(
+    db_layer: ConfigLayer,
+    config_file: Option<&str>,
+) -> Result<Config, ConfigError> {
+    Config::from_layer(merge_layers(db_layer, config_file)?)
+}
+
+/// Stacks the config layers without validating the result, for reading single values before the
+/// rest of the config has been filled in.
+pub fn merge_layers(
+    db_layer: ConfigLayer,
+    config_file: Option<&str>,
+) -> Result<ConfigLayer, ConfigError> {
+    let required = config_file.is_some();
+    let config_file = config_file.unwrap_or(DEFAULT_CONFIG_FILE).to_string();
+
+    let file_layer = match ConfigLayer::from_file(Path::new(&config_file))? {
+        Some(x) => {
+            info!("Loaded config file '{}'", config_file);
+            x
+        }
+        None if required => {
+            return Err(ConfigError::File {
+                path: config_file,
+                reason: "file does not exist".to_string(),
+            })
+        }
+        None => ConfigLayer::default(),
+    };
+
+    Ok(ConfigLayer::defaults()
+        .merge(db_layer)
+        .merge(file_layer)
+        .merge(ConfigLayer::from_env()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    fn layer(plex_url: &str, plex_token: &str, anilist_token: &str) -> ConfigLayer {
+        ConfigLayer {
+            plex_url: Some(plex_url.to_string()),
+            plex_token: Some(plex_token.to_string()),
+            anilist_token: Some(anilist_token.to_string()),
+            ..ConfigLayer::defaults()
+        }
+    }
+
+    #[test]
+    fn test_merge_prefers_overrides() {
+        let base = layer("http://localhost:32400", "plex123", "anilist123");
+        let overrides = ConfigLayer {
+            plex_token: Some("plex456".to_string()),
+            ..Default::default()
+        };
+
+        let result = base.merge(overrides);
+
+        assert_eq!(
+            layer("http://localhost:32400", "plex456", "anilist123"),
+            result
+        );
+    }
+
+    #[test]
+    fn test_from_vars_ignores_empty_values() {
+        let vars = HashMap::from([
+            (PLEX_URL_ENV, "http://plex:32400"),
+            (PLEX_TOKEN_ENV, ""),
+            (ANILIST_TOKEN_ENV, "anilist123"),
+        ]);
+
+        let result = ConfigLayer::from_vars(|key| vars.get(key).map(|x| x.to_string()))
+            .expect("Failed to read variables");
+
+        assert_eq!(Some("http://plex:32400".to_string()), result.plex_url);
+        assert_eq!(None, result.plex_token);
+        assert_eq!(Some("anilist123".to_string()), result.anilist_token);
+    }
+
+    #[test]
+    fn test_from_layer_with_valid_values() {
+        let result = Config::from_layer(layer("https://plex.local", "plex123", "anilist123"))
+            .expect("Failed to build config");
+
+        assert_eq!("https://plex.local", result.plex_url);
+        assert_eq!("plex123", result.plex_token);
+        assert_eq!(Some("anilist123".to_string()), result.anilist_token);
+    }
+
+    #[test]
+    fn test_from_layer_missing_token() {
+        let mut config_layer = layer("http://localhost:32400", "plex123", "anilist123");
+        config_layer.anilist_token = None;
+
+        let result = Config::from_layer(config_layer).expect("Failed to build config");
+
+        assert_eq!(None, result.anilist_token);
+        assert_eq!(Err(ConfigError::NotLoggedIn), result.get_anilist_token());
+    }
+
+    #[test]
+    fn test_from_layer_invalid_url() {
+        let result = Config::from_layer(layer("ftp://localhost", "plex123", "anilist123"));
+
+        assert!(matches!(
+            result,
+            Err(ConfigError::InvalidUrl {
+                key: "plex_url",
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn test_from_vars_parses_schedule_settings() {
+        let vars = HashMap::from([
+            (SCHEDULE_ENV, "6h"),
+            (RUN_ON_STARTUP_ENV, "true"),
+            (JITTER_SECONDS_ENV, "300"),
+        ]);
+
+        let result = ConfigLayer::from_vars(|key| vars.get(key).map(|x| x.to_string()))
+            .expect("Failed to read variables");
+
+        assert_eq!(Some("6h".to_string()), result.schedule);
+        assert_eq!(Some(true), result.run_on_startup);
+        assert_eq!(Some(300), result.jitter_seconds);
+    }
+
+    #[test]
+    fn test_from_vars_timezone_falls_back_to_tz() {
+        let vars = HashMap::from([(TIMEZONE_FALLBACK_ENV, "Europe/London")]);
+        let result = ConfigLayer::from_vars(|key| vars.get(key).map(|x| x.to_string()))
+            .expect("Failed to read variables");
+        assert_eq!(Some("Europe/London".to_string()), result.timezone);
+
+        let vars = HashMap::from([
+            (TIMEZONE_ENV, "Asia/Tokyo"),
+            (TIMEZONE_FALLBACK_ENV, "Europe/London"),
+        ]);
+        let result = ConfigLayer::from_vars(|key| vars.get(key).map(|x| x.to_string()))
+            .expect("Failed to read variables");
+        assert_eq!(Some("Asia/Tokyo".to_string()), result.timezone);
+    }
+
+    #[test]
+    fn test_from_vars_invalid_bool() {
+        let vars = HashMap::from([(RUN_ON_STARTUP_ENV, "sometimes")]);
+
+        let result = ConfigLayer::from_vars(|key| vars.get(key).map(|x| x.to_string()));
+
+        assert!(matches!(
+            result,
+            Err(ConfigError::InvalidValue {
+                key: RUN_ON_STARTUP_ENV,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn test_from_vars_splits_libraries() {
+        let vars = HashMap::from([(LIBRARIES_ENV, "Anime, key:5,,type:show")]);
+
+        let result = ConfigLayer::from_vars(|key| vars.get(key).map(|x| x.to_string()))
+            .expect("Failed to read variables");
+
+        assert_eq!(
+            Some(vec![
+                "Anime".to_string(),
+                "key:5".to_string(),
+                "type:show".to_string()
+            ]),
+            result.libraries
+        );
+    }
+
+    #[test]
+    fn test_from_layer_invalid_library_selector() {
+        let mut config_layer = layer("http://localhost:32400", "plex123", "anilist123");
+        config_layer.libraries = Some(vec!["section:1".to_string()]);
+
+        let result = Config::from_layer(config_layer);
+
+        assert!(matches!(
+            result,
+            Err(ConfigError::InvalidValue {
+                key: "libraries",
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn test_from_layer_invalid_timezone() {
+        let mut config_layer = layer("http://localhost:32400", "plex123", "anilist123");
+        config_layer.timezone = Some("Mars/Olympus_Mons".to_string());
+
+        let result = Config::from_layer(config_layer);
+
+        assert!(matches!(
+            result,
+            Err(ConfigError::InvalidValue {
+                key: "timezone",
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn test_from_layer_zero_plex_page_size() {
+        let mut config_layer = layer("http://localhost:32400", "plex123", "anilist123");
+        config_layer.plex_page_size = Some(0);
+
+        let result = Config::from_layer(config_layer);
+
+        assert!(matches!(
+            result,
+            Err(ConfigError::InvalidValue {
+                key: "plex_page_size",
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn test_from_layer_invalid_watched_percent() {
+        let mut config_layer = layer("http://localhost:32400", "plex123", "anilist123");
+        config_layer.watched_percent = Some(0);
+
+        let result = Config::from_layer(config_layer);
+
+        assert!(matches!(
+            result,
+            Err(ConfigError::InvalidValue {
+                key: "watched_percent",
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn test_from_layer_conflict_policy() {
+        let mut config_layer = layer("http://localhost:32400", "plex123", "anilist123");
+        config_layer.conflict_policy = Some("anilist".to_string());
+        let result = Config::from_layer(config_layer.clone()).expect("Invalid config");
+        assert_eq!(ConflictPolicy::Anilist, result.conflict_policy);
+        assert!(!result.two_way_sync);
+
+        config_layer.conflict_policy = Some("both".to_string());
+        let result = Config::from_layer(config_layer);
+        assert!(matches!(
+            result,
+            Err(ConfigError::InvalidValue {
+                key: "conflict_policy",
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn test_from_layer_jellyfin() {
+        let mut config_layer = ConfigLayer {
+            media_server: Some("jellyfin".to_string()),
+            media_server_url: Some("http://localhost:8096".to_string()),
+            media_server_api_key: Some("key123".to_string()),
+            ..ConfigLayer::defaults()
+        };
+        let result = Config::from_layer(config_layer.clone());
+        assert_eq!(
+            Err(ConfigError::Missing {
+                key: "media_server_user",
+                env_var: MEDIA_SERVER_USER_ENV,
+            }),
+            result
+        );
+
+        config_layer.media_server_user = Some("alice".to_string());
+        let result = Config::from_layer(config_layer).expect("Invalid config");
+        assert_eq!(MediaServerKind::Jellyfin, result.media_server);
+        assert_eq!("alice", result.media_server_user);
+        assert_eq!("", result.plex_token);
+        assert_eq!(REDACTED, result.redacted().media_server_api_key);
+    }
+
+    #[test]
+    fn test_from_layer_placeholder_token() {
+        let result = Config::from_layer(layer("http://localhost:32400", "PLEX_TOKEN", "abc"));
+
+        assert!(matches!(
+            result,
+            Err(ConfigError::InvalidToken {
+                key: "plex_token",
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn test_from_layer_zero_days_disables_status() {
+        let mut config_layer = layer("http://localhost:32400", "plex123", "anilist123");
+        config_layer.dropped_after_days = Some(0);
+
+        let result = Config::from_layer(config_layer).expect("Failed to build config");
+
+        assert_eq!(None, result.status_rules.dropped_after_days);
+        assert_eq!(Some(14), result.status_rules.paused_after_days);
+    }
+
+    #[test]
+    fn test_get_status_rules_for_library() {
+        let mut config_layer = layer("http://localhost:32400", "plex123", "anilist123");
+        config_layer.library_rules = Some(vec![LibraryRulesLayer {
+            library: "title:Kids Anime".to_string(),
+            dropped_after_days: Some(0),
+            update_planning: Some(true),
+            ..Default::default()
+        }]);
+        let config = Config::from_layer(config_layer).expect("Failed to build config");
+        let mut library = ResponsePlexLibrary {
+            key: "2".to_string(),
+            title: "Kids Anime".to_string(),
+            ..Default::default()
+        };
+
+        let rules = config.get_status_rules(&library);
+
+        assert_eq!(None, rules.dropped_after_days);
+        assert_eq!(Some(14), rules.paused_after_days);
+        assert!(rules.update_planning);
+
+        library.title = "Anime".to_string();
+        assert_eq!(&config.status_rules, config.get_status_rules(&library));
+    }
+
+    #[test]
+    fn test_from_file() {
+        let path = env::temp_dir().join("plex_ani_sync_test_from_file.toml");
+        fs::write(
+            &path,
+            "plex_url = \"http://plex:32400\"\nanilist_token = \"abc\"\n",
+        )
+        .expect("Failed to write test config file");
+
+        let result = ConfigLayer::from_file(&path)
+            .expect("Failed to read config file")
+            .expect("Config file not found");
+        let _ = fs::remove_file(&path);
+
+        assert_eq!(Some("http://plex:32400".to_string()), result.plex_url);
+        assert_eq!(None, result.plex_token);
+        assert_eq!(Some("abc".to_string()), result.anilist_token);
+    }
+
+    #[test]
+    fn test_from_file_with_library_rules() {
+        let path = env::temp_dir().join("plex_ani_sync_test_library_rules.toml");
+        fs::write(
+            &path,
+            "dropped_after_days = 60\n\n[[library_rules]]\nlibrary = \"key:3\"\ndropped_after_days = 0\n",
+        )
+        .expect("Failed to write test config file");
+
+        let result = ConfigLayer::from_file(&path)
+            .expect("Failed to read config file")
+            .expect("Config file not found");
+        let _ = fs::remove_file(&path);
+
+        assert_eq!(Some(60), result.dropped_after_days);
+        assert_eq!(
+            Some(vec![LibraryRulesLayer {
+                library: "key:3".to_string(),
+                dropped_after_days: Some(0),
+                ..Default::default()
+            }]),
+            result.library_rules
+        );
+    }
+
+    #[test]
+    fn test_from_file_with_users() {
+        let path = env::temp_dir().join("plex_ani_sync_test_users.toml");
+        fs::write(
+            &path,
+            "[[users]]\nplex_user = \"Alice\"\nanilist_token = \"alice123\"\npin = \"1234\"\n",
+        )
+        .expect("Failed to write test config file");
+
+        let result = ConfigLayer::from_file(&path)
+            .expect("Failed to read config file")
+            .expect("Config file not found");
+        let _ = fs::remove_file(&path);
+
+        let mut config_layer = layer("http://localhost:32400", "plex123", "anilist123");
+        config_layer.users = result.users;
+        let config = Config::from_layer(config_layer.clone()).expect("Invalid config");
+        assert_eq!(
+            vec![PlexUser {
+                plex_user: "Alice".to_string(),
+                anilist_token: "alice123".to_string(),
+                pin: Some("1234".to_string()),
+            }],
+            config.users
+        );
+
+        let redacted = config_layer.redacted().users.expect("Users are missing");
+        assert_eq!(Some(REDACTED.to_string()), redacted[0].anilist_token);
+        assert_eq!(Some(REDACTED.to_string()), redacted[0].pin);
+    }
+
+    #[test]
+    fn test_from_layer_user_without_anilist_token() {
+        let mut config_layer = layer("http://localhost:32400", "plex123", "anilist123");
+        config_layer.users = Some(vec![PlexUserLayer {
+            plex_user: "Alice".to_string(),
+            ..Default::default()
+        }]);
+
+        let result = Config::from_layer(config_layer);
+
+        assert!(matches!(
+            result,
+            Err(ConfigError::InvalidValue {
+                key: "users.anilist_token",
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn test_from_file_missing_file() {
+        let result = ConfigLayer::from_file(Path::new("./test_data/does_not_exist.toml"));
+
+        assert_eq!(Ok(None), result);
+    }
+}
@@ -1,8 +1,10 @@
 use async_trait::async_trait;
 
-use crate::services::anime_list_service::anime_list_service::AnimeResult;
+use crate::services::{
+    anime_list_service::anime_list_service::AnimeResult, config::config::ConfigLayer,
+};
 
-use super::sqlite::{Config, Mapping};
+use super::sqlite::{EpisodeViews, Mapping, SyncRun, WatchHistoryEntry};
 
 #[async_trait]
 pub trait DbStore: Sync + Send {
@@ -11,12 +13,36 @@ pub trait DbStore: Sync + Send {
     async fn get_cached_anime_result(&self, anime_id: u32) -> Option<AnimeResult>;
     async fn save_cached_anime_result(&self, anime_id: u32, data: AnimeResult);
     async fn clear_anime_search_cache(&self);
-    async fn get_config(&self) -> Config;
+    async fn get_config(&self) -> ConfigLayer;
+    async fn save_config(&self, config: &ConfigLayer) -> Result<(), sqlx::Error>;
     async fn get_mappings(&self) -> Result<Vec<Mapping>, sqlx::Error>;
-    async fn save_mapping(&self, mapping: &Mapping) -> Result<(), sqlx::Error>;
+    /// Returns the id of the new mapping
+    async fn save_mapping(&self, mapping: &Mapping) -> Result<u32, sqlx::Error>;
     async fn get_mapping_for_series(
         &self,
         plex_series_id: &str,
     ) -> Result<Vec<Mapping>, sqlx::Error>;
-    async fn get_all_mappings(&self) -> Result<Vec<Mapping>, sqlx::Error>;
+    async fn get_mapping(&self, id: u32) -> Result<Option<Mapping>, sqlx::Error>;
+    async fn update_mapping(&self, mapping: &Mapping) -> Result<bool, sqlx::Error>;
+    async fn delete_mapping(&self, id: u32) -> Result<bool, sqlx::Error>;
+    /// Records the start of a sync and returns the id of the run
+    /// `plex_user` is `None` for syncs of the server owner
+    async fn start_sync_run(
+        &self,
+        dry_run: bool,
+        plex_user: Option<&str>,
+    ) -> Result<u32, sqlx::Error>;
+    async fn finish_sync_run(&self, run: &SyncRun) -> Result<(), sqlx::Error>;
+    async fn get_last_sync_run(&self) -> Result<Option<SyncRun>, sqlx::Error>;
+    /// Views that are already saved are skipped
+    async fn save_watch_history(&self, views: &[WatchHistoryEntry]) -> Result<(), sqlx::Error>;
+    /// The time of the latest saved view of a Plex account
+    async fn get_last_watch_history_view(
+        &self,
+        account_id: u64,
+    ) -> Result<Option<i64>, sqlx::Error>;
+    async fn get_episode_views(&self, account_id: u64) -> Result<Vec<EpisodeViews>, sqlx::Error>;
+    /// The id this install identifies itself to Plex with, `None` before it was first saved
+    async fn get_client_identifier(&self) -> Result<Option<String>, sqlx::Error>;
+    async fn save_client_identifier(&self, identifier: &str) -> Result<(), sqlx::Error>;
 }
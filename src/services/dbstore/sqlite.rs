@@ -1,7 +1,10 @@
 use std::str::FromStr;
 
 use super::dbstore::DbStore;
-use crate::services::anime_list_service::anime_list_service::AnimeResult;
+use crate::services::{
+    anime_list_service::anime_list_service::AnimeResult,
+    config::config::{ConfigLayer, DEFAULT_PLEX_URL},
+};
 
 use async_trait::async_trait;
 use log::{error, info};
@@ -45,13 +48,115 @@ impl Sqlite {
 
 #[async_trait]
 impl DbStore for Sqlite {
-    async fn get_config(&self) -> Config {
-        let result = sqlx::query_as::<_, Config>("SELECT * FROM config LIMIT 1")
-            .fetch_one(&self.pool)
+    async fn get_config(&self) -> ConfigLayer {
+        let result = sqlx::query_as::<_, ConfigRow>("SELECT * FROM config LIMIT 1")
+            .fetch_optional(&self.pool)
             .await
             .expect("Failed to load config");
 
-        return result;
+        match result {
+            Some(x) => x.into(),
+            None => ConfigLayer::default(),
+        }
+    }
+
+    async fn save_config(&self, config: &ConfigLayer) -> Result<(), sqlx::Error> {
+        // The url and tokens have their own columns, everything else is stored as JSON
+        let settings = ConfigLayer {
+            plex_url: None,
+            plex_token: None,
+            anilist_token: None,
+            ..config.clone()
+        };
+        let settings = serde_json::to_string(&settings).expect("Failed to serialize config");
+
+        sqlx::query("UPDATE config SET plex_url = ?, plex_token = ?, anilist_token = ?, settings = ? WHERE id = (SELECT id FROM config LIMIT 1)")
+            .bind(config.plex_url.as_deref().unwrap_or(DEFAULT_PLEX_URL))
+            .bind(&config.plex_token)
+            .bind(&config.anilist_token)
+            .bind(settings)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn start_sync_run(
+        &self,
+        dry_run: bool,
+        plex_user: Option<&str>,
+    ) -> Result<u32, sqlx::Error> {
+        let result = sqlx::query(
+            "INSERT INTO sync_run (status, dry_run, started_at, plex_user) VALUES (?, ?, strftime('%s', 'now'), ?)",
+        )
+        .bind(SyncRunStatus::Running)
+        .bind(dry_run)
+        .bind(plex_user)
+        .execute(&self.pool)
+        .await?;
+        Ok(result.last_insert_rowid() as u32)
+    }
+
+    async fn finish_sync_run(&self, run: &SyncRun) -> Result<(), sqlx::Error> {
+        sqlx::query("UPDATE sync_run SET status = ?, finished_at = strftime('%s', 'now'), updated = ?, failed = ?, unchanged = ?, error = ? WHERE id = ?")
+            .bind(run.status)
+            .bind(run.updated)
+            .bind(run.failed)
+            .bind(run.unchanged)
+            .bind(&run.error)
+            .bind(run.id)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn get_last_sync_run(&self) -> Result<Option<SyncRun>, sqlx::Error> {
+        sqlx::query_as::<_, SyncRun>("SELECT * FROM sync_run ORDER BY id DESC LIMIT 1")
+            .fetch_optional(&self.pool)
+            .await
+    }
+
+    async fn save_watch_history(&self, views: &[WatchHistoryEntry]) -> Result<(), sqlx::Error> {
+        let mut transaction = self.pool.begin().await?;
+        for view in views.iter() {
+            sqlx::query("INSERT OR IGNORE INTO watch_history (account_id, rating_key, viewed_at) VALUES (?, ?, ?)")
+                .bind(view.account_id as i64)
+                .bind(&view.rating_key)
+                .bind(view.viewed_at)
+                .execute(&mut transaction)
+                .await?;
+        }
+        transaction.commit().await
+    }
+
+    async fn get_last_watch_history_view(
+        &self,
+        account_id: u64,
+    ) -> Result<Option<i64>, sqlx::Error> {
+        sqlx::query_scalar("SELECT MAX(viewed_at) FROM watch_history WHERE account_id = ?")
+            .bind(account_id as i64)
+            .fetch_one(&self.pool)
+            .await
+    }
+
+    async fn get_episode_views(&self, account_id: u64) -> Result<Vec<EpisodeViews>, sqlx::Error> {
+        sqlx::query_as::<_, EpisodeViews>("SELECT rating_key, MIN(viewed_at) AS first_viewed_at, MAX(viewed_at) AS last_viewed_at, COUNT(*) AS views FROM watch_history WHERE account_id = ? GROUP BY rating_key")
+            .bind(account_id as i64)
+            .fetch_all(&self.pool)
+            .await
+    }
+
+    async fn get_client_identifier(&self) -> Result<Option<String>, sqlx::Error> {
+        sqlx::query_scalar("SELECT identifier FROM client WHERE id = 1")
+            .fetch_optional(&self.pool)
+            .await
+    }
+
+    async fn save_client_identifier(&self, identifier: &str) -> Result<(), sqlx::Error> {
+        sqlx::query("INSERT OR REPLACE INTO client (id, identifier) VALUES (1, ?)")
+            .bind(identifier)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
     }
 
     async fn get_cached_anime_search_result(&self, search_term: &str) -> Option<Vec<AnimeResult>> {
@@ -110,13 +215,7 @@ impl DbStore for Sqlite {
                 .await
                 .unwrap_or(None);
 
-        if !search_result.is_some() {
-            return None;
-        }
-
-        let data = search_result
-            .expect("Errored getting cached anime data")
-            .data;
+        let data = search_result?.data;
 
         let anime_data: AnimeResult =
             serde_json::from_str(data.as_str()).expect("Failed to deserialize cached data");
@@ -146,50 +245,83 @@ impl DbStore for Sqlite {
     }
 
     async fn get_mappings(&self) -> Result<Vec<Mapping>, sqlx::Error> {
-        sqlx::query_as::<_, Mapping>("SELECT * FROM mapping")
-            .fetch_all(&self.pool)
-            .await
+        sqlx::query_as::<_, Mapping>(
+            "SELECT mapping.*, a.episodes FROM mapping LEFT JOIN anime a on anime_id = anime_list_id ORDER BY mapping.id",
+        )
+        .fetch_all(&self.pool)
+        .await
     }
 
     async fn get_mapping_for_series(
         &self,
         plex_series_id: &str,
     ) -> Result<Vec<Mapping>, sqlx::Error> {
-        sqlx::query_as::<_, Mapping>("SELECT *, a.episodes FROM mapping INNER JOIN anime a on anime_id = anime_list_id WHERE plex_series_id = ?")
+        sqlx::query_as::<_, Mapping>("SELECT mapping.*, a.episodes FROM mapping LEFT JOIN anime a on anime_id = anime_list_id WHERE plex_series_id = ?")
             .bind(plex_series_id)
             .fetch_all(&self.pool)
             .await
     }
 
-    async fn get_all_mappings(&self) -> Result<Vec<Mapping>, sqlx::Error> {
+    async fn get_mapping(&self, id: u32) -> Result<Option<Mapping>, sqlx::Error> {
         sqlx::query_as::<_, Mapping>(
-            "SELECT *, a.episodes FROM mapping INNER JOIN anime a on anime_id = anime_list_id;",
+            "SELECT mapping.*, a.episodes FROM mapping LEFT JOIN anime a on anime_id = anime_list_id WHERE mapping.id = ?",
         )
-        .fetch_all(&__self.pool)
+        .bind(id)
+        .fetch_optional(&self.pool)
         .await
     }
 
-    async fn save_mapping(&self, mapping: &Mapping) -> Result<(), sqlx::Error> {
-        sqlx::query("INSERT OR REPLACE INTO anime (anime_id, episodes) VALUES (?, ?); INSERT INTO mapping (list_provider_id, plex_id, plex_series_id, plex_episode_start, season_length, anime_list_id, episode_start, enabled, ignored) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?)")
-        .bind(&mapping.anime_list_id)
+    async fn update_mapping(&self, mapping: &Mapping) -> Result<bool, sqlx::Error> {
+        // The mapping can be moved to an entry that isn't in the anime table yet
+        sqlx::query("INSERT INTO anime (anime_id, episodes) VALUES (?, ?) ON CONFLICT(anime_id) DO UPDATE SET episodes = COALESCE(excluded.episodes, anime.episodes)")
+            .bind(mapping.anime_list_id)
+            .bind(mapping.episodes)
+            .execute(&self.pool)
+            .await?;
+
+        let result = sqlx::query("UPDATE mapping SET plex_id = ?, plex_series_id = ?, plex_episode_start = ?, season_length = ?, anime_list_id = ?, episode_start = ?, enabled = ?, ignored = ? WHERE id = ?")
+            .bind(&mapping.plex_id)
+            .bind(&mapping.plex_series_id)
+            .bind(mapping.plex_episode_start)
+            .bind(mapping.season_length)
+            .bind(mapping.anime_list_id)
+            .bind(mapping.episode_start)
+            .bind(mapping.enabled)
+            .bind(mapping.ignored)
+            .bind(mapping.id)
+            .execute(&self.pool)
+            .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    async fn delete_mapping(&self, id: u32) -> Result<bool, sqlx::Error> {
+        let result = sqlx::query("DELETE FROM mapping WHERE id = ?")
+            .bind(id)
+            .execute(&self.pool)
+            .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    async fn save_mapping(&self, mapping: &Mapping) -> Result<u32, sqlx::Error> {
+        // A mapping saved without an episode count keeps the one already known
+        let result = sqlx::query("INSERT INTO anime (anime_id, episodes) VALUES (?, ?) ON CONFLICT(anime_id) DO UPDATE SET episodes = COALESCE(excluded.episodes, anime.episodes); INSERT INTO mapping (list_provider_id, plex_id, plex_series_id, plex_episode_start, season_length, anime_list_id, episode_start, enabled, ignored) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?)")
+        .bind(mapping.anime_list_id)
         .bind(mapping.episodes)
             .bind(mapping.list_provider_id)
             .bind(&mapping.plex_id)
             .bind(&mapping.plex_series_id)
             .bind(mapping.plex_episode_start)
             .bind(mapping.season_length)
-            .bind(&mapping.anime_list_id)
+            .bind(mapping.anime_list_id)
             .bind(mapping.episode_start)
             .bind(mapping.enabled)
             .bind(mapping.ignored)
             .execute(&self.pool)
             .await?;
-        Ok(())
+        Ok(result.last_insert_rowid() as u32)
     }
 }
 
-trait CustomTrait: Copy + Sync + Send {}
-
 #[derive(FromRow, Clone, Serialize, Deserialize)]
 pub struct CachedAnimeResult {
     id: u32,
@@ -205,28 +337,77 @@ pub struct CachedAnime {
 }
 
 #[derive(Debug, FromRow, Clone, Serialize, Deserialize)]
-pub struct Config {
+pub struct ConfigRow {
     id: u32,
     pub plex_url: String,
-    pub plex_token: String,
-    pub anilist_token: String,
+    pub plex_token: Option<String>,
+    pub anilist_token: Option<String>,
+    pub settings: Option<String>,
 }
 
-impl Config {
-    pub fn new(plex_url: String, plex_token: String, anilist_token: String) -> Self {
+impl From<ConfigRow> for ConfigLayer {
+    fn from(row: ConfigRow) -> Self {
+        let settings = match row
+            .settings
+            .map(|x| serde_json::from_str::<ConfigLayer>(&x))
+        {
+            Some(Ok(x)) => x,
+            Some(Err(e)) => {
+                error!("Failed to parse config settings, ignoring them. {}", e);
+                ConfigLayer::default()
+            }
+            None => ConfigLayer::default(),
+        };
+
         Self {
-            id: 1,
-            plex_url,
-            plex_token,
-            anilist_token,
+            plex_url: Some(row.plex_url),
+            plex_token: row.plex_token,
+            anilist_token: row.anilist_token,
+            ..settings
         }
     }
 }
 
-#[derive(FromRow, Serialize, Deserialize)]
-pub struct ListProvider {
+#[derive(FromRow, Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub struct SyncRun {
     pub id: u32,
-    pub name: String,
+    pub status: SyncRunStatus,
+    pub dry_run: bool,
+    /// Unix timestamps in seconds
+    pub started_at: i64,
+    pub finished_at: Option<i64>,
+    pub updated: u32,
+    pub failed: u32,
+    pub unchanged: u32,
+    pub error: Option<String>,
+    /// The Plex Home user the run synced, `None` for the server owner
+    pub plex_user: Option<String>,
+}
+
+#[derive(sqlx::Type, Clone, Copy, Serialize, Deserialize, Debug, PartialEq)]
+#[sqlx(type_name = "TEXT", rename_all = "snake_case")]
+#[serde(rename_all = "snake_case")]
+pub enum SyncRunStatus {
+    Running,
+    Succeeded,
+    Failed,
+}
+
+/// A view from the Plex watch history. Timestamps are Unix timestamps in seconds.
+#[derive(Clone, Debug, PartialEq)]
+pub struct WatchHistoryEntry {
+    pub account_id: u64,
+    pub rating_key: String,
+    pub viewed_at: i64,
+}
+
+/// When an episode or movie was first and last watched according to the watch history.
+#[derive(FromRow, Clone, Debug, PartialEq)]
+pub struct EpisodeViews {
+    pub rating_key: String,
+    pub first_viewed_at: i64,
+    pub last_viewed_at: i64,
+    pub views: u32,
 }
 
 #[derive(FromRow, Clone, Serialize, Deserialize, Debug)]
@@ -320,22 +501,22 @@ mod tests {
         result2[0].id = 2;
 
         dbstore
-            .save_cached_anime_search_result(&search_term, result)
+            .save_cached_anime_search_result(search_term, result)
             .await;
 
         let cached_data = dbstore
-            .get_cached_anime_search_result(&search_term)
+            .get_cached_anime_search_result(search_term)
             .await
             .expect("Failed to get cached data");
 
         assert_eq!(cached_data[0].id, 1);
 
         dbstore
-            .save_cached_anime_search_result(&search_term, result2)
+            .save_cached_anime_search_result(search_term, result2)
             .await;
 
         let cached_data = dbstore
-            .get_cached_anime_search_result(&search_term)
+            .get_cached_anime_search_result(search_term)
             .await
             .expect("Failed to get cached data");
 
@@ -372,4 +553,176 @@ mod tests {
             }
         }
     }
+
+    #[tokio::test]
+    async fn test_update_and_delete_mapping() {
+        init_logger();
+
+        let mut mapping = Mapping {
+            id: 0,
+            list_provider_id: 1,
+            plex_id: "17457".to_string(),
+            plex_series_id: "17456".to_string(),
+            plex_episode_start: 1,
+            season_length: 25,
+            anime_list_id: 16498,
+            episode_start: 1,
+            enabled: true,
+            ignored: false,
+            episodes: Some(25),
+        };
+
+        let mut dbstore = Sqlite::new("sqlite::memory:").await;
+        dbstore.migrate().await;
+
+        dbstore
+            .save_mapping(&mapping)
+            .await
+            .expect("Failed to insert mapping");
+        mapping.id = dbstore
+            .get_mappings()
+            .await
+            .expect("Failed to get mappings")[0]
+            .id;
+
+        mapping.ignored = true;
+        let updated = dbstore
+            .update_mapping(&mapping)
+            .await
+            .expect("Failed to update mapping");
+        assert!(updated);
+
+        let saved = dbstore
+            .get_mapping(mapping.id)
+            .await
+            .expect("Failed to get mapping")
+            .expect("Mapping not found");
+        assert!(saved.ignored);
+        assert_eq!(Some(25), saved.episodes);
+
+        let deleted = dbstore
+            .delete_mapping(mapping.id)
+            .await
+            .expect("Failed to delete mapping");
+        assert!(deleted);
+        assert!(dbstore
+            .get_mapping(mapping.id)
+            .await
+            .expect("Failed to get mapping")
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn test_save_mapping_keeps_episode_count() {
+        init_logger();
+
+        let mapping = |plex_id: &str, episodes: Option<u16>| Mapping {
+            id: 0,
+            list_provider_id: 1,
+            plex_id: plex_id.to_string(),
+            plex_series_id: "17456".to_string(),
+            plex_episode_start: 1,
+            season_length: 12,
+            anime_list_id: 16498,
+            episode_start: 1,
+            enabled: true,
+            ignored: false,
+            episodes,
+        };
+
+        let mut dbstore = Sqlite::new("sqlite::memory:").await;
+        dbstore.migrate().await;
+
+        dbstore
+            .save_mapping(&mapping("17457", Some(25)))
+            .await
+            .expect("Failed to insert mapping");
+        dbstore
+            .save_mapping(&mapping("17458", None))
+            .await
+            .expect("Failed to insert mapping");
+
+        // A mapping can be moved to an entry that has no anime row yet
+        let mut moved = dbstore
+            .get_mappings()
+            .await
+            .expect("Failed to get mappings")[1]
+            .clone();
+        moved.anime_list_id = 20958;
+        moved.episodes = None;
+        dbstore
+            .update_mapping(&moved)
+            .await
+            .expect("Failed to update mapping");
+
+        let saved = dbstore
+            .get_mapping_for_series("17456")
+            .await
+            .expect("Failed to get mappings");
+        assert_eq!(2, saved.len());
+        assert_eq!(Some(25), saved[0].episodes);
+        assert_eq!(20958, saved[1].anime_list_id);
+        assert_eq!(None, saved[1].episodes);
+    }
+
+    #[tokio::test]
+    async fn test_save_watch_history() {
+        init_logger();
+
+        let mut dbstore = Sqlite::new("sqlite::memory:").await;
+        dbstore.migrate().await;
+
+        let view = |account_id, rating_key: &str, viewed_at| WatchHistoryEntry {
+            account_id,
+            rating_key: rating_key.to_string(),
+            viewed_at,
+        };
+        dbstore
+            .save_watch_history(&[view(1, "10", 100), view(1, "10", 300), view(2, "10", 500)])
+            .await
+            .expect("Failed to save watch history");
+        // Views that were already saved are skipped
+        dbstore
+            .save_watch_history(&[view(1, "10", 300), view(1, "11", 200)])
+            .await
+            .expect("Failed to save watch history");
+
+        let mut result = dbstore
+            .get_episode_views(1)
+            .await
+            .expect("Failed to get episode views");
+        result.sort_by(|a, b| a.rating_key.cmp(&b.rating_key));
+
+        assert_eq!(
+            vec![
+                EpisodeViews {
+                    rating_key: "10".to_string(),
+                    first_viewed_at: 100,
+                    last_viewed_at: 300,
+                    views: 2,
+                },
+                EpisodeViews {
+                    rating_key: "11".to_string(),
+                    first_viewed_at: 200,
+                    last_viewed_at: 200,
+                    views: 1,
+                },
+            ],
+            result
+        );
+        assert_eq!(
+            Some(300),
+            dbstore
+                .get_last_watch_history_view(1)
+                .await
+                .expect("Failed to get last view")
+        );
+        assert_eq!(
+            None,
+            dbstore
+                .get_last_watch_history_view(3)
+                .await
+                .expect("Failed to get last view")
+        );
+    }
 }
@@ -0,0 +1,184 @@
+use std::collections::HashMap;
+
+use chrono::DateTime;
+use serde::{Deserialize, Serialize};
+
+use crate::services::plex::plex_api::{
+    PlexEpisode, PlexLibraryLocation, ResponsePlexGuid, ResponsePlexLibrary, ResponsePlexMovie,
+    ResponsePlexSeries, ResponsePlexTag,
+};
+
+/// Jellyfin and Emby count time in ticks of 100 nanoseconds
+const TICKS_PER_MILLISECOND: u64 = 10_000;
+
+/// A page of items, Jellyfin and Emby answer every item query with one.
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct JellyfinItemsResponse {
+    pub items: Vec<JellyfinItem>,
+    #[serde(default)]
+    pub total_record_count: Option<u32>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct JellyfinUser {
+    pub id: String,
+    pub name: String,
+}
+
+/// A library, series, season, episode or movie.
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct JellyfinItem {
+    pub id: String,
+    #[serde(default)]
+    pub name: String,
+    /// `tvshows` or `movies` for libraries
+    pub collection_type: Option<String>,
+    /// The episode or season number
+    pub index_number: Option<u32>,
+    /// The season number of an episode
+    pub parent_index_number: Option<u32>,
+    pub series_name: Option<String>,
+    pub production_year: Option<u16>,
+    /// The air date, such as `2013-04-07T00:00:00.0000000Z`
+    pub premiere_date: Option<String>,
+    pub run_time_ticks: Option<u64>,
+    /// Ids in external databases by provider name, such as `Tvdb`, `Tmdb`, `Imdb` or `AniDB`
+    #[serde(default)]
+    pub provider_ids: HashMap<String, String>,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    /// The watch state of the user the items were requested for
+    pub user_data: Option<JellyfinUserData>,
+    #[serde(default)]
+    pub locations: Vec<String>,
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct JellyfinUserData {
+    #[serde(default)]
+    pub played: bool,
+    #[serde(default)]
+    pub play_count: i32,
+    pub last_played_date: Option<String>,
+    #[serde(default)]
+    pub playback_position_ticks: u64,
+}
+
+impl JellyfinItem {
+    /// Libraries are given the Plex library types so they're selected and synced the same way.
+    pub fn into_library(self) -> ResponsePlexLibrary {
+        let library_type = match self.collection_type.as_deref() {
+            Some("tvshows") => "show".to_string(),
+            Some("movies") => "movie".to_string(),
+            x => x.unwrap_or_default().to_string(),
+        };
+
+        ResponsePlexLibrary {
+            key: self.id,
+            title: self.name,
+            library_type,
+            locations: self
+                .locations
+                .into_iter()
+                .map(|path| PlexLibraryLocation { path })
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    pub fn into_series(self) -> ResponsePlexSeries {
+        ResponsePlexSeries {
+            guids: self.get_guids(),
+            labels: self.get_labels(),
+            rating_key: self.id,
+            title: self.name,
+            last_viewed_at: None,
+            guid: None,
+            collections: vec![],
+        }
+    }
+
+    pub fn into_movie(self) -> ResponsePlexMovie {
+        let episode = self.to_episode();
+        ResponsePlexMovie {
+            guids: self.get_guids(),
+            labels: self.get_labels(),
+            rating_key: self.id,
+            title: self.name,
+            year: self.production_year,
+            view_count: episode.view_count,
+            last_viewed_at: episode.last_viewed_at,
+            view_offset: episode.view_offset,
+            duration: episode.duration,
+            guid: None,
+            collections: vec![],
+        }
+    }
+
+    pub fn to_episode(&self) -> PlexEpisode {
+        let user_data = self.user_data.as_ref();
+        PlexEpisode {
+            rating_key: self.id.clone(),
+            // Unplayed items keep their play count
+            view_count: match user_data {
+                Some(x) if x.played => x.play_count.max(1),
+                _ => 0,
+            },
+            last_viewed_at: user_data
+                .and_then(|x| x.last_played_date.as_deref())
+                .and_then(parse_date),
+            first_viewed_at: None,
+            index: self.index_number,
+            parent_index: self.parent_index_number,
+            originally_available_at: self
+                .premiere_date
+                .as_deref()
+                .and_then(|x| DateTime::parse_from_rfc3339(x).ok())
+                .map(|x| x.date_naive()),
+            view_offset: user_data
+                .map(|x| x.playback_position_ticks / TICKS_PER_MILLISECOND)
+                .filter(|x| *x > 0),
+            duration: self.run_time_ticks.map(|x| x / TICKS_PER_MILLISECOND),
+        }
+    }
+
+    /// The provider ids written as the guids Plex uses, so they're matched the same way.
+    pub fn get_guids(&self) -> Vec<ResponsePlexGuid> {
+        let mut guids: Vec<ResponsePlexGuid> = self
+            .provider_ids
+            .iter()
+            .filter_map(|(provider, id)| {
+                let scheme = match provider.to_lowercase().as_str() {
+                    "tvdb" => "tvdb",
+                    "tmdb" => "tmdb",
+                    "imdb" => "imdb",
+                    "anidb" => "anidb",
+                    _ => return None,
+                };
+                Some(ResponsePlexGuid {
+                    id: format!("{}://{}", scheme, id),
+                })
+            })
+            .collect();
+        guids.sort_by(|a, b| a.id.cmp(&b.id));
+        guids
+    }
+
+    pub fn get_labels(&self) -> Vec<ResponsePlexTag> {
+        self.tags
+            .iter()
+            .map(|x| ResponsePlexTag { tag: x.clone() })
+            .collect()
+    }
+}
+
+/// Dates are sent in RFC 3339, returns the Unix timestamp in seconds.
+fn parse_date(date: &str) -> Option<i64> {
+    DateTime::parse_from_rfc3339(date)
+        .ok()
+        .map(|x| x.timestamp())
+}
@@ -0,0 +1,611 @@
+use std::{sync::Arc, time::Duration};
+
+use async_trait::async_trait;
+use futures::future::join_all;
+use log::{info, warn};
+use reqwest::{
+    header::{self, HeaderValue, ACCEPT, AUTHORIZATION},
+    Method,
+};
+use serde::de::DeserializeOwned;
+use tokio::sync::Semaphore;
+use url::Url;
+
+use crate::services::{
+    media_server::media_server::MediaServerKind,
+    plex::{
+        plex_api::{
+            PlexInterface, PlexPage, PlexSeason, PlexSeries, ResponsePlexHistoryItem,
+            ResponsePlexLibrary, ResponsePlexMovie, ResponsePlexSeries,
+        },
+        plex_api_service::{with_retries, RETRY_DELAY},
+        plex_tv::{client_identifier, PRODUCT, VERSION},
+    },
+};
+
+use super::jellyfin_api::{JellyfinItem, JellyfinItemsResponse, JellyfinUser};
+
+/// The optional fields requested with every item
+const ITEM_FIELDS: &str = "ProviderIds,Tags";
+
+/// Reads the watch state of one Jellyfin or Emby user into the same series, seasons and episodes
+/// that are read from Plex. Emby is the server Jellyfin was forked from, their APIs only differ
+/// in how requests are authorized.
+#[derive(Debug)]
+pub struct JellyfinApi {
+    kind: MediaServerKind,
+    url: String,
+    user_id: String,
+    http_client: reqwest::Client,
+    headers: header::HeaderMap,
+    page_size: u32,
+    retry_delay: Duration,
+    /// Limits how many requests are sent to the server at the same time
+    semaphore: Arc<Semaphore>,
+}
+
+impl JellyfinApi {
+    /// Connects as the user called `user_name`. The API key has to be allowed to list the users
+    /// of the server, which API keys made in the admin dashboard are. `page_size` is the number
+    /// of series requested at a time and `concurrency` the number of requests the server gets at
+    /// the same time.
+    pub async fn connect(
+        kind: MediaServerKind,
+        url: String,
+        api_key: &str,
+        user_name: &str,
+        page_size: u32,
+        concurrency: usize,
+    ) -> Result<Self, anyhow::Error> {
+        let mut api = Self {
+            kind,
+            url,
+            user_id: String::new(),
+            http_client: reqwest::Client::new(),
+            headers: jellyfin_headers(kind, api_key),
+            page_size,
+            retry_delay: RETRY_DELAY,
+            semaphore: Arc::new(Semaphore::new(concurrency)),
+        };
+
+        let users: Vec<JellyfinUser> = api.make_request("/Users").await?;
+        api.user_id = match users
+            .into_iter()
+            .find(|x| x.name.eq_ignore_ascii_case(user_name))
+        {
+            Some(x) => x.id,
+            None => anyhow::bail!("There is no {} user called '{}'", kind, user_name),
+        };
+
+        Ok(api)
+    }
+
+    /// Requests that fail because of the connection or the server are retried with backoff, the
+    /// same way Plex requests are.
+    async fn make_request<T>(&self, path: &str) -> Result<T, reqwest::Error>
+    where
+        T: DeserializeOwned,
+    {
+        with_retries(self.retry_delay, path, || async {
+            self.send_request(Method::GET, path)
+                .await?
+                .json::<T>()
+                .await
+        })
+        .await
+    }
+
+    async fn send_request(
+        &self,
+        method: Method,
+        path: &str,
+    ) -> Result<reqwest::Response, reqwest::Error> {
+        let _permit = self
+            .semaphore
+            .acquire()
+            .await
+            .expect("Jellyfin request semaphore was closed");
+
+        self.http_client
+            .request(method, self.build_request_url(path))
+            .headers(self.headers.clone())
+            .send()
+            .await?
+            .error_for_status()
+    }
+
+    /// Requests `page_size` items of a library starting at the `start`th item. `item_type` is
+    /// `Series` or `Movie`.
+    async fn get_page(
+        &self,
+        item_type: &str,
+        library_key: &str,
+        start: u32,
+    ) -> Result<PlexPage<JellyfinItem>, reqwest::Error> {
+        let path = format!(
+            "/Users/{}/Items?ParentId={}&IncludeItemTypes={}&Recursive=true&Fields={}&SortBy=SortName&StartIndex={}&Limit={}",
+            self.user_id, library_key, item_type, ITEM_FIELDS, start, self.page_size
+        );
+
+        info!(
+            "Getting {} {} items {} to {} for library id: {}",
+            self.kind,
+            item_type,
+            start,
+            start + self.page_size,
+            library_key
+        );
+        let response: JellyfinItemsResponse = self.make_request(&path).await?;
+
+        Ok(PlexPage::new(
+            response.items,
+            start,
+            response.total_record_count,
+            self.page_size,
+        ))
+    }
+
+    fn build_request_url(&self, path: &str) -> String {
+        // Servers can be hosted under a path, such as `https://example.com/jellyfin`
+        let base = format!("{}/", self.url.trim_end_matches('/'));
+        Url::parse(&base)
+            .expect("Failed to parse media server base url")
+            .join(path.trim_start_matches('/'))
+            .expect("Failed to join media server path to base url")
+            .to_string()
+    }
+}
+
+/// Jellyfin reads the API key from the `Authorization` header, Emby from `X-Emby-Token`.
+fn jellyfin_headers(kind: MediaServerKind, api_key: &str) -> header::HeaderMap {
+    let mut headers = header::HeaderMap::new();
+    headers.insert(ACCEPT, HeaderValue::from_static("application/json"));
+    match kind {
+        MediaServerKind::Emby => {
+            headers.insert(
+                "X-Emby-Token",
+                HeaderValue::from_str(api_key).expect("Failed to parse Emby API key"),
+            );
+        }
+        _ => {
+            let authorization = format!(
+                "MediaBrowser Client=\"{}\", Device=\"{}\", DeviceId=\"{}\", Version=\"{}\", Token=\"{}\"",
+                PRODUCT,
+                PRODUCT,
+                client_identifier(),
+                VERSION,
+                api_key
+            );
+            headers.insert(
+                AUTHORIZATION,
+                HeaderValue::from_str(&authorization).expect("Failed to parse Jellyfin API key"),
+            );
+        }
+    }
+    headers
+}
+
+#[async_trait]
+impl PlexInterface for JellyfinApi {
+    async fn get_libraries(&self) -> Result<Vec<ResponsePlexLibrary>, reqwest::Error> {
+        info!("Getting {} libraries", self.kind);
+        let path = format!("/Users/{}/Views", self.user_id);
+        let response: JellyfinItemsResponse = self.make_request(&path).await?;
+
+        info!("Found {} libraries", response.items.len());
+        Ok(response
+            .items
+            .into_iter()
+            .map(JellyfinItem::into_library)
+            .collect())
+    }
+
+    async fn get_series_page(
+        &self,
+        library_key: &str,
+        start: u32,
+    ) -> Result<PlexPage<ResponsePlexSeries>, reqwest::Error> {
+        let page = self.get_page("Series", library_key, start).await?;
+        Ok(PlexPage {
+            items: page
+                .items
+                .into_iter()
+                .map(JellyfinItem::into_series)
+                .collect(),
+            next_start: page.next_start,
+        })
+    }
+
+    async fn get_series(
+        &self,
+        rating_key: &str,
+    ) -> Result<Option<ResponsePlexSeries>, reqwest::Error> {
+        let path = format!("/Users/{}/Items/{}", self.user_id, rating_key);
+        let item: JellyfinItem = match self.make_request(&path).await {
+            Ok(x) => x,
+            Err(e) if e.status() == Some(reqwest::StatusCode::NOT_FOUND) => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        Ok(Some(item.into_series()))
+    }
+
+    async fn get_movies_page(
+        &self,
+        library_key: &str,
+        start: u32,
+    ) -> Result<PlexPage<ResponsePlexMovie>, reqwest::Error> {
+        let page = self.get_page("Movie", library_key, start).await?;
+        Ok(PlexPage {
+            items: page
+                .items
+                .into_iter()
+                .map(JellyfinItem::into_movie)
+                .collect(),
+            next_start: page.next_start,
+        })
+    }
+
+    /// Jellyfin and Emby don't keep a history of every view, rewatches can't be told apart.
+    async fn get_watch_history_page(
+        &self,
+        _: u64,
+        _: Option<i64>,
+        _: u32,
+    ) -> Result<PlexPage<ResponsePlexHistoryItem>, reqwest::Error> {
+        Ok(PlexPage {
+            items: vec![],
+            next_start: None,
+        })
+    }
+
+    async fn mark_watched(&self, rating_key: &str) -> Result<(), reqwest::Error> {
+        let path = format!("/Users/{}/PlayedItems/{}", self.user_id, rating_key);
+        info!("Marking {} item {} as watched", self.kind, rating_key);
+        with_retries(self.retry_delay, &path, || async {
+            self.send_request(Method::POST, &path).await.map(|_| ())
+        })
+        .await
+    }
+
+    async fn populate_episodes(&self, season: &mut PlexSeason) -> Result<(), reqwest::Error> {
+        let path = format!(
+            "/Users/{}/Items?ParentId={}&IncludeItemTypes=Episode&Fields={}&SortBy=ParentIndexNumber,IndexNumber",
+            self.user_id, season.rating_key, ITEM_FIELDS
+        );
+
+        let response: JellyfinItemsResponse = self.make_request(&path).await?;
+        season.episodes = response
+            .items
+            .iter()
+            .map(JellyfinItem::to_episode)
+            .collect();
+
+        Ok(())
+    }
+
+    async fn populate_seasons(&self, series: &mut PlexSeries) -> Result<(), reqwest::Error> {
+        let path = format!(
+            "/Shows/{}/Seasons?userId={}&Fields={}",
+            series.rating_key, self.user_id, ITEM_FIELDS
+        );
+
+        let response: JellyfinItemsResponse = self.make_request(&path).await?;
+        let mut seasons: Vec<PlexSeason> = response
+            .items
+            .into_iter()
+            .filter_map(|x| {
+                // Guessing the number would map the season to the wrong Anilist entry
+                let index = match x.index_number.and_then(|i| u8::try_from(i).ok()) {
+                    Some(index) => index,
+                    None => {
+                        warn!(
+                            "Skipping {} season {} of '{}', it has no valid season number: {:?}",
+                            self.kind, x.id, series.title, x.index_number
+                        );
+                        return None;
+                    }
+                };
+
+                Some(PlexSeason {
+                    guids: vec![],
+                    labels: x.tags,
+                    index,
+                    parent_title: x.series_name.unwrap_or_else(|| series.title.clone()),
+                    rating_key: x.id,
+                    episodes: vec![],
+                })
+            })
+            .collect();
+
+        // A season without its episodes would look unwatched
+        let results = join_all(seasons.iter_mut().map(|x| self.populate_episodes(x))).await;
+        if let Some(e) = results.into_iter().find_map(Result::err) {
+            return Err(e);
+        }
+
+        series.seasons = seasons;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use wiremock::{
+        matchers::{header, header_exists, method, path, query_param},
+        Mock, MockServer, ResponseTemplate,
+    };
+
+    use crate::services::plex::{
+        plex_api::ExternalId,
+        plex_api_service::{DEFAULT_CONCURRENCY, DEFAULT_PAGE_SIZE},
+    };
+
+    use super::*;
+
+    async fn connect_as(
+        kind: MediaServerKind,
+        url: String,
+        api_key: &str,
+        user_name: &str,
+    ) -> Result<JellyfinApi, anyhow::Error> {
+        JellyfinApi::connect(
+            kind,
+            url,
+            api_key,
+            user_name,
+            DEFAULT_PAGE_SIZE,
+            DEFAULT_CONCURRENCY,
+        )
+        .await
+    }
+
+    async fn mock_users(mock_server: &MockServer) {
+        Mock::given(method("GET"))
+            .and(path("/Users"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!([
+                { "Id": "u1", "Name": "admin" },
+                { "Id": "u2", "Name": "Alice" },
+            ])))
+            .mount(mock_server)
+            .await;
+    }
+
+    #[tokio::test]
+    async fn test_connect_as_unknown_user() {
+        let mock_server = MockServer::start().await;
+        mock_users(&mock_server).await;
+
+        let result = connect_as(
+            MediaServerKind::Jellyfin,
+            mock_server.uri(),
+            "key123",
+            "bob",
+        )
+        .await;
+
+        assert!(result.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_get_libraries() {
+        let mock_server = MockServer::start().await;
+        mock_users(&mock_server).await;
+        Mock::given(method("GET"))
+            .and(path("/Users/u2/Views"))
+            .and(header("X-Emby-Token", "key123"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "Items": [
+                    { "Id": "lib1", "Name": "Anime", "CollectionType": "tvshows" },
+                    { "Id": "lib2", "Name": "Anime Movies", "CollectionType": "movies" },
+                    { "Id": "lib3", "Name": "Music", "CollectionType": "music" },
+                ],
+                "TotalRecordCount": 3,
+            })))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        let jellyfin_api = connect_as(MediaServerKind::Emby, mock_server.uri(), "key123", "alice")
+            .await
+            .expect("Failed to connect");
+        let libraries = jellyfin_api
+            .get_libraries()
+            .await
+            .expect("Failed to get libraries");
+
+        assert_eq!(3, libraries.len());
+        assert!(libraries[0].is_show_library());
+        assert!(libraries[1].is_movie_library());
+        assert_eq!("music", libraries[2].library_type);
+    }
+
+    #[tokio::test]
+    async fn test_get_series_page() {
+        let mock_server = MockServer::start().await;
+        mock_users(&mock_server).await;
+        Mock::given(method("GET"))
+            .and(path("/Users/u1/Items"))
+            .and(query_param("ParentId", "lib1"))
+            .and(query_param("IncludeItemTypes", "Series"))
+            .and(query_param("StartIndex", "0"))
+            .and(header_exists("Authorization"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "Items": [{
+                    "Id": "s1",
+                    "Name": "Attack on Titan",
+                    "ProviderIds": { "Tvdb": "267440", "Imdb": "tt2560140", "AniList": "16498" },
+                    "Tags": ["anisync-private"],
+                }],
+                "TotalRecordCount": 2,
+            })))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        let jellyfin_api = connect_as(
+            MediaServerKind::Jellyfin,
+            mock_server.uri(),
+            "key123",
+            "admin",
+        )
+        .await
+        .expect("Failed to connect");
+        let page = jellyfin_api
+            .get_series_page("lib1", 0)
+            .await
+            .expect("Failed to get series");
+        let series = PlexSeries::from(page.items.into_iter().next().expect("Expected a series"));
+
+        assert_eq!(Some(1), page.next_start);
+        assert_eq!("s1", series.rating_key);
+        assert_eq!(
+            vec![
+                ExternalId::Imdb("tt2560140".to_string()),
+                ExternalId::Tvdb(267440)
+            ],
+            series.guids
+        );
+        assert!(series.has_label("anisync-private"));
+    }
+
+    #[tokio::test]
+    async fn test_populate_seasons() {
+        let mock_server = MockServer::start().await;
+        mock_users(&mock_server).await;
+        Mock::given(method("GET"))
+            .and(path("/Shows/s1/Seasons"))
+            .and(query_param("userId", "u1"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "Items": [
+                    { "Id": "season1", "Name": "Season 1", "IndexNumber": 1, "SeriesName": "Attack on Titan" },
+                    { "Id": "extras", "Name": "Extras", "SeriesName": "Attack on Titan" },
+                ],
+            })))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/Users/u1/Items"))
+            .and(query_param("ParentId", "season1"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "Items": [
+                    {
+                        "Id": "e1",
+                        "IndexNumber": 1,
+                        "ParentIndexNumber": 1,
+                        "RunTimeTicks": 14_400_000_000u64,
+                        "UserData": { "Played": true, "PlayCount": 2, "LastPlayedDate": "2024-03-01T20:15:00.0000000Z", "PlaybackPositionTicks": 0 },
+                    },
+                    {
+                        "Id": "e2",
+                        "IndexNumber": 2,
+                        "ParentIndexNumber": 1,
+                        "RunTimeTicks": 14_400_000_000u64,
+                        "UserData": { "Played": false, "PlayCount": 0, "PlaybackPositionTicks": 7_200_000_000u64 },
+                    },
+                ],
+            })))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        let jellyfin_api = connect_as(
+            MediaServerKind::Jellyfin,
+            mock_server.uri(),
+            "key123",
+            "admin",
+        )
+        .await
+        .expect("Failed to connect");
+        let mut series = PlexSeries::from(
+            JellyfinItem {
+                id: "s1".to_string(),
+                name: "Attack on Titan".to_string(),
+                ..Default::default()
+            }
+            .into_series(),
+        );
+        jellyfin_api
+            .populate_seasons(&mut series)
+            .await
+            .expect("Failed to get seasons");
+
+        assert_eq!(1, series.seasons.len());
+        let season = &series.seasons[0];
+        assert_eq!(1, season.index);
+        assert_eq!("Attack on Titan", season.parent_title);
+        assert_eq!(2, season.episodes[0].view_count);
+        assert_eq!(Some(1709324100), season.episodes[0].last_viewed_at);
+        assert!(season.episodes[0].is_watched(90));
+        assert_eq!(0, season.episodes[1].view_count);
+        assert_eq!(Some(720_000), season.episodes[1].view_offset);
+        assert_eq!(Some(1_440_000), season.episodes[1].duration);
+        assert!(season.episodes[1].is_started());
+        assert!(!season.episodes[1].is_watched(90));
+    }
+
+    #[tokio::test]
+    async fn test_mark_watched() {
+        let mock_server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/jellyfin/Users/u1/PlayedItems/e2"))
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/jellyfin/Users"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!([
+                { "Id": "u1", "Name": "admin" },
+            ])))
+            .mount(&mock_server)
+            .await;
+
+        let jellyfin_api = connect_as(
+            MediaServerKind::Jellyfin,
+            format!("{}/jellyfin", mock_server.uri()),
+            "key123",
+            "admin",
+        )
+        .await
+        .expect("Failed to connect");
+
+        let result = jellyfin_api.mark_watched("e2").await;
+
+        assert!(result.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_retry_server_errors() {
+        let mock_server = MockServer::start().await;
+        mock_users(&mock_server).await;
+        Mock::given(method("POST"))
+            .and(path("/Users/u1/PlayedItems/e2"))
+            .respond_with(ResponseTemplate::new(503))
+            .up_to_n_times(2)
+            .expect(2)
+            .mount(&mock_server)
+            .await;
+        Mock::given(method("POST"))
+            .and(path("/Users/u1/PlayedItems/e2"))
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        let mut jellyfin_api = connect_as(
+            MediaServerKind::Jellyfin,
+            mock_server.uri(),
+            "key123",
+            "admin",
+        )
+        .await
+        .expect("Failed to connect");
+        jellyfin_api.retry_delay = Duration::from_millis(1);
+
+        let result = jellyfin_api.mark_watched("e2").await;
+
+        assert!(result.is_ok());
+    }
+}
@@ -0,0 +1,2 @@
+pub mod jellyfin_api;
+pub mod jellyfin_api_service;
@@ -0,0 +1,395 @@
+use std::{collections::HashMap, fs, io, path::Path};
+
+use anyhow::Context;
+use log::{info, warn};
+use serde::{Deserialize, Deserializer};
+
+use crate::services::plex::plex_api::{ExternalId, HamaTvdbMode};
+
+pub const DEFAULT_ID_MAPPING_FILE: &str = "./data/anime-list-full.json";
+/// The Fribb anime-lists dataset, which links Anilist ids to AniDB, tvdb, tmdb and imdb ids. It
+/// doubles as the AniDB lookup table for libraries using the HAMA agent
+pub const ID_MAPPING_URL: &str =
+    "https://raw.githubusercontent.com/Fribb/anime-lists/master/anime-list-full.json";
+
+/// One anime in the dataset. Ids that are missing or can't be read are `None`.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+pub struct IdMappingEntry {
+    #[serde(default, deserialize_with = "deserialize_id")]
+    pub anilist_id: Option<u32>,
+    #[serde(default, deserialize_with = "deserialize_id")]
+    pub anidb_id: Option<u32>,
+    #[serde(default, deserialize_with = "deserialize_id")]
+    pub thetvdb_id: Option<u32>,
+    #[serde(default, deserialize_with = "deserialize_id")]
+    pub themoviedb_id: Option<u32>,
+    #[serde(default)]
+    pub imdb_id: Option<String>,
+    /// The tvdb and tmdb season the anime is listed under
+    #[serde(default)]
+    pub season: Option<IdMappingSeason>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+pub struct IdMappingSeason {
+    #[serde(default, deserialize_with = "deserialize_id")]
+    pub tvdb: Option<u32>,
+    #[serde(default, deserialize_with = "deserialize_id")]
+    pub tmdb: Option<u32>,
+}
+
+/// A local copy of an id-mapping dataset used to match Plex series to Anilist without searching
+/// by title.
+#[derive(Debug, Clone, Default)]
+pub struct IdMappings {
+    entries: Vec<IdMappingEntry>,
+    /// The positions of the entries with an Anilist id, by each of their external ids
+    by_id: HashMap<ExternalId, Vec<usize>>,
+}
+
+impl IdMappings {
+    pub fn new(entries: Vec<IdMappingEntry>) -> Self {
+        let mut by_id: HashMap<ExternalId, Vec<usize>> = HashMap::new();
+        for (position, entry) in entries.iter().enumerate() {
+            if entry.anilist_id.is_none() {
+                continue;
+            }
+            for id in entry.get_ids() {
+                by_id.entry(id).or_default().push(position);
+            }
+        }
+
+        Self { entries, by_id }
+    }
+
+    /// Reads the dataset. A missing file gives an empty dataset so matching falls back to title
+    /// search.
+    pub fn load(path: &str) -> Result<Self, anyhow::Error> {
+        let contents = match fs::read_to_string(path) {
+            Ok(x) => x,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                info!(
+                    "No id mapping file at '{}', series are matched by title only",
+                    path
+                );
+                return Ok(Self::default());
+            }
+            Err(e) => {
+                return Err(e).with_context(|| format!("Failed to read id mapping file '{}'", path))
+            }
+        };
+
+        let entries: Vec<IdMappingEntry> = serde_json::from_str(&contents)
+            .with_context(|| format!("Failed to parse id mapping file '{}'", path))?;
+        info!("Loaded {} id mappings from '{}'", entries.len(), path);
+        Ok(Self::new(entries))
+    }
+
+    /// The Anilist ids of a season of a series with the given external ids, in the order the
+    /// entries are watched. An AniDB id of the season itself names its entry and is tried first,
+    /// unless it's the id of the series, which HAMA repeats on every season.
+    /// The ids of the series are then tried one at a time from the most to the least precise:
+    /// AniDB, tvdb, tmdb and imdb. Other ids of the season are the server's own season ids,
+    /// which the dataset doesn't list.
+    ///
+    /// HAMA's `tvdb2-` seasons follow tvdb and `tvdb3-` seasons are the AniDB entries of the
+    /// series, both number episodes across the whole series. The `tvdb4-` and `tvdb5-` story arc
+    /// layouts aren't in the dataset, so they're skipped and the season is matched by title.
+    pub fn find_anilist_ids(
+        &self,
+        series_ids: &[ExternalId],
+        season_ids: &[ExternalId],
+        season_index: u8,
+    ) -> Vec<u32> {
+        for id in season_ids
+            .iter()
+            .filter(|x| matches!(x, ExternalId::Anidb(_)) && !series_ids.contains(x))
+        {
+            let result: Vec<u32> = self
+                .get_entries(id)
+                .iter()
+                .filter_map(|x| x.anilist_id)
+                .collect();
+            if !result.is_empty() {
+                return result;
+            }
+        }
+
+        let mut ordered = series_ids.to_vec();
+        ordered.sort_by_key(|x| match x {
+            ExternalId::Anidb(_) => 0,
+            ExternalId::HamaTvdb { .. } => 1,
+            ExternalId::Tvdb(_) => 2,
+            ExternalId::Tmdb(_) => 3,
+            ExternalId::Imdb(_) => 4,
+        });
+
+        for id in ordered.iter() {
+            let result = match id {
+                ExternalId::HamaTvdb {
+                    id,
+                    mode: HamaTvdbMode::Tvdb2,
+                } => {
+                    let tvdb_id = ExternalId::Tvdb(*id);
+                    get_season_ids(&self.get_entries(&tvdb_id), &tvdb_id, season_index)
+                }
+                ExternalId::HamaTvdb {
+                    id,
+                    mode: HamaTvdbMode::Tvdb3,
+                } => self.get_anidb_season_ids(*id, season_index),
+                ExternalId::HamaTvdb { id, mode } => {
+                    warn!(
+                        "The HAMA {:?} layout of tvdb {} is unsupported, season {} is matched by title",
+                        mode, id, season_index
+                    );
+                    continue;
+                }
+                id => get_season_ids(&self.get_entries(id), id, season_index),
+            };
+            if !result.is_empty() {
+                return result;
+            }
+        }
+
+        vec![]
+    }
+
+    /// The `season_index`th AniDB entry of the tvdb series, counted in the order of their tvdb
+    /// seasons and then their AniDB ids. Specials and entries without a tvdb season don't count.
+    fn get_anidb_season_ids(&self, tvdb_id: u32, season_index: u8) -> Vec<u32> {
+        let tvdb_id = ExternalId::Tvdb(tvdb_id);
+        let mut entries: Vec<(u32, u32, u32)> = self
+            .get_entries(&tvdb_id)
+            .into_iter()
+            .filter_map(|x| {
+                let season = x.get_season(&tvdb_id).filter(|x| *x > 0)?;
+                Some((season, x.anidb_id?, x.anilist_id?))
+            })
+            .collect();
+        entries.sort();
+
+        usize::from(season_index)
+            .checked_sub(1)
+            .and_then(|x| entries.get(x))
+            .map(|(_, _, anilist_id)| *anilist_id)
+            .into_iter()
+            .collect()
+    }
+
+    /// The entries with an Anilist id that have the external id, in the order of the dataset.
+    fn get_entries(&self, id: &ExternalId) -> Vec<&IdMappingEntry> {
+        self.by_id
+            .get(id)
+            .map(|positions| positions.iter().map(|x| &self.entries[*x]).collect())
+            .unwrap_or_default()
+    }
+}
+
+impl IdMappingEntry {
+    fn get_ids(&self) -> Vec<ExternalId> {
+        let mut ids = vec![];
+        ids.extend(self.anidb_id.map(ExternalId::Anidb));
+        ids.extend(self.thetvdb_id.map(ExternalId::Tvdb));
+        ids.extend(self.themoviedb_id.map(ExternalId::Tmdb));
+        ids.extend(self.imdb_id.clone().map(ExternalId::Imdb));
+        ids
+    }
+
+    fn get_season(&self, id: &ExternalId) -> Option<u32> {
+        let season = self.season.as_ref()?;
+        match id {
+            ExternalId::Tvdb(_) | ExternalId::HamaTvdb { .. } => season.tvdb,
+            ExternalId::Tmdb(_) => season.tmdb,
+            ExternalId::Imdb(_) | ExternalId::Anidb(_) => None,
+        }
+    }
+}
+
+/// Entries listing their season are matched on it. Without season numbers an id that points at
+/// a single entry can only be trusted for the first season.
+fn get_season_ids(candidates: &[&IdMappingEntry], id: &ExternalId, season_index: u8) -> Vec<u32> {
+    let with_season: Vec<u32> = candidates
+        .iter()
+        .filter(|x| x.get_season(id) == Some(u32::from(season_index)))
+        .filter_map(|x| x.anilist_id)
+        .collect();
+    if !with_season.is_empty() {
+        return with_season;
+    }
+
+    match candidates {
+        [entry] if season_index == 1 && entry.get_season(id).is_none() => {
+            entry.anilist_id.into_iter().collect()
+        }
+        _ => vec![],
+    }
+}
+
+/// Downloads the dataset to `path`, replacing any older copy. Returns the number of entries.
+pub async fn download(path: &str) -> Result<usize, anyhow::Error> {
+    info!("Downloading id mappings from '{}'", ID_MAPPING_URL);
+    let contents = reqwest::get(ID_MAPPING_URL)
+        .await?
+        .error_for_status()?
+        .text()
+        .await?;
+
+    // Make sure the file is usable before replacing the old one
+    let entries: Vec<IdMappingEntry> =
+        serde_json::from_str(&contents).context("The downloaded id mappings can't be read")?;
+    if let Some(parent) = Path::new(path).parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::write(path, contents).with_context(|| format!("Failed to write '{}'", path))?;
+
+    Ok(entries.len())
+}
+
+/// The dataset has a few ids written as strings and some that aren't numbers at all.
+fn deserialize_id<'de, D>(deserializer: D) -> Result<Option<u32>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Ok(match serde_json::Value::deserialize(deserializer)? {
+        serde_json::Value::Number(x) => x.as_u64().and_then(|x| u32::try_from(x).ok()),
+        serde_json::Value::String(x) => x.parse().ok(),
+        _ => None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn id_mappings() -> IdMappings {
+        serde_json::from_str::<Vec<IdMappingEntry>>(
+            r#"[
+                { "anidb_id": 9541, "anilist_id": 16498, "thetvdb_id": 267440, "imdb_id": "tt2560140", "season": { "tvdb": 1 } },
+                { "anidb_id": 10944, "anilist_id": 20958, "thetvdb_id": 267440, "season": { "tvdb": 2 } },
+                { "anidb_id": 13241, "anilist_id": 99147, "thetvdb_id": 267440, "season": { "tvdb": 3 } },
+                { "anidb_id": 14444, "anilist_id": 104578, "thetvdb_id": 267440, "season": { "tvdb": 3 } },
+                { "anidb_id": 9970, "anilist_id": 18397, "thetvdb_id": 267440, "season": { "tvdb": 0 } },
+                { "anidb_id": 8692, "anilist_id": 11757, "thetvdb_id": "259640", "themoviedb_id": 45782 },
+                { "anidb_id": 1, "thetvdb_id": 76885, "themoviedb_id": "unknown" }
+            ]"#,
+        )
+        .map(IdMappings::new)
+        .expect("Failed to parse id mappings")
+    }
+
+    #[test]
+    fn test_find_anilist_ids_by_tvdb_season() {
+        let id_mappings = id_mappings();
+        let ids = [
+            ExternalId::Imdb("tt2560140".to_string()),
+            ExternalId::Tvdb(267440),
+        ];
+
+        assert_eq!(vec![20958], id_mappings.find_anilist_ids(&ids, &[], 2));
+        assert_eq!(
+            vec![99147, 104578],
+            id_mappings.find_anilist_ids(&ids, &[], 3)
+        );
+        assert!(id_mappings.find_anilist_ids(&ids, &[], 4).is_empty());
+    }
+
+    #[test]
+    fn test_find_anilist_ids_for_hama_guids() {
+        let id_mappings = id_mappings();
+
+        assert_eq!(
+            vec![20958],
+            id_mappings.find_anilist_ids(&[ExternalId::Anidb(10944)], &[], 1)
+        );
+        assert!(id_mappings
+            .find_anilist_ids(&[ExternalId::Anidb(10944)], &[], 2)
+            .is_empty());
+
+        // The AniDB id of the season picks its entry out of the ones sharing the tvdb season
+        assert_eq!(
+            vec![99147],
+            id_mappings.find_anilist_ids(
+                &[ExternalId::Tvdb(267440)],
+                &[ExternalId::Tvdb(3), ExternalId::Anidb(13241)],
+                3
+            )
+        );
+
+        // HAMA repeats the AniDB id of the series on each season, it only names the first one
+        let series_ids = [
+            ExternalId::parse("com.plexapp.agents.hama://anidb-10944?lang=en")
+                .expect("Failed to parse series guid"),
+        ];
+        let season_ids = [
+            ExternalId::parse("com.plexapp.agents.hama://anidb-10944/2?lang=en")
+                .expect("Failed to parse season guid"),
+        ];
+        assert_eq!(
+            vec![20958],
+            id_mappings.find_anilist_ids(&series_ids, &season_ids, 1)
+        );
+        assert!(id_mappings
+            .find_anilist_ids(&series_ids, &season_ids, 2)
+            .is_empty());
+
+        // Story arc layouts aren't in the dataset, they're left to the title search
+        for mode in [HamaTvdbMode::Tvdb4, HamaTvdbMode::Tvdb5] {
+            let hama_tvdb = ExternalId::HamaTvdb { id: 267440, mode };
+            assert!(id_mappings
+                .find_anilist_ids(&[hama_tvdb], &[], 1)
+                .is_empty());
+        }
+    }
+
+    #[test]
+    fn test_find_anilist_ids_for_hama_absolute_layouts() {
+        let id_mappings = id_mappings();
+        let tvdb2 = [
+            ExternalId::parse("com.plexapp.agents.hama://tvdb2-267440?lang=en")
+                .expect("Failed to parse guid"),
+        ];
+        let tvdb3 = [
+            ExternalId::parse("com.plexapp.agents.hama://tvdb3-267440?lang=en")
+                .expect("Failed to parse guid"),
+        ];
+
+        // tvdb2 seasons are the tvdb seasons
+        assert_eq!(vec![20958], id_mappings.find_anilist_ids(&tvdb2, &[], 2));
+        assert_eq!(
+            vec![99147, 104578],
+            id_mappings.find_anilist_ids(&tvdb2, &[], 3)
+        );
+
+        // tvdb3 seasons are the AniDB entries, the specials don't count
+        assert_eq!(vec![16498], id_mappings.find_anilist_ids(&tvdb3, &[], 1));
+        assert_eq!(vec![99147], id_mappings.find_anilist_ids(&tvdb3, &[], 3));
+        assert_eq!(vec![104578], id_mappings.find_anilist_ids(&tvdb3, &[], 4));
+        assert!(id_mappings.find_anilist_ids(&tvdb3, &[], 5).is_empty());
+        assert!(id_mappings.find_anilist_ids(&tvdb3, &[], 0).is_empty());
+    }
+
+    #[test]
+    fn test_find_anilist_ids_without_season() {
+        let id_mappings = id_mappings();
+
+        assert_eq!(
+            vec![11757],
+            id_mappings.find_anilist_ids(&[ExternalId::Tmdb(45782)], &[], 1)
+        );
+        assert!(id_mappings
+            .find_anilist_ids(&[ExternalId::Tvdb(259640)], &[], 2)
+            .is_empty());
+        assert!(id_mappings
+            .find_anilist_ids(&[ExternalId::Tvdb(76885)], &[], 1)
+            .is_empty());
+    }
+
+    #[test]
+    fn test_load_missing_file() {
+        let result = IdMappings::load("./test_data/does_not_exist.json").expect("Failed to load");
+
+        assert!(result
+            .find_anilist_ids(&[ExternalId::Tvdb(267440)], &[], 1)
+            .is_empty());
+    }
+}
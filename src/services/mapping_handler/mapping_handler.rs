@@ -1,27 +1,36 @@
 use async_trait::async_trait;
-use std::vec;
+use log::{info, warn};
+use std::{collections::HashSet, vec};
 
-use crate::services::anime_list_service::anime_list_service::{AnimeListService, AnimeResult};
+use crate::services::anime_list_service::anime_list_service::{
+    AnimeListService, AnimeResult, MediaFormat,
+};
 use crate::services::dbstore::dbstore::DbStore;
 use crate::services::dbstore::sqlite::Mapping;
-use crate::services::plex::plex_api::{PlexSeason, PlexSeries};
+use crate::services::metrics::metrics::{self, MappingOutcome};
+use crate::services::plex::plex_api::{PlexMediaKind, PlexSeason, PlexSeries};
 
-use super::mapping_utils::{find_match, get_mapped_episode_count, get_prev_mapping};
+use super::id_mappings::IdMappings;
+use super::mapping_utils::{
+    find_match, find_movie_match, get_mapped_episode_count, get_prev_mapping,
+};
 
 #[async_trait]
 pub trait MappingHandlerInterface {
+    /// Maps every season of the series. Seasons in `excluded_seasons` still take part in
+    /// following the sequels from season to season, but their mappings aren't saved or returned.
     async fn create_mapping(
         &self,
         anime_list_service: &impl AnimeListService,
         series: &PlexSeries,
+        excluded_seasons: &HashSet<String>,
     ) -> Result<Vec<Mapping>, anyhow::Error>;
     async fn find_match_for_season(
         &self,
         anime_list_service: &impl AnimeListService,
         season: &PlexSeason,
     ) -> Result<Option<AnimeResult>, anyhow::Error>;
-    async fn get_all_relevant_mappings(&self, all_series: &Vec<PlexSeries>) -> Vec<Mapping>;
-    async fn get_all_mappings(&self) -> Vec<Mapping>;
+    async fn get_all_relevant_mappings(&self, all_series: &[PlexSeries]) -> Vec<Mapping>;
 }
 
 pub struct MappingHandler<J>
@@ -29,86 +38,120 @@ where
     J: DbStore,
 {
     db_store: J,
+    dry_run: bool,
+    id_mappings: IdMappings,
 }
 
 impl<J> MappingHandler<J>
 where
     J: DbStore,
 {
-    pub fn new(db_store: J) -> Self {
-        Self { db_store }
+    /// Mappings aren't saved when `dry_run` is set, they're only returned from `create_mapping`.
+    /// Seasons are looked up in `id_mappings` before searching Anilist by title.
+    pub fn new(db_store: J, dry_run: bool, id_mappings: IdMappings) -> Self {
+        Self {
+            db_store,
+            dry_run,
+            id_mappings,
+        }
     }
-}
-
-#[derive(Clone, Debug)]
-pub struct MappingWithListData {
-    pub mapping: Mapping,
-    pub anilist_series: AnimeResult,
-}
 
-#[async_trait]
-impl<J> MappingHandlerInterface for MappingHandler<J>
-where
-    J: DbStore,
-{
-    async fn find_match_for_season(
+    /// Finds the Anilist entries of a season through its external ids and those of the series,
+    /// in the order their episodes appear in the season. Empty when any of them can't be found.
+    async fn find_matches_by_ids(
         &self,
         anime_list_service: &impl AnimeListService,
+        series: &PlexSeries,
         season: &PlexSeason,
-    ) -> Result<Option<AnimeResult>, anyhow::Error> {
-        let mut results = anime_list_service
-            .search_anime(&season.parent_title)
-            .await?;
-
-        results.sort_by_key(|x| x.start_date.year);
-
-        if results.is_empty() {
-            return Ok(None);
+    ) -> Result<Vec<AnimeResult>, anyhow::Error> {
+        let mut results = vec![];
+        for anime_id in
+            self.id_mappings
+                .find_anilist_ids(&series.guids, &season.guids, season.index)
+        {
+            match anime_list_service.get_anime(anime_id).await? {
+                Some(x) => results.push(x),
+                None => return Ok(vec![]),
+            }
         }
 
-        return Ok(find_match(results, season, 0));
+        Ok(results)
     }
 
-    async fn get_all_relevant_mappings(&self, all_series: &Vec<PlexSeries>) -> Vec<Mapping> {
-        let mut mappings: Vec<Mapping> = vec![];
-        for series in all_series {
-            let mut series_mappings = self
-                .db_store
-                .get_mapping_for_series(&series.rating_key)
-                .await
-                .unwrap();
-
-            mappings.append(&mut series_mappings);
-        }
+    /// Maps a movie to a single Anilist film, by id or by its title and release year.
+    async fn find_movie_mapping(
+        &self,
+        anime_list_service: &impl AnimeListService,
+        series: &PlexSeries,
+        year: Option<u16>,
+        mappings: &mut Vec<Mapping>,
+    ) -> Result<(), anyhow::Error> {
+        let movie = match series.seasons.first() {
+            Some(x) if mappings.is_empty() => x,
+            _ => return Ok(()),
+        };
 
-        mappings
-    }
+        // The id mappings can point a movie at a TV entry of the same series
+        let id_match = self
+            .find_matches_by_ids(anime_list_service, series, movie)
+            .await?
+            .into_iter()
+            .find(|x| x.format == Some(MediaFormat::Movie));
+        let found_match = match id_match {
+            Some(x) => Some(x),
+            None => find_movie_match(anime_list_service.search_movie(&series.title).await?, year),
+        };
+        let found_match = match found_match {
+            Some(x) => x,
+            None => {
+                warn!(
+                    "No Anilist match found for the movie '{}', it can be mapped from the web UI",
+                    series.title
+                );
+                metrics::record_mapping(MappingOutcome::Unmatched);
+                return Ok(());
+            }
+        };
 
-    async fn get_all_mappings(&self) -> Vec<Mapping> {
-        let result = self.db_store.get_all_mappings().await;
-        match result {
-            Ok(x) => x,
-            Err(_) => vec![],
-        }
+        info!(
+            "Matched the movie '{}' to '{}'",
+            series.title,
+            found_match.get_title()
+        );
+        mappings.push(Mapping {
+            id: 0,
+            list_provider_id: 1,
+            plex_id: movie.rating_key.clone(),
+            plex_series_id: series.rating_key.clone(),
+            plex_episode_start: 1,
+            season_length: 1,
+            anime_list_id: found_match.id,
+            episode_start: 1,
+            enabled: true,
+            ignored: false,
+            // Watching the movie completes the entry
+            episodes: Some(1),
+        });
+
+        Ok(())
     }
 
-    async fn create_mapping(
+    async fn find_new_mappings(
         &self,
         anime_list_service: &impl AnimeListService,
         series: &PlexSeries,
-    ) -> Result<Vec<Mapping>, anyhow::Error> {
-        // TODO: Reduce the chance of mapping errors by building up a vec of mappings for one
-        // season then only push them if all the episodes are covered
-
-        // Load any existing mappings
-        let mut mappings = self
-            .db_store
-            .get_mapping_for_series(&series.rating_key)
-            .await?;
+        mappings: &mut Vec<Mapping>,
+    ) -> Result<(), anyhow::Error> {
+        if let PlexMediaKind::Movie { year } = series.kind {
+            return self
+                .find_movie_mapping(anime_list_service, series, year, mappings)
+                .await;
+        }
 
         // Just skip big series for now
         if series.seasons.len() > 6 {
-            return Ok(mappings);
+            metrics::record_mapping(MappingOutcome::SkippedLargeSeries);
+            return Ok(());
         }
 
         for (i, season) in series.seasons.iter().enumerate() {
@@ -117,36 +160,81 @@ where
                 continue;
             }
 
-            // We start by just mapping the first season
+            // Any season can be matched through the id mappings, otherwise we start by just
+            // mapping the first season by title and follow the sequels from there
             let is_first_season = season.index == 1;
-            if is_first_season
-                && get_mapped_episode_count(&mappings, &season.rating_key)
-                    < season.episodes.len().try_into().unwrap()
-            {
-                let found_match = self
-                    .find_match_for_season(anime_list_service, season)
-                    .await?;
-                let found_match = match found_match {
-                    Some(x) => x,
-                    None => return Ok(mappings),
-                };
+            let mapped_episodes = get_mapped_episode_count(mappings, &season.rating_key);
+            let id_matches = match mapped_episodes {
+                0 => {
+                    self.find_matches_by_ids(anime_list_service, series, season)
+                        .await?
+                }
+                _ => vec![],
+            };
+            for found_match in id_matches.iter() {
+                info!(
+                    "Matched '{}' season {} to '{}' by id",
+                    series.title,
+                    season.index,
+                    found_match.get_title()
+                );
+            }
 
+            let found_matches = match id_matches.is_empty() {
+                false => id_matches,
+                true if is_first_season
+                    && mapped_episodes < season.episodes.len().try_into().unwrap() =>
+                {
+                    let found_match = self
+                        .find_match_for_season(anime_list_service, season)
+                        .await?;
+                    match found_match {
+                        Some(x) => {
+                            info!(
+                                "Matched '{}' season {} to '{}'",
+                                series.title,
+                                season.index,
+                                x.get_title()
+                            );
+                            vec![x]
+                        }
+                        None => {
+                            warn!(
+                                "No Anilist match found for '{}' season {}, it can be mapped from the web UI",
+                                series.title, season.index
+                            );
+                            metrics::record_mapping(MappingOutcome::Unmatched);
+                            return Ok(());
+                        }
+                    }
+                }
+                true => vec![],
+            };
+
+            // A season can hold several entries, such as both cours of a split season
+            let first_episode = get_first_episode_number(series, season);
+            let mut plex_episode_start = first_episode;
+            for found_match in found_matches {
+                let remaining_episodes = season
+                    .get_episode_count()
+                    .saturating_sub(plex_episode_start - first_episode);
                 let mapping = Mapping {
                     id: 0,
                     list_provider_id: 1,
                     plex_id: season.rating_key.clone(),
                     plex_series_id: series.rating_key.clone(),
-                    plex_episode_start: 1,
+                    plex_episode_start,
                     season_length: found_match
                         .episodes
-                        .unwrap_or(season.episodes.len().try_into().unwrap())
-                        .into(),
+                        .map(u32::from)
+                        .unwrap_or(remaining_episodes),
                     anime_list_id: found_match.id,
                     episode_start: 1,
                     enabled: true,
                     ignored: false,
                     episodes: found_match.episodes,
                 };
+                plex_episode_start += mapping.season_length;
                 mappings.push(mapping);
             }
 
@@ -155,23 +243,23 @@ where
                 let mut counter = 0;
 
                 while counter < limit
-                    && get_mapped_episode_count(&mappings, &season.rating_key)
+                    && get_mapped_episode_count(mappings, &season.rating_key)
                         < season.episodes.len().try_into().unwrap()
                 {
                     counter += 1;
-                    let mut prev_mapping = get_prev_mapping(&mappings, &season.rating_key);
+                    let mut prev_mapping = get_prev_mapping(mappings, &season.rating_key);
                     // If we got a prev mapping matching the current season this is a multi entry
                     // season
 
                     let mutli_entry_season = prev_mapping.is_some();
                     if prev_mapping.is_none() && i > 0 {
                         let prev_plex_season = &series.seasons[i - 1];
-                        prev_mapping = get_prev_mapping(&mappings, &prev_plex_season.rating_key);
+                        prev_mapping = get_prev_mapping(mappings, &prev_plex_season.rating_key);
                     }
 
                     let prev_mapping = match prev_mapping {
                         Some(x) => x,
-                        None => return Ok(mappings),
+                        None => return Ok(()),
                     };
 
                     let prev_mapping_entry = anime_list_service
@@ -180,7 +268,7 @@ where
 
                     let prev_mapping_entry = match prev_mapping_entry {
                         Some(x) => x,
-                        None => return Ok(mappings),
+                        None => return Ok(()),
                     };
 
                     let sequel = anime_list_service
@@ -189,11 +277,11 @@ where
 
                     let mut sequel = match sequel {
                         Some(x) => x,
-                        None => return Ok(mappings),
+                        None => return Ok(()),
                     };
 
                     let current_mapped_episodes =
-                        get_mapped_episode_count(&mappings, &season.rating_key);
+                        get_mapped_episode_count(mappings, &season.rating_key);
                     // TODO: Don't just use 0 if the episode number isn't known
                     // This likely means it's still releasing but we need to check
 
@@ -211,13 +299,13 @@ where
                         let found_match = match found_match {
                             Some(x) => x,
                             None => {
-                                return Ok(mappings);
+                                return Ok(());
                             }
                         };
                         sequel = found_match;
                     }
 
-                    let mut plex_episode_start = 0;
+                    let mut plex_episode_start = get_first_episode_number(series, season);
                     if mutli_entry_season {
                         plex_episode_start =
                             prev_mapping.plex_episode_start + prev_mapping.season_length;
@@ -244,9 +332,82 @@ where
             }
         }
 
+        Ok(())
+    }
+}
+
+/// The number of the first episode of the season. Seasons start at episode 1, unless the series
+/// uses one of HAMA's absolute layouts and the numbers carry on from the season before.
+fn get_first_episode_number(series: &PlexSeries, season: &PlexSeason) -> u32 {
+    match series.has_absolute_numbering() {
+        true => season.get_first_episode_number().unwrap_or(1),
+        false => 1,
+    }
+}
+
+#[async_trait]
+impl<J> MappingHandlerInterface for MappingHandler<J>
+where
+    J: DbStore,
+{
+    async fn find_match_for_season(
+        &self,
+        anime_list_service: &impl AnimeListService,
+        season: &PlexSeason,
+    ) -> Result<Option<AnimeResult>, anyhow::Error> {
+        let mut results = anime_list_service
+            .search_anime(&season.parent_title)
+            .await?;
+
+        results.sort_by_key(|x| x.start_date.year);
+
+        if results.is_empty() {
+            return Ok(None);
+        }
+
+        return Ok(find_match(results, season, 0));
+    }
+
+    async fn get_all_relevant_mappings(&self, all_series: &[PlexSeries]) -> Vec<Mapping> {
+        let mut mappings: Vec<Mapping> = vec![];
+        for series in all_series {
+            let mut series_mappings = self
+                .db_store
+                .get_mapping_for_series(&series.rating_key)
+                .await
+                .unwrap();
+
+            mappings.append(&mut series_mappings);
+        }
+
+        mappings
+    }
+
+    async fn create_mapping(
+        &self,
+        anime_list_service: &impl AnimeListService,
+        series: &PlexSeries,
+        excluded_seasons: &HashSet<String>,
+    ) -> Result<Vec<Mapping>, anyhow::Error> {
+        // TODO: Reduce the chance of mapping errors by building up a vec of mappings for one
+        // season then only push them if all the episodes are covered
+
+        // Load any existing mappings
+        let mut mappings = self
+            .db_store
+            .get_mapping_for_series(&series.rating_key)
+            .await?;
+
+        self.find_new_mappings(anime_list_service, series, &mut mappings)
+            .await?;
+        mappings.retain(|x| !excluded_seasons.contains(&x.plex_id));
+
         let new_mappings = mappings.iter().filter(|x| x.id == 0);
         for mapping in new_mappings {
-            let _ = self.db_store.save_mapping(mapping).await;
+            metrics::record_mapping(MappingOutcome::Created);
+            if !self.dry_run {
+                let _ = self.db_store.save_mapping(mapping).await;
+            }
         }
 
         return Ok(mappings);
@@ -257,11 +418,18 @@ where
 mod tests {
     use tracing::info;
 
+    use wiremock::{
+        matchers::{body_partial_json, method},
+        Mock, MockServer, ResponseTemplate,
+    };
+
     use crate::{
         services::{
             anime_list_service::anilist_service::AnilistService,
-            dbstore::{dbstore::DbStore, sqlite::Sqlite},
-            plex::plex_api::PlexEpisode,
+            config::config::load_config,
+            dbstore::sqlite::Sqlite,
+            mapping_handler::id_mappings::{IdMappingEntry, IdMappingSeason},
+            plex::plex_api::{ExternalId, PlexEpisode, ResponsePlexMovie},
         },
         utils::{get_db_file_location, init_logger},
     };
@@ -274,13 +442,22 @@ mod tests {
         let mut db_store = Sqlite::new(&get_db_file_location()).await;
         db_store.migrate().await;
 
-        let config = db_store.get_config().await;
+        let config = load_config(&db_store, None)
+            .await
+            .expect("Failed to load config");
 
-        let list_service = AnilistService::new(config.anilist_token, db_store, None);
+        let list_service = AnilistService::new(
+            config.get_anilist_token().expect("Anilist token not set"),
+            db_store,
+            None,
+        );
 
         let db_store = Sqlite::new(&get_db_file_location()).await;
 
-        (MappingHandler::new(db_store), list_service)
+        (
+            MappingHandler::new(db_store, false, IdMappings::default()),
+            list_service,
+        )
     }
 
     fn generate_episodes(num_episodes: u16) -> Vec<PlexEpisode> {
@@ -291,20 +468,309 @@ mod tests {
                 rating_key: i.to_string(),
                 view_count: 0,
                 last_viewed_at: None,
+                first_viewed_at: None,
+                index: None,
+                parent_index: None,
+                originally_available_at: None,
+                view_offset: None,
+                duration: None,
             })
         }
 
         return episodes;
     }
 
+    /// Maps a one season series with the given guids, Anilist only knows 'Sword Art Online'.
+    async fn map_by_id(guids: Vec<ExternalId>, entry: IdMappingEntry) -> Vec<Mapping> {
+        let season = PlexSeason {
+            guids: vec![],
+            labels: vec![],
+            rating_key: "501".to_string(),
+            parent_title: "SAO".to_string(),
+            index: 1,
+            episodes: generate_episodes(25),
+        };
+
+        map_season_by_id(guids, entry, season).await
+    }
+
+    async fn map_season_by_id(
+        guids: Vec<ExternalId>,
+        entry: IdMappingEntry,
+        season: PlexSeason,
+    ) -> Vec<Mapping> {
+        init_logger();
+        let data = std::fs::read_to_string("./test_data/anilist_responses.json")
+            .expect("Unable to read anilist responses test file");
+        let responses: Vec<serde_json::Value> =
+            serde_json::from_str(&data).expect("Failed to parse anilist responses test file");
+        let response = responses
+            .iter()
+            .find(|x| x["name"] == "get_anime")
+            .and_then(|x| x["response"].as_str())
+            .expect("Failed to find response 'get_anime'");
+
+        // Only the matched entry is requested, there's no title search
+        let mock_server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(body_partial_json(
+                serde_json::json!({ "variables": { "anime_id": 11757 } }),
+            ))
+            .respond_with(ResponseTemplate::new(200).set_body_string(response))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        let mut db_store = Sqlite::new("sqlite::memory:").await;
+        db_store.migrate().await;
+        let list_service =
+            AnilistService::new("abc".to_string(), db_store.clone(), Some(mock_server.uri()));
+        let mapper = MappingHandler::new(db_store, true, IdMappings::new(vec![entry]));
+
+        let series = PlexSeries {
+            guids,
+            labels: vec![],
+            kind: PlexMediaKind::Show,
+            title: "SAO".to_string(),
+            rating_key: "500".to_string(),
+            seasons: vec![season],
+        };
+
+        mapper
+            .create_mapping(&list_service, &series, &HashSet::new())
+            .await
+            .expect("Failed to create mapping")
+    }
+
+    #[tokio::test]
+    async fn test_mapping_by_external_id() {
+        let entry = IdMappingEntry {
+            anilist_id: Some(11757),
+            thetvdb_id: Some(259640),
+            ..Default::default()
+        };
+
+        let result = map_by_id(vec![ExternalId::Tvdb(259640)], entry).await;
+
+        assert_eq!(1, result.len());
+        assert_eq!(11757, result[0].anime_list_id);
+        assert_eq!(25, result[0].season_length);
+    }
+
+    #[tokio::test]
+    async fn test_mapping_by_hama_anidb_id() {
+        let entry = IdMappingEntry {
+            anilist_id: Some(11757),
+            anidb_id: Some(8692),
+            ..Default::default()
+        };
+        let guids = vec![
+            ExternalId::parse("com.plexapp.agents.hama://anidb-8692?lang=en")
+                .expect("Failed to parse guid"),
+        ];
+
+        let result = map_by_id(guids, entry).await;
+
+        assert_eq!(1, result.len());
+        assert_eq!(11757, result[0].anime_list_id);
+    }
+
+    #[tokio::test]
+    async fn test_mapping_hama_absolute_numbered_season() {
+        let entry = IdMappingEntry {
+            anilist_id: Some(11757),
+            anidb_id: Some(8692),
+            thetvdb_id: Some(259640),
+            season: Some(IdMappingSeason {
+                tvdb: Some(2),
+                tmdb: None,
+            }),
+            ..Default::default()
+        };
+        let guids = vec![
+            ExternalId::parse("com.plexapp.agents.hama://tvdb2-259640?lang=en")
+                .expect("Failed to parse guid"),
+        ];
+        // The second season carries on from the 25 episodes of the first
+        let mut episodes = generate_episodes(25);
+        for (i, episode) in episodes.iter_mut().enumerate() {
+            episode.index = Some(26 + i as u32);
+        }
+        let season = PlexSeason {
+            guids: vec![],
+            labels: vec![],
+            rating_key: "502".to_string(),
+            parent_title: "SAO".to_string(),
+            index: 2,
+            episodes,
+        };
+
+        let result = map_season_by_id(guids, entry, season).await;
+
+        assert_eq!(1, result.len());
+        assert_eq!(11757, result[0].anime_list_id);
+        assert_eq!(26, result[0].plex_episode_start);
+        assert_eq!(25, result[0].season_length);
+    }
+
+    #[tokio::test]
+    async fn test_excluded_season_keeps_sequel_chain() {
+        init_logger();
+        let anime = |id: u32, sequel: Option<u32>| {
+            serde_json::json!({ "data": { "Media": {
+                "id": id,
+                "format": "TV",
+                "episodes": 12,
+                "synonyms": [],
+                "status": "FINISHED",
+                "startDate": { "year": 2010 + id },
+                "endDate": { "year": 2010 + id },
+                "title": { "english": format!("Season {}", id), "romaji": format!("Season {}", id) },
+                "relations": {
+                    "edges": sequel.iter().map(|_| serde_json::json!({ "relationType": "SEQUEL" })).collect::<Vec<_>>(),
+                    "nodes": sequel.iter().map(|x| serde_json::json!({
+                        "id": x,
+                        "format": "TV",
+                        "startDate": { "year": 2010 + x },
+                        "endDate": { "year": 2010 + x },
+                    })).collect::<Vec<_>>(),
+                },
+            }}})
+        };
+        let mock_server = MockServer::start().await;
+        for (id, sequel) in [(1, Some(2)), (2, Some(3)), (3, None)] {
+            Mock::given(method("POST"))
+                .and(body_partial_json(
+                    serde_json::json!({ "variables": { "anime_id": id } }),
+                ))
+                .respond_with(ResponseTemplate::new(200).set_body_json(anime(id, sequel)))
+                .mount(&mock_server)
+                .await;
+        }
+
+        let mut db_store = Sqlite::new("sqlite::memory:").await;
+        db_store.migrate().await;
+        let list_service =
+            AnilistService::new("abc".to_string(), db_store.clone(), Some(mock_server.uri()));
+        let entry = IdMappingEntry {
+            anilist_id: Some(1),
+            thetvdb_id: Some(100),
+            season: Some(IdMappingSeason {
+                tvdb: Some(1),
+                tmdb: None,
+            }),
+            ..Default::default()
+        };
+        let mapper = MappingHandler::new(db_store.clone(), false, IdMappings::new(vec![entry]));
+        let season = |index: u8| PlexSeason {
+            guids: vec![],
+            labels: vec![],
+            rating_key: format!("50{}", index),
+            parent_title: "Show".to_string(),
+            index,
+            episodes: generate_episodes(12),
+        };
+        let series = PlexSeries {
+            guids: vec![ExternalId::Tvdb(100)],
+            labels: vec![],
+            kind: PlexMediaKind::Show,
+            title: "Show".to_string(),
+            rating_key: "500".to_string(),
+            seasons: vec![season(1), season(2), season(3)],
+        };
+
+        let result = mapper
+            .create_mapping(&list_service, &series, &HashSet::from(["502".to_string()]))
+            .await
+            .expect("Failed to create mapping");
+        let saved = db_store
+            .get_mapping_for_series("500")
+            .await
+            .expect("Failed to get mappings");
+
+        let mapped = |mappings: &[Mapping]| {
+            mappings
+                .iter()
+                .map(|x| (x.plex_id.clone(), x.anime_list_id))
+                .collect::<Vec<_>>()
+        };
+        let expected = vec![("501".to_string(), 1), ("503".to_string(), 3)];
+        assert_eq!(expected, mapped(&result));
+        assert_eq!(expected, mapped(&saved));
+    }
+
+    #[tokio::test]
+    async fn test_mapping_movie_by_title_and_year() {
+        init_logger();
+        let movie = |id: u32, year: i64| {
+            serde_json::json!({
+                "id": id,
+                "format": "MOVIE",
+                "episodes": 1,
+                "synonyms": [],
+                "status": "FINISHED",
+                "startDate": { "year": year },
+                "endDate": { "year": year },
+                "title": { "english": "A Silent Voice", "romaji": "Koe no Katachi" },
+                "relations": { "edges": [], "nodes": [] },
+            })
+        };
+        let mock_server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(body_partial_json(serde_json::json!({
+                "variables": { "anime_name": "A Silent Voice", "format": "MOVIE" }
+            })))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "data": { "Page": { "media": [movie(1, 2010), movie(20954, 2016)] } }
+            })))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        let mut db_store = Sqlite::new("sqlite::memory:").await;
+        db_store.migrate().await;
+        let list_service =
+            AnilistService::new("abc".to_string(), db_store.clone(), Some(mock_server.uri()));
+        let mapper = MappingHandler::new(db_store, true, IdMappings::default());
+        let series = PlexSeries::from(ResponsePlexMovie {
+            rating_key: "700".to_string(),
+            title: "A Silent Voice".to_string(),
+            year: Some(2016),
+            view_count: 1,
+            last_viewed_at: Some(1700000000),
+            view_offset: None,
+            duration: None,
+            guid: None,
+            guids: vec![],
+            labels: vec![],
+            collections: vec![],
+        });
+
+        let result = mapper
+            .create_mapping(&list_service, &series, &HashSet::new())
+            .await
+            .expect("Failed to create mapping");
+
+        assert_eq!(1, result.len());
+        assert_eq!(20954, result[0].anime_list_id);
+        assert_eq!("700", result[0].plex_id);
+        assert_eq!(1, result[0].season_length);
+        assert_eq!(Some(1), result[0].episodes);
+    }
+
     #[tokio::test]
     async fn test_one_to_one_mapping() {
         let (mapper, list_service) = init().await;
 
         let series = PlexSeries {
+            guids: vec![],
+            labels: vec![],
+            kind: PlexMediaKind::Show,
             title: "Mysterious Girlfriend X".to_string(),
             rating_key: "12345".to_string(),
             seasons: vec![PlexSeason {
+                guids: vec![],
+                labels: vec![],
                 rating_key: "12345".to_string(),
                 parent_title: "Mysterious Girlfriend X".to_string(),
                 index: 1,
@@ -313,7 +779,7 @@ mod tests {
         };
 
         let result = mapper
-            .create_mapping(&list_service, &series)
+            .create_mapping(&list_service, &series, &HashSet::new())
             .await
             .expect("Faied to get result for one to one mapping");
 
@@ -331,16 +797,23 @@ mod tests {
         let (mapper, list_service) = init().await;
 
         let series = PlexSeries {
+            guids: vec![],
+            labels: vec![],
+            kind: PlexMediaKind::Show,
             title: "Vinland Saga".to_string(),
             rating_key: "12794".to_string(),
             seasons: vec![
                 PlexSeason {
+                    guids: vec![],
+                    labels: vec![],
                     rating_key: "12795".to_string(),
                     parent_title: "Vinland Saga".to_string(),
                     index: 1,
                     episodes: generate_episodes(24),
                 },
                 PlexSeason {
+                    guids: vec![],
+                    labels: vec![],
                     rating_key: "45711".to_string(),
                     parent_title: "Vinland Saga".to_string(),
                     index: 2,
@@ -350,7 +823,7 @@ mod tests {
         };
 
         let result = mapper
-            .create_mapping(&list_service, &series)
+            .create_mapping(&list_service, &series, &HashSet::new())
             .await
             .expect("Faied to get result for two season mapping");
 
@@ -364,34 +837,47 @@ mod tests {
         let (mapper, list_service) = init().await;
 
         let series = PlexSeries {
+            guids: vec![],
+            labels: vec![],
+            kind: PlexMediaKind::Show,
             title: "Overlord".to_string(),
             rating_key: "10618".to_string(),
             seasons: vec![
                 PlexSeason {
+                    guids: vec![],
+                    labels: vec![],
                     rating_key: "29790".to_string(),
                     index: 0,
                     parent_title: "Overlord".to_string(),
                     episodes: generate_episodes(37),
                 },
                 PlexSeason {
+                    guids: vec![],
+                    labels: vec![],
                     rating_key: "10619".to_string(),
                     index: 1,
                     parent_title: "Overlord".to_string(),
                     episodes: generate_episodes(13),
                 },
                 PlexSeason {
+                    guids: vec![],
+                    labels: vec![],
                     rating_key: "10647".to_string(),
                     index: 2,
                     parent_title: "Overlord".to_string(),
                     episodes: generate_episodes(13),
                 },
                 PlexSeason {
+                    guids: vec![],
+                    labels: vec![],
                     rating_key: "10663".to_string(),
                     index: 3,
                     parent_title: "Overlord".to_string(),
                     episodes: generate_episodes(13),
                 },
                 PlexSeason {
+                    guids: vec![],
+                    labels: vec![],
                     rating_key: "43158".to_string(),
                     index: 4,
                     parent_title: "Overlord".to_string(),
@@ -401,7 +887,7 @@ mod tests {
         };
 
         let result = mapper
-            .create_mapping(&list_service, &series)
+            .create_mapping(&list_service, &series, &HashSet::new())
             .await
             .expect("Faied to get result for complex name mapping");
 
@@ -417,34 +903,47 @@ mod tests {
         let (mapper, list_service) = init().await;
 
         let series = PlexSeries {
+            guids: vec![],
+            labels: vec![],
+            kind: PlexMediaKind::Show,
             title: "Attack on Titan".to_string(),
             rating_key: "17456".to_string(),
             seasons: vec![
                 PlexSeason {
+                    guids: vec![],
+                    labels: vec![],
                     rating_key: "30037".to_string(),
                     index: 0,
                     parent_title: "Attack on Titan".to_string(),
                     episodes: generate_episodes(8),
                 },
                 PlexSeason {
+                    guids: vec![],
+                    labels: vec![],
                     rating_key: "17457".to_string(),
                     index: 1,
                     parent_title: "Attack on Titan".to_string(),
                     episodes: generate_episodes(25),
                 },
                 PlexSeason {
+                    guids: vec![],
+                    labels: vec![],
                     rating_key: "17483".to_string(),
                     index: 2,
                     parent_title: "Attack on Titan".to_string(),
                     episodes: generate_episodes(12),
                 },
                 PlexSeason {
+                    guids: vec![],
+                    labels: vec![],
                     rating_key: "17496".to_string(),
                     index: 3,
                     parent_title: "Attack on Titan".to_string(),
                     episodes: generate_episodes(22),
                 },
                 PlexSeason {
+                    guids: vec![],
+                    labels: vec![],
                     rating_key: "22191".to_string(),
                     index: 4,
                     parent_title: "Attack on Titan".to_string(),
@@ -454,7 +953,7 @@ mod tests {
         };
 
         let result = mapper
-            .create_mapping(&list_service, &series)
+            .create_mapping(&list_service, &series, &HashSet::new())
             .await
             .expect("Faied to get result for up to three anilist entries for one plex season");
 
@@ -473,34 +972,47 @@ mod tests {
         let (mapper, list_service) = init().await;
 
         let series = PlexSeries {
+            guids: vec![],
+            labels: vec![],
+            kind: PlexMediaKind::Show,
             title: "JoJo's Bizarre Adventure".to_string(),
             rating_key: "28602".to_string(),
             seasons: vec![
                 PlexSeason {
+                    guids: vec![],
+                    labels: vec![],
                     rating_key: "28603".to_string(),
                     index: 1,
                     parent_title: "JoJo's Bizarre Adventure".to_string(),
                     episodes: generate_episodes(26),
                 },
                 PlexSeason {
+                    guids: vec![],
+                    labels: vec![],
                     rating_key: "28630".to_string(),
                     index: 2,
                     parent_title: "JoJo's Bizarre Adventure".to_string(),
                     episodes: generate_episodes(48),
                 },
                 PlexSeason {
+                    guids: vec![],
+                    labels: vec![],
                     rating_key: "28719".to_string(),
                     index: 3,
                     parent_title: "JoJo's Bizarre Adventure".to_string(),
                     episodes: generate_episodes(39),
                 },
                 PlexSeason {
+                    guids: vec![],
+                    labels: vec![],
                     rating_key: "28679".to_string(),
                     index: 4,
                     parent_title: "JoJo's Bizarre Adventure".to_string(),
                     episodes: generate_episodes(39),
                 },
                 PlexSeason {
+                    guids: vec![],
+                    labels: vec![],
                     rating_key: "37904".to_string(),
                     index: 5,
                     parent_title: "JoJo's Bizarre Adventure".to_string(),
@@ -510,7 +1022,7 @@ mod tests {
         };
 
         let result = mapper
-            .create_mapping(&list_service, &series)
+            .create_mapping(&list_service, &series, &HashSet::new())
             .await
             .expect("Faied to get result for up to three anilist entries for one plex season");
 
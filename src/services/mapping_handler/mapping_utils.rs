@@ -4,7 +4,7 @@ struct ResultScore {
 }
 
 use crate::services::{
-    anime_list_service::anime_list_service::{AnimeResult, RelationType},
+    anime_list_service::anime_list_service::{AnimeResult, MediaFormat, RelationType},
     dbstore::sqlite::Mapping,
     plex::plex_api::PlexSeason,
 };
@@ -78,6 +78,12 @@ pub fn find_match(
             }
         }
 
+        // Match the year the season started airing
+        let start_year = potential_match.result.start_date.year;
+        if start_year.is_some() && start_year == target.get_first_air_year() {
+            potential_match.score += 25;
+        }
+
         let has_no_prequel = potential_match
             .result
             .relations
@@ -102,6 +108,29 @@ pub fn find_match(
     }
 }
 
+/// Picks the first film released in `year`, or the year either side of it since Plex and Anilist
+/// don't always agree on premieres. Without a year the best search match is used.
+pub fn find_movie_match(results: Vec<AnimeResult>, year: Option<u16>) -> Option<AnimeResult> {
+    let movies = results
+        .into_iter()
+        .filter(|x| x.format == Some(MediaFormat::Movie));
+    let year = match year {
+        Some(x) => i64::from(x),
+        None => return movies.into_iter().next(),
+    };
+
+    let mut close_match = None;
+    for movie in movies {
+        match movie.start_date.year.map(|x| (x - year).abs()) {
+            Some(0) => return Some(movie),
+            Some(1) if close_match.is_none() => close_match = Some(movie),
+            _ => {}
+        }
+    }
+
+    close_match
+}
+
 pub fn get_prev_mapping(mappings: &[Mapping], rating_key: &str) -> Option<Mapping> {
     let mut prev_mappings: Vec<&Mapping> = mappings
         .iter()
@@ -119,6 +148,10 @@ pub fn get_prev_mapping(mappings: &[Mapping], rating_key: &str) -> Option<Mappin
 
 #[cfg(test)]
 mod tests {
+    use chrono::NaiveDate;
+
+    use crate::services::plex::plex_api::PlexEpisode;
+
     use super::*;
 
     fn create_mapping(id: u32, plex_id: &str, season_length: u32) -> Mapping {
@@ -207,4 +240,69 @@ mod tests {
         let result = get_prev_mapping(&mappings, rating_key);
         assert!(result.is_none())
     }
+
+    fn anime(id: u32, format: &str, year: i64) -> AnimeResult {
+        serde_json::from_value(serde_json::json!({
+            "id": id,
+            "format": format,
+            "episodes": 1,
+            "synonyms": [],
+            "status": "FINISHED",
+            "startDate": { "year": year },
+            "endDate": { "year": year },
+            "title": { "romaji": id.to_string() },
+            "relations": { "edges": [], "nodes": [] },
+        }))
+        .expect("Failed to build anime result")
+    }
+
+    #[test]
+    fn test_find_match_by_air_year() {
+        let episode = |year: i32| PlexEpisode {
+            rating_key: year.to_string(),
+            view_count: 0,
+            last_viewed_at: None,
+            first_viewed_at: None,
+            index: None,
+            parent_index: Some(2),
+            originally_available_at: NaiveDate::from_ymd_opt(year, 4, 1),
+            view_offset: None,
+            duration: None,
+        };
+        let season = PlexSeason {
+            guids: vec![],
+            labels: vec![],
+            rating_key: "502".to_string(),
+            parent_title: "Show".to_string(),
+            index: 2,
+            episodes: vec![episode(2017)],
+        };
+        let results = vec![anime(1, "TV", 2016), anime(2, "TV", 2017)];
+
+        assert_eq!(Some(2), find_match(results, &season, 0).map(|x| x.id));
+    }
+
+    #[test]
+    fn test_find_movie_match_by_year() {
+        let results = vec![
+            anime(1, "TV", 2016),
+            anime(2, "MOVIE", 2015),
+            anime(3, "MOVIE", 2016),
+            anime(4, "MOVIE", 2020),
+        ];
+
+        assert_eq!(
+            Some(3),
+            find_movie_match(results.clone(), Some(2016)).map(|x| x.id)
+        );
+        assert_eq!(
+            Some(2),
+            find_movie_match(results.clone(), Some(2014)).map(|x| x.id)
+        );
+        assert_eq!(
+            None,
+            find_movie_match(results.clone(), Some(2018)).map(|x| x.id)
+        );
+        assert_eq!(Some(2), find_movie_match(results, None).map(|x| x.id));
+    }
 }
@@ -1,2 +1,3 @@
+pub mod id_mappings;
 pub mod mapping_handler;
 pub mod mapping_utils;
@@ -0,0 +1,183 @@
+use std::{fmt, str::FromStr};
+
+use async_trait::async_trait;
+use serde::Serialize;
+
+use crate::services::{
+    config::config::Config,
+    jellyfin::jellyfin_api_service::JellyfinApi,
+    plex::{
+        plex_api::{
+            PlexInterface, PlexPage, PlexSeason, PlexSeries, ResponsePlexHistoryItem,
+            ResponsePlexLibrary, ResponsePlexMovie, ResponsePlexSeries,
+        },
+        plex_api_service::PlexApi,
+    },
+};
+
+/// The kind of server the watch state is read from.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MediaServerKind {
+    Plex,
+    Jellyfin,
+    Emby,
+}
+
+impl FromStr for MediaServerKind {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.trim().to_lowercase().as_str() {
+            "plex" => Ok(MediaServerKind::Plex),
+            "jellyfin" => Ok(MediaServerKind::Jellyfin),
+            "emby" => Ok(MediaServerKind::Emby),
+            _ => Err(format!(
+                "'{}' isn't a media server, use one of plex, jellyfin or emby",
+                value
+            )),
+        }
+    }
+}
+
+impl fmt::Display for MediaServerKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MediaServerKind::Plex => write!(f, "Plex"),
+            MediaServerKind::Jellyfin => write!(f, "Jellyfin"),
+            MediaServerKind::Emby => write!(f, "Emby"),
+        }
+    }
+}
+
+/// The configured media server. Every server is read into the same series, seasons and episodes,
+/// so mapping and syncing don't depend on which one is used.
+#[derive(Debug)]
+pub enum MediaServer {
+    Plex(PlexApi),
+    /// Jellyfin or Emby
+    Jellyfin(JellyfinApi),
+}
+
+impl MediaServer {
+    /// Connects as the owner of the server, or the configured user for Jellyfin and Emby.
+    pub async fn connect(config: &Config) -> Result<Self, anyhow::Error> {
+        Self::connect_as(config, None, &config.plex_token).await
+    }
+
+    /// Connects as one of the synced users, `user` is `None` for the owner. Plex users are told
+    /// apart by their token, Jellyfin and Emby users by their name.
+    pub async fn connect_as(
+        config: &Config,
+        user: Option<&str>,
+        plex_token: &str,
+    ) -> Result<Self, anyhow::Error> {
+        match config.media_server {
+            MediaServerKind::Plex => Ok(MediaServer::Plex(PlexApi::with_limits(
+                config.plex_url.clone(),
+                plex_token.to_string(),
+                config.plex_page_size,
+                config.plex_concurrency,
+            ))),
+            kind => {
+                let api = JellyfinApi::connect(
+                    kind,
+                    config.media_server_url.clone(),
+                    &config.media_server_api_key,
+                    user.unwrap_or(&config.media_server_user),
+                    config.plex_page_size,
+                    config.plex_concurrency,
+                )
+                .await?;
+                Ok(MediaServer::Jellyfin(api))
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl PlexInterface for MediaServer {
+    async fn get_libraries(&self) -> Result<Vec<ResponsePlexLibrary>, reqwest::Error> {
+        match self {
+            MediaServer::Plex(x) => x.get_libraries().await,
+            MediaServer::Jellyfin(x) => x.get_libraries().await,
+        }
+    }
+
+    async fn get_series_page(
+        &self,
+        library_key: &str,
+        start: u32,
+    ) -> Result<PlexPage<ResponsePlexSeries>, reqwest::Error> {
+        match self {
+            MediaServer::Plex(x) => x.get_series_page(library_key, start).await,
+            MediaServer::Jellyfin(x) => x.get_series_page(library_key, start).await,
+        }
+    }
+
+    async fn get_series(
+        &self,
+        rating_key: &str,
+    ) -> Result<Option<ResponsePlexSeries>, reqwest::Error> {
+        match self {
+            MediaServer::Plex(x) => x.get_series(rating_key).await,
+            MediaServer::Jellyfin(x) => x.get_series(rating_key).await,
+        }
+    }
+
+    async fn get_movies_page(
+        &self,
+        library_key: &str,
+        start: u32,
+    ) -> Result<PlexPage<ResponsePlexMovie>, reqwest::Error> {
+        match self {
+            MediaServer::Plex(x) => x.get_movies_page(library_key, start).await,
+            MediaServer::Jellyfin(x) => x.get_movies_page(library_key, start).await,
+        }
+    }
+
+    async fn get_watch_history_page(
+        &self,
+        account_id: u64,
+        since: Option<i64>,
+        start: u32,
+    ) -> Result<PlexPage<ResponsePlexHistoryItem>, reqwest::Error> {
+        match self {
+            MediaServer::Plex(x) => x.get_watch_history_page(account_id, since, start).await,
+            MediaServer::Jellyfin(x) => x.get_watch_history_page(account_id, since, start).await,
+        }
+    }
+
+    async fn mark_watched(&self, rating_key: &str) -> Result<(), reqwest::Error> {
+        match self {
+            MediaServer::Plex(x) => x.mark_watched(rating_key).await,
+            MediaServer::Jellyfin(x) => x.mark_watched(rating_key).await,
+        }
+    }
+
+    async fn populate_episodes(&self, season: &mut PlexSeason) -> Result<(), reqwest::Error> {
+        match self {
+            MediaServer::Plex(x) => x.populate_episodes(season).await,
+            MediaServer::Jellyfin(x) => x.populate_episodes(season).await,
+        }
+    }
+
+    async fn populate_seasons(&self, series: &mut PlexSeries) -> Result<(), reqwest::Error> {
+        match self {
+            MediaServer::Plex(x) => x.populate_seasons(series).await,
+            MediaServer::Jellyfin(x) => x.populate_seasons(series).await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_media_server_kind() {
+        assert_eq!(Ok(MediaServerKind::Jellyfin), "Jellyfin".parse());
+        assert_eq!(Ok(MediaServerKind::Emby), " emby ".parse());
+        assert!("kodi".parse::<MediaServerKind>().is_err());
+    }
+}
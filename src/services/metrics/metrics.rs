@@ -0,0 +1,145 @@
+use std::{sync::OnceLock, time::Duration};
+
+use ::metrics::{
+    counter, describe_counter, describe_histogram, histogram, increment_counter, Unit,
+};
+use log::warn;
+use metrics_exporter_prometheus::{Matcher, PrometheusBuilder, PrometheusHandle};
+
+const SYNC_DURATION: &str = "plex_ani_sync_sync_duration_seconds";
+const PLEX_SERIES: &str = "plex_ani_sync_plex_series_processed_total";
+const PLEX_REQUEST_DURATION: &str = "plex_ani_sync_plex_request_duration_seconds";
+const MAPPINGS: &str = "plex_ani_sync_mappings_total";
+const ANILIST_REQUESTS: &str = "plex_ani_sync_anilist_requests_total";
+const LIST_UPDATES: &str = "plex_ani_sync_list_updates_total";
+const CACHE_LOOKUPS: &str = "plex_ani_sync_cache_lookups_total";
+
+static HANDLE: OnceLock<Option<PrometheusHandle>> = OnceLock::new();
+
+/// What happened when looking for a new mapping for a season.
+#[derive(Debug, Clone, Copy)]
+pub enum MappingOutcome {
+    Created,
+    Unmatched,
+    /// Series with lots of seasons aren't mapped automatically
+    SkippedLargeSeries,
+}
+
+impl MappingOutcome {
+    fn as_str(&self) -> &'static str {
+        match self {
+            MappingOutcome::Created => "created",
+            MappingOutcome::Unmatched => "unmatched",
+            MappingOutcome::SkippedLargeSeries => "skipped_large_series",
+        }
+    }
+}
+
+/// Starts collecting metrics. Safe to call more than once, only the first call installs the
+/// recorder.
+pub fn init() {
+    HANDLE.get_or_init(|| {
+        let recorder = PrometheusBuilder::new()
+            .set_buckets_for_metric(
+                Matcher::Full(SYNC_DURATION.to_string()),
+                &[1.0, 5.0, 15.0, 30.0, 60.0, 120.0, 300.0, 600.0, 1800.0],
+            )
+            .and_then(|x| {
+                x.set_buckets_for_metric(
+                    Matcher::Full(PLEX_REQUEST_DURATION.to_string()),
+                    &[0.01, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0],
+                )
+            })
+            .and_then(|x| x.install_recorder());
+
+        match recorder {
+            Ok(x) => {
+                describe();
+                Some(x)
+            }
+            Err(e) => {
+                warn!("Failed to start collecting metrics. {}", e);
+                None
+            }
+        }
+    });
+}
+
+/// The metrics in the Prometheus text format, `None` when metrics aren't being collected.
+pub fn render() -> Option<String> {
+    HANDLE.get()?.as_ref().map(|x| x.render())
+}
+
+fn describe() {
+    describe_histogram!(SYNC_DURATION, Unit::Seconds, "How long syncs take");
+    describe_counter!(
+        PLEX_SERIES,
+        "Series loaded with their seasons and episodes from Plex"
+    );
+    describe_histogram!(
+        PLEX_REQUEST_DURATION,
+        Unit::Seconds,
+        "How long Plex takes to answer requests"
+    );
+    describe_counter!(MAPPINGS, "Attempts to map Plex seasons by outcome");
+    describe_counter!(ANILIST_REQUESTS, "Requests made to the Anilist API");
+    describe_counter!(LIST_UPDATES, "Anilist list entry updates by result");
+    describe_counter!(CACHE_LOOKUPS, "Anilist cache lookups by result");
+}
+
+/// `kind` is `full` or `targeted`, `status` is the status the run was recorded with.
+pub fn record_sync(kind: &'static str, status: &'static str, duration: Duration) {
+    histogram!(SYNC_DURATION, duration.as_secs_f64(), "kind" => kind, "status" => status);
+}
+
+pub fn record_plex_series(count: usize) {
+    counter!(PLEX_SERIES, count as u64);
+}
+
+pub fn record_plex_request(endpoint: &'static str, duration: Duration) {
+    histogram!(PLEX_REQUEST_DURATION, duration.as_secs_f64(), "endpoint" => endpoint);
+}
+
+pub fn record_mapping(outcome: MappingOutcome) {
+    increment_counter!(MAPPINGS, "outcome" => outcome.as_str());
+}
+
+pub fn record_anilist_request(query: &'static str) {
+    increment_counter!(ANILIST_REQUESTS, "query" => query);
+}
+
+pub fn record_list_update(success: bool) {
+    let result = match success {
+        true => "success",
+        false => "failure",
+    };
+    increment_counter!(LIST_UPDATES, "result" => result);
+}
+
+/// `cache` is `anime_search` or `anime`
+pub fn record_cache_lookup(cache: &'static str, hit: bool) {
+    let result = match hit {
+        true => "hit",
+        false => "miss",
+    };
+    increment_counter!(CACHE_LOOKUPS, "cache" => cache, "result" => result);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_recorded_metrics() {
+        init();
+
+        record_cache_lookup("anime", true);
+        record_plex_request("libraries", Duration::from_millis(20));
+        let result = render().expect("Metrics aren't being collected");
+
+        assert!(result.contains(r#"plex_ani_sync_cache_lookups_total{cache="anime",result="hit"}"#));
+        assert!(result.contains(
+            r#"plex_ani_sync_plex_request_duration_seconds_bucket{endpoint="libraries",le="0.05"}"#
+        ));
+    }
+}
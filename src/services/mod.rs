@@ -1,5 +1,11 @@
 pub mod anime_list_service;
+pub mod api;
+pub mod config;
 pub mod dbstore;
+pub mod jellyfin;
 pub mod mapping_handler;
+pub mod media_server;
+pub mod metrics;
 pub mod plex;
+pub mod scheduler;
 pub mod sync_service;
@@ -0,0 +1,179 @@
+use std::{fmt, str::FromStr};
+
+use serde::{Serialize, Serializer};
+
+use super::plex_api::ResponsePlexLibrary;
+
+/// Picks Plex libraries to sync. Written as `title:Anime`, `key:3`, `type:show` or
+/// `agent:com.plexapp.agents.hama`, a value without a prefix is matched against the title.
+#[derive(Debug, Clone, PartialEq)]
+pub enum LibrarySelector {
+    Title(String),
+    Key(String),
+    Type(String),
+    Agent(String),
+}
+
+impl LibrarySelector {
+    pub fn matches(&self, library: &ResponsePlexLibrary) -> bool {
+        match self {
+            LibrarySelector::Title(x) => library.title.eq_ignore_ascii_case(x),
+            LibrarySelector::Key(x) => &library.key == x,
+            LibrarySelector::Type(x) => library.library_type.eq_ignore_ascii_case(x),
+            LibrarySelector::Agent(x) => library.agent.eq_ignore_ascii_case(x),
+        }
+    }
+}
+
+impl FromStr for LibrarySelector {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let value = value.trim();
+        let (kind, argument) = match value.split_once(':') {
+            Some((kind, argument)) => (kind.trim(), argument.trim()),
+            None => ("title", value),
+        };
+
+        if argument.is_empty() {
+            return Err(format!("library selector '{}' is empty", value));
+        }
+
+        let argument = argument.to_string();
+        match kind {
+            "title" => Ok(LibrarySelector::Title(argument)),
+            "key" => Ok(LibrarySelector::Key(argument)),
+            "type" => Ok(LibrarySelector::Type(argument)),
+            "agent" => Ok(LibrarySelector::Agent(argument)),
+            _ => Err(format!(
+                "'{}' has an unknown selector '{}', use one of title, key, type or agent",
+                value, kind
+            )),
+        }
+    }
+}
+
+impl fmt::Display for LibrarySelector {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LibrarySelector::Title(x) => write!(f, "title:{}", x),
+            LibrarySelector::Key(x) => write!(f, "key:{}", x),
+            LibrarySelector::Type(x) => write!(f, "type:{}", x),
+            LibrarySelector::Agent(x) => write!(f, "agent:{}", x),
+        }
+    }
+}
+
+impl Serialize for LibrarySelector {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.to_string())
+    }
+}
+
+/// Returns the libraries matching any of the selectors, in the order Plex lists them.
+pub fn select_libraries(
+    libraries: Vec<ResponsePlexLibrary>,
+    selectors: &[LibrarySelector],
+) -> Vec<ResponsePlexLibrary> {
+    libraries
+        .into_iter()
+        .filter(|library| selectors.iter().any(|x| x.matches(library)))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn library(key: &str, title: &str, library_type: &str, agent: &str) -> ResponsePlexLibrary {
+        ResponsePlexLibrary {
+            key: key.to_string(),
+            title: title.to_string(),
+            library_type: library_type.to_string(),
+            agent: agent.to_string(),
+            ..Default::default()
+        }
+    }
+
+    fn libraries() -> Vec<ResponsePlexLibrary> {
+        vec![
+            library("1", "Movies", "movie", "tv.plex.agents.movie"),
+            library("2", "Anime", "show", "com.plexapp.agents.hama"),
+            library("3", "TV Shows", "show", "tv.plex.agents.series"),
+            library("4", "Anime Movies", "movie", "com.plexapp.agents.hama"),
+        ]
+    }
+
+    #[test]
+    fn test_parse_selectors() {
+        assert_eq!(
+            Ok(LibrarySelector::Title("Anime".to_string())),
+            "Anime".parse()
+        );
+        assert_eq!(
+            Ok(LibrarySelector::Title("Anime: Part 2".to_string())),
+            "title:Anime: Part 2".parse()
+        );
+        assert_eq!(Ok(LibrarySelector::Key("3".to_string())), "key:3".parse());
+        assert_eq!(
+            Ok(LibrarySelector::Type("show".to_string())),
+            "type: show".parse()
+        );
+        assert_eq!(
+            Ok(LibrarySelector::Agent(
+                "com.plexapp.agents.hama".to_string()
+            )),
+            "agent:com.plexapp.agents.hama".parse()
+        );
+    }
+
+    #[test]
+    fn test_parse_invalid_selectors() {
+        assert!("name:Anime".parse::<LibrarySelector>().is_err());
+        assert!("key:".parse::<LibrarySelector>().is_err());
+        assert!("".parse::<LibrarySelector>().is_err());
+    }
+
+    #[test]
+    fn test_select_libraries_by_title() {
+        let selectors = vec![LibrarySelector::Title("anime".to_string())];
+
+        let result = select_libraries(libraries(), &selectors);
+
+        assert_eq!(1, result.len());
+        assert_eq!("2", result[0].key);
+    }
+
+    #[test]
+    fn test_select_libraries_with_multiple_selectors() {
+        let selectors = vec![
+            LibrarySelector::Agent("com.plexapp.agents.hama".to_string()),
+            LibrarySelector::Key("3".to_string()),
+        ];
+
+        let result = select_libraries(libraries(), &selectors);
+        let keys: Vec<&str> = result.iter().map(|x| x.key.as_str()).collect();
+
+        assert_eq!(vec!["2", "3", "4"], keys);
+    }
+
+    #[test]
+    fn test_is_likely_anime() {
+        let mut library = library("3", "TV Shows", "show", "tv.plex.agents.series");
+        assert!(!library.is_likely_anime());
+
+        library.scanner = "Absolute Series Scanner".to_string();
+        assert!(library.is_likely_anime());
+
+        library.scanner = "Plex TV Series".to_string();
+        library.language = "ja-JP".to_string();
+        assert!(library.is_likely_anime());
+    }
+
+    #[test]
+    fn test_select_libraries_without_matches() {
+        let selectors = vec![LibrarySelector::Type("artist".to_string())];
+
+        assert!(select_libraries(libraries(), &selectors).is_empty());
+    }
+}
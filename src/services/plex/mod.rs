@@ -1,2 +1,4 @@
+pub mod library_selector;
 pub mod plex_api;
 pub mod plex_api_service;
+pub mod plex_tv;
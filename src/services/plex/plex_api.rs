@@ -1,25 +1,125 @@
-use std::vec;
+use std::{fmt, vec};
 
 use async_trait::async_trait;
+use chrono::{Datelike, NaiveDate};
 use serde::{Deserialize, Serialize};
 
 pub type PlexLibraryResponse = BaseResponse<DirectoryResponse<ResponsePlexLibrary>>;
 pub type PlexSeriesResponse = BaseResponse<MetadataResponse<Vec<ResponsePlexSeries>>>;
 pub type PlexSeasonResponse = BaseResponse<MetadataResponse<Vec<ResponsePlexSeason>>>;
 pub type PlexEpisodesResponse = BaseResponse<MetadataResponse<Vec<ResponsePlexEpisode>>>;
+pub type PlexMovieResponse = BaseResponse<MetadataResponse<Vec<ResponsePlexMovie>>>;
+pub type PlexIdentityResponse = BaseResponse<ResponsePlexIdentity>;
+pub type PlexHistoryResponse = BaseResponse<MetadataResponse<Vec<ResponsePlexHistoryItem>>>;
 
+/// Reads and updates the watch state on a media server. Jellyfin and Emby implement it as well,
+/// returning the same types as Plex.
 #[async_trait]
 pub trait PlexInterface {
-    async fn get_libraries(self) -> Result<Vec<ResponsePlexLibrary>, reqwest::Error>;
-    async fn get_series(&self, library_id: u8) -> Result<Vec<ResponsePlexSeries>, reqwest::Error>;
+    async fn get_libraries(&self) -> Result<Vec<ResponsePlexLibrary>, reqwest::Error>;
+    /// One page of the series in a library, starting at the `start`th series.
+    async fn get_series_page(
+        &self,
+        library_key: &str,
+        start: u32,
+    ) -> Result<PlexPage<ResponsePlexSeries>, reqwest::Error>;
+    /// A single series with all of its labels and collections, `None` if it doesn't exist.
+    async fn get_series(
+        &self,
+        rating_key: &str,
+    ) -> Result<Option<ResponsePlexSeries>, reqwest::Error>;
+    /// One page of the movies in a movie library, starting at the `start`th movie.
+    async fn get_movies_page(
+        &self,
+        library_key: &str,
+        start: u32,
+    ) -> Result<PlexPage<ResponsePlexMovie>, reqwest::Error>;
+    /// One page of the views of a Plex account, oldest first, starting at the `start`th view
+    /// made at or after `since`.
+    async fn get_watch_history_page(
+        &self,
+        account_id: u64,
+        since: Option<i64>,
+        start: u32,
+    ) -> Result<PlexPage<ResponsePlexHistoryItem>, reqwest::Error>;
+    /// Marks an episode or movie as watched, the same as watching it to the end.
+    async fn mark_watched(&self, rating_key: &str) -> Result<(), reqwest::Error>;
     async fn populate_episodes(&self, season: &mut PlexSeason) -> Result<(), reqwest::Error>;
     async fn populate_seasons(&self, series: &mut PlexSeries) -> Result<(), reqwest::Error>;
 }
 
+pub struct PlexPage<T> {
+    pub items: Vec<T>,
+    /// Where the next page starts, `None` after the last page
+    pub next_start: Option<u32>,
+}
+
+impl<T> PlexPage<T> {
+    /// The page of up to `page_size` items starting at the `start`th item, `total` is the number
+    /// of items there are when the server says so.
+    pub fn new(items: Vec<T>, start: u32, total: Option<u32>, page_size: u32) -> Self {
+        let end = start + items.len() as u32;
+        let has_more = match total {
+            Some(total) => end < total,
+            // Without a total a full page means there could be more
+            None => items.len() as u32 == page_size,
+        };
+
+        Self {
+            next_start: match has_more && !items.is_empty() {
+                true => Some(end),
+                false => None,
+            },
+            items,
+        }
+    }
+}
+
+/// A series whose seasons or episodes couldn't be loaded from Plex.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct SeriesFetchError {
+    pub rating_key: String,
+    pub title: String,
+    pub error: String,
+}
+
+impl fmt::Display for SeriesFetchError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "'{}' ({}): {}", self.title, self.rating_key, self.error)
+    }
+}
+
 pub struct PlexSeries {
     pub rating_key: String,
     pub seasons: Vec<PlexSeason>,
     pub title: String,
+    pub guids: Vec<ExternalId>,
+    /// The labels and the names of the collections the series is in
+    pub labels: Vec<String>,
+    pub kind: PlexMediaKind,
+}
+
+impl PlexSeries {
+    pub fn has_label(&self, label: &str) -> bool {
+        has_label(&self.labels, label)
+    }
+
+    /// Series matched with HAMA's `tvdb2-` to `tvdb5-` guids keep counting episodes from one
+    /// season into the next.
+    pub fn has_absolute_numbering(&self) -> bool {
+        self.guids
+            .iter()
+            .any(|x| matches!(x, ExternalId::HamaTvdb { .. }))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PlexMediaKind {
+    Show,
+    /// Movies are handled as a series with one season holding the movie as its only episode
+    Movie {
+        year: Option<u16>,
+    },
 }
 
 impl From<ResponsePlexSeries> for PlexSeries {
@@ -28,6 +128,41 @@ impl From<ResponsePlexSeries> for PlexSeries {
             rating_key: series.rating_key,
             seasons: vec![],
             title: series.title,
+            guids: parse_guids(series.guid.as_deref(), &series.guids),
+            labels: get_labels(&series.labels, &series.collections),
+            kind: PlexMediaKind::Show,
+        }
+    }
+}
+
+impl From<ResponsePlexMovie> for PlexSeries {
+    fn from(movie: ResponsePlexMovie) -> Self {
+        let guids = parse_guids(movie.guid.as_deref(), &movie.guids);
+        let labels = get_labels(&movie.labels, &movie.collections);
+        Self {
+            seasons: vec![PlexSeason {
+                rating_key: movie.rating_key.clone(),
+                index: 1,
+                parent_title: movie.title.clone(),
+                episodes: vec![PlexEpisode {
+                    rating_key: movie.rating_key.clone(),
+                    view_count: movie.view_count,
+                    last_viewed_at: movie.last_viewed_at,
+                    first_viewed_at: None,
+                    index: Some(1),
+                    parent_index: Some(1),
+                    originally_available_at: None,
+                    view_offset: movie.view_offset,
+                    duration: movie.duration,
+                }],
+                guids: guids.clone(),
+                labels: vec![],
+            }],
+            rating_key: movie.rating_key,
+            title: movie.title,
+            guids,
+            labels,
+            kind: PlexMediaKind::Movie { year: movie.year },
         }
     }
 }
@@ -37,6 +172,15 @@ pub struct PlexSeason {
     pub index: u8,
     pub parent_title: String,
     pub episodes: Vec<PlexEpisode>,
+    pub guids: Vec<ExternalId>,
+    /// The labels and the names of the collections the season is in
+    pub labels: Vec<String>,
+}
+
+impl PlexSeason {
+    pub fn has_label(&self, label: &str) -> bool {
+        has_label(&self.labels, label)
+    }
 }
 
 impl From<ResponsePlexSeason> for PlexSeason {
@@ -46,21 +190,167 @@ impl From<ResponsePlexSeason> for PlexSeason {
             parent_title: season.parent_title,
             index: season.index,
             episodes: vec![],
+            guids: parse_guids(season.guid.as_deref(), &season.guids),
+            labels: get_labels(&season.labels, &season.collections),
+        }
+    }
+}
+
+/// Labels and collections are matched the same way, ignoring case.
+fn get_labels(labels: &[ResponsePlexTag], collections: &[ResponsePlexTag]) -> Vec<String> {
+    labels
+        .iter()
+        .chain(collections.iter())
+        .map(|x| x.tag.clone())
+        .collect()
+}
+
+fn has_label(labels: &[String], label: &str) -> bool {
+    labels.iter().any(|x| x.trim().eq_ignore_ascii_case(label))
+}
+
+/// The legacy HAMA agent identifies anime by their AniDB id
+const HAMA_AGENT: &str = "com.plexapp.agents.hama";
+
+/// An id in an external database, read from the guids Plex matched the media with.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ExternalId {
+    Tvdb(u32),
+    Tmdb(u32),
+    Imdb(String),
+    Anidb(u32),
+    /// A tvdb id the HAMA agent uses with absolute episode numbering
+    HamaTvdb {
+        id: u32,
+        mode: HamaTvdbMode,
+    },
+}
+
+/// How HAMA lays out the seasons of a series matched with `tvdb2-` to `tvdb5-` guids.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HamaTvdbMode {
+    /// Seasons follow tvdb, episodes are numbered across the whole series
+    Tvdb2,
+    /// Every AniDB entry of the series is its own season
+    Tvdb3,
+    /// Seasons follow story arcs
+    Tvdb4,
+    /// Like tvdb4 without specials
+    Tvdb5,
+}
+
+impl ExternalId {
+    /// Parses guids such as `tvdb://267440`, `tmdb://1429`, `imdb://tt2560140` or `anidb://9541`,
+    /// and HAMA guids
+    /// such as `com.plexapp.agents.hama://anidb-9541?lang=en` or
+    /// `com.plexapp.agents.hama://tvdb2-267440/1?lang=en`.
+    pub fn parse(guid: &str) -> Option<Self> {
+        let (scheme, id) = guid.split_once("://")?;
+        match scheme {
+            "tvdb" => id.parse().ok().map(ExternalId::Tvdb),
+            "tmdb" => id.parse().ok().map(ExternalId::Tmdb),
+            "imdb" if id.starts_with("tt") => Some(ExternalId::Imdb(id.to_string())),
+            "anidb" => id.parse().ok().map(ExternalId::Anidb),
+            HAMA_AGENT => Self::parse_hama(id),
+            _ => None,
+        }
+    }
+
+    fn parse_hama(guid: &str) -> Option<Self> {
+        // Seasons and episodes add `/<season>` and `/<episode>` after the series id
+        let guid = guid.split(['?', '/']).next()?;
+        let (source, id) = guid.split_once('-')?;
+        let mode = match source {
+            "tvdb2" => Some(HamaTvdbMode::Tvdb2),
+            "tvdb3" => Some(HamaTvdbMode::Tvdb3),
+            "tvdb4" => Some(HamaTvdbMode::Tvdb4),
+            "tvdb5" => Some(HamaTvdbMode::Tvdb5),
+            _ => None,
+        };
+        if let Some(mode) = mode {
+            return id.parse().ok().map(|id| ExternalId::HamaTvdb { id, mode });
+        }
+
+        match source {
+            "anidb" => id.parse().ok().map(ExternalId::Anidb),
+            "tvdb" => id.parse().ok().map(ExternalId::Tvdb),
+            "tmdb" => id.parse().ok().map(ExternalId::Tmdb),
+            "imdb" if id.starts_with("tt") => Some(ExternalId::Imdb(id.to_string())),
+            _ => None,
         }
     }
 }
 
+/// Legacy agents only set `guid`, the new Plex agents list the external ids in `Guid`.
+fn parse_guids(guid: Option<&str>, guids: &[ResponsePlexGuid]) -> Vec<ExternalId> {
+    guid.into_iter()
+        .chain(guids.iter().map(|x| x.id.as_str()))
+        .filter_map(ExternalId::parse)
+        .collect()
+}
+
 impl PlexSeason {
     pub fn get_episode_count(&self) -> u32 {
         return u32::try_from(self.episodes.len()).unwrap();
     }
+
+    /// The year the first episode of the season aired, `None` when no air date is known.
+    pub fn get_first_air_year(&self) -> Option<i64> {
+        self.episodes
+            .iter()
+            .filter_map(|x| x.originally_available_at)
+            .min()
+            .map(|x| i64::from(x.year()))
+    }
+
+    /// The lowest episode number in the season, `None` when Plex couldn't number any episode.
+    pub fn get_first_episode_number(&self) -> Option<u32> {
+        self.episodes.iter().filter_map(|x| x.index).min()
+    }
 }
 
 #[derive(Clone)]
 pub struct PlexEpisode {
     pub rating_key: String,
     pub last_viewed_at: Option<i64>,
+    /// When the episode was first watched, only known from the watch history
+    pub first_viewed_at: Option<i64>,
     pub view_count: i32,
+    /// The episode number within the season, missing for episodes Plex couldn't match
+    pub index: Option<u32>,
+    /// The season number, which only differs from the season the episode is listed in for
+    /// specials Jellyfin shows within the season they aired in
+    pub parent_index: Option<u32>,
+    /// The air date
+    pub originally_available_at: Option<NaiveDate>,
+    /// How far an episode that's being watched was played, in milliseconds
+    pub view_offset: Option<u64>,
+    /// The length in milliseconds
+    pub duration: Option<u64>,
+}
+
+impl PlexEpisode {
+    /// Marked as watched in Plex or played to at least `watched_percent` of its length.
+    pub fn is_watched(&self, watched_percent: u8) -> bool {
+        self.view_count > 0
+            || self
+                .get_played_percent()
+                .is_some_and(|x| x >= u64::from(watched_percent))
+    }
+
+    /// Watched or partially played.
+    pub fn is_started(&self) -> bool {
+        self.view_count > 0 || self.view_offset.is_some_and(|x| x > 0)
+    }
+
+    fn get_played_percent(&self) -> Option<u64> {
+        match (self.view_offset, self.duration) {
+            (Some(offset), Some(duration)) if duration > 0 => Some(offset * 100 / duration),
+            _ => None,
+        }
+    }
 }
 
 impl From<ResponsePlexEpisode> for PlexEpisode {
@@ -68,7 +358,15 @@ impl From<ResponsePlexEpisode> for PlexEpisode {
         Self {
             rating_key: episode.rating_key,
             last_viewed_at: episode.last_viewed_at,
+            first_viewed_at: None,
             view_count: episode.view_count,
+            index: episode.index,
+            parent_index: episode.parent_index,
+            originally_available_at: episode
+                .originally_available_at
+                .and_then(|x| NaiveDate::parse_from_str(&x, "%Y-%m-%d").ok()),
+            view_offset: episode.view_offset,
+            duration: episode.duration,
         }
     }
 }
@@ -81,8 +379,42 @@ pub struct ResponsePlexEpisode {
     #[serde(rename = "lastViewedAt")]
     pub last_viewed_at: Option<i64>,
 
-    #[serde(rename = "viewCount")]
+    /// Left out for episodes that haven't been watched
+    #[serde(rename = "viewCount", default)]
     pub view_count: i32,
+
+    pub index: Option<u32>,
+
+    #[serde(rename = "parentIndex")]
+    pub parent_index: Option<u32>,
+
+    /// The air date, such as `2013-04-07`
+    #[serde(rename = "originallyAvailableAt")]
+    pub originally_available_at: Option<String>,
+
+    /// Only set while the episode is partially watched
+    #[serde(rename = "viewOffset")]
+    pub view_offset: Option<u64>,
+
+    pub duration: Option<u64>,
+}
+
+/// A single view in the watch history of the Plex server.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ResponsePlexHistoryItem {
+    /// Missing when the item was deleted from the library
+    #[serde(rename = "ratingKey")]
+    pub rating_key: Option<String>,
+
+    #[serde(rename = "viewedAt")]
+    pub viewed_at: i64,
+
+    #[serde(rename = "accountID")]
+    pub account_id: Option<u64>,
+
+    /// `episode` or `movie`
+    #[serde(rename = "type")]
+    pub item_type: String,
 }
 
 #[derive(Debug, Deserialize, Serialize)]
@@ -91,10 +423,22 @@ pub struct BaseResponse<T> {
     pub media_container: T,
 }
 
+/// Identifies the Plex server, plex.tv knows the server by its machine identifier
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ResponsePlexIdentity {
+    #[serde(rename = "machineIdentifier")]
+    pub machine_identifier: String,
+}
+
 #[derive(Debug, Deserialize, Serialize)]
 pub struct MetadataResponse<T> {
-    #[serde(rename = "Metadata")]
+    /// Plex leaves this out when there's nothing to list
+    #[serde(rename = "Metadata", default)]
     pub metadata: T,
+
+    /// The number of items across all pages, only sent for paged requests
+    #[serde(rename = "totalSize")]
+    pub total_size: Option<u32>,
 }
 
 #[derive(Debug, Deserialize, Serialize)]
@@ -105,7 +449,65 @@ pub struct DirectoryResponse<T> {
 
 #[derive(Clone, Debug, Deserialize, Serialize, Default)]
 pub struct ResponsePlexLibrary {
+    /// The section id used in `/library/sections/{key}` paths
+    pub key: String,
+
     pub title: String,
+
+    #[serde(rename = "type")]
+    pub library_type: String,
+
+    #[serde(default)]
+    pub agent: String,
+
+    #[serde(default)]
+    pub scanner: String,
+
+    /// Such as `en-US` or `ja-JP`
+    #[serde(default)]
+    pub language: String,
+
+    #[serde(rename = "Location", default)]
+    pub locations: Vec<PlexLibraryLocation>,
+}
+
+impl ResponsePlexLibrary {
+    pub fn is_show_library(&self) -> bool {
+        self.library_type == "show"
+    }
+
+    pub fn is_movie_library(&self) -> bool {
+        self.library_type == "movie"
+    }
+
+    /// Guesses whether the library holds anime from its agent, scanner and language. HAMA and the
+    /// Absolute Series Scanner are made for anime libraries.
+    pub fn is_likely_anime(&self) -> bool {
+        let agent = self.agent.to_lowercase();
+        let scanner = self.scanner.to_lowercase();
+
+        agent.contains("hama")
+            || scanner.contains("absolute series")
+            || scanner.contains("anime")
+            || self.language.to_lowercase().starts_with("ja")
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, Default, PartialEq)]
+pub struct PlexLibraryLocation {
+    pub path: String,
+}
+
+/// Only sent when the request has `includeGuids=1`
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct ResponsePlexGuid {
+    pub id: String,
+}
+
+/// A label, collection or other tag of a Plex item
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct ResponsePlexTag {
+    pub tag: String,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -129,6 +531,50 @@ pub struct ResponsePlexSeason {
 
     #[serde(rename = "lastViewedAt")]
     pub last_viewed_at: Option<u32>,
+
+    /// The guid of the agent that matched the season, such as `plex://season/...`
+    pub guid: Option<String>,
+
+    #[serde(rename = "Guid", default)]
+    pub guids: Vec<ResponsePlexGuid>,
+
+    #[serde(rename = "Label", default)]
+    pub labels: Vec<ResponsePlexTag>,
+
+    #[serde(rename = "Collection", default)]
+    pub collections: Vec<ResponsePlexTag>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ResponsePlexMovie {
+    #[serde(rename = "ratingKey")]
+    pub rating_key: String,
+
+    pub title: String,
+
+    pub year: Option<u16>,
+
+    #[serde(rename = "viewCount", default)]
+    pub view_count: i32,
+
+    #[serde(rename = "lastViewedAt")]
+    pub last_viewed_at: Option<i64>,
+
+    #[serde(rename = "viewOffset")]
+    pub view_offset: Option<u64>,
+
+    pub duration: Option<u64>,
+
+    pub guid: Option<String>,
+
+    #[serde(rename = "Guid", default)]
+    pub guids: Vec<ResponsePlexGuid>,
+
+    #[serde(rename = "Label", default)]
+    pub labels: Vec<ResponsePlexTag>,
+
+    #[serde(rename = "Collection", default)]
+    pub collections: Vec<ResponsePlexTag>,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -140,12 +586,18 @@ pub struct ResponsePlexSeries {
 
     #[serde(rename = "lastViewedAt")]
     pub last_viewed_at: Option<u32>,
-}
 
-#[derive(Debug, Clone)]
-pub struct SeriesWithSeason {
-    pub series: ResponsePlexSeries,
-    pub seasons: Vec<ResponsePlexSeason>,
+    /// The guid of the agent that matched the series, such as `plex://show/...`
+    pub guid: Option<String>,
+
+    #[serde(rename = "Guid", default)]
+    pub guids: Vec<ResponsePlexGuid>,
+
+    #[serde(rename = "Label", default)]
+    pub labels: Vec<ResponsePlexTag>,
+
+    #[serde(rename = "Collection", default)]
+    pub collections: Vec<ResponsePlexTag>,
 }
 
 impl Default for BaseResponse<DirectoryResponse<ResponsePlexLibrary>> {
@@ -163,3 +615,121 @@ impl Default for DirectoryResponse<ResponsePlexLibrary> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_external_ids() {
+        assert_eq!(
+            Some(ExternalId::Tvdb(267440)),
+            ExternalId::parse("tvdb://267440")
+        );
+        assert_eq!(
+            Some(ExternalId::Tmdb(1429)),
+            ExternalId::parse("tmdb://1429")
+        );
+        assert_eq!(
+            Some(ExternalId::Imdb("tt2560140".to_string())),
+            ExternalId::parse("imdb://tt2560140")
+        );
+        assert_eq!(
+            None,
+            ExternalId::parse("plex://show/5d9c086c46115600200aa2fe")
+        );
+        assert_eq!(
+            Some(ExternalId::Anidb(9541)),
+            ExternalId::parse("anidb://9541")
+        );
+        assert_eq!(None, ExternalId::parse("tvdb://abc"));
+    }
+
+    #[test]
+    fn test_parse_hama_guids() {
+        assert_eq!(
+            Some(ExternalId::Anidb(9541)),
+            ExternalId::parse("com.plexapp.agents.hama://anidb-9541?lang=en")
+        );
+        assert_eq!(
+            Some(ExternalId::Anidb(9541)),
+            ExternalId::parse("com.plexapp.agents.hama://anidb-9541/1?lang=en")
+        );
+        assert_eq!(
+            Some(ExternalId::Tvdb(267440)),
+            ExternalId::parse("com.plexapp.agents.hama://tvdb-267440?lang=en")
+        );
+        assert_eq!(
+            Some(ExternalId::HamaTvdb {
+                id: 267440,
+                mode: HamaTvdbMode::Tvdb2
+            }),
+            ExternalId::parse("com.plexapp.agents.hama://tvdb2-267440/2?lang=en")
+        );
+        assert_eq!(
+            Some(ExternalId::HamaTvdb {
+                id: 81797,
+                mode: HamaTvdbMode::Tvdb3
+            }),
+            ExternalId::parse("com.plexapp.agents.hama://tvdb3-81797")
+        );
+        assert_eq!(
+            None,
+            ExternalId::parse("com.plexapp.agents.hama://anidb-abc?lang=en")
+        );
+    }
+
+    #[test]
+    fn test_series_labels_and_collections() {
+        let response: ResponsePlexSeries = serde_json::from_value(serde_json::json!({
+            "ratingKey": "17456",
+            "title": "Attack on Titan",
+            "Label": [{ "tag": "No-AniSync" }],
+            "Collection": [{ "tag": "Shounen" }],
+        }))
+        .expect("Failed to parse series");
+
+        let series = PlexSeries::from(response);
+
+        assert_eq!(vec!["No-AniSync", "Shounen"], series.labels);
+        assert!(series.has_label("no-anisync"));
+        assert!(series.has_label("shounen"));
+        assert!(!series.has_label("anisync-private"));
+    }
+
+    #[test]
+    fn test_episode_numbers_and_air_date() {
+        let response: ResponsePlexEpisode = serde_json::from_value(serde_json::json!({
+            "ratingKey": "101",
+            "index": 3,
+            "parentIndex": 2,
+            "originallyAvailableAt": "2013-04-21",
+        }))
+        .expect("Failed to parse episode");
+
+        let episode = PlexEpisode::from(response);
+
+        assert_eq!(Some(3), episode.index);
+        assert_eq!(Some(2), episode.parent_index);
+        assert_eq!(
+            NaiveDate::from_ymd_opt(2013, 4, 21),
+            episode.originally_available_at
+        );
+    }
+
+    #[test]
+    fn test_unwatched_episode() {
+        let response: Vec<ResponsePlexEpisode> = serde_json::from_value(serde_json::json!([
+            { "ratingKey": "101", "index": 1, "viewCount": 2, "lastViewedAt": 1709324100 },
+            { "ratingKey": "102", "index": 2 },
+        ]))
+        .expect("Failed to parse episodes");
+
+        let episodes: Vec<PlexEpisode> = response.into_iter().map(PlexEpisode::from).collect();
+
+        assert_eq!(2, episodes[0].view_count);
+        assert_eq!(0, episodes[1].view_count);
+        assert_eq!(None, episodes[1].last_viewed_at);
+        assert!(!episodes[1].is_watched(90));
+    }
+}
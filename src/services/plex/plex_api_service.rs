@@ -1,88 +1,205 @@
+use std::{
+    future::Future,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
 use async_trait::async_trait;
-use futures::{future::join_all, stream::FuturesUnordered};
-use log::{error, info};
-use reqwest::header::{self, HeaderMap, HeaderValue, ACCEPT, AUTHORIZATION, CONTENT_TYPE};
+use futures::{
+    future::join_all,
+    stream::{self, FuturesUnordered},
+    Stream, TryStreamExt,
+};
+use log::{error, info, warn};
+use reqwest::{
+    header::{self, HeaderValue, CONTENT_TYPE},
+    StatusCode,
+};
 use serde::de::DeserializeOwned;
+use tokio::sync::Semaphore;
 use tracing::instrument;
 use url::Url;
 
-use crate::services::plex::plex_api::{
-    PlexLibraryResponse, PlexSeasonResponse, PlexSeriesResponse,
+use crate::services::{
+    metrics::metrics,
+    plex::plex_api::{
+        PlexHistoryResponse, PlexIdentityResponse, PlexLibraryResponse, PlexSeasonResponse,
+        PlexSeriesResponse,
+    },
 };
 
 use super::plex_api::{
-    PlexEpisode, PlexEpisodesResponse, PlexInterface, PlexSeason, PlexSeries, ResponsePlexLibrary,
-    ResponsePlexSeries,
+    MetadataResponse, PlexEpisode, PlexEpisodesResponse, PlexInterface, PlexMovieResponse,
+    PlexPage, PlexSeason, PlexSeries, ResponsePlexHistoryItem, ResponsePlexLibrary,
+    ResponsePlexMovie, ResponsePlexSeries, SeriesFetchError,
 };
+use super::plex_tv::plex_headers;
+
+pub const DEFAULT_PAGE_SIZE: u32 = 100;
+pub const DEFAULT_CONCURRENCY: usize = 10;
+/// Failed requests are retried this many times, waiting twice as long before each retry
+const MAX_RETRIES: u32 = 3;
+pub const RETRY_DELAY: Duration = Duration::from_millis(500);
 
 #[derive(Debug)]
 pub struct PlexApi {
     plex_url: String,
-    plex_token: String,
     http_client: reqwest::Client,
     headers: header::HeaderMap,
+    page_size: u32,
+    /// Limits how many requests are sent to Plex at the same time
+    semaphore: Arc<Semaphore>,
+    retry_delay: Duration,
 }
 
 impl PlexApi {
     pub fn new(plex_url: String, plex_token: String) -> Self {
-        let mut header_map = header::HeaderMap::new();
+        Self::with_limits(plex_url, plex_token, DEFAULT_PAGE_SIZE, DEFAULT_CONCURRENCY)
+    }
+
+    /// `page_size` is the number of series requested at a time and `concurrency` the number of
+    /// requests Plex gets at the same time.
+    pub fn with_limits(
+        plex_url: String,
+        plex_token: String,
+        page_size: u32,
+        concurrency: usize,
+    ) -> Self {
+        let mut header_map = plex_headers(&plex_token);
         header_map.insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
-        header_map.insert(ACCEPT, HeaderValue::from_static("application/json"));
 
         Self {
             plex_url,
-            plex_token,
             http_client: reqwest::Client::new(),
             headers: header_map,
+            page_size,
+            semaphore: Arc::new(Semaphore::new(concurrency)),
+            retry_delay: RETRY_DELAY,
         }
     }
 
-    fn get_headers(&self) -> HeaderMap {
-        let auth_value = format!("Bearer {}", self.plex_token);
-        let mut headers = self.headers.clone();
-        headers.insert(
-            AUTHORIZATION,
-            HeaderValue::from_str(auth_value.as_str()).expect("Failed to parse anilist token"),
-        );
-        headers
+    #[cfg(test)]
+    fn with_retry_delay(self, retry_delay: Duration) -> Self {
+        Self {
+            retry_delay,
+            ..self
+        }
     }
 
-    async fn make_request<T>(&self, path: &str) -> Result<T, reqwest::Error>
+    /// `endpoint` names the kind of request in the latency metrics. Requests that fail because
+    /// of the connection or the server are retried with backoff.
+    async fn make_request<T>(&self, endpoint: &'static str, path: &str) -> Result<T, reqwest::Error>
     where
         T: DeserializeOwned,
     {
         let url = self.build_request_url(path);
-        self.http_client
-            .get(&url)
-            .headers(self.get_headers())
-            .send()
-            .await?
-            .json::<T>()
+        with_retries(self.retry_delay, path, || async {
+            self.send_request(endpoint, &url).await?.json::<T>().await
+        })
+        .await
+    }
+
+    /// Like `make_request`, for requests that don't answer with anything.
+    async fn make_empty_request(
+        &self,
+        endpoint: &'static str,
+        path: &str,
+    ) -> Result<(), reqwest::Error> {
+        let url = self.build_request_url(path);
+        with_retries(self.retry_delay, path, || async {
+            self.send_request(endpoint, &url).await.map(|_| ())
+        })
+        .await
+    }
+
+    async fn send_request(
+        &self,
+        endpoint: &'static str,
+        url: &str,
+    ) -> Result<reqwest::Response, reqwest::Error> {
+        // The permit isn't held while waiting to retry
+        let _permit = self
+            .semaphore
+            .acquire()
             .await
+            .expect("Plex request semaphore was closed");
+
+        let started = Instant::now();
+        let response = self
+            .http_client
+            .get(url)
+            .headers(self.headers.clone())
+            .send()
+            .await;
+        metrics::record_plex_request(endpoint, started.elapsed());
+
+        response?.error_for_status()
     }
 
-    fn build_request_url(&self, path: &str) -> String {
-        let base_url = &self.plex_url;
-        let token = &self.plex_token;
+    /// Requests `page_size` items of a library starting at the `start`th item. `endpoint` is
+    /// `series` or `movies`.
+    async fn get_page<T>(
+        &self,
+        endpoint: &'static str,
+        library_key: &str,
+        start: u32,
+    ) -> Result<T, reqwest::Error>
+    where
+        T: DeserializeOwned,
+    {
+        // The guids link the items to tvdb, tmdb and imdb
+        let path = format!(
+            "/library/sections/{}/all?includeGuids=1&X-Plex-Container-Start={}&X-Plex-Container-Size={}",
+            library_key, start, self.page_size
+        );
 
-        let url_builder = Url::parse(base_url).expect("Failed to parse Plex base url");
-        let mut url_builder = url_builder
-            .join(path)
-            .expect("Failed to join Plex sections url path to base url");
-        let query = format!("X-Plex-Token={}", token);
-        url_builder.set_query(Some(&query));
+        info!(
+            "Getting Plex {} {} to {} for library id: {}",
+            endpoint,
+            start,
+            start + self.page_size,
+            library_key
+        );
+        match self.make_request(endpoint, &path).await {
+            Ok(x) => Ok(x),
+            Err(e) => {
+                error!("Error getting {} for library_id: {}", endpoint, library_key);
+                Err(e)
+            }
+        }
+    }
 
-        url_builder.to_string()
+    fn to_page<T>(&self, start: u32, response: MetadataResponse<Vec<T>>) -> PlexPage<T> {
+        PlexPage::new(
+            response.metadata,
+            start,
+            response.total_size,
+            self.page_size,
+        )
+    }
+
+    /// The machine identifier of the Plex server.
+    pub async fn get_machine_identifier(&self) -> Result<String, reqwest::Error> {
+        let response: PlexIdentityResponse = self.make_request("identity", "/identity").await?;
+        Ok(response.media_container.machine_identifier)
+    }
+
+    fn build_request_url(&self, path: &str) -> String {
+        Url::parse(&self.plex_url)
+            .expect("Failed to parse Plex base url")
+            .join(path)
+            .expect("Failed to join Plex sections url path to base url")
+            .to_string()
     }
 }
 
 #[async_trait]
 impl PlexInterface for PlexApi {
-    async fn get_libraries(self) -> Result<Vec<ResponsePlexLibrary>, reqwest::Error> {
+    async fn get_libraries(&self) -> Result<Vec<ResponsePlexLibrary>, reqwest::Error> {
         let path = "/library/sections/";
 
         info!("Getting Plex libraries");
-        let response: PlexLibraryResponse = self.make_request(path).await?;
+        let response: PlexLibraryResponse = self.make_request("libraries", path).await?;
 
         let library_count = response.media_container.directory.len();
         info!("Found {} libraries", { library_count });
@@ -90,49 +207,95 @@ impl PlexInterface for PlexApi {
     }
 
     #[instrument(skip(self))]
-    async fn get_series(&self, library_id: u8) -> Result<Vec<ResponsePlexSeries>, reqwest::Error> {
-        let path = format!("/library/sections/{}/all", library_id);
+    async fn get_series_page(
+        &self,
+        library_key: &str,
+        start: u32,
+    ) -> Result<PlexPage<ResponsePlexSeries>, reqwest::Error> {
+        let response: PlexSeriesResponse = self.get_page("series", library_key, start).await?;
+        Ok(self.to_page(start, response.media_container))
+    }
 
-        info!("Getting Plex series for library id: {}", library_id);
-        let response: PlexSeriesResponse = match __self.make_request(&path).await {
-            Ok(x) => x,
-            Err(e) => {
-                error!("Error getting series for library_id: {}", library_id);
-                return Err(e);
-            }
-        };
+    async fn get_series(
+        &self,
+        rating_key: &str,
+    ) -> Result<Option<ResponsePlexSeries>, reqwest::Error> {
+        let path = format!("/library/metadata/{}?includeGuids=1", rating_key);
+
+        let response: PlexSeriesResponse = self.make_request("series", &path).await?;
+        Ok(response.media_container.metadata.into_iter().next())
+    }
+
+    #[instrument(skip(self))]
+    async fn get_movies_page(
+        &self,
+        library_key: &str,
+        start: u32,
+    ) -> Result<PlexPage<ResponsePlexMovie>, reqwest::Error> {
+        let response: PlexMovieResponse = self.get_page("movies", library_key, start).await?;
+        Ok(self.to_page(start, response.media_container))
+    }
+
+    #[instrument(skip(self))]
+    async fn get_watch_history_page(
+        &self,
+        account_id: u64,
+        since: Option<i64>,
+        start: u32,
+    ) -> Result<PlexPage<ResponsePlexHistoryItem>, reqwest::Error> {
+        let mut path = format!(
+            "/status/sessions/history/all?sort=viewedAt:asc&accountID={}&X-Plex-Container-Start={}&X-Plex-Container-Size={}",
+            account_id, start, self.page_size
+        );
+        if let Some(since) = since {
+            path.push_str(&format!("&viewedAt>={}", since));
+        }
 
-        let series_count = response.media_container.metadata.len();
         info!(
-            "Found {} series for library_id {}",
-            series_count, library_id
+            "Getting Plex watch history {} to {} for account {}",
+            start,
+            start + self.page_size,
+            account_id
+        );
+        let response: PlexHistoryResponse = self.make_request("history", &path).await?;
+        Ok(self.to_page(start, response.media_container))
+    }
+
+    async fn mark_watched(&self, rating_key: &str) -> Result<(), reqwest::Error> {
+        let path = format!(
+            "/:/scrobble?identifier=com.plexapp.plugins.library&key={}",
+            rating_key
         );
-        return Ok(response.media_container.metadata);
+        info!("Marking Plex item {} as watched", rating_key);
+        self.make_empty_request("scrobble", &path).await
     }
 
     async fn populate_episodes(&self, season: &mut PlexSeason) -> Result<(), reqwest::Error> {
         let path = format!("/library/metadata/{}/children", season.rating_key);
 
-        let response: PlexEpisodesResponse = self.make_request(&path).await?;
+        let response: PlexEpisodesResponse = self.make_request("episodes", &path).await?;
         season.episodes = response
             .media_container
             .metadata
             .into_iter()
-            .map(|x| PlexEpisode::from(x))
+            .map(PlexEpisode::from)
             .collect();
 
         Ok(())
     }
 
     async fn populate_seasons(&self, series: &mut PlexSeries) -> Result<(), reqwest::Error> {
-        let path = format!("/library/metadata/{}/children", series.rating_key);
+        let path = format!(
+            "/library/metadata/{}/children?includeGuids=1",
+            series.rating_key
+        );
 
-        let response: PlexSeasonResponse = self.make_request(&path).await?;
+        let response: PlexSeasonResponse = self.make_request("seasons", &path).await?;
         let mut seasons: Vec<PlexSeason> = response
             .media_container
             .metadata
             .into_iter()
-            .map(|x| PlexSeason::from(x))
+            .map(PlexSeason::from)
             .collect();
 
         let futures = FuturesUnordered::new();
@@ -140,45 +303,141 @@ impl PlexInterface for PlexApi {
             futures.push(self.populate_episodes(season));
         }
 
-        join_all(futures).await;
+        // A season without its episodes would look unwatched
+        let results: Vec<_> = join_all(futures).await;
+        if let Some(e) = results.into_iter().find_map(Result::err) {
+            return Err(e);
+        }
 
         series.seasons = seasons;
         Ok(())
     }
 }
 
+/// Sends the request until it succeeds, fails in a way that isn't worth retrying or has been
+/// retried `MAX_RETRIES` times, waiting twice as long before each retry. Shared by every media
+/// server.
+pub async fn with_retries<T, F, Fut>(
+    retry_delay: Duration,
+    path: &str,
+    request: F,
+) -> Result<T, reqwest::Error>
+where
+    F: Fn() -> Fut,
+    Fut: Future<Output = Result<T, reqwest::Error>>,
+{
+    let mut retries = 0;
+    loop {
+        match request().await {
+            Err(e) if retries < MAX_RETRIES && is_retryable(&e) => {
+                let delay = retry_delay * 2u32.pow(retries);
+                retries += 1;
+                warn!(
+                    "Request for '{}' failed, retry {}/{} in {:?}. {}",
+                    path, retries, MAX_RETRIES, delay, e
+                );
+                tokio::time::sleep(delay).await;
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Connection problems, timeouts and server errors are worth retrying, anything else would fail
+/// the same way again.
+fn is_retryable(e: &reqwest::Error) -> bool {
+    match e.status() {
+        Some(status) => status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS,
+        None => e.is_timeout() || e.is_connect() || e.is_request() || e.is_body(),
+    }
+}
+
+/// Loads the series of a library a page at a time with their seasons and episodes, so each page
+/// can be handled and dropped before the next one is loaded. Series whose seasons or episodes
+/// couldn't be loaded are returned as errors so they aren't mistaken for unwatched series.
+///
+/// Movies are loaded as series with a single episode.
+pub fn stream_series_data<'a>(
+    plex_service: &'a impl PlexInterface,
+    library: &'a ResponsePlexLibrary,
+) -> impl Stream<Item = Result<Vec<Result<PlexSeries, SeriesFetchError>>, reqwest::Error>> + 'a {
+    stream::try_unfold(Some(0), move |start| async move {
+        let start = match start {
+            Some(x) => x,
+            None => return Ok(None),
+        };
+
+        if library.is_movie_library() {
+            let page = plex_service.get_movies_page(&library.key, start).await?;
+            metrics::record_plex_series(page.items.len());
+            let movies = page.items.into_iter().map(|x| Ok(x.into())).collect();
+            return Ok(Some((movies, page.next_start)));
+        }
+
+        let page = plex_service.get_series_page(&library.key, start).await?;
+        let mut series: Vec<PlexSeries> = page.items.into_iter().map(PlexSeries::from).collect();
+        metrics::record_plex_series(series.len());
+
+        // The number of requests running at once is limited by the Plex service. The results
+        // come back in the same order as the series
+        let results = join_all(series.iter_mut().map(|x| plex_service.populate_seasons(x))).await;
+
+        let series = series
+            .into_iter()
+            .zip(results)
+            .map(|(s, result)| match result {
+                Ok(()) => Ok(s),
+                Err(e) => {
+                    warn!("Failed to load the seasons of '{}'. {}", s.title, e);
+                    Err(SeriesFetchError {
+                        rating_key: s.rating_key,
+                        title: s.title,
+                        error: e.to_string(),
+                    })
+                }
+            })
+            .collect();
+
+        Ok(Some((series, page.next_start)))
+    })
+}
+
+/// Every series in the library that could be loaded completely, and the series that couldn't be
+/// loaded even after retrying.
 pub async fn get_full_series_data(
     plex_service: &impl PlexInterface,
-    library_id: u8,
-) -> Result<Vec<PlexSeries>, reqwest::Error> {
-    let all_series = plex_service.get_series(library_id).await?;
-    let mut all_series: Vec<PlexSeries> = all_series
-        .into_iter()
-        .map(|x| PlexSeries::from(x))
-        .collect();
-
-    for chunk in all_series.chunks_mut(50) {
-        info!("Processing chunk");
-        let futures = FuturesUnordered::new();
-        for series in chunk.iter_mut() {
-            futures.push(plex_service.populate_seasons(series));
+    library: &ResponsePlexLibrary,
+) -> Result<(Vec<PlexSeries>, Vec<SeriesFetchError>), reqwest::Error> {
+    let pages: Vec<Vec<_>> = stream_series_data(plex_service, library)
+        .try_collect()
+        .await?;
+
+    let mut series = vec![];
+    let mut errors = vec![];
+    for result in pages.into_iter().flatten() {
+        match result {
+            Ok(x) => series.push(x),
+            Err(e) => errors.push(e),
         }
-        join_all(futures).await;
     }
-
-    return Ok(all_series);
+    Ok((series, errors))
 }
 
 #[cfg(test)]
 mod tests {
     use std::fs;
 
+    use reqwest::header::ACCEPT;
     use serde::Deserialize;
     use wiremock::{
-        matchers::{headers, method, path, query_param},
+        matchers::{header, headers, method, path, path_regex, query_param},
         Mock, MockServer, ResponseTemplate,
     };
 
+    use crate::services::plex::{
+        plex_api::{ExternalId, PlexMediaKind},
+        plex_tv::client_identifier,
+    };
     use crate::utils::init_logger;
 
     use super::*;
@@ -210,6 +469,15 @@ mod tests {
         panic!("Failed to find response '{}'", response)
     }
 
+    fn library(library_type: &str) -> ResponsePlexLibrary {
+        ResponsePlexLibrary {
+            key: "1".to_string(),
+            title: "Anime".to_string(),
+            library_type: library_type.to_string(),
+            ..Default::default()
+        }
+    }
+
     #[tokio::test]
     async fn test_get_full_series_data() {
         let plex_token = "123abc".to_string();
@@ -217,9 +485,11 @@ mod tests {
         let series_response = get_response("series");
         Mock::given(method("GET"))
             .and(path("/library/sections/1/all"))
+            .and(query_param("includeGuids", "1"))
             .and(headers(CONTENT_TYPE, vec!["application/json"]))
             .and(headers(ACCEPT, vec!["application/json"]))
-            .and(query_param("X-Plex-Token".to_string(), &plex_token))
+            .and(header("X-Plex-Token", plex_token.as_str()))
+            .and(header("X-Plex-Client-Identifier", client_identifier()))
             .respond_with(ResponseTemplate::new(200).set_body_string(series_response))
             .expect(1)
             .mount(&mock_server)
@@ -230,7 +500,7 @@ mod tests {
             .and(path("/library/metadata/17456/children"))
             .and(headers(CONTENT_TYPE, vec!["application/json"]))
             .and(headers(ACCEPT, vec!["application/json"]))
-            .and(query_param("X-Plex-Token".to_string(), &plex_token))
+            .and(header("X-Plex-Token", plex_token.as_str()))
             .respond_with(ResponseTemplate::new(200).set_body_string(seasons_response))
             .expect(1)
             .mount(&mock_server)
@@ -241,18 +511,37 @@ mod tests {
             .and(path("/library/metadata/30037/children"))
             .and(headers(CONTENT_TYPE, vec!["application/json"]))
             .and(headers(ACCEPT, vec!["application/json"]))
-            .and(query_param("X-Plex-Token".to_string(), &plex_token))
+            .and(header("X-Plex-Token", plex_token.as_str()))
             .respond_with(ResponseTemplate::new(200).set_body_string(episodes_response))
             .expect(1)
             .mount(&mock_server)
             .await;
+        Mock::given(method("GET"))
+            .and(path_regex("^/library/metadata/[0-9]+/children$"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "MediaContainer": { "size": 0 },
+            })))
+            .mount(&mock_server)
+            .await;
 
         let plex_service = PlexApi::new(mock_server.uri(), plex_token);
 
-        let data = get_full_series_data(&plex_service, 1).await.unwrap();
+        let (data, errors) = get_full_series_data(&plex_service, &library("show"))
+            .await
+            .unwrap();
+        assert!(errors.is_empty());
         assert_eq!(1, data.len());
         let series = &data[0];
         assert_eq!(5, series.seasons.len());
+        assert_eq!(
+            vec![
+                ExternalId::Anidb(7662),
+                ExternalId::Imdb("tt2560140".to_string()),
+                ExternalId::Tmdb(1429),
+                ExternalId::Tvdb(267440)
+            ],
+            series.guids
+        );
         let seasons = &series.seasons;
         assert_eq!(8, seasons[0].episodes.len());
     }
@@ -269,7 +558,7 @@ mod tests {
             .and(path("/library/sections/"))
             .and(headers(CONTENT_TYPE, vec!["application/json"]))
             .and(headers(ACCEPT, vec!["application/json"]))
-            .and(query_param("X-Plex-Token".to_string(), &plex_token))
+            .and(header("X-Plex-Token", plex_token.as_str()))
             .respond_with(ResponseTemplate::new(200).set_body_string(response))
             .expect(1)
             .mount(&mock_server)
@@ -282,6 +571,13 @@ mod tests {
         assert_eq!(libraries.len(), 4);
         assert_eq!(libraries[0].clone().title, "Movies".to_string());
         assert_eq!(libraries[1].clone().title, "Anime".to_string());
+        assert_eq!("2", libraries[1].key);
+        assert_eq!("show", libraries[1].library_type);
+        assert_eq!("com.plexapp.agents.hama", libraries[1].agent);
+        assert_eq!("Plex Series Scanner", libraries[1].scanner);
+        assert_eq!("/data/anime", libraries[1].locations[0].path);
+        assert!(libraries[1].is_likely_anime());
+        assert!(!libraries[2].is_likely_anime());
     }
 
     #[tokio::test]
@@ -314,7 +610,7 @@ mod tests {
             .and(path("/library/sections/1/all"))
             .and(headers(CONTENT_TYPE, vec!["application/json"]))
             .and(headers(ACCEPT, vec!["application/json"]))
-            .and(query_param("X-Plex-Token".to_string(), &plex_token))
+            .and(header("X-Plex-Token", plex_token.as_str()))
             .respond_with(ResponseTemplate::new(200).set_body_string(response))
             .expect(1)
             .mount(&mock_server)
@@ -322,12 +618,253 @@ mod tests {
 
         let plex_api = PlexApi::new(mock_server.uri(), plex_token);
 
-        let series = plex_api.get_series(1).await.unwrap();
+        let page = plex_api.get_series_page("1", 0).await.unwrap();
+        let series = page.items;
 
         assert_eq!(1, series.len());
+        assert_eq!(None, page.next_start);
         assert_eq!("17456".to_string(), series[0].clone().rating_key);
     }
 
+    #[tokio::test]
+    async fn test_get_series_in_pages() {
+        init_logger();
+
+        let page = |start: u32, keys: &[&str]| {
+            let metadata: Vec<_> = keys
+                .iter()
+                .map(|x| serde_json::json!({ "ratingKey": x, "title": x }))
+                .collect();
+            Mock::given(method("GET"))
+                .and(path("/library/sections/1/all"))
+                .and(query_param("X-Plex-Container-Start", start.to_string()))
+                .and(query_param("X-Plex-Container-Size", "2"))
+                .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                    "MediaContainer": { "totalSize": 3, "Metadata": metadata },
+                })))
+                .expect(1)
+        };
+        let mock_server = MockServer::start().await;
+        page(0, &["1", "2"]).mount(&mock_server).await;
+        page(2, &["3"]).mount(&mock_server).await;
+        Mock::given(method("GET"))
+            .and(path_regex("^/library/metadata/[0-9]+/children$"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "MediaContainer": { "size": 0 },
+            })))
+            .mount(&mock_server)
+            .await;
+
+        let plex_api = PlexApi::with_limits(mock_server.uri(), "123abc".to_string(), 2, 1);
+
+        let pages: Vec<Vec<_>> = stream_series_data(&plex_api, &library("show"))
+            .try_collect()
+            .await
+            .expect("Failed to get series");
+
+        assert_eq!(
+            vec![vec!["1", "2"], vec!["3"]],
+            pages
+                .iter()
+                .map(|x| {
+                    x.iter()
+                        .map(|s| {
+                            s.as_ref()
+                                .expect("Failed to load series")
+                                .rating_key
+                                .as_str()
+                        })
+                        .collect::<Vec<_>>()
+                })
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_get_movies_as_series() {
+        init_logger();
+
+        let mock_server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/library/sections/1/all"))
+            .and(query_param("X-Plex-Container-Start", "0"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "MediaContainer": { "totalSize": 1, "Metadata": [{
+                    "ratingKey": "700",
+                    "title": "A Silent Voice",
+                    "type": "movie",
+                    "year": 2016,
+                    "viewCount": 1,
+                    "lastViewedAt": 1700000000,
+                    "Guid": [{ "id": "tmdb://378064" }],
+                }] },
+            })))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        let plex_api = PlexApi::new(mock_server.uri(), "123abc".to_string());
+
+        let (series, _) = get_full_series_data(&plex_api, &library("movie"))
+            .await
+            .expect("Failed to get movies");
+
+        assert_eq!(1, series.len());
+        let movie = &series[0];
+        assert_eq!(PlexMediaKind::Movie { year: Some(2016) }, movie.kind);
+        assert_eq!(vec![ExternalId::Tmdb(378064)], movie.guids);
+        assert_eq!(1, movie.seasons.len());
+        assert_eq!(1, movie.seasons[0].index);
+        assert_eq!(1, movie.seasons[0].episodes.len());
+        assert_eq!(1, movie.seasons[0].episodes[0].view_count);
+    }
+
+    #[tokio::test]
+    async fn test_retry_server_errors() {
+        init_logger();
+
+        let mock_server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/library/sections/"))
+            .respond_with(ResponseTemplate::new(503))
+            .up_to_n_times(2)
+            .expect(2)
+            .mount(&mock_server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/library/sections/"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "MediaContainer": { "Directory": [] },
+            })))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        let plex_api = PlexApi::new(mock_server.uri(), "123abc".to_string())
+            .with_retry_delay(Duration::from_millis(1));
+
+        let result = plex_api.get_libraries().await;
+
+        assert!(result.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_get_single_series() {
+        init_logger();
+
+        let mock_server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/library/metadata/17456"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "MediaContainer": {
+                    "Metadata": [{
+                        "ratingKey": "17456",
+                        "title": "Attack on Titan",
+                        "Label": [{ "tag": "no-anisync" }],
+                    }],
+                },
+            })))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        let plex_api = PlexApi::new(mock_server.uri(), "123abc".to_string());
+
+        let series = plex_api
+            .get_series("17456")
+            .await
+            .expect("Failed to get series")
+            .map(PlexSeries::from)
+            .expect("Expected a series");
+
+        assert_eq!("Attack on Titan", series.title);
+        assert!(series.has_label("no-anisync"));
+    }
+
+    #[tokio::test]
+    async fn test_mark_watched() {
+        init_logger();
+
+        let mock_server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/:/scrobble"))
+            .and(query_param("identifier", "com.plexapp.plugins.library"))
+            .and(query_param("key", "1234"))
+            .and(header("X-Plex-Token", "123abc"))
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        let plex_api = PlexApi::new(mock_server.uri(), "123abc".to_string());
+
+        let result = plex_api.mark_watched("1234").await;
+
+        assert!(result.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_series_with_missing_episodes_is_an_error() {
+        init_logger();
+
+        let mock_server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/library/sections/1/all"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "MediaContainer": { "Metadata": [
+                    { "ratingKey": "1", "title": "Complete" },
+                    { "ratingKey": "2", "title": "Broken" },
+                ] },
+            })))
+            .mount(&mock_server)
+            .await;
+        for series in ["1", "2"] {
+            Mock::given(method("GET"))
+                .and(path(format!("/library/metadata/{}/children", series)))
+                .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                    "MediaContainer": { "Metadata": [
+                        {
+                            "ratingKey": format!("{}1", series),
+                            "title": "Season 1",
+                            "index": 1,
+                            "parentTitle": series,
+                            "viewedLeafCount": 0,
+                            "leafCount": 12,
+                        },
+                    ] },
+                })))
+                .mount(&mock_server)
+                .await;
+        }
+        Mock::given(method("GET"))
+            .and(path("/library/metadata/11/children"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "MediaContainer": { "size": 0 },
+            })))
+            .mount(&mock_server)
+            .await;
+        // Retried before giving up
+        Mock::given(method("GET"))
+            .and(path("/library/metadata/21/children"))
+            .respond_with(ResponseTemplate::new(500))
+            .expect(u64::from(MAX_RETRIES) + 1)
+            .mount(&mock_server)
+            .await;
+
+        let plex_api = PlexApi::new(mock_server.uri(), "123abc".to_string())
+            .with_retry_delay(Duration::from_millis(1));
+
+        let (series, errors) = get_full_series_data(&plex_api, &library("show"))
+            .await
+            .expect("Failed to get series");
+
+        assert_eq!(1, series.len());
+        assert_eq!("Complete", series[0].title);
+        assert_eq!(1, errors.len());
+        assert_eq!("2", errors[0].rating_key);
+        assert_eq!("Broken", errors[0].title);
+    }
+
     #[tokio::test]
     async fn test_get_series_404_error_response() {
         init_logger();
@@ -343,6 +880,6 @@ mod tests {
 
         let plex_api = PlexApi::new(mock_server.uri(), plex_token);
 
-        assert!(plex_api.get_series(1).await.is_err());
+        assert!(plex_api.get_series_page("1", 0).await.is_err());
     }
 }
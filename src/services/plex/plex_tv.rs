@@ -0,0 +1,490 @@
+use std::sync::OnceLock;
+
+use log::{info, warn};
+use rand::{distributions::Alphanumeric, Rng};
+use reqwest::header::{self, HeaderMap, HeaderValue, ACCEPT};
+use serde::{de::DeserializeOwned, Deserialize};
+use url::Url;
+
+use crate::services::dbstore::dbstore::DbStore;
+
+pub const PLEX_TV_URL: &str = "https://clients.plex.tv";
+/// Where the user approves a PIN login
+const PLEX_AUTH_URL: &str = "https://app.plex.tv/auth";
+/// Used until the identifier of this install is loaded
+const DEFAULT_CLIENT_IDENTIFIER: &str = "plex-ani-sync";
+pub const PRODUCT: &str = "Plex Ani Sync";
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+static CLIENT_IDENTIFIER: OnceLock<String> = OnceLock::new();
+
+/// Identifies this install to Plex and plex.tv. Tokens from a PIN login belong to it, so it has
+/// to stay the same between runs.
+pub fn client_identifier() -> &'static str {
+    CLIENT_IDENTIFIER
+        .get()
+        .map(String::as_str)
+        .unwrap_or(DEFAULT_CLIENT_IDENTIFIER)
+}
+
+/// Loads the client identifier of this install from the database, creating one on the first run.
+pub async fn load_client_identifier(db_store: &impl DbStore) {
+    let identifier = match db_store.get_client_identifier().await {
+        Ok(Some(x)) => x,
+        Ok(None) => {
+            let identifier: String = rand::thread_rng()
+                .sample_iter(&Alphanumeric)
+                .take(24)
+                .map(char::from)
+                .collect();
+            if let Err(e) = db_store.save_client_identifier(&identifier).await {
+                warn!("Failed to save the Plex client identifier. {}", e);
+                return;
+            }
+            identifier
+        }
+        Err(e) => {
+            warn!("Failed to load the Plex client identifier. {}", e);
+            return;
+        }
+    };
+    let _ = CLIENT_IDENTIFIER.set(identifier);
+}
+
+/// The headers every request to Plex and plex.tv needs. `token` is left out when it's empty.
+pub fn plex_headers(token: &str) -> HeaderMap {
+    let mut headers = header::HeaderMap::new();
+    headers.insert(ACCEPT, HeaderValue::from_static("application/json"));
+    headers.insert(
+        "X-Plex-Client-Identifier",
+        HeaderValue::from_str(client_identifier()).expect("Invalid Plex client identifier"),
+    );
+    headers.insert("X-Plex-Product", HeaderValue::from_static(PRODUCT));
+    headers.insert("X-Plex-Version", HeaderValue::from_static(VERSION));
+    if !token.is_empty() {
+        headers.insert(
+            "X-Plex-Token",
+            HeaderValue::from_str(token).expect("Failed to parse Plex token"),
+        );
+    }
+    headers
+}
+
+/// A Plex Home user, including managed users.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct PlexHomeUser {
+    pub id: u64,
+    pub uuid: String,
+    pub title: String,
+    /// Empty for managed users
+    #[serde(default)]
+    pub username: String,
+    #[serde(default)]
+    pub admin: bool,
+    /// The user needs a PIN to switch to them
+    #[serde(default)]
+    pub protected: bool,
+}
+
+impl PlexHomeUser {
+    /// Users are named in the config by their title or username.
+    pub fn has_name(&self, name: &str) -> bool {
+        self.title.eq_ignore_ascii_case(name)
+            || (!self.username.is_empty() && self.username.eq_ignore_ascii_case(name))
+    }
+}
+
+#[derive(Deserialize)]
+struct PlexHomeUsersResponse {
+    users: Vec<PlexHomeUser>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct PlexSwitchUserResponse {
+    auth_token: String,
+}
+
+/// A PIN the user links to their account at `get_auth_url`, which gives us a token for it.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PlexPin {
+    pub id: u64,
+    pub code: String,
+    /// Set once the user has signed in
+    pub auth_token: Option<String>,
+}
+
+/// A server or player the user has access to.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PlexResource {
+    #[serde(default)]
+    pub name: String,
+    pub client_identifier: String,
+    /// A comma separated list such as `server` or `client,player`
+    #[serde(default)]
+    pub provides: String,
+    #[serde(default)]
+    pub owned: bool,
+    pub access_token: Option<String>,
+    #[serde(default)]
+    pub connections: Vec<PlexConnection>,
+}
+
+impl PlexResource {
+    pub fn is_server(&self) -> bool {
+        self.provides.split(',').any(|x| x == "server")
+    }
+
+    /// Local connections are tried first and connections relayed through plex.tv last, since
+    /// those are slow and limited.
+    pub fn get_best_connection(&self) -> Option<&PlexConnection> {
+        self.connections.iter().min_by_key(|x| (x.relay, !x.local))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct PlexConnection {
+    pub uri: String,
+    #[serde(default)]
+    pub local: bool,
+    #[serde(default)]
+    pub relay: bool,
+}
+
+/// Talks to plex.tv to get tokens for the users in the server owner's Plex Home.
+pub struct PlexTv {
+    base_url: String,
+    token: String,
+    http_client: reqwest::Client,
+}
+
+impl PlexTv {
+    pub fn new(token: String, base_url: Option<String>) -> Self {
+        Self {
+            base_url: base_url.unwrap_or(PLEX_TV_URL.to_string()),
+            token,
+            http_client: reqwest::Client::new(),
+        }
+    }
+
+    /// For signing in, when there's no token yet.
+    pub fn without_token(base_url: Option<String>) -> Self {
+        Self::new(String::new(), base_url)
+    }
+
+    fn get_headers(&self, token: &str) -> HeaderMap {
+        plex_headers(token)
+    }
+
+    async fn read_response<T>(response: reqwest::Response) -> Result<T, anyhow::Error>
+    where
+        T: DeserializeOwned,
+    {
+        let status = response.status();
+        if !status.is_success() {
+            let body = response.text().await.unwrap_or_default();
+            anyhow::bail!("plex.tv responded with {}. {}", status, body);
+        }
+
+        Ok(response.json::<T>().await?)
+    }
+
+    /// Starts a PIN login.
+    pub async fn create_pin(&self) -> Result<PlexPin, anyhow::Error> {
+        let response = self
+            .http_client
+            .post(format!("{}/api/v2/pins", self.base_url))
+            .query(&[("strong", "true")])
+            .headers(self.get_headers(&self.token))
+            .send()
+            .await?;
+
+        Self::read_response(response).await
+    }
+
+    /// The page where the user signs in to link the PIN to their account.
+    pub fn get_auth_url(pin: &PlexPin) -> String {
+        let mut url = Url::parse(PLEX_AUTH_URL).expect("Failed to parse Plex auth url");
+        let query = url::form_urlencoded::Serializer::new(String::new())
+            .append_pair("clientID", client_identifier())
+            .append_pair("code", &pin.code)
+            .append_pair("context[device][product]", PRODUCT)
+            .finish();
+        // The auth page reads its parameters from the fragment
+        url.set_fragment(Some(&format!("?{}", query)));
+        url.to_string()
+    }
+
+    /// Checks whether the user has signed in, the PIN then holds their token.
+    pub async fn check_pin(&self, pin: &PlexPin) -> Result<PlexPin, anyhow::Error> {
+        let response = self
+            .http_client
+            .get(format!("{}/api/v2/pins/{}", self.base_url, pin.id))
+            .query(&[("code", pin.code.as_str())])
+            .headers(self.get_headers(&self.token))
+            .send()
+            .await?;
+
+        Self::read_response(response).await
+    }
+
+    /// The Plex servers the user owns or that are shared with them, with their connections.
+    pub async fn get_servers(&self) -> Result<Vec<PlexResource>, anyhow::Error> {
+        info!("Getting Plex servers");
+        let response = self
+            .http_client
+            .get(format!("{}/api/v2/resources", self.base_url))
+            .query(&[("includeHttps", "1"), ("includeRelay", "1")])
+            .headers(self.get_headers(&self.token))
+            .send()
+            .await?;
+
+        let resources: Vec<PlexResource> = Self::read_response(response).await?;
+        Ok(resources.into_iter().filter(|x| x.is_server()).collect())
+    }
+
+    /// The users in the Plex Home of the account the token belongs to.
+    pub async fn get_home_users(&self) -> Result<Vec<PlexHomeUser>, anyhow::Error> {
+        info!("Getting Plex Home users");
+        let response = self
+            .http_client
+            .get(format!("{}/api/home/users", self.base_url))
+            .headers(self.get_headers(&self.token))
+            .send()
+            .await?;
+
+        let response: PlexHomeUsersResponse = Self::read_response(response).await?;
+        Ok(response.users)
+    }
+
+    /// Switches to a Plex Home user and returns their plex.tv token. Protected users need their
+    /// PIN.
+    pub async fn switch_user(
+        &self,
+        user: &PlexHomeUser,
+        pin: Option<&str>,
+    ) -> Result<String, anyhow::Error> {
+        info!("Switching to Plex Home user '{}'", user.title);
+        let mut request = self
+            .http_client
+            .post(format!(
+                "{}/api/home/users/{}/switch",
+                self.base_url, user.uuid
+            ))
+            .headers(self.get_headers(&self.token));
+        if let Some(pin) = pin {
+            request = request.query(&[("pin", pin)]);
+        }
+
+        let response: PlexSwitchUserResponse = Self::read_response(request.send().await?).await?;
+        Ok(response.auth_token)
+    }
+
+    /// The token a user has to use with a Plex server. plex.tv tokens of Plex Home users only
+    /// work on the servers the owner shares with them through this token.
+    pub async fn get_server_token(
+        &self,
+        user_token: &str,
+        machine_identifier: &str,
+    ) -> Result<String, anyhow::Error> {
+        let response = self
+            .http_client
+            .get(format!("{}/api/v2/resources", self.base_url))
+            .headers(self.get_headers(user_token))
+            .send()
+            .await?;
+
+        let resources: Vec<PlexResource> = Self::read_response(response).await?;
+        resources
+            .into_iter()
+            .find(|x| x.client_identifier == machine_identifier)
+            .and_then(|x| x.access_token)
+            .ok_or(anyhow::anyhow!(
+                "The user doesn't have access to the Plex server"
+            ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use wiremock::{
+        matchers::{header, method, path, query_param},
+        Mock, MockServer, ResponseTemplate,
+    };
+
+    use super::*;
+
+    fn user() -> PlexHomeUser {
+        PlexHomeUser {
+            id: 2,
+            uuid: "abc123".to_string(),
+            title: "Alice".to_string(),
+            username: String::new(),
+            admin: false,
+            protected: true,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_get_home_users() {
+        let mock_server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/api/home/users"))
+            .and(header("X-Plex-Token", "owner123"))
+            .and(header("X-Plex-Client-Identifier", client_identifier()))
+            .and(header("X-Plex-Product", PRODUCT))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "id": 1,
+                "users": [
+                    { "id": 1, "uuid": "owner", "title": "owner", "username": "owner", "admin": true },
+                    { "id": 2, "uuid": "abc123", "title": "Alice", "username": "", "protected": true },
+                ],
+            })))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+        let plex_tv = PlexTv::new("owner123".to_string(), Some(mock_server.uri()));
+
+        let result = plex_tv.get_home_users().await.expect("Failed to get users");
+
+        assert_eq!(2, result.len());
+        assert_eq!(user(), result[1]);
+        assert!(result[1].has_name("alice"));
+        assert!(!result[1].has_name(""));
+    }
+
+    #[tokio::test]
+    async fn test_switch_user_and_get_server_token() {
+        let mock_server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/api/home/users/abc123/switch"))
+            .and(query_param("pin", "1234"))
+            .and(header("X-Plex-Token", "owner123"))
+            .respond_with(ResponseTemplate::new(201).set_body_json(json!({
+                "id": 2,
+                "authToken": "alice123",
+            })))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/api/v2/resources"))
+            .and(header("X-Plex-Token", "alice123"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!([
+                { "clientIdentifier": "other", "accessToken": "wrong" },
+                { "clientIdentifier": "server1", "accessToken": "alice-server" },
+            ])))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+        let plex_tv = PlexTv::new("owner123".to_string(), Some(mock_server.uri()));
+
+        let user_token = plex_tv
+            .switch_user(&user(), Some("1234"))
+            .await
+            .expect("Failed to switch user");
+        let result = plex_tv
+            .get_server_token(&user_token, "server1")
+            .await
+            .expect("Failed to get server token");
+
+        assert_eq!("alice-server", result);
+    }
+
+    #[tokio::test]
+    async fn test_switch_user_with_wrong_pin() {
+        let mock_server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/api/home/users/abc123/switch"))
+            .respond_with(ResponseTemplate::new(401).set_body_string("Invalid PIN"))
+            .mount(&mock_server)
+            .await;
+        let plex_tv = PlexTv::new("owner123".to_string(), Some(mock_server.uri()));
+
+        let result = plex_tv.switch_user(&user(), Some("0000")).await;
+
+        assert!(result.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_pin_login() {
+        let mock_server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/api/v2/pins"))
+            .and(query_param("strong", "true"))
+            .and(header("X-Plex-Version", VERSION))
+            .respond_with(ResponseTemplate::new(201).set_body_json(json!({
+                "id": 42,
+                "code": "abcd",
+                "authToken": null,
+            })))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/api/v2/pins/42"))
+            .and(query_param("code", "abcd"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "id": 42,
+                "code": "abcd",
+                "authToken": "user123",
+            })))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+        let plex_tv = PlexTv::without_token(Some(mock_server.uri()));
+
+        let pin = plex_tv.create_pin().await.expect("Failed to create PIN");
+        let url = PlexTv::get_auth_url(&pin);
+        let result = plex_tv.check_pin(&pin).await.expect("Failed to check PIN");
+
+        assert_eq!(None, pin.auth_token);
+        assert!(url.starts_with("https://app.plex.tv/auth#?clientID="));
+        assert!(url.contains("code=abcd"));
+        assert_eq!(Some("user123".to_string()), result.auth_token);
+    }
+
+    #[tokio::test]
+    async fn test_get_servers() {
+        let mock_server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/api/v2/resources"))
+            .and(header("X-Plex-Token", "user123"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!([
+                {
+                    "name": "Living room",
+                    "clientIdentifier": "player1",
+                    "provides": "client,player",
+                    "connections": [],
+                },
+                {
+                    "name": "Home server",
+                    "clientIdentifier": "server1",
+                    "provides": "server",
+                    "owned": true,
+                    "accessToken": "server123",
+                    "connections": [
+                        { "uri": "https://relay.plex.direct:8443", "local": false, "relay": true },
+                        { "uri": "https://1-2-3-4.plex.direct:32400", "local": false, "relay": false },
+                        { "uri": "https://192-168-1-2.plex.direct:32400", "local": true, "relay": false },
+                    ],
+                },
+            ])))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+        let plex_tv = PlexTv::new("user123".to_string(), Some(mock_server.uri()));
+
+        let result = plex_tv.get_servers().await.expect("Failed to get servers");
+
+        assert_eq!(1, result.len());
+        assert_eq!("Home server", result[0].name);
+        assert_eq!(
+            Some("https://192-168-1-2.plex.direct:32400"),
+            result[0].get_best_connection().map(|x| x.uri.as_str())
+        );
+    }
+}
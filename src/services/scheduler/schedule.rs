@@ -0,0 +1,201 @@
+use std::{fmt, str::FromStr, time::Duration};
+
+use chrono::{DateTime, TimeZone};
+use serde::{Serialize, Serializer};
+
+/// When syncs should run. Either a cron expression or a fixed interval between runs.
+#[derive(Debug, Clone)]
+pub enum Schedule {
+    Cron {
+        expression: String,
+        schedule: Box<cron::Schedule>,
+    },
+    Interval(Duration),
+}
+
+impl Schedule {
+    /// The first run time after `now`. For intervals this is counted from the last run.
+    pub fn next_run<Tz: TimeZone>(
+        &self,
+        now: &DateTime<Tz>,
+        last_run: Option<&DateTime<Tz>>,
+    ) -> Option<DateTime<Tz>> {
+        match self {
+            Schedule::Cron { schedule, .. } => schedule.after(now).next(),
+            Schedule::Interval(interval) => {
+                let interval = chrono::Duration::from_std(*interval).ok()?;
+                let next = match last_run {
+                    Some(x) => x.clone() + interval,
+                    None => now.clone() + interval,
+                };
+                Some(next.max(now.clone()))
+            }
+        }
+    }
+}
+
+impl FromStr for Schedule {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let value = value.trim();
+        if value.is_empty() {
+            return Err("schedule is empty".to_string());
+        }
+
+        if let Some(interval) = parse_interval(value)? {
+            return Ok(Schedule::Interval(interval));
+        }
+
+        // The cron crate expects a seconds field, accept the more common five field format too
+        let expression = match value.split_whitespace().count() {
+            5 => format!("0 {}", value),
+            _ => value.to_string(),
+        };
+
+        let schedule = cron::Schedule::from_str(&expression)
+            .map_err(|e| format!("'{}' is not a valid cron expression: {}", value, e))?;
+
+        Ok(Schedule::Cron {
+            expression: value.to_string(),
+            schedule: Box::new(schedule),
+        })
+    }
+}
+
+impl fmt::Display for Schedule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Schedule::Cron { expression, .. } => write!(f, "{}", expression),
+            Schedule::Interval(interval) => write!(f, "{}s", interval.as_secs()),
+        }
+    }
+}
+
+impl PartialEq for Schedule {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Schedule::Interval(a), Schedule::Interval(b)) => a == b,
+            (Schedule::Cron { expression: a, .. }, Schedule::Cron { expression: b, .. }) => a == b,
+            _ => false,
+        }
+    }
+}
+
+impl Serialize for Schedule {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.to_string())
+    }
+}
+
+/// Parses intervals such as `30m`, `6h` or `1h30m`. Returns `None` if the value doesn't look like
+/// an interval so it can be treated as a cron expression instead.
+fn parse_interval(value: &str) -> Result<Option<Duration>, String> {
+    let looks_like_interval = value.chars().next().is_some_and(|x| x.is_ascii_digit())
+        && value.chars().all(|x| x.is_ascii_alphanumeric());
+    if !looks_like_interval {
+        return Ok(None);
+    }
+
+    let mut seconds: u64 = 0;
+    let mut number = String::new();
+    for char in value.chars() {
+        if char.is_ascii_digit() {
+            number.push(char);
+            continue;
+        }
+
+        let multiplier = match char {
+            's' => 1,
+            'm' => 60,
+            'h' => 60 * 60,
+            'd' => 24 * 60 * 60,
+            _ => {
+                return Err(format!(
+                    "'{}' has an unknown interval unit '{}'",
+                    value, char
+                ))
+            }
+        };
+        let amount: u64 = number
+            .parse()
+            .map_err(|_| format!("'{}' is not a valid interval", value))?;
+        seconds += amount * multiplier;
+        number.clear();
+    }
+
+    if !number.is_empty() {
+        return Err(format!(
+            "'{}' is missing a unit, use one of s, m, h or d",
+            value
+        ));
+    }
+
+    if seconds == 0 {
+        return Err("the interval must be greater than zero".to_string());
+    }
+
+    Ok(Some(Duration::from_secs(seconds)))
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Timelike;
+    use chrono_tz::Tz;
+
+    use super::*;
+
+    #[test]
+    fn test_parse_interval() {
+        let result: Schedule = "1h30m".parse().expect("Failed to parse interval");
+
+        assert_eq!(Schedule::Interval(Duration::from_secs(5400)), result);
+    }
+
+    #[test]
+    fn test_parse_interval_without_unit() {
+        let result = "90".parse::<Schedule>();
+
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_parse_five_field_cron_expression() {
+        let result: Schedule = "0 23 * * *".parse().expect("Failed to parse cron");
+
+        assert_eq!("0 23 * * *", result.to_string());
+    }
+
+    #[test]
+    fn test_parse_invalid_cron_expression() {
+        let result = "every day".parse::<Schedule>();
+
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_cron_next_run_uses_timezone() {
+        let schedule: Schedule = "0 23 * * *".parse().expect("Failed to parse cron");
+        let timezone: Tz = "Europe/London".parse().expect("Failed to parse timezone");
+        let now = timezone.with_ymd_and_hms(2023, 7, 1, 12, 0, 0).unwrap();
+
+        let next = schedule.next_run(&now, None).expect("No next run");
+
+        assert_eq!(23, next.hour());
+        assert_eq!(now.date_naive(), next.date_naive());
+    }
+
+    #[test]
+    fn test_interval_next_run_counts_from_last_run() {
+        let schedule = Schedule::Interval(Duration::from_secs(60 * 60));
+        let now = chrono::Utc.with_ymd_and_hms(2023, 7, 1, 12, 0, 0).unwrap();
+        let last_run = chrono::Utc.with_ymd_and_hms(2023, 7, 1, 11, 30, 0).unwrap();
+
+        let next = schedule
+            .next_run(&now, Some(&last_run))
+            .expect("No next run");
+
+        assert_eq!(12, next.hour());
+        assert_eq!(30, next.minute());
+    }
+}
@@ -0,0 +1,263 @@
+use std::sync::{Arc, Mutex};
+
+use chrono::{DateTime, Utc};
+use log::{error, info, warn};
+use rand::Rng;
+use tokio::sync::Notify;
+
+use crate::services::{
+    config::config::{load_config, Config},
+    dbstore::dbstore::DbStore,
+    sync_service::sync_runner::{run_sync, run_targeted_sync, SyncTarget},
+};
+
+/// Runs syncs on the configured schedule. The config is reloaded after every run and whenever the
+/// process receives SIGHUP.
+pub struct Scheduler<D>
+where
+    D: DbStore + Clone,
+{
+    db_store: D,
+    config_file: Option<String>,
+    handle: SchedulerHandle,
+}
+
+/// Lets other tasks such as the API ask a running scheduler to reload or sync.
+#[derive(Debug, Clone, Default)]
+pub struct SchedulerHandle {
+    reload: Arc<Notify>,
+    sync_now: Arc<Notify>,
+    targets: Arc<Mutex<Vec<SyncTarget>>>,
+    sync_targets: Arc<Notify>,
+}
+
+impl SchedulerHandle {
+    /// Reloads the config and reschedules the next sync.
+    pub fn reload(&self) {
+        self.reload.notify_one();
+    }
+
+    /// Starts a sync straight away, or right after the current one if a sync is running.
+    pub fn sync_now(&self) {
+        self.sync_now.notify_one();
+    }
+
+    /// Queues a sync of a few Anilist entries. Targets for the same series and Plex user are
+    /// merged while they wait for the current sync to finish.
+    pub fn sync_target(&self, target: SyncTarget) {
+        let mut targets = self.targets.lock().expect("Sync target queue was poisoned");
+        match targets.iter_mut().find(|x| {
+            x.series_rating_key == target.series_rating_key && x.plex_user == target.plex_user
+        }) {
+            Some(existing) => {
+                for id in target.anime_ids {
+                    if !existing.anime_ids.contains(&id) {
+                        existing.anime_ids.push(id);
+                    }
+                }
+            }
+            None => targets.push(target),
+        }
+        self.sync_targets.notify_one();
+    }
+
+    fn take_targets(&self) -> Vec<SyncTarget> {
+        let mut targets = self.targets.lock().expect("Sync target queue was poisoned");
+        std::mem::take(&mut *targets)
+    }
+}
+
+impl<D> Scheduler<D>
+where
+    D: DbStore + Clone + 'static,
+{
+    pub fn new(db_store: D, config_file: Option<String>) -> Self {
+        Self {
+            db_store,
+            config_file,
+            handle: SchedulerHandle::default(),
+        }
+    }
+
+    pub fn handle(&self) -> SchedulerHandle {
+        self.handle.clone()
+    }
+
+    pub async fn run(self, mut config: Config) {
+        listen_for_reload_signal(self.handle.clone());
+
+        let mut last_run: Option<DateTime<Utc>> = None;
+        if config.run_on_startup {
+            info!("Running sync on startup");
+            self.sync(&config).await;
+            last_run = Some(Utc::now());
+            config = self.reload_config(config).await;
+        }
+
+        loop {
+            let now = Utc::now().with_timezone(&config.timezone);
+            let last_run_local = last_run.map(|x| x.with_timezone(&config.timezone));
+            let next_run = match config.schedule.next_run(&now, last_run_local.as_ref()) {
+                Some(x) => x + get_jitter(config.jitter_seconds),
+                None => {
+                    error!(
+                        "Schedule '{}' has no upcoming runs, waiting for the config to be reloaded",
+                        config.schedule
+                    );
+                    tokio::select! {
+                        _ = self.handle.reload.notified() => {}
+                        _ = self.handle.sync_now.notified() => {
+                            self.sync(&config).await;
+                            last_run = Some(Utc::now());
+                        }
+                        _ = self.handle.sync_targets.notified() => {
+                            self.sync_targets(&config).await;
+                        }
+                    }
+                    config = self.reload_config(config).await;
+                    continue;
+                }
+            };
+
+            info!(
+                "Next sync planned for {}",
+                next_run.format("%Y-%m-%d %H:%M:%S %Z")
+            );
+            let wait = (next_run - now).to_std().unwrap_or_default();
+
+            tokio::select! {
+                _ = tokio::time::sleep(wait) => {
+                    self.sync(&config).await;
+                    last_run = Some(Utc::now());
+                }
+                _ = self.handle.reload.notified() => {
+                    info!("Reload requested");
+                }
+                _ = self.handle.sync_now.notified() => {
+                    info!("Sync requested");
+                    self.sync(&config).await;
+                    last_run = Some(Utc::now());
+                }
+                _ = self.handle.sync_targets.notified() => {
+                    self.sync_targets(&config).await;
+                }
+            }
+
+            config = self.reload_config(config).await;
+        }
+    }
+
+    async fn sync(&self, config: &Config) {
+        for result in run_sync(&self.db_store, config).await {
+            match result {
+                Ok(report) if report.dry_run => info!("Dry run changes\n{}", report.to_table()),
+                Ok(_) => {}
+                Err(e) => error!("Sync failed. {:#}", e),
+            }
+        }
+    }
+
+    async fn sync_targets(&self, config: &Config) {
+        for target in self.handle.take_targets() {
+            match run_targeted_sync(&self.db_store, config, &target).await {
+                Ok(Some(report)) if report.dry_run => {
+                    info!("Dry run changes\n{}", report.to_table())
+                }
+                Ok(_) => {}
+                Err(e) => error!("Sync of '{}' failed. {:#}", target.series_title, e),
+            }
+        }
+    }
+
+    /// Loads the latest config, keeping the current one if the new config is invalid.
+    async fn reload_config(&self, current: Config) -> Config {
+        match load_config(&self.db_store, self.config_file.as_deref()).await {
+            Ok(x) => {
+                if x.schedule != current.schedule || x.timezone != current.timezone {
+                    info!("Schedule changed to '{}' ({})", x.schedule, x.timezone);
+                }
+                x
+            }
+            Err(e) => {
+                warn!("Failed to reload config, keeping the current config. {}", e);
+                current
+            }
+        }
+    }
+}
+
+fn get_jitter(jitter_seconds: u64) -> chrono::Duration {
+    if jitter_seconds == 0 {
+        return chrono::Duration::zero();
+    }
+
+    let seconds = rand::thread_rng().gen_range(0..=jitter_seconds);
+    chrono::Duration::seconds(seconds as i64)
+}
+
+#[cfg(unix)]
+fn listen_for_reload_signal(handle: SchedulerHandle) {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut hangup = match signal(SignalKind::hangup()) {
+        Ok(x) => x,
+        Err(e) => {
+            warn!(
+                "Failed to listen for SIGHUP, config reloads on signal are disabled. {}",
+                e
+            );
+            return;
+        }
+    };
+
+    tokio::spawn(async move {
+        while hangup.recv().await.is_some() {
+            info!("Received SIGHUP");
+            handle.reload();
+        }
+    });
+}
+
+#[cfg(not(unix))]
+fn listen_for_reload_signal(_: SchedulerHandle) {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_get_jitter_within_bounds() {
+        for _ in 0..100 {
+            let jitter = get_jitter(30);
+            assert!(jitter >= chrono::Duration::zero());
+            assert!(jitter <= chrono::Duration::seconds(30));
+        }
+    }
+
+    #[test]
+    fn test_get_jitter_disabled() {
+        assert_eq!(chrono::Duration::zero(), get_jitter(0));
+    }
+
+    #[test]
+    fn test_sync_targets_for_the_same_series_are_merged() {
+        let handle = SchedulerHandle::default();
+        let target = |series: &str, plex_user: Option<&str>, anime_ids: Vec<u32>| SyncTarget {
+            library_key: "1".to_string(),
+            series_rating_key: series.to_string(),
+            series_title: "Attack on Titan".to_string(),
+            anime_ids,
+            plex_user: plex_user.map(String::from),
+        };
+
+        handle.sync_target(target("17456", None, vec![16498]));
+        handle.sync_target(target("17456", None, vec![16498, 20958]));
+        handle.sync_target(target("17456", Some("Alice"), vec![16498]));
+        handle.sync_target(target("20000", None, vec![1]));
+
+        let targets = handle.take_targets();
+        assert_eq!(3, targets.len());
+        assert_eq!(vec![16498, 20958], targets[0].anime_ids);
+        assert!(handle.take_targets().is_empty());
+    }
+}
@@ -1 +1,4 @@
 pub mod sync_handler;
+pub mod sync_report;
+pub mod sync_runner;
+pub mod watch_history;
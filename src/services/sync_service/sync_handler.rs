@@ -1,6 +1,7 @@
-use std::cmp::min;
+use std::{collections::BTreeMap, fmt, str::FromStr};
 
 use chrono::{Duration, Utc};
+use serde::Serialize;
 
 use crate::services::{
     anime_list_service::anime_list_service::{AnilistWatchStatus, AnimeListEntry},
@@ -8,19 +9,127 @@ use crate::services::{
     plex::plex_api::{PlexEpisode, PlexSeries},
 };
 
+pub const DEFAULT_WATCHED_PERCENT: u8 = 90;
+
+/// How watch activity in Plex is turned into an Anilist status. A threshold of `None` means the
+/// status is never set automatically.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct StatusRules {
+    pub dropped_after_days: Option<u32>,
+    pub paused_after_days: Option<u32>,
+    /// Add entries that haven't been watched yet to the list as Planning
+    pub update_planning: bool,
+    /// How much of an episode has to be played before it counts as watched
+    pub watched_percent: u8,
+}
+
+impl Default for StatusRules {
+    fn default() -> Self {
+        Self {
+            dropped_after_days: Some(30),
+            paused_after_days: Some(14),
+            update_planning: false,
+            watched_percent: DEFAULT_WATCHED_PERCENT,
+        }
+    }
+}
+
+/// Which side wins when an Anilist entry has more progress than was watched in Plex, used when
+/// syncing both ways.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ConflictPolicy {
+    /// The missing episodes are marked as watched in Plex
+    Anilist,
+    /// The Anilist entry is set back to the progress in Plex
+    Plex,
+    /// The side that changed last wins
+    Newest,
+}
+
+impl ConflictPolicy {
+    /// Whether the progress on Anilist should be kept and written to Plex. `plex_last_viewed_at`
+    /// is the last time an episode mapped to the entry was watched in Plex.
+    pub fn anilist_wins(&self, current: &AnimeListEntry, plex_last_viewed_at: Option<i64>) -> bool {
+        match self {
+            ConflictPolicy::Anilist => true,
+            ConflictPolicy::Plex => false,
+            ConflictPolicy::Newest => match (current.updated_at, plex_last_viewed_at) {
+                (Some(updated_at), Some(last_viewed_at)) => updated_at > last_viewed_at,
+                (_, None) => true,
+                (None, Some(_)) => false,
+            },
+        }
+    }
+}
+
+impl FromStr for ConflictPolicy {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.trim().to_lowercase().as_str() {
+            "anilist" => Ok(ConflictPolicy::Anilist),
+            "plex" => Ok(ConflictPolicy::Plex),
+            "newest" => Ok(ConflictPolicy::Newest),
+            _ => Err(format!(
+                "'{}' isn't a conflict policy, use one of anilist, plex or newest",
+                value
+            )),
+        }
+    }
+}
+
+impl fmt::Display for ConflictPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConflictPolicy::Anilist => write!(f, "anilist"),
+            ConflictPolicy::Plex => write!(f, "plex"),
+            ConflictPolicy::Newest => write!(f, "newest"),
+        }
+    }
+}
+
 pub fn plex_series_to_animelist_entry(
     plex_anime_entry: AnimeEntryPlexRepresentation,
+    rules: &StatusRules,
 ) -> AnimeListEntry {
     let watched_episodes = plex_anime_entry
         .plex_episodes
         .iter()
-        .filter(|x| x.last_viewed_at.is_some())
+        .filter(|x| x.is_watched(rules.watched_percent))
         .count() as u16;
+    let rewatched_episodes = get_rewatched_episodes(&plex_anime_entry.plex_episodes);
+    let media_id = plex_anime_entry.anime_list_id;
+    let status = get_watch_status(plex_anime_entry, rules);
+
+    // Anilist tracks the progress of the current rewatch
+    let progress = match (&status, rewatched_episodes) {
+        (AnilistWatchStatus::Repeating, Some(x)) => x,
+        _ => watched_episodes,
+    };
 
     AnimeListEntry {
-        media_id: plex_anime_entry.anime_list_id,
-        status: get_watch_status(plex_anime_entry),
-        progress: watched_episodes,
+        media_id,
+        status,
+        progress,
+        updated_at: None,
+        private: false,
+    }
+}
+
+/// The number of episodes watched again since every episode was first watched, when a rewatch
+/// is underway. Needs the watch history to know when each episode was first watched.
+fn get_rewatched_episodes(episodes: &[PlexEpisode]) -> Option<u16> {
+    let first_viewed_at: Option<Vec<i64>> = episodes.iter().map(|x| x.first_viewed_at).collect();
+    let completed_at = first_viewed_at?.into_iter().max()?;
+
+    let rewatched = episodes
+        .iter()
+        .filter(|x| x.last_viewed_at.is_some_and(|x| x > completed_at))
+        .count();
+    match rewatched > 0 && rewatched < episodes.len() {
+        true => u16::try_from(rewatched).ok(),
+        false => None,
     }
 }
 
@@ -28,16 +137,63 @@ pub struct AnimeEntryPlexRepresentation {
     anime_list_id: u32,
     episodes: Option<u16>,
     plex_episodes: Vec<PlexEpisode>,
+    /// Episodes missing from Plex between episodes that are there
+    pub gaps: Vec<EpisodeGap>,
+}
+
+/// Episode numbers a mapping covers that aren't in the Plex season, while later episodes of the
+/// mapping are.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct EpisodeGap {
+    pub anime_list_id: u32,
+    pub plex_id: String,
+    pub missing_episodes: Vec<u32>,
+}
+
+impl fmt::Display for EpisodeGap {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let missing: Vec<String> = self
+            .missing_episodes
+            .iter()
+            .map(|x| x.to_string())
+            .collect();
+        write!(
+            f,
+            "Anilist {} is missing episode(s) {} in Plex season {}",
+            self.anime_list_id,
+            missing.join(", "),
+            self.plex_id
+        )
+    }
+}
+
+impl AnimeEntryPlexRepresentation {
+    /// The last time any of the episodes was watched
+    pub fn get_last_viewed_at(&self) -> Option<i64> {
+        self.plex_episodes
+            .iter()
+            .filter_map(|x| x.last_viewed_at)
+            .max()
+    }
+}
+
+/// Episodes are numbered by their Plex index. Episodes Plex couldn't number fall back to their
+/// position in the season.
+fn get_episode_number(episode: &PlexEpisode, position: usize) -> u32 {
+    episode
+        .index
+        .unwrap_or_else(|| u32::try_from(position + 1).unwrap_or(u32::MAX))
 }
 
 pub fn get_plex_episodes_for_anime_list_id(
-    all_plex_series: &Vec<PlexSeries>,
-    all_mappings: &Vec<Mapping>,
+    all_plex_series: &[PlexSeries],
+    all_mappings: &[Mapping],
     anime_list_id: u32,
 ) -> AnimeEntryPlexRepresentation {
     let mut plex_episodes: Vec<PlexEpisode> = vec![];
+    let mut gaps: Vec<EpisodeGap> = vec![];
     let relevant_mappings: Vec<&Mapping> = all_mappings
-        .into_iter()
+        .iter()
         .filter(|x| x.anime_list_id == anime_list_id)
         .collect();
 
@@ -48,25 +204,45 @@ pub fn get_plex_episodes_for_anime_list_id(
                     continue;
                 }
 
-                let start = usize::try_from(mapping.episode_start - 1).unwrap();
-                let end = min(
-                    usize::try_from(mapping.episode_start + mapping.season_length - 1).unwrap(),
-                    season.episodes.len(),
-                );
-
-                let mut selected_episodes: Vec<PlexEpisode> = season
-                    .episodes
-                    .iter()
-                    .enumerate()
-                    .filter(|(i, _)| i >= &start && i < &end)
-                    .map(|(_, x)| x.clone())
-                    .collect();
-                plex_episodes.append(&mut selected_episodes);
+                let start = mapping.plex_episode_start;
+                let end = mapping.plex_episode_start + mapping.season_length;
+
+                // The first of several files with the same number is used
+                let mut selected_episodes: BTreeMap<u32, &PlexEpisode> = BTreeMap::new();
+                for (i, episode) in season.episodes.iter().enumerate() {
+                    // Specials shown within a season keep their own season number
+                    if episode
+                        .parent_index
+                        .is_some_and(|x| x != u32::from(season.index))
+                    {
+                        continue;
+                    }
+
+                    let number = get_episode_number(episode, i);
+                    if number >= start && number < end {
+                        selected_episodes.entry(number).or_insert(episode);
+                    }
+                }
+
+                if let Some(last) = selected_episodes.keys().next_back().copied() {
+                    let missing_episodes: Vec<u32> = (start..last)
+                        .filter(|x| !selected_episodes.contains_key(x))
+                        .collect();
+                    if !missing_episodes.is_empty() {
+                        gaps.push(EpisodeGap {
+                            anime_list_id,
+                            plex_id: season.rating_key.clone(),
+                            missing_episodes,
+                        });
+                    }
+                }
+
+                plex_episodes.extend(selected_episodes.into_values().cloned());
             }
         }
     }
 
-    let episodes = match relevant_mappings.get(0) {
+    let episodes = match relevant_mappings.first() {
         Some(x) => x.episodes,
         None => None,
     };
@@ -75,24 +251,93 @@ pub fn get_plex_episodes_for_anime_list_id(
         plex_episodes,
         episodes,
         anime_list_id,
+        gaps,
     };
 }
 
+/// The rating keys of the episodes that are unwatched in Plex but covered by the progress of the
+/// Anilist entry. Each mapping numbers its Plex episodes from `episode_start` on Anilist.
+pub fn get_episodes_to_mark_watched(
+    all_plex_series: &[PlexSeries],
+    all_mappings: &[Mapping],
+    anime_list_id: u32,
+    progress: u16,
+) -> Vec<String> {
+    let mut rating_keys = vec![];
+    for mapping in all_mappings
+        .iter()
+        .filter(|x| x.anime_list_id == anime_list_id)
+    {
+        let seasons = all_plex_series
+            .iter()
+            .flat_map(|x| x.seasons.iter())
+            .filter(|x| x.rating_key == mapping.plex_id);
+        for season in seasons {
+            for (i, episode) in season.episodes.iter().enumerate() {
+                let number = get_episode_number(episode, i);
+                if number < mapping.plex_episode_start
+                    || number >= mapping.plex_episode_start + mapping.season_length
+                {
+                    continue;
+                }
+
+                let anilist_number = mapping.episode_start + number - mapping.plex_episode_start;
+                if anilist_number <= u32::from(progress)
+                    && episode.view_count == 0
+                    && !rating_keys.contains(&episode.rating_key)
+                {
+                    rating_keys.push(episode.rating_key.clone());
+                }
+            }
+        }
+    }
+
+    rating_keys
+}
+
+/// Whether a Plex series or season the Anilist entry is mapped to has the label or is in a
+/// collection with that name.
+pub fn has_mapped_label(
+    all_plex_series: &[PlexSeries],
+    all_mappings: &[Mapping],
+    anime_list_id: u32,
+    label: &str,
+) -> bool {
+    all_mappings
+        .iter()
+        .filter(|x| x.anime_list_id == anime_list_id)
+        .any(|mapping| {
+            all_plex_series
+                .iter()
+                .filter(|x| x.rating_key == mapping.plex_series_id)
+                .any(|series| {
+                    series.has_label(label)
+                        || series
+                            .seasons
+                            .iter()
+                            .any(|x| x.rating_key == mapping.plex_id && x.has_label(label))
+                })
+        })
+}
+
 fn get_watch_status(
     anime_entry_representation: AnimeEntryPlexRepresentation,
+    rules: &StatusRules,
 ) -> AnilistWatchStatus {
     let episodes_watched = anime_entry_representation
         .plex_episodes
         .iter()
-        .filter(|x| x.view_count > 0)
+        .filter(|x| x.is_watched(rules.watched_percent))
         .count();
     let episodes_watched: u16 = u16::try_from(episodes_watched).unwrap();
+    // An episode that's partially watched is enough to be watching the series
+    let started = anime_entry_representation
+        .plex_episodes
+        .iter()
+        .any(|x| x.is_started());
 
     let total_episodes = anime_entry_representation.episodes;
-
-    if total_episodes == Some(episodes_watched) {
-        return AnilistWatchStatus::Completed;
-    }
+    let is_rewatching = get_rewatched_episodes(&anime_entry_representation.plex_episodes).is_some();
 
     let last_viewed_at = anime_entry_representation
         .plex_episodes
@@ -101,24 +346,33 @@ fn get_watch_status(
         .max()
         .unwrap_or(None);
 
-    let dropped_threshold = Utc::now() - Duration::days(30);
-    if episodes_watched > 0
-        && last_viewed_at.is_some()
-        && last_viewed_at.unwrap() <= dropped_threshold.timestamp()
-    {
+    let inactive_for = |days: Option<u32>| match (days, last_viewed_at) {
+        (Some(days), Some(last_viewed_at)) => {
+            let threshold = Utc::now() - Duration::days(days.into());
+            started && last_viewed_at <= threshold.timestamp()
+        }
+        _ => false,
+    };
+
+    if total_episodes == Some(episodes_watched) {
+        // A rewatch that was left alone doesn't take away the completion
+        let inactive =
+            inactive_for(rules.dropped_after_days) || inactive_for(rules.paused_after_days);
+        return match is_rewatching && !inactive {
+            true => AnilistWatchStatus::Repeating,
+            false => AnilistWatchStatus::Completed,
+        };
+    }
+
+    if inactive_for(rules.dropped_after_days) {
         return AnilistWatchStatus::Dropped;
     }
 
-    let paused_threshold = Utc::now() - Duration::days(14);
-    if episodes_watched > 0
-        && last_viewed_at.is_some()
-        && last_viewed_at.unwrap() <= paused_threshold.timestamp()
-    {
+    if inactive_for(rules.paused_after_days) {
         return AnilistWatchStatus::Paused;
     }
 
-    if episodes_watched > 0 && (total_episodes.is_none() || Some(episodes_watched) < total_episodes)
-    {
+    if started && (total_episodes.is_none() || Some(episodes_watched) < total_episodes) {
         return AnilistWatchStatus::Current;
     }
 
@@ -127,7 +381,7 @@ fn get_watch_status(
 
 #[cfg(test)]
 mod tests {
-    use crate::services::plex::plex_api::PlexSeason;
+    use crate::services::plex::plex_api::{PlexMediaKind, PlexSeason};
     use chrono::{Duration, Utc};
 
     use super::*;
@@ -137,12 +391,16 @@ mod tests {
         let current = AnimeListEntry {
             media_id: 1234567,
             progress: 3,
+            updated_at: None,
+            private: false,
             status: AnilistWatchStatus::Completed,
         };
 
         let new = AnimeListEntry {
             media_id: 16498,
             progress: 3,
+            updated_at: None,
+            private: false,
             status: AnilistWatchStatus::Completed,
         };
 
@@ -154,12 +412,16 @@ mod tests {
         let current = AnimeListEntry {
             media_id: 16498,
             progress: 3,
+            updated_at: None,
+            private: false,
             status: AnilistWatchStatus::Planning,
         };
 
         let new = AnimeListEntry {
             media_id: 16498,
             progress: 3,
+            updated_at: None,
+            private: false,
             status: AnilistWatchStatus::Completed,
         };
 
@@ -171,12 +433,16 @@ mod tests {
         let current = AnimeListEntry {
             media_id: 16498,
             progress: 3,
+            updated_at: None,
+            private: false,
             status: AnilistWatchStatus::Completed,
         };
 
         let new = AnimeListEntry {
             media_id: 16498,
             progress: 4,
+            updated_at: None,
+            private: false,
             status: AnilistWatchStatus::Completed,
         };
 
@@ -188,12 +454,16 @@ mod tests {
         let current = AnimeListEntry {
             media_id: 16498,
             progress: 3,
+            updated_at: None,
+            private: false,
             status: AnilistWatchStatus::Completed,
         };
 
         let new = AnimeListEntry {
             media_id: 16498,
             progress: 3,
+            updated_at: None,
+            private: false,
             status: AnilistWatchStatus::Completed,
         };
 
@@ -203,6 +473,7 @@ mod tests {
     #[test]
     fn test_plex_series_to_animelist_entry() {
         let anime_entry_representation = AnimeEntryPlexRepresentation {
+            gaps: vec![],
             episodes: Some(3),
             anime_list_id: 16498,
             plex_episodes: vec![
@@ -210,16 +481,34 @@ mod tests {
                     view_count: 1,
                     rating_key: "1".to_string(),
                     last_viewed_at: Some(12345),
+                    first_viewed_at: None,
+                    index: None,
+                    parent_index: None,
+                    originally_available_at: None,
+                    view_offset: None,
+                    duration: None,
                 },
                 PlexEpisode {
                     view_count: 1,
                     rating_key: "2".to_string(),
                     last_viewed_at: Some(12345),
+                    first_viewed_at: None,
+                    index: None,
+                    parent_index: None,
+                    originally_available_at: None,
+                    view_offset: None,
+                    duration: None,
                 },
                 PlexEpisode {
                     view_count: 1,
                     rating_key: "3".to_string(),
                     last_viewed_at: Some(12345),
+                    first_viewed_at: None,
+                    index: None,
+                    parent_index: None,
+                    originally_available_at: None,
+                    view_offset: None,
+                    duration: None,
                 },
             ],
         };
@@ -227,9 +516,12 @@ mod tests {
         let expected = AnimeListEntry {
             media_id: 16498,
             progress: 3,
+            updated_at: None,
+            private: false,
             status: AnilistWatchStatus::Completed,
         };
-        let result = plex_series_to_animelist_entry(anime_entry_representation);
+        let result =
+            plex_series_to_animelist_entry(anime_entry_representation, &StatusRules::default());
 
         assert_eq!(expected, result);
     }
@@ -237,6 +529,7 @@ mod tests {
     #[test]
     fn test_get_watch_status_complete() {
         let anime_entry_representation = AnimeEntryPlexRepresentation {
+            gaps: vec![],
             episodes: Some(3),
             anime_list_id: 6789,
             plex_episodes: vec![
@@ -244,20 +537,38 @@ mod tests {
                     view_count: 1,
                     rating_key: "1".to_string(),
                     last_viewed_at: Some(12345),
+                    first_viewed_at: None,
+                    index: None,
+                    parent_index: None,
+                    originally_available_at: None,
+                    view_offset: None,
+                    duration: None,
                 },
                 PlexEpisode {
                     view_count: 1,
                     rating_key: "2".to_string(),
                     last_viewed_at: Some(12345),
+                    first_viewed_at: None,
+                    index: None,
+                    parent_index: None,
+                    originally_available_at: None,
+                    view_offset: None,
+                    duration: None,
                 },
                 PlexEpisode {
                     view_count: 1,
                     rating_key: "3".to_string(),
                     last_viewed_at: Some(12345),
+                    first_viewed_at: None,
+                    index: None,
+                    parent_index: None,
+                    originally_available_at: None,
+                    view_offset: None,
+                    duration: None,
                 },
             ],
         };
-        let result = get_watch_status(anime_entry_representation);
+        let result = get_watch_status(anime_entry_representation, &StatusRules::default());
 
         assert_eq!(AnilistWatchStatus::Completed, result);
     }
@@ -265,6 +576,7 @@ mod tests {
     #[test]
     fn test_get_watch_status_planning() {
         let anime_entry_representation = AnimeEntryPlexRepresentation {
+            gaps: vec![],
             episodes: Some(3),
             anime_list_id: 6789,
             plex_episodes: vec![
@@ -272,20 +584,38 @@ mod tests {
                     view_count: 0,
                     rating_key: "1".to_string(),
                     last_viewed_at: None,
+                    first_viewed_at: None,
+                    index: None,
+                    parent_index: None,
+                    originally_available_at: None,
+                    view_offset: None,
+                    duration: None,
                 },
                 PlexEpisode {
                     view_count: 0,
                     rating_key: "2".to_string(),
                     last_viewed_at: None,
+                    first_viewed_at: None,
+                    index: None,
+                    parent_index: None,
+                    originally_available_at: None,
+                    view_offset: None,
+                    duration: None,
                 },
                 PlexEpisode {
                     view_count: 0,
                     rating_key: "3".to_string(),
                     last_viewed_at: None,
+                    first_viewed_at: None,
+                    index: None,
+                    parent_index: None,
+                    originally_available_at: None,
+                    view_offset: None,
+                    duration: None,
                 },
             ],
         };
-        let result = get_watch_status(anime_entry_representation);
+        let result = get_watch_status(anime_entry_representation, &StatusRules::default());
 
         assert_eq!(AnilistWatchStatus::Planning, result);
     }
@@ -295,6 +625,7 @@ mod tests {
         let a_month_ago = Utc::now() - Duration::days(30);
 
         let anime_entry_representation = AnimeEntryPlexRepresentation {
+            gaps: vec![],
             episodes: Some(3),
             anime_list_id: 6789,
             plex_episodes: vec![
@@ -302,20 +633,111 @@ mod tests {
                     view_count: 1,
                     rating_key: "1".to_string(),
                     last_viewed_at: Some(a_month_ago.timestamp()),
+                    first_viewed_at: None,
+                    index: None,
+                    parent_index: None,
+                    originally_available_at: None,
+                    view_offset: None,
+                    duration: None,
                 },
                 PlexEpisode {
                     view_count: 0,
                     rating_key: "2".to_string(),
                     last_viewed_at: None,
+                    first_viewed_at: None,
+                    index: None,
+                    parent_index: None,
+                    originally_available_at: None,
+                    view_offset: None,
+                    duration: None,
                 },
                 PlexEpisode {
                     view_count: 0,
                     rating_key: "3".to_string(),
                     last_viewed_at: None,
+                    first_viewed_at: None,
+                    index: None,
+                    parent_index: None,
+                    originally_available_at: None,
+                    view_offset: None,
+                    duration: None,
                 },
             ],
         };
-        let result = get_watch_status(anime_entry_representation);
+        let result = get_watch_status(anime_entry_representation, &StatusRules::default());
+
+        assert_eq!(AnilistWatchStatus::Dropped, result);
+    }
+
+    #[test]
+    fn test_get_watch_status_dropped_disabled() {
+        let a_month_ago = Utc::now() - Duration::days(30);
+        let rules = StatusRules {
+            dropped_after_days: None,
+            ..Default::default()
+        };
+
+        let anime_entry_representation = AnimeEntryPlexRepresentation {
+            gaps: vec![],
+            episodes: Some(3),
+            anime_list_id: 6789,
+            plex_episodes: vec![
+                PlexEpisode {
+                    view_count: 1,
+                    rating_key: "1".to_string(),
+                    last_viewed_at: Some(a_month_ago.timestamp()),
+                    first_viewed_at: None,
+                    index: None,
+                    parent_index: None,
+                    originally_available_at: None,
+                    view_offset: None,
+                    duration: None,
+                },
+                PlexEpisode {
+                    view_count: 0,
+                    rating_key: "2".to_string(),
+                    last_viewed_at: None,
+                    first_viewed_at: None,
+                    index: None,
+                    parent_index: None,
+                    originally_available_at: None,
+                    view_offset: None,
+                    duration: None,
+                },
+            ],
+        };
+        let result = get_watch_status(anime_entry_representation, &rules);
+
+        assert_eq!(AnilistWatchStatus::Paused, result);
+    }
+
+    #[test]
+    fn test_get_watch_status_custom_thresholds() {
+        let ten_days_ago = Utc::now() - Duration::days(10);
+        let rules = StatusRules {
+            dropped_after_days: Some(7),
+            paused_after_days: None,
+            update_planning: false,
+            ..Default::default()
+        };
+
+        let anime_entry_representation = AnimeEntryPlexRepresentation {
+            gaps: vec![],
+            episodes: Some(3),
+            anime_list_id: 6789,
+            plex_episodes: vec![PlexEpisode {
+                view_count: 1,
+                rating_key: "1".to_string(),
+                last_viewed_at: Some(ten_days_ago.timestamp()),
+                first_viewed_at: None,
+                index: None,
+                parent_index: None,
+                originally_available_at: None,
+                view_offset: None,
+                duration: None,
+            }],
+        };
+        let result = get_watch_status(anime_entry_representation, &rules);
 
         assert_eq!(AnilistWatchStatus::Dropped, result);
     }
@@ -327,6 +749,7 @@ mod tests {
         let a_month_ago = now - Duration::days(30);
 
         let anime_entry_representation = AnimeEntryPlexRepresentation {
+            gaps: vec![],
             episodes: Some(3),
             anime_list_id: 6789,
             plex_episodes: vec![
@@ -334,20 +757,38 @@ mod tests {
                     rating_key: "1".to_string(),
                     view_count: 1,
                     last_viewed_at: Some(two_weeks_ago.timestamp()),
+                    first_viewed_at: None,
+                    index: None,
+                    parent_index: None,
+                    originally_available_at: None,
+                    view_offset: None,
+                    duration: None,
                 },
                 PlexEpisode {
                     rating_key: "2".to_string(),
                     view_count: 0,
                     last_viewed_at: None,
+                    first_viewed_at: None,
+                    index: None,
+                    parent_index: None,
+                    originally_available_at: None,
+                    view_offset: None,
+                    duration: None,
                 },
                 PlexEpisode {
                     rating_key: "3".to_string(),
                     view_count: 1,
                     last_viewed_at: Some(a_month_ago.timestamp()),
+                    first_viewed_at: None,
+                    index: None,
+                    parent_index: None,
+                    originally_available_at: None,
+                    view_offset: None,
+                    duration: None,
                 },
             ],
         };
-        let result = get_watch_status(anime_entry_representation);
+        let result = get_watch_status(anime_entry_representation, &StatusRules::default());
 
         assert_eq!(AnilistWatchStatus::Paused, result);
     }
@@ -357,6 +798,7 @@ mod tests {
         let now = Utc::now();
 
         let anime_entry_representation = AnimeEntryPlexRepresentation {
+            gaps: vec![],
             episodes: Some(3),
             anime_list_id: 6789,
             plex_episodes: vec![
@@ -364,20 +806,38 @@ mod tests {
                     rating_key: "1".to_string(),
                     view_count: 1,
                     last_viewed_at: Some(now.timestamp()),
+                    first_viewed_at: None,
+                    index: None,
+                    parent_index: None,
+                    originally_available_at: None,
+                    view_offset: None,
+                    duration: None,
                 },
                 PlexEpisode {
                     rating_key: "2".to_string(),
                     view_count: 0,
                     last_viewed_at: None,
+                    first_viewed_at: None,
+                    index: None,
+                    parent_index: None,
+                    originally_available_at: None,
+                    view_offset: None,
+                    duration: None,
                 },
                 PlexEpisode {
                     rating_key: "3".to_string(),
                     view_count: 0,
                     last_viewed_at: None,
+                    first_viewed_at: None,
+                    index: None,
+                    parent_index: None,
+                    originally_available_at: None,
+                    view_offset: None,
+                    duration: None,
                 },
             ],
         };
-        let result = get_watch_status(anime_entry_representation);
+        let result = get_watch_status(anime_entry_representation, &StatusRules::default());
 
         assert_eq!(AnilistWatchStatus::Current, result);
     }
@@ -385,10 +845,15 @@ mod tests {
     #[test]
     fn test_get_plex_episodes_for_anime_list_id_multiple_mappings_across_multiple_plex_seasons() {
         let all_plex_series = vec![PlexSeries {
+            guids: vec![],
+            labels: vec![],
+            kind: PlexMediaKind::Show,
             title: "".to_string(),
             rating_key: "1234".to_string(),
             seasons: vec![
                 PlexSeason {
+                    guids: vec![],
+                    labels: vec![],
                     rating_key: "17457".to_string(),
                     index: 1,
                     parent_title: "".to_string(),
@@ -397,15 +862,29 @@ mod tests {
                             rating_key: "1".to_string(),
                             view_count: 1,
                             last_viewed_at: Some(12345),
+                            first_viewed_at: None,
+                            index: None,
+                            parent_index: None,
+                            originally_available_at: None,
+                            view_offset: None,
+                            duration: None,
                         },
                         PlexEpisode {
                             rating_key: "2".to_string(),
                             view_count: 1,
                             last_viewed_at: Some(12345),
+                            first_viewed_at: None,
+                            index: None,
+                            parent_index: None,
+                            originally_available_at: None,
+                            view_offset: None,
+                            duration: None,
                         },
                     ],
                 },
                 PlexSeason {
+                    guids: vec![],
+                    labels: vec![],
                     rating_key: "12345".to_string(),
                     index: 2,
                     parent_title: "".to_string(),
@@ -414,11 +893,23 @@ mod tests {
                             rating_key: "3".to_string(),
                             view_count: 1,
                             last_viewed_at: Some(12345),
+                            first_viewed_at: None,
+                            index: None,
+                            parent_index: None,
+                            originally_available_at: None,
+                            view_offset: None,
+                            duration: None,
                         },
                         PlexEpisode {
                             rating_key: "4".to_string(),
                             view_count: 1,
                             last_viewed_at: Some(12345),
+                            first_viewed_at: None,
+                            index: None,
+                            parent_index: None,
+                            originally_available_at: None,
+                            view_offset: None,
+                            duration: None,
                         },
                     ],
                 },
@@ -464,9 +955,14 @@ mod tests {
     #[test]
     fn test_get_plex_episodes_for_anime_list_id() {
         let all_plex_series = vec![PlexSeries {
+            guids: vec![],
+            labels: vec![],
+            kind: PlexMediaKind::Show,
             title: "".to_string(),
             rating_key: "1234".to_string(),
             seasons: vec![PlexSeason {
+                guids: vec![],
+                labels: vec![],
                 rating_key: "17457".to_string(),
                 index: 1,
                 parent_title: "".to_string(),
@@ -475,11 +971,23 @@ mod tests {
                         rating_key: "1".to_string(),
                         view_count: 1,
                         last_viewed_at: Some(12345),
+                        first_viewed_at: None,
+                        index: None,
+                        parent_index: None,
+                        originally_available_at: None,
+                        view_offset: None,
+                        duration: None,
                     },
                     PlexEpisode {
                         rating_key: "2".to_string(),
                         view_count: 1,
                         last_viewed_at: Some(12345),
+                        first_viewed_at: None,
+                        index: None,
+                        parent_index: None,
+                        originally_available_at: None,
+                        view_offset: None,
+                        duration: None,
                     },
                 ],
             }],
@@ -507,9 +1015,14 @@ mod tests {
     #[test]
     fn test_get_plex_episodes_for_anime_list_id_when_plex_season_has_more_episodes_than_mapping() {
         let all_plex_series = vec![PlexSeries {
+            guids: vec![],
+            labels: vec![],
+            kind: PlexMediaKind::Show,
             title: "".to_string(),
             rating_key: "1234".to_string(),
             seasons: vec![PlexSeason {
+                guids: vec![],
+                labels: vec![],
                 rating_key: "17457".to_string(),
                 index: 1,
                 parent_title: "".to_string(),
@@ -518,11 +1031,23 @@ mod tests {
                         rating_key: "1".to_string(),
                         view_count: 1,
                         last_viewed_at: Some(12345),
+                        first_viewed_at: None,
+                        index: None,
+                        parent_index: None,
+                        originally_available_at: None,
+                        view_offset: None,
+                        duration: None,
                     },
                     PlexEpisode {
                         rating_key: "2".to_string(),
                         view_count: 1,
                         last_viewed_at: Some(12345),
+                        first_viewed_at: None,
+                        index: None,
+                        parent_index: None,
+                        originally_available_at: None,
+                        view_offset: None,
+                        duration: None,
                     },
                 ],
             }],
@@ -550,9 +1075,14 @@ mod tests {
     #[test]
     fn test_get_plex_episodes_for_anime_list_id_when_plex_season_has_less_episodes_than_mapping() {
         let all_plex_series = vec![PlexSeries {
+            guids: vec![],
+            labels: vec![],
+            kind: PlexMediaKind::Show,
             title: "".to_string(),
             rating_key: "1234".to_string(),
             seasons: vec![PlexSeason {
+                guids: vec![],
+                labels: vec![],
                 index: 1,
                 parent_title: "".to_string(),
                 rating_key: "17457".to_string(),
@@ -560,6 +1090,12 @@ mod tests {
                     rating_key: "1".to_string(),
                     view_count: 1,
                     last_viewed_at: Some(12345),
+                    first_viewed_at: None,
+                    index: None,
+                    parent_index: None,
+                    originally_available_at: None,
+                    view_offset: None,
+                    duration: None,
                 }],
             }],
         }];
@@ -581,4 +1117,288 @@ mod tests {
 
         assert_eq!(1, result.plex_episodes.len());
     }
+
+    fn episode(rating_key: &str, index: Option<u32>) -> PlexEpisode {
+        PlexEpisode {
+            rating_key: rating_key.to_string(),
+            view_count: 1,
+            last_viewed_at: Some(12345),
+            first_viewed_at: None,
+            index,
+            parent_index: Some(1),
+            originally_available_at: None,
+            view_offset: None,
+            duration: None,
+        }
+    }
+
+    #[test]
+    fn test_get_plex_episodes_for_anime_list_id_by_episode_index() {
+        // Episode 3 is missing and episode 2 is there twice, the second cour starts at episode 5.
+        // The special numbered 3 is shown within the season but isn't part of it.
+        let special = PlexEpisode {
+            parent_index: Some(0),
+            ..episode("s3", Some(3))
+        };
+        let all_plex_series = vec![PlexSeries {
+            guids: vec![],
+            labels: vec![],
+            kind: PlexMediaKind::Show,
+            title: "".to_string(),
+            rating_key: "1234".to_string(),
+            seasons: vec![PlexSeason {
+                guids: vec![],
+                labels: vec![],
+                index: 1,
+                parent_title: "".to_string(),
+                rating_key: "17457".to_string(),
+                episodes: vec![
+                    episode("1", Some(1)),
+                    episode("2", Some(2)),
+                    episode("2b", Some(2)),
+                    special,
+                    episode("4", Some(4)),
+                    episode("5", Some(5)),
+                    episode("6", Some(6)),
+                ],
+            }],
+        }];
+        let mapping = |anime_list_id, plex_episode_start| Mapping {
+            id: 1,
+            list_provider_id: 1,
+            plex_id: "17457".to_string(),
+            plex_series_id: "1234".to_string(),
+            plex_episode_start,
+            season_length: 4,
+            anime_list_id,
+            episode_start: 1,
+            enabled: true,
+            ignored: false,
+            episodes: Some(4),
+        };
+        let all_mappings = vec![mapping(16498, 1), mapping(20958, 5)];
+
+        let first = get_plex_episodes_for_anime_list_id(&all_plex_series, &all_mappings, 16498);
+        let second = get_plex_episodes_for_anime_list_id(&all_plex_series, &all_mappings, 20958);
+
+        let keys: Vec<&str> = first
+            .plex_episodes
+            .iter()
+            .map(|x| x.rating_key.as_str())
+            .collect();
+        assert_eq!(vec!["1", "2", "4"], keys);
+        assert_eq!(
+            vec![EpisodeGap {
+                anime_list_id: 16498,
+                plex_id: "17457".to_string(),
+                missing_episodes: vec![3],
+            }],
+            first.gaps
+        );
+        assert_eq!(2, second.plex_episodes.len());
+        assert!(second.gaps.is_empty());
+    }
+
+    #[test]
+    fn test_plex_series_to_animelist_entry_when_rewatching() {
+        let now = Utc::now().timestamp();
+        let watched = |rating_key: &str, first_viewed_at: i64, last_viewed_at: i64| PlexEpisode {
+            rating_key: rating_key.to_string(),
+            view_count: 1,
+            last_viewed_at: Some(last_viewed_at),
+            first_viewed_at: Some(first_viewed_at),
+            index: None,
+            parent_index: None,
+            originally_available_at: None,
+            view_offset: None,
+            duration: None,
+        };
+        let entry = |plex_episodes| AnimeEntryPlexRepresentation {
+            gaps: vec![],
+            episodes: Some(3),
+            anime_list_id: 16498,
+            plex_episodes,
+        };
+        let completed_at = now - 100_000;
+
+        // The first two episodes were watched again after the series was completed
+        let rewatching = entry(vec![
+            watched("1", completed_at - 2000, now - 200),
+            watched("2", completed_at - 1000, now - 100),
+            watched("3", completed_at, completed_at),
+        ]);
+        let result = plex_series_to_animelist_entry(rewatching, &StatusRules::default());
+        assert_eq!(AnilistWatchStatus::Repeating, result.status);
+        assert_eq!(2, result.progress);
+
+        let rewatched = entry(vec![
+            watched("1", completed_at - 2000, now - 200),
+            watched("2", completed_at - 1000, now - 100),
+            watched("3", completed_at, now),
+        ]);
+        let result = plex_series_to_animelist_entry(rewatched, &StatusRules::default());
+        assert_eq!(AnilistWatchStatus::Completed, result.status);
+        assert_eq!(3, result.progress);
+    }
+
+    #[test]
+    fn test_plex_series_to_animelist_entry_with_partially_watched_episodes() {
+        let now = Utc::now().timestamp();
+        let playing = |rating_key: &str, view_offset| PlexEpisode {
+            view_count: 0,
+            last_viewed_at: Some(now),
+            view_offset: Some(view_offset),
+            duration: Some(1_440_000),
+            ..episode(rating_key, None)
+        };
+        let entry = |plex_episodes| AnimeEntryPlexRepresentation {
+            gaps: vec![],
+            episodes: Some(3),
+            anime_list_id: 16498,
+            plex_episodes,
+        };
+
+        // Played to 95% counts as watched, 30% doesn't
+        let result = plex_series_to_animelist_entry(
+            entry(vec![
+                episode("1", None),
+                playing("2", 1_368_000),
+                playing("3", 432_000),
+            ]),
+            &StatusRules::default(),
+        );
+        assert_eq!(AnilistWatchStatus::Current, result.status);
+        assert_eq!(2, result.progress);
+
+        // A started episode is enough to be watching
+        let result = plex_series_to_animelist_entry(
+            entry(vec![playing("1", 432_000)]),
+            &StatusRules::default(),
+        );
+        assert_eq!(AnilistWatchStatus::Current, result.status);
+        assert_eq!(0, result.progress);
+
+        let rules = StatusRules {
+            watched_percent: 25,
+            ..Default::default()
+        };
+        let result = plex_series_to_animelist_entry(entry(vec![playing("1", 432_000)]), &rules);
+        assert_eq!(1, result.progress);
+    }
+
+    #[test]
+    fn test_get_episodes_to_mark_watched() {
+        let unwatched = |rating_key: &str, index| PlexEpisode {
+            view_count: 0,
+            last_viewed_at: None,
+            ..episode(rating_key, Some(index))
+        };
+        let all_plex_series = vec![PlexSeries {
+            guids: vec![],
+            labels: vec![],
+            kind: PlexMediaKind::Show,
+            title: "".to_string(),
+            rating_key: "1234".to_string(),
+            seasons: vec![PlexSeason {
+                guids: vec![],
+                labels: vec![],
+                index: 1,
+                parent_title: "".to_string(),
+                rating_key: "17457".to_string(),
+                episodes: vec![
+                    episode("1", Some(1)),
+                    unwatched("2", 2),
+                    unwatched("3", 3),
+                    unwatched("4", 4),
+                    unwatched("5", 5),
+                ],
+            }],
+        }];
+        // The second cour is numbered 13 and up on Anilist
+        let all_mappings = vec![Mapping {
+            id: 1,
+            list_provider_id: 1,
+            plex_id: "17457".to_string(),
+            plex_series_id: "1234".to_string(),
+            plex_episode_start: 2,
+            season_length: 4,
+            anime_list_id: 20958,
+            episode_start: 13,
+            enabled: true,
+            ignored: false,
+            episodes: Some(24),
+        }];
+
+        let result = get_episodes_to_mark_watched(&all_plex_series, &all_mappings, 20958, 14);
+
+        assert_eq!(vec!["2", "3"], result);
+    }
+
+    #[test]
+    fn test_has_mapped_label() {
+        let season = |rating_key: &str, labels: Vec<String>| PlexSeason {
+            guids: vec![],
+            labels,
+            index: 1,
+            parent_title: "".to_string(),
+            rating_key: rating_key.to_string(),
+            episodes: vec![],
+        };
+        let all_plex_series = vec![PlexSeries {
+            guids: vec![],
+            labels: vec![],
+            kind: PlexMediaKind::Show,
+            title: "".to_string(),
+            rating_key: "1234".to_string(),
+            seasons: vec![
+                season("17457", vec![]),
+                season("17458", vec!["anisync-private".to_string()]),
+            ],
+        }];
+        let mapping = |plex_id: &str, anime_list_id| Mapping {
+            id: 1,
+            list_provider_id: 1,
+            plex_id: plex_id.to_string(),
+            plex_series_id: "1234".to_string(),
+            plex_episode_start: 1,
+            season_length: 12,
+            anime_list_id,
+            episode_start: 1,
+            enabled: true,
+            ignored: false,
+            episodes: Some(12),
+        };
+        let all_mappings = vec![mapping("17457", 16498), mapping("17458", 20958)];
+
+        assert!(!has_mapped_label(
+            &all_plex_series,
+            &all_mappings,
+            16498,
+            "anisync-private"
+        ));
+        assert!(has_mapped_label(
+            &all_plex_series,
+            &all_mappings,
+            20958,
+            "Anisync-Private"
+        ));
+    }
+
+    #[test]
+    fn test_conflict_policy() {
+        let current = AnimeListEntry {
+            media_id: 1,
+            status: AnilistWatchStatus::Current,
+            progress: 5,
+            updated_at: Some(2000),
+            private: false,
+        };
+
+        assert!(ConflictPolicy::Anilist.anilist_wins(&current, Some(3000)));
+        assert!(!ConflictPolicy::Plex.anilist_wins(&current, None));
+        assert!(ConflictPolicy::Newest.anilist_wins(&current, Some(1000)));
+        assert!(!ConflictPolicy::Newest.anilist_wins(&current, Some(3000)));
+        assert_eq!(Ok(ConflictPolicy::Newest), "Newest".parse());
+        assert!("both".parse::<ConflictPolicy>().is_err());
+    }
 }
@@ -0,0 +1,317 @@
+use std::fmt::Write;
+
+use serde::Serialize;
+
+use crate::services::{
+    anime_list_service::anime_list_service::{AnilistWatchStatus, AnimeListEntry},
+    plex::plex_api::SeriesFetchError,
+};
+
+use super::sync_handler::EpisodeGap;
+
+#[derive(Debug, Default, Clone, PartialEq, Serialize)]
+pub struct SyncReport {
+    /// The Plex Home user that was synced, `None` for the server owner
+    pub plex_user: Option<String>,
+    pub dry_run: bool,
+    pub updated: u32,
+    pub failed: u32,
+    pub unchanged: u32,
+    /// Entries that weren't synced because a series they're mapped to couldn't be loaded
+    pub skipped: u32,
+    pub changes: Vec<PlannedChange>,
+    pub fetch_errors: Vec<SeriesFetchError>,
+    /// Mapped episodes missing from Plex, they aren't counted towards progress
+    pub episode_gaps: Vec<EpisodeGap>,
+    /// Entries whose progress on Anilist was written to Plex
+    pub plex_changes: Vec<PlexChange>,
+}
+
+impl SyncReport {
+    pub fn is_success(&self) -> bool {
+        self.failed == 0 && self.fetch_errors.is_empty()
+    }
+
+    /// Formats the changes as a table with one row per Anilist entry.
+    pub fn to_table(&self) -> String {
+        let mut table = match &self.plex_user {
+            Some(x) => format!("Plex user '{}'\n", x),
+            None => String::new(),
+        };
+        table.push_str(&format!(
+            "{:>8}  {:<6}  {:>10}  {:>10}  {:>8}  {:>8}  {}\n",
+            "media id", "action", "old status", "new status", "old prog", "new prog", "title"
+        ));
+        for change in self.changes.iter() {
+            let private = match change.make_private {
+                true => " (made private)",
+                false => "",
+            };
+            let _ = writeln!(
+                table,
+                "{:>8}  {:<6}  {:>10}  {:>10}  {:>8}  {:>8}  {}{}",
+                change.media_id,
+                change.action.to_string(),
+                format_optional(change.old_status.as_ref().map(format_status)),
+                format_status(&change.new_status),
+                format_optional(change.old_progress),
+                change.new_progress,
+                change.title.as_deref().unwrap_or("Unknown"),
+                private
+            );
+        }
+
+        let _ = write!(
+            table,
+            "{} changes. {} updated, {} failed, {} unchanged",
+            self.changes.len(),
+            self.updated,
+            self.failed,
+            self.unchanged
+        );
+        if self.skipped > 0 {
+            let _ = write!(table, ", {} skipped", self.skipped);
+        }
+        if self.dry_run {
+            table.push_str(" (dry run, nothing was changed)");
+        }
+        if !self.plex_changes.is_empty() {
+            table.push_str("\nMarked as watched in Plex to catch up with Anilist:");
+            for change in self.plex_changes.iter() {
+                let _ = write!(
+                    table,
+                    "\n  {} ({}): {} episodes, up to episode {}",
+                    change.title.as_deref().unwrap_or("Unknown"),
+                    change.media_id,
+                    change.episodes.len(),
+                    change.progress
+                );
+            }
+        }
+        if !self.fetch_errors.is_empty() {
+            table.push_str("\nThese Plex series couldn't be loaded, the entries mapped to them weren't synced:");
+            for e in self.fetch_errors.iter() {
+                let _ = write!(table, "\n  {}", e);
+            }
+        }
+        if !self.episode_gaps.is_empty() {
+            table.push_str("\nThese entries have episodes missing in Plex:");
+            for gap in self.episode_gaps.iter() {
+                let _ = write!(table, "\n  {}", gap);
+            }
+        }
+
+        table
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ChangeAction {
+    Add,
+    Update,
+}
+
+impl std::fmt::Display for ChangeAction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ChangeAction::Add => write!(f, "add"),
+            ChangeAction::Update => write!(f, "update"),
+        }
+    }
+}
+
+/// Episodes marked as watched in Plex because the Anilist entry was further along.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct PlexChange {
+    pub media_id: u32,
+    pub title: Option<String>,
+    /// The progress on Anilist
+    pub progress: u16,
+    /// The rating keys of the episodes
+    pub episodes: Vec<String>,
+}
+
+/// A change to a single Anilist entry. The old values are `None` when the entry isn't on the list
+/// yet.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct PlannedChange {
+    pub media_id: u32,
+    pub title: Option<String>,
+    pub action: ChangeAction,
+    pub old_status: Option<AnilistWatchStatus>,
+    pub new_status: AnilistWatchStatus,
+    pub old_progress: Option<u16>,
+    pub new_progress: u16,
+    /// The entry is made private on Anilist
+    pub make_private: bool,
+}
+
+/// Compares the current list entry with the entry built from Plex. Returns `None` if nothing
+/// needs to change.
+pub fn get_planned_change(
+    current: Option<&AnimeListEntry>,
+    new: &AnimeListEntry,
+) -> Option<PlannedChange> {
+    let (action, old_status, old_progress) = match current {
+        None => (ChangeAction::Add, None, None),
+        Some(x)
+            if x.status == new.status
+                && x.progress == new.progress
+                && (x.private || !new.private) =>
+        {
+            return None
+        }
+        Some(x) => (
+            ChangeAction::Update,
+            Some(x.status.clone()),
+            Some(x.progress),
+        ),
+    };
+
+    Some(PlannedChange {
+        media_id: new.media_id,
+        title: None,
+        action,
+        old_status,
+        new_status: new.status.clone(),
+        old_progress,
+        new_progress: new.progress,
+        make_private: new.private && !current.is_some_and(|x| x.private),
+    })
+}
+
+fn format_status(status: &AnilistWatchStatus) -> String {
+    format!("{:?}", status).to_lowercase()
+}
+
+fn format_optional<T: ToString>(value: Option<T>) -> String {
+    match value {
+        Some(x) => x.to_string(),
+        None => "-".to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(media_id: u32, status: AnilistWatchStatus, progress: u16) -> AnimeListEntry {
+        AnimeListEntry {
+            media_id,
+            status,
+            progress,
+            updated_at: None,
+            private: false,
+        }
+    }
+
+    #[test]
+    fn test_get_planned_change_when_not_on_list() {
+        let new = entry(1, AnilistWatchStatus::Current, 3);
+
+        let result = get_planned_change(None, &new).expect("Expected a change");
+
+        assert_eq!(ChangeAction::Add, result.action);
+        assert_eq!(None, result.old_status);
+        assert_eq!(None, result.old_progress);
+        assert_eq!(AnilistWatchStatus::Current, result.new_status);
+        assert_eq!(3, result.new_progress);
+    }
+
+    #[test]
+    fn test_get_planned_change_when_progress_changed() {
+        let current = entry(1, AnilistWatchStatus::Current, 3);
+        let new = entry(1, AnilistWatchStatus::Completed, 12);
+
+        let result = get_planned_change(Some(&current), &new).expect("Expected a change");
+
+        assert_eq!(ChangeAction::Update, result.action);
+        assert_eq!(Some(AnilistWatchStatus::Current), result.old_status);
+        assert_eq!(Some(3), result.old_progress);
+        assert_eq!(AnilistWatchStatus::Completed, result.new_status);
+        assert_eq!(12, result.new_progress);
+    }
+
+    #[test]
+    fn test_get_planned_change_when_unchanged() {
+        let current = entry(1, AnilistWatchStatus::Current, 3);
+        let new = entry(1, AnilistWatchStatus::Current, 3);
+
+        assert_eq!(None, get_planned_change(Some(&current), &new));
+    }
+
+    #[test]
+    fn test_get_planned_change_when_made_private() {
+        let current = entry(1, AnilistWatchStatus::Current, 3);
+        let mut new = entry(1, AnilistWatchStatus::Current, 3);
+        new.private = true;
+
+        let result = get_planned_change(Some(&current), &new).expect("Expected a change");
+        assert!(result.make_private);
+
+        // Entries that are already private aren't changed
+        assert_eq!(None, get_planned_change(Some(&new), &new));
+    }
+
+    #[test]
+    fn test_report_to_table() {
+        let mut change = get_planned_change(None, &entry(21, AnilistWatchStatus::Current, 3))
+            .expect("Expected a change");
+        change.title = Some("One Piece".to_string());
+        let report = SyncReport {
+            dry_run: true,
+            unchanged: 2,
+            changes: vec![change],
+            ..Default::default()
+        };
+
+        let table = report.to_table();
+        let lines: Vec<&str> = table.lines().collect();
+
+        assert_eq!(3, lines.len());
+        assert!(lines[1].contains("21"));
+        assert!(lines[1].contains("add"));
+        assert!(lines[1].contains("current"));
+        assert!(lines[1].ends_with("One Piece"));
+        assert!(lines[2].contains("(dry run, nothing was changed)"));
+    }
+
+    #[test]
+    fn test_report_with_fetch_errors() {
+        let report = SyncReport {
+            skipped: 2,
+            fetch_errors: vec![SeriesFetchError {
+                rating_key: "17456".to_string(),
+                title: "Sword Art Online".to_string(),
+                error: "HTTP status server error (500 Internal Server Error)".to_string(),
+            }],
+            ..Default::default()
+        };
+
+        let table = report.to_table();
+        let lines: Vec<&str> = table.lines().collect();
+
+        assert!(!report.is_success());
+        assert!(lines[1].ends_with(", 2 skipped"));
+        assert!(lines[3].contains("'Sword Art Online' (17456)"));
+    }
+
+    #[test]
+    fn test_report_serializes_changes() {
+        let change = get_planned_change(None, &entry(21, AnilistWatchStatus::Current, 3))
+            .expect("Expected a change");
+        let report = SyncReport {
+            dry_run: true,
+            changes: vec![change],
+            ..Default::default()
+        };
+
+        let json = serde_json::to_value(&report).expect("Failed to serialize report");
+
+        assert_eq!(21, json["changes"][0]["media_id"]);
+        assert_eq!("add", json["changes"][0]["action"]);
+        assert_eq!("CURRENT", json["changes"][0]["new_status"]);
+        assert!(json["changes"][0]["old_status"].is_null());
+    }
+}
@@ -0,0 +1,1269 @@
+use std::{
+    collections::{HashMap, HashSet},
+    future::Future,
+    pin::pin,
+    slice,
+    time::Instant,
+};
+
+use anyhow::Context;
+use futures::TryStreamExt;
+use log::{error, info, warn};
+
+use crate::services::{
+    anime_list_service::{
+        anilist_service::{AnilistService, InvalidTokenError},
+        anime_list_service::{AnilistWatchStatus, AnimeListEntry, AnimeListService},
+    },
+    config::config::{Config, PlexUser},
+    dbstore::{
+        dbstore::DbStore,
+        sqlite::{EpisodeViews, Mapping, SyncRun, SyncRunStatus},
+    },
+    mapping_handler::{
+        id_mappings::IdMappings,
+        mapping_handler::{MappingHandler, MappingHandlerInterface},
+    },
+    media_server::media_server::{MediaServer, MediaServerKind},
+    metrics::metrics,
+    plex::{
+        library_selector::select_libraries,
+        plex_api::{PlexInterface, PlexSeries, ResponsePlexLibrary, SeriesFetchError},
+        plex_api_service::{get_full_series_data, stream_series_data, PlexApi},
+        plex_tv::PlexTv,
+    },
+};
+
+use super::{
+    sync_handler::{
+        get_episodes_to_mark_watched, get_plex_episodes_for_anime_list_id, has_mapped_label,
+        plex_series_to_animelist_entry, StatusRules,
+    },
+    sync_report::{get_planned_change, PlexChange, SyncReport},
+    watch_history::{
+        apply_episode_views, get_episode_views, update_watch_history, OWNER_ACCOUNT_ID,
+    },
+};
+
+/// The Anilist entries to update after something was watched in one Plex series.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SyncTarget {
+    pub library_key: String,
+    pub series_rating_key: String,
+    pub series_title: String,
+    pub anime_ids: Vec<u32>,
+    /// The Plex account that watched the episode
+    pub plex_user: Option<String>,
+}
+
+/// The tokens used to sync one Plex user to their Anilist account.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SyncAccount {
+    /// `None` for the server owner
+    pub plex_user: Option<String>,
+    /// The id of the account on the Plex server, used to read its watch history
+    pub plex_account_id: u64,
+    pub plex_token: String,
+    pub anilist_token: String,
+}
+
+impl SyncAccount {
+    fn describe(&self) -> String {
+        match &self.plex_user {
+            Some(x) => format!("Plex user '{}'", x),
+            None => "the server owner".to_string(),
+        }
+    }
+}
+
+/// Runs a sync for every account and records the outcomes in the sync run history. An account
+/// failing doesn't stop the others from syncing.
+pub async fn run_sync<D>(db_store: &D, config: &Config) -> Vec<Result<SyncReport, anyhow::Error>>
+where
+    D: DbStore + Clone,
+{
+    let plex_tv = PlexTv::new(config.plex_token.clone(), None);
+
+    let mut results = vec![];
+    for user in get_sync_users(config) {
+        let sync = async {
+            let account = get_sync_account(config, user, &plex_tv).await?;
+            sync(db_store, config, &account).await
+        };
+        let plex_user = user.map(|x| x.plex_user.as_str());
+        results.push(record_run(db_store, config.dry_run, "full", plex_user, sync).await);
+    }
+
+    results
+}
+
+/// Syncs only the given Anilist entries, used when Plex tells us something was watched. Returns
+/// `None` when the Plex account that watched isn't synced.
+pub async fn run_targeted_sync<D>(
+    db_store: &D,
+    config: &Config,
+    target: &SyncTarget,
+) -> Result<Option<SyncReport>, anyhow::Error>
+where
+    D: DbStore + Clone,
+{
+    let users = get_sync_users(config);
+    let user = match users.iter().flatten().find(|x| {
+        target
+            .plex_user
+            .as_deref()
+            .is_some_and(|name| x.plex_user.eq_ignore_ascii_case(name))
+    }) {
+        Some(x) => Some(*x),
+        // Anyone not paired with an Anilist account is treated as the server owner
+        None if users.contains(&None) => None,
+        None => {
+            info!(
+                "Plex user '{}' isn't paired with an Anilist account, skipping '{}'",
+                target.plex_user.as_deref().unwrap_or("Unknown"),
+                target.series_title
+            );
+            return Ok(None);
+        }
+    };
+
+    let plex_tv = PlexTv::new(config.plex_token.clone(), None);
+    let sync = async {
+        let account = get_sync_account(config, user, &plex_tv).await?;
+        sync_target(db_store, config, &account, target).await
+    };
+    let plex_user = user.map(|x| x.plex_user.as_str());
+    record_run(db_store, config.dry_run, "targeted", plex_user, sync)
+        .await
+        .map(Some)
+}
+
+/// The server owner, when they're logged in to Anilist or there's nobody else to sync, followed
+/// by the configured Plex Home users.
+fn get_sync_users(config: &Config) -> Vec<Option<&PlexUser>> {
+    let mut users = vec![];
+    if config.anilist_token.is_some() || config.users.is_empty() {
+        users.push(None);
+    }
+    users.extend(config.users.iter().map(Some));
+    users
+}
+
+/// Gets the tokens to sync a user with. Plex Home users are switched to through plex.tv to get
+/// their own token for the Plex server, so their own watch state is read. Jellyfin and Emby users
+/// are read with the API key by their name instead.
+pub async fn get_sync_account(
+    config: &Config,
+    user: Option<&PlexUser>,
+    plex_tv: &PlexTv,
+) -> Result<SyncAccount, anyhow::Error> {
+    if config.media_server != MediaServerKind::Plex {
+        return Ok(SyncAccount {
+            plex_user: user.map(|x| x.plex_user.clone()),
+            plex_account_id: OWNER_ACCOUNT_ID,
+            plex_token: config.media_server_api_key.clone(),
+            anilist_token: match user {
+                Some(x) => x.anilist_token.clone(),
+                None => config.get_anilist_token()?,
+            },
+        });
+    }
+
+    let user = match user {
+        Some(x) => x,
+        None => {
+            return Ok(SyncAccount {
+                plex_user: None,
+                plex_account_id: OWNER_ACCOUNT_ID,
+                plex_token: config.plex_token.clone(),
+                anilist_token: config.get_anilist_token()?,
+            })
+        }
+    };
+
+    let plex_service = PlexApi::new(config.plex_url.clone(), config.plex_token.clone());
+    let machine_identifier = plex_service
+        .get_machine_identifier()
+        .await
+        .context("Failed to get the Plex server identity")?;
+
+    let home_users = plex_tv
+        .get_home_users()
+        .await
+        .context("Failed to get Plex Home users")?;
+    let home_user = match home_users.iter().find(|x| x.has_name(&user.plex_user)) {
+        Some(x) => x,
+        None => anyhow::bail!("There is no Plex Home user called '{}'", user.plex_user),
+    };
+    if home_user.protected && user.pin.is_none() {
+        anyhow::bail!(
+            "Plex user '{}' is protected with a PIN, add it to the config",
+            user.plex_user
+        );
+    }
+
+    let user_token = plex_tv
+        .switch_user(home_user, user.pin.as_deref())
+        .await
+        .with_context(|| format!("Failed to switch to Plex user '{}'", user.plex_user))?;
+    let plex_token = plex_tv
+        .get_server_token(&user_token, &machine_identifier)
+        .await
+        .with_context(|| format!("Failed to get a server token for '{}'", user.plex_user))?;
+
+    Ok(SyncAccount {
+        plex_user: Some(user.plex_user.clone()),
+        plex_account_id: home_user.id,
+        plex_token,
+        anilist_token: user.anilist_token.clone(),
+    })
+}
+
+/// Connects to the media server as the account being synced.
+async fn connect_media_server(
+    config: &Config,
+    account: &SyncAccount,
+) -> Result<MediaServer, anyhow::Error> {
+    MediaServer::connect_as(config, account.plex_user.as_deref(), &account.plex_token)
+        .await
+        .with_context(|| format!("Failed to connect to {}", config.media_server))
+}
+
+/// Records the run in the sync run history and its duration in the metrics. `kind` is `full` or
+/// `targeted`.
+async fn record_run<D>(
+    db_store: &D,
+    dry_run: bool,
+    kind: &'static str,
+    plex_user: Option<&str>,
+    sync: impl Future<Output = Result<SyncReport, anyhow::Error>>,
+) -> Result<SyncReport, anyhow::Error>
+where
+    D: DbStore + Clone,
+{
+    let started = Instant::now();
+    let run_id = match db_store.start_sync_run(dry_run, plex_user).await {
+        Ok(x) => Some(x),
+        Err(e) => {
+            warn!("Failed to record the start of the sync. {}", e);
+            None
+        }
+    };
+
+    let result = sync.await;
+
+    let mut run = SyncRun {
+        id: run_id.unwrap_or(0),
+        status: SyncRunStatus::Succeeded,
+        dry_run,
+        started_at: 0,
+        finished_at: None,
+        updated: 0,
+        failed: 0,
+        unchanged: 0,
+        error: None,
+        plex_user: plex_user.map(String::from),
+    };
+    match &result {
+        Ok(report) => {
+            if !report.is_success() {
+                run.status = SyncRunStatus::Failed;
+            }
+            if !report.fetch_errors.is_empty() {
+                run.error = Some(format!(
+                    "Failed to load {} Plex series, the entries mapped to them were skipped",
+                    report.fetch_errors.len()
+                ));
+            }
+            run.updated = report.updated;
+            run.failed = report.failed;
+            run.unchanged = report.unchanged;
+        }
+        Err(e) => {
+            run.status = SyncRunStatus::Failed;
+            run.error = Some(format!("{:#}", e));
+        }
+    }
+    let status = match run.status {
+        SyncRunStatus::Failed => "failed",
+        _ => "succeeded",
+    };
+    metrics::record_sync(kind, status, started.elapsed());
+
+    if run_id.is_some() {
+        if let Err(e) = db_store.finish_sync_run(&run).await {
+            warn!("Failed to record the result of the sync. {}", e);
+        }
+    }
+
+    match (result, plex_user) {
+        (Ok(report), plex_user) => Ok(SyncReport {
+            plex_user: plex_user.map(String::from),
+            ..report
+        }),
+        (Err(e), Some(plex_user)) => {
+            Err(e.context(format!("Sync of Plex user '{}' failed", plex_user)))
+        }
+        (Err(e), None) => Err(e),
+    }
+}
+
+async fn sync<D>(
+    db_store: &D,
+    config: &Config,
+    account: &SyncAccount,
+) -> Result<SyncReport, anyhow::Error>
+where
+    D: DbStore + Clone,
+{
+    match config.dry_run {
+        true => info!(
+            "----- Plex Ani Sync started for {} (dry run) -----",
+            account.describe()
+        ),
+        false => info!(
+            "----- Plex Ani Sync started for {} -----",
+            account.describe()
+        ),
+    }
+
+    info!("Creating {} service", config.media_server);
+    let plex_service = connect_media_server(config, account).await?;
+    let libraries = get_sync_libraries(&plex_service, config).await?;
+
+    info!("Creating Anilist service");
+    let anilist_service =
+        AnilistService::new(account.anilist_token.clone(), db_store.clone(), None);
+    let anime_list = get_anime_list(&anilist_service).await?;
+
+    let id_mappings = match IdMappings::load(&config.id_mapping_file) {
+        Ok(x) => x,
+        Err(e) => {
+            warn!("{:#}, series are matched by title only", e);
+            IdMappings::default()
+        }
+    };
+    let mapping_handler = MappingHandler::new(db_store.clone(), config.dry_run, id_mappings);
+    let episode_views = load_watch_history(db_store, &plex_service, account).await;
+
+    // An entry can be mapped to seasons of several series and is only synced once all of them
+    // are loaded
+    let mut entry_series: HashMap<u32, HashSet<String>> = HashMap::new();
+    let mut entry_seasons: HashMap<u32, HashSet<String>> = HashMap::new();
+    for mapping in db_store.get_mappings().await? {
+        if mapping.enabled && !mapping.ignored {
+            entry_series
+                .entry(mapping.anime_list_id)
+                .or_default()
+                .insert(mapping.plex_series_id);
+            entry_seasons
+                .entry(mapping.anime_list_id)
+                .or_default()
+                .insert(mapping.plex_id);
+        }
+    }
+
+    let mut report = SyncReport {
+        dry_run: config.dry_run,
+        ..Default::default()
+    };
+
+    // Series are loaded a page at a time and dropped once every entry they're mapped to is
+    // synced, so large libraries aren't held in memory all at once
+    let mut series: Vec<PlexSeries> = vec![];
+    let mut series_rules = HashMap::new();
+    let mut mappings: Vec<Mapping> = vec![];
+    let mut loaded = HashSet::new();
+    let mut incomplete = HashSet::new();
+    let mut excluded = HashSet::new();
+    let mut done = HashSet::new();
+    for library in libraries.iter() {
+        info!("Getting series for library '{}'", library.title);
+        let rules = config.get_status_rules(library);
+        let mut pages = pin!(stream_series_data(&plex_service, library));
+        while let Some(page) = pages
+            .try_next()
+            .await
+            .with_context(|| format!("Failed to get Plex series for '{}'", library.title))?
+        {
+            for s in page.into_iter() {
+                let mut s = match s {
+                    Ok(x) => x,
+                    Err(e) => {
+                        incomplete.insert(e.rating_key.clone());
+                        report.fetch_errors.push(e);
+                        continue;
+                    }
+                };
+                info!("Checking mappings for '{}': {}", s.title, loaded.len() + 1);
+                apply_episode_views(&mut s, &episode_views);
+                if s.has_label(&config.exclude_label) {
+                    info!(
+                        "Skipping '{}', it's labelled '{}'",
+                        s.title, config.exclude_label
+                    );
+                    excluded.insert(s.rating_key.clone());
+                    loaded.insert(s.rating_key.clone());
+                    continue;
+                }
+                for season in s.seasons.iter() {
+                    if season.has_label(&config.exclude_label) {
+                        info!(
+                            "Skipping season {} of '{}', it's labelled '{}'",
+                            season.index, s.title, config.exclude_label
+                        );
+                        excluded.insert(season.rating_key.clone());
+                    }
+                }
+                // Mappings are collected from here rather than reloaded from the database, new
+                // mappings aren't saved during a dry run. Excluded seasons are still passed in
+                // since sequels are followed from one season to the next.
+                let mut series_mappings = match mapping_handler
+                    .create_mapping(&anilist_service, &s, &excluded)
+                    .await
+                {
+                    Ok(x) => x,
+                    Err(e) => {
+                        warn!("Failed to create mappings for '{}'. {}", s.title, e);
+                        mapping_handler
+                            .get_all_relevant_mappings(slice::from_ref(&s))
+                            .await
+                    }
+                };
+                s.seasons.retain(|x| !excluded.contains(&x.rating_key));
+                series_mappings
+                    .retain(|x| x.enabled && !x.ignored && !excluded.contains(&x.plex_id));
+                for mapping in series_mappings.iter() {
+                    entry_series
+                        .entry(mapping.anime_list_id)
+                        .or_default()
+                        .insert(mapping.plex_series_id.clone());
+                    entry_seasons
+                        .entry(mapping.anime_list_id)
+                        .or_default()
+                        .insert(mapping.plex_id.clone());
+                }
+                mappings.append(&mut series_mappings);
+
+                loaded.insert(s.rating_key.clone());
+                series_rules.insert(s.rating_key.clone(), rules);
+                series.push(s);
+            }
+
+            let ready = get_ready_entries(
+                &mappings,
+                &entry_series,
+                &entry_seasons,
+                &loaded,
+                &incomplete,
+                &excluded,
+                &mut done,
+            );
+            sync_entries(
+                &anilist_service,
+                &plex_service,
+                config,
+                &anime_list,
+                &series,
+                &mappings,
+                &series_rules,
+                &ready,
+                &mut report,
+            )
+            .await;
+            done.extend(ready);
+
+            mappings.retain(|x| !done.contains(&x.anime_list_id));
+            let needed: HashSet<&String> = mappings.iter().map(|x| &x.plex_series_id).collect();
+            series.retain(|x| needed.contains(&x.rating_key));
+            series_rules.retain(|k, _| needed.contains(k));
+        }
+    }
+    info!("Done checking mappings");
+
+    // Entries mapped to series that weren't found in any library
+    let remaining: Vec<u32> = get_anime_ids(&mappings)
+        .into_iter()
+        .filter(|x| !done.contains(x))
+        .filter(|x| !is_incomplete(entry_series.get(x), &incomplete))
+        .filter(|x| !is_excluded(entry_series.get(x), entry_seasons.get(x), &excluded))
+        .collect();
+    sync_entries(
+        &anilist_service,
+        &plex_service,
+        config,
+        &anime_list,
+        &series,
+        &mappings,
+        &series_rules,
+        &remaining,
+        &mut report,
+    )
+    .await;
+
+    report.skipped = entry_series
+        .values()
+        .filter(|x| is_incomplete(Some(x), &incomplete))
+        .count() as u32;
+    for e in report.fetch_errors.iter() {
+        warn!("Skipped the entries mapped to {}", e);
+    }
+
+    info!(
+        "----- Plex Ani Sync finished for {}. {} changes, {} updated, {} failed, {} unchanged -----",
+        account.describe(),
+        report.changes.len(),
+        report.updated,
+        report.failed,
+        report.unchanged
+    );
+
+    // ulimit changed with "ulimit -n 256" to go back to default
+    // use command "ulimit -n"
+
+    Ok(report)
+}
+
+async fn sync_target<D>(
+    db_store: &D,
+    config: &Config,
+    account: &SyncAccount,
+    target: &SyncTarget,
+) -> Result<SyncReport, anyhow::Error>
+where
+    D: DbStore + Clone,
+{
+    info!(
+        "----- Plex Ani Sync started for '{}' watched by {} -----",
+        target.series_title,
+        account.describe()
+    );
+
+    let plex_service = connect_media_server(config, account).await?;
+    let libraries = plex_service
+        .get_libraries()
+        .await
+        .context("Failed to get Plex libraries")?;
+    let library = match select_libraries(libraries, &config.libraries)
+        .into_iter()
+        .find(|x| x.key == target.library_key)
+    {
+        Some(x) => x,
+        None => anyhow::bail!(
+            "Plex library {} isn't selected for syncing",
+            target.library_key
+        ),
+    };
+    let rules = config.get_status_rules(&library);
+
+    // The Anilist entries can also be mapped to seasons of other series, those are needed to get
+    // the full progress
+    let mappings: Vec<Mapping> = db_store
+        .get_mappings()
+        .await?
+        .into_iter()
+        .filter(|x| x.enabled && !x.ignored && target.anime_ids.contains(&x.anime_list_id))
+        .collect();
+    let mut series_keys = vec![target.series_rating_key.clone()];
+    for mapping in mappings.iter() {
+        if !series_keys.contains(&mapping.plex_series_id) {
+            series_keys.push(mapping.plex_series_id.clone());
+        }
+    }
+
+    let episode_views = load_watch_history(db_store, &plex_service, account).await;
+    let mut series = vec![];
+    let mut series_rules = HashMap::new();
+    for rating_key in series_keys {
+        let mut s = match plex_service
+            .get_series(&rating_key)
+            .await
+            .with_context(|| format!("Failed to get Plex series {}", rating_key))?
+        {
+            Some(x) => PlexSeries::from(x),
+            None => anyhow::bail!("Plex series {} doesn't exist", rating_key),
+        };
+        plex_service
+            .populate_seasons(&mut s)
+            .await
+            .with_context(|| format!("Failed to get Plex seasons for '{}'", s.title))?;
+        apply_episode_views(&mut s, &episode_views);
+        series_rules.insert(s.rating_key.clone(), rules);
+        series.push(s);
+    }
+
+    let anime_ids: Vec<u32> = target
+        .anime_ids
+        .iter()
+        .copied()
+        .filter(|x| {
+            let excluded = has_mapped_label(&series, &mappings, *x, &config.exclude_label);
+            if excluded {
+                info!(
+                    "Skipping Anilist entry {}, it's mapped to Plex media labelled '{}'",
+                    x, config.exclude_label
+                );
+            }
+            !excluded
+        })
+        .collect();
+
+    let anilist_service =
+        AnilistService::new(account.anilist_token.clone(), db_store.clone(), None);
+    let anime_list = get_anime_list(&anilist_service).await?;
+
+    let mut report = SyncReport {
+        dry_run: config.dry_run,
+        ..Default::default()
+    };
+    sync_entries(
+        &anilist_service,
+        &plex_service,
+        config,
+        &anime_list,
+        &series,
+        &mappings,
+        &series_rules,
+        &anime_ids,
+        &mut report,
+    )
+    .await;
+
+    info!(
+        "----- Plex Ani Sync finished for '{}'. {} changes, {} updated, {} failed, {} unchanged -----",
+        target.series_title,
+        report.changes.len(),
+        report.updated,
+        report.failed,
+        report.unchanged
+    );
+
+    Ok(report)
+}
+
+async fn get_anime_list<D>(
+    anilist_service: &AnilistService<D>,
+) -> Result<Vec<AnimeListEntry>, anyhow::Error>
+where
+    D: DbStore,
+{
+    info!("Getting Anilist user");
+    let anilist_user = match anilist_service.get_user().await {
+        Ok(x) => x,
+        Err(e) if e.is::<InvalidTokenError>() => {
+            warn!("Anilist rejected the token, log in again from the web UI at /auth/anilist");
+            return Err(e);
+        }
+        Err(e) => return Err(e.context("Failed to get anilist user")),
+    };
+
+    info!("Getting Anilist list for '{}'", anilist_user.name);
+    anilist_service
+        .get_list(anilist_user.id)
+        .await
+        .context("Failed to get anilist list")
+}
+
+/// Brings the saved watch history of the account up to date and returns its views. Without the
+/// history entries are synced from what Plex reports for each episode.
+async fn load_watch_history<D>(
+    db_store: &D,
+    plex_service: &impl PlexInterface,
+    account: &SyncAccount,
+) -> HashMap<String, EpisodeViews>
+where
+    D: DbStore,
+{
+    if let Err(e) = update_watch_history(db_store, plex_service, account.plex_account_id).await {
+        warn!("Failed to load the Plex watch history. {:#}", e);
+    }
+
+    match get_episode_views(db_store, account.plex_account_id).await {
+        Ok(x) => x,
+        Err(e) => {
+            warn!("Failed to read the saved watch history. {:#}", e);
+            HashMap::new()
+        }
+    }
+}
+
+/// The Anilist ids the mappings point at, without duplicates. Several mappings can point at the
+/// same entry, they're all handled together.
+fn get_anime_ids(mappings: &[Mapping]) -> Vec<u32> {
+    let mut anime_ids = vec![];
+    let mut seen_ids = HashSet::new();
+    for mapping in mappings.iter() {
+        if seen_ids.insert(mapping.anime_list_id) {
+            anime_ids.push(mapping.anime_list_id);
+        }
+    }
+    anime_ids
+}
+
+/// The entries of `mappings` whose series are all loaded and that haven't been synced yet.
+/// Entries mapped to a series that's incomplete or excluded are added to `done` without syncing.
+/// An entry synced on an earlier page isn't synced again when a later page maps another series
+/// to it, the episodes of the series it was synced with are gone by then.
+fn get_ready_entries(
+    mappings: &[Mapping],
+    entry_series: &HashMap<u32, HashSet<String>>,
+    entry_seasons: &HashMap<u32, HashSet<String>>,
+    loaded: &HashSet<String>,
+    incomplete: &HashSet<String>,
+    excluded: &HashSet<String>,
+    done: &mut HashSet<u32>,
+) -> Vec<u32> {
+    let mut ready = vec![];
+    for anime_id in get_anime_ids(mappings) {
+        if done.contains(&anime_id) {
+            continue;
+        }
+
+        let keys = entry_series.get(&anime_id);
+        if is_incomplete(keys, incomplete)
+            || is_excluded(keys, entry_seasons.get(&anime_id), excluded)
+        {
+            done.insert(anime_id);
+        } else if keys.is_none_or(|x| x.iter().all(|k| loaded.contains(k))) {
+            ready.push(anime_id);
+        }
+    }
+    ready
+}
+
+/// Whether an entry is mapped to a series that couldn't be loaded completely. Syncing it would
+/// work out the progress from partial data.
+fn is_incomplete(series_keys: Option<&HashSet<String>>, incomplete: &HashSet<String>) -> bool {
+    series_keys.is_some_and(|x| x.iter().any(|k| incomplete.contains(k)))
+}
+
+/// Whether an entry is mapped to a Plex series or season that's excluded from syncing with a
+/// label. Entries are left alone even when only part of them is excluded.
+fn is_excluded(
+    series_keys: Option<&HashSet<String>>,
+    season_keys: Option<&HashSet<String>>,
+    excluded: &HashSet<String>,
+) -> bool {
+    [series_keys, season_keys]
+        .into_iter()
+        .flatten()
+        .flatten()
+        .any(|k| excluded.contains(k))
+}
+
+/// Works out the new status and progress of each Anilist entry from the mapped Plex episodes and
+/// updates the entries that changed, adding the outcome to `report`.
+#[allow(clippy::too_many_arguments)]
+async fn sync_entries<D>(
+    anilist_service: &AnilistService<D>,
+    plex_service: &impl PlexInterface,
+    config: &Config,
+    anime_list: &[AnimeListEntry],
+    series: &[PlexSeries],
+    mappings: &[Mapping],
+    series_rules: &HashMap<String, &StatusRules>,
+    anime_ids: &[u32],
+    report: &mut SyncReport,
+) where
+    D: DbStore,
+{
+    for anime_id in anime_ids.iter().copied() {
+        let mapping = match mappings.iter().find(|x| x.anime_list_id == anime_id) {
+            Some(x) => x,
+            None => continue,
+        };
+        let list_entry = anime_list.iter().find(|x| x.media_id == anime_id);
+
+        let mut thing = get_plex_episodes_for_anime_list_id(series, mappings, anime_id);
+        for gap in thing.gaps.drain(..) {
+            warn!("{}", gap);
+            report.episode_gaps.push(gap);
+        }
+        let rules = series_rules
+            .get(&mapping.plex_series_id)
+            .copied()
+            .unwrap_or(&config.status_rules);
+        let plex_last_viewed_at = thing.get_last_viewed_at();
+        let mut new_anilist_entry = plex_series_to_animelist_entry(thing, rules);
+        new_anilist_entry.private =
+            has_mapped_label(series, mappings, anime_id, &config.private_label);
+
+        // Rewatch progress can't be compared with the episodes watched in Plex
+        let anilist_ahead = list_entry.filter(|x| {
+            config.two_way_sync
+                && x.progress > new_anilist_entry.progress
+                && x.status != AnilistWatchStatus::Repeating
+                && new_anilist_entry.status != AnilistWatchStatus::Repeating
+        });
+        let mut wrote_back = false;
+        if let Some(current) = anilist_ahead {
+            if config
+                .conflict_policy
+                .anilist_wins(current, plex_last_viewed_at)
+            {
+                write_back_progress(
+                    anilist_service,
+                    plex_service,
+                    config,
+                    series,
+                    mappings,
+                    current,
+                    report,
+                )
+                .await;
+                // Anilist keeps its progress and the status that goes with it, other changes
+                // such as making the entry private are still applied
+                new_anilist_entry.progress = current.progress;
+                new_anilist_entry.status = current.status.clone();
+                wrote_back = true;
+            }
+        }
+
+        if !rules.update_planning && new_anilist_entry.status == AnilistWatchStatus::Planning {
+            continue;
+        }
+
+        let mut change = match get_planned_change(list_entry, &new_anilist_entry) {
+            Some(x) => x,
+            None => {
+                // Already counted when the progress was written back
+                if !wrote_back {
+                    report.unchanged += 1;
+                }
+                continue;
+            }
+        };
+
+        change.title = match anilist_service.get_anime(change.media_id).await {
+            Ok(Some(x)) => Some(x.get_title().to_string()),
+            _ => None,
+        };
+        info!(
+            "'{}' ({}) needs to {} list entry\n{:?}\n",
+            change.title.as_deref().unwrap_or("Unknown"),
+            change.media_id,
+            change.action,
+            new_anilist_entry
+        );
+        report.changes.push(change);
+
+        if config.dry_run {
+            continue;
+        }
+
+        let updated_entry = anilist_service
+            .update_list_entry(
+                new_anilist_entry.media_id,
+                new_anilist_entry.status,
+                new_anilist_entry.progress,
+                new_anilist_entry.private,
+            )
+            .await;
+
+        match updated_entry {
+            Ok(_) => {
+                info!("Update successful");
+                metrics::record_list_update(true);
+                report.updated += 1;
+            }
+            Err(e) => {
+                error!("Failed to update. Error: {}", e);
+                metrics::record_list_update(false);
+                report.failed += 1;
+            }
+        }
+    }
+}
+
+/// Marks the episodes covered by the progress of the Anilist entry as watched in Plex, so Plex
+/// catches up with episodes that were watched somewhere else.
+async fn write_back_progress<D>(
+    anilist_service: &AnilistService<D>,
+    plex_service: &impl PlexInterface,
+    config: &Config,
+    series: &[PlexSeries],
+    mappings: &[Mapping],
+    current: &AnimeListEntry,
+    report: &mut SyncReport,
+) where
+    D: DbStore,
+{
+    let episodes =
+        get_episodes_to_mark_watched(series, mappings, current.media_id, current.progress);
+    if episodes.is_empty() {
+        report.unchanged += 1;
+        return;
+    }
+
+    let title = match anilist_service.get_anime(current.media_id).await {
+        Ok(Some(x)) => Some(x.get_title().to_string()),
+        _ => None,
+    };
+    info!(
+        "'{}' ({}) is at episode {} on Anilist, marking {} episodes as watched in Plex",
+        title.as_deref().unwrap_or("Unknown"),
+        current.media_id,
+        current.progress,
+        episodes.len()
+    );
+
+    if !config.dry_run {
+        for rating_key in episodes.iter() {
+            if let Err(e) = plex_service.mark_watched(rating_key).await {
+                error!("Failed to mark Plex item {} as watched. {}", rating_key, e);
+                report.failed += 1;
+                return;
+            }
+        }
+        report.updated += 1;
+    }
+
+    report.plex_changes.push(PlexChange {
+        media_id: current.media_id,
+        title,
+        progress: current.progress,
+        episodes,
+    });
+}
+
+/// The show and movie libraries selected in the config.
+async fn get_sync_libraries(
+    plex_service: &impl PlexInterface,
+    config: &Config,
+) -> Result<Vec<ResponsePlexLibrary>, anyhow::Error> {
+    let libraries = plex_service
+        .get_libraries()
+        .await
+        .context("Failed to get Plex libraries")?;
+    let libraries = select_libraries(libraries, &config.libraries);
+    if libraries.is_empty() {
+        let selectors: Vec<String> = config.libraries.iter().map(|x| x.to_string()).collect();
+        anyhow::bail!("No Plex libraries match '{}'", selectors.join(", "));
+    }
+
+    Ok(libraries
+        .into_iter()
+        .filter(|x| {
+            let can_sync = x.is_show_library() || x.is_movie_library();
+            if !can_sync {
+                warn!(
+                    "Skipping library '{}', only show and movie libraries can be synced",
+                    x.title
+                );
+            }
+            can_sync
+        })
+        .collect())
+}
+
+/// The series of a library with their seasons and episodes.
+pub struct LibrarySeries {
+    pub library: ResponsePlexLibrary,
+    pub series: Vec<PlexSeries>,
+    /// Series whose seasons or episodes couldn't be loaded
+    pub fetch_errors: Vec<SeriesFetchError>,
+}
+
+/// Gets the series with their seasons and episodes for every library selected in the config.
+pub async fn get_library_series(
+    plex_service: &impl PlexInterface,
+    config: &Config,
+) -> Result<Vec<LibrarySeries>, anyhow::Error> {
+    let mut result = vec![];
+    for library in get_sync_libraries(plex_service, config).await? {
+        info!("Getting series for library '{}'", library.title);
+        let (series, fetch_errors) = get_full_series_data(plex_service, &library)
+            .await
+            .with_context(|| format!("Failed to get Plex series for '{}'", library.title))?;
+        for e in fetch_errors.iter() {
+            warn!("Failed to load {}", e);
+        }
+        if !fetch_errors.is_empty() {
+            warn!(
+                "{} series in '{}' couldn't be loaded",
+                fetch_errors.len(),
+                library.title
+            );
+        }
+        result.push(LibrarySeries {
+            library,
+            series,
+            fetch_errors,
+        });
+    }
+
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use wiremock::{
+        matchers::{body_partial_json, method, path},
+        Mock, MockServer, ResponseTemplate,
+    };
+
+    use crate::services::{
+        config::config::{ConfigLayer, PlexUserLayer},
+        dbstore::sqlite::Sqlite,
+        plex::plex_api::{PlexEpisode, PlexMediaKind, PlexSeason},
+    };
+
+    use super::*;
+
+    fn config(plex_url: &str, anilist_token: Option<&str>, pin: Option<&str>) -> Config {
+        Config::from_layer(ConfigLayer {
+            plex_url: Some(plex_url.to_string()),
+            plex_token: Some("owner123".to_string()),
+            anilist_token: anilist_token.map(String::from),
+            users: Some(vec![PlexUserLayer {
+                plex_user: "alice".to_string(),
+                anilist_token: Some("alice-anilist".to_string()),
+                pin: pin.map(String::from),
+            }]),
+            ..ConfigLayer::defaults()
+        })
+        .expect("Invalid config")
+    }
+
+    async fn mock_plex() -> MockServer {
+        let mock_server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/identity"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "MediaContainer": { "machineIdentifier": "server1" },
+            })))
+            .mount(&mock_server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/api/home/users"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "users": [{ "id": 2, "uuid": "abc123", "title": "Alice", "protected": true }],
+            })))
+            .mount(&mock_server)
+            .await;
+        Mock::given(method("POST"))
+            .and(path("/api/home/users/abc123/switch"))
+            .respond_with(
+                ResponseTemplate::new(201).set_body_json(json!({ "authToken": "alice123" })),
+            )
+            .mount(&mock_server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/api/v2/resources"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!([
+                { "clientIdentifier": "server1", "accessToken": "alice-server" },
+            ])))
+            .mount(&mock_server)
+            .await;
+        mock_server
+    }
+
+    #[test]
+    fn test_get_ready_entries_skips_entries_synced_on_earlier_pages() {
+        let mapping = |plex_series_id: &str| Mapping {
+            id: 0,
+            list_provider_id: 1,
+            plex_id: format!("{}1", plex_series_id),
+            plex_series_id: plex_series_id.to_string(),
+            plex_episode_start: 1,
+            season_length: 12,
+            anime_list_id: 5,
+            episode_start: 1,
+            enabled: true,
+            ignored: false,
+            episodes: Some(24),
+        };
+        let mut entry_series = HashMap::new();
+        let entry_seasons = HashMap::new();
+        let mut loaded = HashSet::new();
+        let mut done = HashSet::new();
+
+        // The first page maps the entry to series 10
+        entry_series.insert(5, HashSet::from(["10".to_string()]));
+        loaded.insert("10".to_string());
+        let ready = get_ready_entries(
+            &[mapping("10")],
+            &entry_series,
+            &entry_seasons,
+            &loaded,
+            &HashSet::new(),
+            &HashSet::new(),
+            &mut done,
+        );
+        assert_eq!(vec![5], ready);
+        done.extend(ready);
+
+        // The second page brings a new mapping of series 20 to the same entry
+        entry_series.entry(5).or_default().insert("20".to_string());
+        loaded.insert("20".to_string());
+        let ready = get_ready_entries(
+            &[mapping("20")],
+            &entry_series,
+            &entry_seasons,
+            &loaded,
+            &HashSet::new(),
+            &HashSet::new(),
+            &mut done,
+        );
+        assert!(ready.is_empty());
+    }
+
+    #[test]
+    fn test_get_sync_users_skips_owner_without_anilist_token() {
+        let config = config("http://localhost:32400", None, None);
+        let users = get_sync_users(&config);
+        assert_eq!(vec![Some(&config.users[0])], users);
+
+        let config = self::config("http://localhost:32400", Some("anilist123"), None);
+        let users = get_sync_users(&config);
+        assert_eq!(vec![None, Some(&config.users[0])], users);
+    }
+
+    #[tokio::test]
+    async fn test_get_sync_account_for_plex_home_user() {
+        let mock_server = mock_plex().await;
+        let config = config(&mock_server.uri(), None, Some("1234"));
+        let plex_tv = PlexTv::new(config.plex_token.clone(), Some(mock_server.uri()));
+
+        let result = get_sync_account(&config, Some(&config.users[0]), &plex_tv)
+            .await
+            .expect("Failed to get sync account");
+
+        assert_eq!(
+            SyncAccount {
+                plex_user: Some("alice".to_string()),
+                plex_account_id: 2,
+                plex_token: "alice-server".to_string(),
+                anilist_token: "alice-anilist".to_string(),
+            },
+            result
+        );
+    }
+
+    #[tokio::test]
+    async fn test_sync_entries_makes_private_after_writing_back() {
+        let anilist_server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(body_partial_json(json!({ "variables": { "anime_id": 5 } })))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "data": { "Media": {
+                    "id": 5,
+                    "format": "TV",
+                    "episodes": 3,
+                    "synonyms": [],
+                    "status": "FINISHED",
+                    "startDate": { "year": 2020 },
+                    "endDate": { "year": 2020 },
+                    "title": { "english": "Show", "romaji": "Show" },
+                    "relations": { "edges": [], "nodes": [] },
+                } },
+            })))
+            .mount(&anilist_server)
+            .await;
+        Mock::given(method("POST"))
+            .and(body_partial_json(json!({
+                "variables": { "media_id": 5, "status": "CURRENT", "progress": 2, "private": true },
+            })))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "data": { "SaveMediaListEntry": { "id": 1, "status": "CURRENT", "progress": 2 } },
+            })))
+            .expect(1)
+            .mount(&anilist_server)
+            .await;
+        let plex_server = MockServer::start().await;
+        Mock::given(path("/:/scrobble"))
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1)
+            .mount(&plex_server)
+            .await;
+
+        let mut db_store = Sqlite::new("sqlite::memory:").await;
+        db_store.migrate().await;
+        let anilist_service =
+            AnilistService::new("abc".to_string(), db_store, Some(anilist_server.uri()));
+        let plex_service = PlexApi::new(plex_server.uri(), "owner123".to_string());
+        let mut config = config(&plex_server.uri(), Some("anilist123"), None);
+        config.two_way_sync = true;
+        config.conflict_policy = "anilist".parse().expect("Invalid policy");
+
+        let episode = |rating_key: &str, view_count| PlexEpisode {
+            rating_key: rating_key.to_string(),
+            view_count,
+            last_viewed_at: None,
+            first_viewed_at: None,
+            index: None,
+            parent_index: None,
+            originally_available_at: None,
+            view_offset: None,
+            duration: None,
+        };
+        let series = vec![PlexSeries {
+            rating_key: "10".to_string(),
+            title: "Show".to_string(),
+            guids: vec![],
+            labels: vec![config.private_label.clone()],
+            kind: PlexMediaKind::Show,
+            seasons: vec![PlexSeason {
+                rating_key: "11".to_string(),
+                index: 1,
+                parent_title: "Show".to_string(),
+                guids: vec![],
+                labels: vec![],
+                episodes: vec![episode("e1", 1), episode("e2", 0), episode("e3", 0)],
+            }],
+        }];
+        let mappings = vec![Mapping {
+            id: 1,
+            list_provider_id: 1,
+            plex_id: "11".to_string(),
+            plex_series_id: "10".to_string(),
+            plex_episode_start: 1,
+            season_length: 3,
+            anime_list_id: 5,
+            episode_start: 1,
+            enabled: true,
+            ignored: false,
+            episodes: Some(3),
+        }];
+        let anime_list = vec![AnimeListEntry {
+            media_id: 5,
+            status: AnilistWatchStatus::Current,
+            progress: 2,
+            updated_at: None,
+            private: false,
+        }];
+        let mut report = SyncReport::default();
+
+        sync_entries(
+            &anilist_service,
+            &plex_service,
+            &config,
+            &anime_list,
+            &series,
+            &mappings,
+            &HashMap::new(),
+            &[5],
+            &mut report,
+        )
+        .await;
+
+        assert_eq!(1, report.plex_changes.len());
+        assert_eq!(vec!["e2".to_string()], report.plex_changes[0].episodes);
+        assert_eq!(1, report.changes.len());
+        assert!(report.changes[0].make_private);
+        assert_eq!(2, report.updated);
+        assert_eq!(0, report.unchanged);
+    }
+
+    #[tokio::test]
+    async fn test_get_sync_account_for_protected_user_without_pin() {
+        let mock_server = mock_plex().await;
+        let config = config(&mock_server.uri(), None, None);
+        let plex_tv = PlexTv::new(config.plex_token.clone(), Some(mock_server.uri()));
+
+        let result = get_sync_account(&config, Some(&config.users[0]), &plex_tv).await;
+
+        assert!(result.is_err());
+    }
+}
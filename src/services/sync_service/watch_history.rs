@@ -0,0 +1,220 @@
+use std::collections::HashMap;
+
+use log::info;
+
+use crate::services::{
+    dbstore::{
+        dbstore::DbStore,
+        sqlite::{EpisodeViews, WatchHistoryEntry},
+    },
+    plex::plex_api::{PlexInterface, PlexSeries},
+};
+
+/// The Plex server always gives its owner the local account id 1, Plex Home users keep their
+/// plex.tv id.
+pub const OWNER_ACCOUNT_ID: u64 = 1;
+
+/// Saves the views of a Plex account that were added to the Plex watch history since the last
+/// sync. Returns the number of views that were loaded.
+pub async fn update_watch_history<D>(
+    db_store: &D,
+    plex_service: &impl PlexInterface,
+    account_id: u64,
+) -> Result<usize, anyhow::Error>
+where
+    D: DbStore,
+{
+    // Views made in the same second as the last saved view are loaded again and skipped when
+    // saving
+    let since = db_store.get_last_watch_history_view(account_id).await?;
+
+    let mut count = 0;
+    let mut start = Some(0);
+    while let Some(page_start) = start {
+        let page = plex_service
+            .get_watch_history_page(account_id, since, page_start)
+            .await?;
+        start = page.next_start;
+
+        let views: Vec<WatchHistoryEntry> = page
+            .items
+            .into_iter()
+            .filter(|x| x.item_type == "episode" || x.item_type == "movie")
+            .filter(|x| x.account_id.is_none_or(|id| id == account_id))
+            .filter_map(|x| {
+                Some(WatchHistoryEntry {
+                    account_id,
+                    rating_key: x.rating_key?,
+                    viewed_at: x.viewed_at,
+                })
+            })
+            .collect();
+        count += views.len();
+        db_store.save_watch_history(&views).await?;
+    }
+
+    info!("Loaded {} views from the Plex watch history", count);
+    Ok(count)
+}
+
+/// The saved views of a Plex account by the rating key of the episode or movie.
+pub async fn get_episode_views<D>(
+    db_store: &D,
+    account_id: u64,
+) -> Result<HashMap<String, EpisodeViews>, anyhow::Error>
+where
+    D: DbStore,
+{
+    Ok(db_store
+        .get_episode_views(account_id)
+        .await?
+        .into_iter()
+        .map(|x| (x.rating_key.clone(), x))
+        .collect())
+}
+
+/// Adds when each episode was first watched. Whether an episode is watched and when it was last
+/// watched still come from Plex, so episodes marked as unwatched in Plex stay unwatched.
+pub fn apply_episode_views(series: &mut PlexSeries, views: &HashMap<String, EpisodeViews>) {
+    for episode in series
+        .seasons
+        .iter_mut()
+        .flat_map(|x| x.episodes.iter_mut())
+    {
+        if let Some(x) = views.get(&episode.rating_key) {
+            episode.first_viewed_at = Some(x.first_viewed_at);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use wiremock::{
+        matchers::{method, path, query_param},
+        Mock, MockServer, ResponseTemplate,
+    };
+
+    use crate::services::{
+        dbstore::sqlite::Sqlite,
+        plex::{
+            plex_api::{PlexEpisode, PlexMediaKind, PlexSeason},
+            plex_api_service::PlexApi,
+        },
+    };
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_update_watch_history() {
+        let mut db_store = Sqlite::new("sqlite::memory:").await;
+        db_store.migrate().await;
+        db_store
+            .save_watch_history(&[WatchHistoryEntry {
+                account_id: 2,
+                rating_key: "100".to_string(),
+                viewed_at: 1000,
+            }])
+            .await
+            .expect("Failed to save watch history");
+
+        let mock_server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/status/sessions/history/all"))
+            .and(query_param("accountID", "2"))
+            .and(query_param("viewedAt>", "1000"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "MediaContainer": {
+                    "totalSize": 4,
+                    "Metadata": [
+                        { "ratingKey": "100", "viewedAt": 1000, "accountID": 2, "type": "episode" },
+                        { "ratingKey": "101", "viewedAt": 2000, "accountID": 2, "type": "episode" },
+                        { "ratingKey": "200", "viewedAt": 2500, "accountID": 2, "type": "track" },
+                        { "viewedAt": 3000, "accountID": 2, "type": "episode" },
+                    ],
+                },
+            })))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+        let plex_service = PlexApi::new(mock_server.uri(), "abc123".to_string());
+
+        let result = update_watch_history(&db_store, &plex_service, 2)
+            .await
+            .expect("Failed to update watch history");
+        let views = get_episode_views(&db_store, 2)
+            .await
+            .expect("Failed to get views");
+
+        assert_eq!(2, result);
+        assert_eq!(2, views.len());
+        assert_eq!(1, views["100"].views);
+        assert_eq!(2000, views["101"].first_viewed_at);
+    }
+
+    #[test]
+    fn test_apply_episode_views() {
+        let episode = |rating_key: &str, last_viewed_at: Option<i64>| PlexEpisode {
+            rating_key: rating_key.to_string(),
+            view_count: i32::from(last_viewed_at.is_some()),
+            last_viewed_at,
+            first_viewed_at: None,
+            index: None,
+            parent_index: None,
+            originally_available_at: None,
+            view_offset: None,
+            duration: None,
+        };
+        let mut series = PlexSeries {
+            rating_key: "1".to_string(),
+            title: "".to_string(),
+            guids: vec![],
+            labels: vec![],
+            kind: PlexMediaKind::Show,
+            seasons: vec![PlexSeason {
+                rating_key: "2".to_string(),
+                index: 1,
+                parent_title: "".to_string(),
+                guids: vec![],
+                labels: vec![],
+                episodes: vec![
+                    episode("10", Some(500)),
+                    episode("11", None),
+                    episode("12", None),
+                ],
+            }],
+        };
+        let views = HashMap::from([
+            (
+                "10".to_string(),
+                EpisodeViews {
+                    rating_key: "10".to_string(),
+                    first_viewed_at: 100,
+                    last_viewed_at: 300,
+                    views: 2,
+                },
+            ),
+            // Watched before, then marked as unwatched in Plex
+            (
+                "12".to_string(),
+                EpisodeViews {
+                    rating_key: "12".to_string(),
+                    first_viewed_at: 200,
+                    last_viewed_at: 400,
+                    views: 1,
+                },
+            ),
+        ]);
+
+        apply_episode_views(&mut series, &views);
+
+        let episodes = &series.seasons[0].episodes;
+        assert_eq!(Some(100), episodes[0].first_viewed_at);
+        assert_eq!(Some(500), episodes[0].last_viewed_at);
+        assert_eq!(None, episodes[1].first_viewed_at);
+        assert_eq!(Some(200), episodes[2].first_viewed_at);
+        assert_eq!(None, episodes[2].last_viewed_at);
+        assert_eq!(0, episodes[2].view_count);
+        assert!(!episodes[2].is_watched(90));
+    }
+}
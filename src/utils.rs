@@ -1,5 +1,8 @@
 pub fn init_logger() {
-    let _ = tracing_subscriber::fmt::try_init();
+    // Log to stderr so command output on stdout can be piped into other tools
+    let _ = tracing_subscriber::fmt()
+        .with_writer(std::io::stderr)
+        .try_init();
 }
 
 pub fn get_db_file_location() -> String {